@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use rust_redis::command::Command;
+use rust_redis::db::Db;
+
+/// Commands issued per measured iteration, split evenly across the tasks.
+const OPS: usize = 32_768;
+const KEYS: usize = 10_000;
+
+/// A 50/50 mix of SET and GET over a fixed key range.
+fn command(i: usize) -> Command {
+    let key = format!("key:{}", i % KEYS);
+    if i.is_multiple_of(2) {
+        Command::Set(key, b"value".to_vec())
+    } else {
+        Command::Get(key)
+    }
+}
+
+async fn run_tasks(db: Arc<Db>, tasks: usize) {
+    let per_task = OPS / tasks;
+    let handles: Vec<_> = (0..tasks)
+        .map(|t| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for i in 0..per_task {
+                    db.apply(command(t * per_task + i)).await;
+                }
+            })
+        })
+        .collect();
+    for h in handles {
+        h.await.unwrap();
+    }
+}
+
+/// Throughput of concurrent clients against a single-shard keyspace, which
+/// behaves like one global lock, and against the default sharding.
+fn bench_keyspace(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+
+    let mut group = c.benchmark_group("keyspace_set_get");
+    group.throughput(Throughput::Elements(OPS as u64));
+
+    for shards in [1, rust_redis::keyspace::DEFAULT_SHARDS] {
+        let db = Arc::new(Db::with_shards(shards));
+        rt.block_on(run_tasks(db.clone(), 1));
+
+        for tasks in [1, 2, 4, 8, 16, 32] {
+            group.bench_with_input(BenchmarkId::new(format!("{}_shards", shards), tasks), &tasks, |b, &tasks| {
+                b.iter(|| rt.block_on(run_tasks(db.clone(), tasks)))
+            });
+        }
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_keyspace);
+criterion_main!(benches);
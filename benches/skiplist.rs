@@ -0,0 +1,325 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkGroup, Criterion};
+use criterion::measurement::WallTime;
+use rand::Rng;
+
+use rust_redis::skiplist::SkipList;
+
+const MEMBERS: usize = 1_000_000;
+/// Set size for comparing against the baseline, whose inserts are O(n).
+const BASELINE_MEMBERS: usize = 10_000;
+
+/// The skiplist as it was before the arena rewrite: nodes behind
+/// `Arc<Mutex<_>>`, no spans and no member index, so lookups and ranks walk
+/// level 0. Kept to measure the rewrite against.
+mod baseline {
+    use rand::Rng;
+    use std::cmp::Ordering;
+    use std::sync::{Arc, Mutex};
+
+    const MAX_LEVEL: usize = 16;
+    const P: f64 = 0.25;
+
+    type NodeRef = Arc<Mutex<Node>>;
+
+    #[derive(Debug)]
+    pub struct Level {
+        pub forward: Option<NodeRef>,
+    }
+
+    #[derive(Debug)]
+    pub struct Node {
+        pub score: f64,
+        pub member: Vec<u8>,
+        pub levels: Vec<Level>,
+    }
+
+    impl Node {
+        pub fn new(score: f64, member: Vec<u8>, level: usize) -> NodeRef {
+            Arc::new(Mutex::new(Self {
+                score,
+                member,
+                levels: (0..level)
+                    .map(|_| Level { forward: None })
+                    .collect(),
+            }))
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct SkipList {
+        pub head: NodeRef,
+        pub level: usize,
+        pub length: usize,
+    }
+
+    impl SkipList {
+        pub fn new() -> Self {
+            let head = Node::new(f64::NEG_INFINITY, Vec::new(), MAX_LEVEL);
+            Self {
+                head,
+                level: 1,
+                length: 0,
+            }
+        }
+
+        fn random_level() -> usize {
+            let mut lvl = 1;
+            let mut rng = rand::thread_rng();
+            while rng.gen::<f64>() < P && lvl < MAX_LEVEL {
+                lvl += 1;
+            }
+            lvl
+        }
+
+        fn compare(a_score: f64, a_member: &[u8], b_score: f64, b_member: &[u8]) -> Ordering {
+            match a_score.partial_cmp(&b_score).unwrap_or(Ordering::Equal) {
+                Ordering::Less => Ordering::Less,
+                Ordering::Greater => Ordering::Greater,
+                Ordering::Equal => a_member.cmp(b_member),
+            }
+        }
+
+        pub fn insert(&mut self, score: f64, member: Vec<u8>) {
+            self.remove_member(&member);
+
+            let mut update: Vec<NodeRef> = Vec::with_capacity(MAX_LEVEL);
+            for _ in 0..MAX_LEVEL {
+                update.push(self.head.clone());
+            }
+
+            let mut current = self.head.clone();
+
+            for lvl in (0..self.level).rev() {
+                loop {
+                    let next_opt = current.lock().unwrap().levels[lvl].forward.clone();
+                    match next_opt {
+                        Some(ref next) => {
+                            let nb = next.lock().unwrap();
+                            if Self::compare(nb.score, &nb.member, score, &member) == Ordering::Less {
+                                current = next.clone();
+                            } else {
+                                break;
+                            }
+                        }
+                        None => break,
+                    }
+                }
+                update[lvl] = current.clone();
+            }
+
+            let new_level = Self::random_level();
+            if new_level > self.level {
+                for slot in update.iter_mut().take(new_level).skip(self.level) {
+                    *slot = self.head.clone();
+                }
+                self.level = new_level;
+            }
+
+            let new_node = Node::new(score, member, new_level);
+
+            for (lvl, prev) in update.iter().enumerate().take(new_level) {
+                let next = prev.lock().unwrap().levels[lvl].forward.clone();
+                new_node.lock().unwrap().levels[lvl].forward = next.clone();
+                prev.lock().unwrap().levels[lvl].forward = Some(new_node.clone());
+            }
+
+            self.length += 1;
+        }
+
+        pub fn remove_member(&mut self, member: &[u8]) -> bool {
+            let mut target: Option<NodeRef> = None;
+            let mut current_opt = self.head.lock().unwrap().levels[0].forward.clone();
+
+            while let Some(node_rc) = current_opt.clone() {
+                if node_rc.lock().unwrap().member == member {
+                    target = Some(node_rc.clone());
+                    break;
+                }
+                current_opt = node_rc.lock().unwrap().levels[0].forward.clone();
+            }
+
+            let target = match target {
+                Some(t) => t,
+                None => return false,
+            };
+
+            for lvl in (0..self.level).rev() {
+                let mut current = self.head.clone();
+                loop {
+                    let next_opt = current.lock().unwrap().levels[lvl].forward.clone();
+                    match next_opt {
+                        Some(ref next) => {
+                            if Arc::ptr_eq(next, &target) {
+                                let next_next = next.lock().unwrap().levels[lvl].forward.clone();
+                                current.lock().unwrap().levels[lvl].forward = next_next;
+                                break;
+                            } else {
+                                current = next.clone();
+                            }
+                        }
+                        None => break,
+                    }
+                }
+            }
+
+            self.length -= 1;
+
+            while self.level > 1
+                && self.head.lock().unwrap().levels[self.level - 1]
+                    .forward
+                    .is_none()
+            {
+                self.level -= 1;
+            }
+
+            true
+        }
+
+        pub fn get_score(&self, member: &[u8]) -> Option<f64> {
+            let mut current_opt = self.head.lock().unwrap().levels[0].forward.clone();
+            while let Some(node_rc) = current_opt {
+                let node = node_rc.lock().unwrap();
+                if node.member == member {
+                    return Some(node.score)
+                }
+                current_opt = node.levels[0].forward.clone();
+            }
+            None
+        }
+
+        /// Entries with ranks in `start..=end`; both must already be in bounds.
+        pub fn range_by_rank(&self, start: usize, end: usize) -> Vec<(Vec<u8>, f64)> {
+            let mut result = Vec::new();
+
+            let mut idx = 0;
+            let mut current_opt = self.head.lock().unwrap().levels[0].forward.clone();
+
+            while let Some(node_rc) = current_opt {
+                let node = node_rc.lock().unwrap();
+
+                if idx > end {
+                    break;
+                }
+
+                if idx >= start {
+                    result.push((node.member.clone(), node.score));
+                }
+
+                idx += 1;
+                current_opt = node.levels[0].forward.clone();
+            }
+
+            result
+        }
+
+        pub fn rank(&self, member: &[u8]) -> Option<usize> {
+            let mut rank: usize = 0;
+            let mut current_opt = self.head.lock().unwrap().levels[0].forward.clone();
+
+            while let Some(node_rc) = current_opt {
+                let node = node_rc.lock().unwrap();
+                if node.member == member {
+                    return Some(rank);
+                }
+                rank += 1;
+                current_opt = node.levels[0].forward.clone();
+            }
+
+            None
+        }
+    }
+}
+
+/// The operations compared between the two implementations.
+trait Zset {
+    fn insert(&mut self, score: f64, member: Vec<u8>);
+    fn remove_member(&mut self, member: &[u8]) -> bool;
+    fn get_score(&self, member: &[u8]) -> Option<f64>;
+    fn rank(&self, member: &[u8]) -> Option<usize>;
+    fn range_by_rank(&self, start: usize, end: usize) -> Vec<(Vec<u8>, f64)>;
+}
+
+macro_rules! impl_zset {
+    ($ty:ty) => {
+        impl Zset for $ty {
+            fn insert(&mut self, score: f64, member: Vec<u8>) {
+                <$ty>::insert(self, score, member)
+            }
+            fn remove_member(&mut self, member: &[u8]) -> bool {
+                <$ty>::remove_member(self, member)
+            }
+            fn get_score(&self, member: &[u8]) -> Option<f64> {
+                <$ty>::get_score(self, member)
+            }
+            fn rank(&self, member: &[u8]) -> Option<usize> {
+                <$ty>::rank(self, member)
+            }
+            fn range_by_rank(&self, start: usize, end: usize) -> Vec<(Vec<u8>, f64)> {
+                <$ty>::range_by_rank(self, start, end)
+            }
+        }
+    };
+}
+
+impl_zset!(SkipList);
+impl_zset!(baseline::SkipList);
+
+fn member(i: usize) -> Vec<u8> {
+    format!("member:{}", i).into_bytes()
+}
+
+fn populated<Z: Zset>(mut zset: Z, members: usize) -> Z {
+    let mut rng = rand::thread_rng();
+    for i in 0..members {
+        zset.insert(rng.gen_range(0.0..1_000_000.0), member(i));
+    }
+    zset
+}
+
+fn bench_ops<Z: Zset>(group: &mut BenchmarkGroup<WallTime>, name: &str, zset: &mut Z, members: usize) {
+    let mut rng = rand::thread_rng();
+
+    group.bench_function(format!("{}/zscore", name), |b| {
+        b.iter(|| zset.get_score(black_box(&member(rng.gen_range(0..members)))))
+    });
+
+    group.bench_function(format!("{}/zrank", name), |b| {
+        b.iter(|| zset.rank(black_box(&member(rng.gen_range(0..members)))))
+    });
+
+    group.bench_function(format!("{}/zrange_by_rank_10", name), |b| {
+        b.iter(|| {
+            let start = rng.gen_range(0..members - 10);
+            zset.range_by_rank(black_box(start), black_box(start + 9))
+        })
+    });
+
+    // Each removed member is put back so the set keeps its size.
+    group.bench_function(format!("{}/zrem_then_zadd", name), |b| {
+        b.iter(|| {
+            let m = member(rng.gen_range(0..members));
+            let score = zset.get_score(&m).unwrap();
+            zset.remove_member(black_box(&m));
+            zset.insert(score, m);
+        })
+    });
+}
+
+fn bench_skiplist(c: &mut Criterion) {
+    let mut zset = populated(SkipList::new(), MEMBERS);
+    let mut group = c.benchmark_group("skiplist_1m");
+    bench_ops(&mut group, "arena", &mut zset, MEMBERS);
+    group.finish();
+}
+
+fn bench_against_baseline(c: &mut Criterion) {
+    let mut group = c.benchmark_group("skiplist_10k");
+    let mut zset = populated(SkipList::new(), BASELINE_MEMBERS);
+    bench_ops(&mut group, "arena", &mut zset, BASELINE_MEMBERS);
+    let mut zset = populated(baseline::SkipList::new(), BASELINE_MEMBERS);
+    bench_ops(&mut group, "baseline", &mut zset, BASELINE_MEMBERS);
+    group.finish();
+}
+
+criterion_group!(benches, bench_skiplist, bench_against_baseline);
+criterion_main!(benches);
@@ -1,3 +1,5 @@
+use std::cell::RefCell;
+use std::future::Future;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use std::sync::Arc;
@@ -28,9 +30,67 @@ impl AofFsync {
     }
 }
 
+/// A write to append to the AOF, along with the database it applies to if
+/// that is not the one selected by the client that caused it.
+pub type Effect = (Option<usize>, Frame);
+
+tokio::task_local! {
+    static PROPAGATED: RefCell<Vec<Effect>>;
+}
+
+/// Runs `fut`, collecting every effect it hands to [`propagate`].
+///
+/// Commands whose literal form should not be replayed (blocking pops, random
+/// choices, ...) propagate the concrete writes they performed instead. Effects
+/// produced on behalf of other clients, such as serving a blocked BLPOP during
+/// an LPUSH, land in the caller's capture so they are logged after its command.
+pub async fn capture<F: Future>(fut: F) -> (F::Output, Vec<Effect>) {
+    PROPAGATED
+        .scope(RefCell::new(Vec::new()), async {
+            let out = fut.await;
+            let effects = PROPAGATED.with(|p| p.take());
+            (out, effects)
+        })
+        .await
+}
+
+/// Records `frame` as a write to append to the AOF. Outside of [`capture`]
+/// (e.g. while replaying the AOF itself) this is a no-op.
+pub fn propagate(frame: Frame) {
+    let _ = PROPAGATED.try_with(|p| p.borrow_mut().push((None, frame)));
+}
+
+/// Like [`propagate`], for a write to database `db` regardless of which one
+/// the client has selected.
+pub fn propagate_to(db: usize, frame: Frame) {
+    let _ = PROPAGATED.try_with(|p| p.borrow_mut().push((Some(db), frame)));
+}
+
+/// Runs `fut`, attributing the effects it propagates without a database to
+/// database `db`.
+pub async fn redirect<F: Future>(db: usize, fut: F) -> F::Output {
+    let (out, effects) = capture(fut).await;
+    for (target, frame) in effects {
+        propagate_to(target.unwrap_or(db), frame);
+    }
+    out
+}
+
+/// Builds the RESP array for a command from its arguments.
+pub fn command_frame<I, A>(args: I) -> Frame
+where
+    I: IntoIterator<Item = A>,
+    A: Into<Vec<u8>>,
+{
+    Frame::Array(args.into_iter().map(|a| Frame::Bulk(a.into())).collect())
+}
+
 #[derive(Debug)]
 struct AofInner {
     writer: BufWriter<tokio::fs::File>,
+    /// Database the commands appended last apply to. Unknown when the file
+    /// is opened, so the first append always records a SELECT.
+    selected: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -55,6 +115,7 @@ impl Aof {
             fsync,
             inner: Mutex::new(AofInner {
                 writer: BufWriter::new(file),
+                selected: None,
             }),
         });
         
@@ -80,10 +141,23 @@ impl Aof {
         self.fsync
     }
 
-    pub async fn append_frame(&self, frame: &Frame) -> Result<(), RedisError> {
-        let bytes = encode_frame(frame);
+    /// Appends writes, each with the database it was made in, preceded by a
+    /// SELECT wherever the database changes. They are written in one go, so
+    /// no other client's writes can end up in between.
+    pub async fn append_frames(&self, frames: &[(usize, Frame)]) -> Result<(), RedisError> {
+        if frames.is_empty() {
+            return Ok(());
+        }
+        let mut bytes = Vec::new();
 
         let mut inner = self.inner.lock().await;
+        for (db, frame) in frames {
+            if inner.selected != Some(*db) {
+                bytes.extend(encode_frame(&command_frame(["SELECT".to_string(), db.to_string()])));
+                inner.selected = Some(*db);
+            }
+            bytes.extend(encode_frame(frame));
+        }
         inner.writer.write_all(&bytes).await?;
 
         inner.writer.flush().await?;
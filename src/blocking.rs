@@ -0,0 +1,151 @@
+use std::collections::{HashMap, VecDeque};
+
+use tokio::sync::oneshot;
+
+use crate::command::{Command, ListEnd, ZSetEnd};
+use crate::resp::Frame;
+
+/// What a blocked client wants done once one of its keys has data.
+#[derive(Debug, Clone)]
+pub enum BlockOp {
+    /// BLPOP / BRPOP: reply with `[key, element]`.
+    Pop(ListEnd),
+    /// BLMPOP: reply with `[key, [element, ...]]`.
+    MPop(ListEnd, usize),
+    /// BLMOVE / BRPOPLPUSH: move the element and reply with it.
+    Move(String, ListEnd, ListEnd),
+    /// BZPOPMIN / BZPOPMAX: reply with `[key, member, score]`.
+    ZPop(ZSetEnd),
+    /// BZMPOP: reply with `[key, [[member, score], ...]]`.
+    ZMPop(ZSetEnd, usize),
+}
+
+/// The type of value a blocked client is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    List,
+    ZSet,
+}
+
+impl BlockOp {
+    pub fn kind(&self) -> BlockKind {
+        match self {
+            BlockOp::Pop(_) | BlockOp::MPop(_, _) | BlockOp::Move(_, _, _) => BlockKind::List,
+            BlockOp::ZPop(_) | BlockOp::ZMPop(_, _) => BlockKind::ZSet,
+        }
+    }
+}
+
+/// A blocking command taken apart.
+#[derive(Debug, Clone)]
+pub struct BlockRequest {
+    /// The keys to wait on, in the order they are tried.
+    pub keys: Vec<String>,
+    pub op: BlockOp,
+    /// Seconds to wait; zero waits forever.
+    pub timeout: f64,
+}
+
+impl BlockRequest {
+    /// Takes apart a blocking command; None for any other command.
+    pub fn from_command(cmd: &Command) -> Option<Self> {
+        let (keys, op, timeout) = match cmd {
+            Command::BLPop(keys, timeout) => (keys.clone(), BlockOp::Pop(ListEnd::Left), *timeout),
+            Command::BRPop(keys, timeout) => (keys.clone(), BlockOp::Pop(ListEnd::Right), *timeout),
+            Command::BLMove(src, dst, from, to, timeout) => {
+                (vec![src.clone()], BlockOp::Move(dst.clone(), *from, *to), *timeout)
+            }
+            Command::BLMPop(timeout, keys, end, count) => (keys.clone(), BlockOp::MPop(*end, *count), *timeout),
+            Command::BZPopMin(keys, timeout) => (keys.clone(), BlockOp::ZPop(ZSetEnd::Min), *timeout),
+            Command::BZPopMax(keys, timeout) => (keys.clone(), BlockOp::ZPop(ZSetEnd::Max), *timeout),
+            Command::BZMPop(timeout, keys, end, count) => (keys.clone(), BlockOp::ZMPop(*end, *count), *timeout),
+            _ => return None,
+        };
+        Some(Self { keys, op, timeout })
+    }
+}
+
+/// A blocked client's registration: its id and where its reply will arrive.
+pub type Registration = (u64, oneshot::Receiver<Frame>);
+
+#[derive(Debug)]
+pub struct Waiter {
+    pub keys: Vec<String>,
+    pub op: BlockOp,
+    pub tx: oneshot::Sender<Frame>,
+}
+
+/// Clients blocked on keys, served in the order they blocked.
+///
+/// Waiters are registered and removed while holding the write locks of all
+/// their keys' shards, and pushers check for waiters while holding the locks
+/// of the keys they push to, so registering a waiter and pushing to one of
+/// its keys can never race.
+#[derive(Debug, Default)]
+pub struct Blocking {
+    next_id: u64,
+    waiters: HashMap<u64, Waiter>,
+    by_key: HashMap<String, VecDeque<u64>>,
+}
+
+impl Blocking {
+    pub fn register(&mut self, keys: Vec<String>, op: BlockOp) -> Registration {
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_id;
+        self.next_id += 1;
+
+        for key in &keys {
+            self.by_key.entry(key.clone()).or_default().push_back(id);
+        }
+        self.waiters.insert(id, Waiter { keys, op, tx });
+
+        (id, rx)
+    }
+
+    /// Whether any client is blocked on `key`.
+    pub fn has_waiters(&self, key: &str) -> bool {
+        self.by_key.contains_key(key)
+    }
+
+    /// Every key some client is blocked on.
+    pub fn keys(&self) -> Vec<String> {
+        self.by_key.keys().cloned().collect()
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Waiter> {
+        let waiter = self.waiters.remove(&id)?;
+        for key in &waiter.keys {
+            if let Some(queue) = self.by_key.get_mut(key) {
+                queue.retain(|w| *w != id);
+                if queue.is_empty() {
+                    self.by_key.remove(key);
+                }
+            }
+        }
+        Some(waiter)
+    }
+
+    /// Takes the longest-waiting client blocked on `key` for a value of
+    /// `kind`, dropping clients that have gone away along the way.
+    pub fn take_next(&mut self, key: &str, kind: BlockKind) -> Option<Waiter> {
+        let mut gone = Vec::new();
+        let mut next = None;
+
+        for id in self.by_key.get(key)? {
+            let Some(waiter) = self.waiters.get(id) else {
+                continue;
+            };
+            if waiter.tx.is_closed() {
+                gone.push(*id);
+            } else if waiter.op.kind() == kind {
+                next = Some(*id);
+                break;
+            }
+        }
+
+        for id in gone {
+            self.remove(id);
+        }
+        self.remove(next?)
+    }
+}
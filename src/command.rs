@@ -1,24 +1,82 @@
 use crate::errors::RedisError;
 use crate::resp::Frame;
+use crate::value::DEFAULT_MEM_SAMPLES;
 
 use serde::{Serialize, Deserialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Command {
     Ping,
+
+    // Transactions
+    Multi,
+    Exec,
+    Discard,
+    Watch(Vec<String>),
+    Unwatch,
+
+    // Scripting
+    /// Script, keys and arguments.
+    Eval(String, Vec<String>, Vec<Vec<u8>>),
+    /// Like [`Command::Eval`], with the SHA1 of a cached script.
+    EvalSha(String, Vec<String>, Vec<Vec<u8>>),
+    ScriptLoad(String),
+    ScriptExists(Vec<String>),
+    ScriptFlush,
+    ScriptKill,
+    /// Function name, keys and arguments.
+    FCall(String, Vec<String>, Vec<Vec<u8>>),
+    /// Like [`Command::FCall`], for functions flagged `no-writes` only.
+    FCallRo(String, Vec<String>, Vec<Vec<u8>>),
+    /// Library code, and whether it may replace a library of the same name.
+    FunctionLoad(String, bool),
+    FunctionDelete(String),
+    FunctionFlush,
+    /// Library name pattern, and whether to include the code.
+    FunctionList(Option<String>, bool),
+    FunctionDump,
+    FunctionRestore(Vec<u8>, RestorePolicy),
+
+    // Pub/Sub
+    Subscribe(Vec<String>),
+    /// No channels means all of the client's channels.
+    Unsubscribe(Vec<String>),
+    /// Channel and message.
+    Publish(String, Vec<u8>),
+    /// Channel pattern.
+    PubSubChannels(Option<String>),
+    PubSubNumSub(Vec<String>),
     
     // Keyspace commands
     Exists(Vec<String>),
     Type(String),
+    ObjectEncoding(String),
+    ObjectFreq(String),
+    ObjectIdleTime(String),
+    ObjectRefCount(String),
+    /// Key and number of elements to sample, 0 meaning all.
+    MemoryUsage(String, usize),
+    MemoryStats,
     Keys(String),
     RandomKey,
     Expire(String, usize),
     Ttl(String),
+    Select(usize),
+    SwapDb(usize, usize),
+    /// Key and destination database.
+    Move(String, usize),
+    DbSize,
 
     // String commands
     Get(String),
     Set(String, Vec<u8>),
     Del(String),
+    Unlink(Vec<String>),
+    /// `Some(true)` for ASYNC, `Some(false)` for SYNC, `None` to follow
+    /// `lazyfree-lazy-user-flush`.
+    FlushAll(Option<bool>),
+    /// Like [`Command::FlushAll`], for the selected database only.
+    FlushDb(Option<bool>),
     Append(String, Vec<u8>),
     StrLen(String),
     GetSet(String, Vec<u8>),
@@ -26,29 +84,52 @@ pub enum Command {
     IncrBy(String, i64),
     MSet(Vec<(String, Vec<u8>)>),
     MGet(Vec<String>),
+    Lcs(String, String, LcsOptions),
 
     // List Commands
     LPush(String, Vec<Vec<u8>>),
-    LPop(String),
+    LPushX(String, Vec<Vec<u8>>),
+    LPop(String, Option<usize>),
     RPush(String, Vec<Vec<u8>>),
-    RPop(String),
+    RPushX(String, Vec<Vec<u8>>),
+    RPop(String, Option<usize>),
     LLen(String),
     LRange(String, i64, i64),
     LIndex(String, i64),
     LSet(String, i64, Vec<u8>),
     LTrim(String, i64, i64),
-    BRPop(String, usize),
+    LInsert(String, InsertPosition, Vec<u8>, Vec<u8>),
+    LRem(String, i64, Vec<u8>),
+    LPos(String, Vec<u8>, LPosOptions),
+    LMove(String, String, ListEnd, ListEnd),
+    LMPop(Vec<String>, ListEnd, usize),
+    BLPop(Vec<String>, f64),
+    BRPop(Vec<String>, f64),
+    BLMove(String, String, ListEnd, ListEnd, f64),
+    BLMPop(f64, Vec<String>, ListEnd, usize),
 
     // Hash commands
-    HSet(String, String, Vec<u8>),
-    HGet(String, String),
-    HDel(String, Vec<String>),
+    HSet(String, Vec<(Vec<u8>, Vec<u8>)>),
+    HMSet(String, Vec<(Vec<u8>, Vec<u8>)>),
+    HSetNx(String, Vec<u8>, Vec<u8>),
+    HGet(String, Vec<u8>),
+    HDel(String, Vec<Vec<u8>>),
     HGetAll(String),
-    HMGet(String, Vec<String>),
-    HExists(String, String),
+    HMGet(String, Vec<Vec<u8>>),
+    HExists(String, Vec<u8>),
     HLen(String),
     HKeys(String),
     HVals(String),
+    HStrLen(String, Vec<u8>),
+    HIncrBy(String, Vec<u8>, i64),
+    HIncrByFloat(String, Vec<u8>, f64),
+    HRandField(String, Option<i64>, bool),
+    HExpire(String, FieldExpiry, Option<ExpireCondition>, Vec<Vec<u8>>),
+    HTtl(String, Vec<Vec<u8>>),
+    HPTtl(String, Vec<Vec<u8>>),
+    HExpireTime(String, Vec<Vec<u8>>),
+    HPExpireTime(String, Vec<Vec<u8>>),
+    HPersist(String, Vec<Vec<u8>>),
 
     // Set commands
     SAdd(String, Vec<Vec<u8>>),
@@ -59,19 +140,242 @@ pub enum Command {
     SUnion(Vec<String>),
     SInter(Vec<String>),
     SDiff(Vec<String>),
+    SUnionStore(String, Vec<String>),
+    SInterStore(String, Vec<String>),
+    SDiffStore(String, Vec<String>),
+    SInterCard(Vec<String>, usize),
+    SPop(String, Option<usize>),
+    SRandMember(String, Option<i64>),
+    SMove(String, String, Vec<u8>),
+    SMIsMember(String, Vec<Vec<u8>>),
     
     // Sorted Set Commands
-    ZAdd(String, f64, Vec<u8>),
-    ZRem(String, Vec<u8>),
-    ZRange(String, i64, i64),
-    ZRevRange(String, i64, i64),
+    ZAdd(String, ZAddOptions, Vec<(f64, Vec<u8>)>),
+    ZIncrBy(String, f64, Vec<u8>),
+    ZMScore(String, Vec<Vec<u8>>),
+    ZRem(String, Vec<Vec<u8>>),
+    ZRange(String, ZRangeOptions),
+    ZRangeStore(String, String, ZRangeOptions),
     ZCard(String),
     ZScore(String, Vec<u8>),
-    ZRangeByScore(String, f64, f64),
-    ZRemRangeByScore(String, f64, f64),
-    ZRank(String, Vec<u8>),
-    ZRevRank(String, Vec<u8>),
-    ZCount(String, f64, f64),
+    ZRemRangeByScore(String, ScoreRange),
+    ZRemRangeByLex(String, LexRange),
+    ZRemRangeByRank(String, i64, i64),
+    ZRank(String, Vec<u8>, bool),
+    ZRevRank(String, Vec<u8>, bool),
+    ZRandMember(String, Option<i64>, bool),
+    ZMPop(Vec<String>, ZSetEnd, usize),
+    ZCount(String, ScoreRange),
+    ZUnion(Vec<String>, ZAlgebraOptions),
+    ZInter(Vec<String>, ZAlgebraOptions),
+    ZDiff(Vec<String>, ZAlgebraOptions),
+    ZUnionStore(String, Vec<String>, ZAlgebraOptions),
+    ZInterStore(String, Vec<String>, ZAlgebraOptions),
+    ZDiffStore(String, Vec<String>, ZAlgebraOptions),
+    ZLexCount(String, LexRange),
+    ZPopMin(String, Option<usize>),
+    ZPopMax(String, Option<usize>),
+    BZPopMin(Vec<String>, f64),
+    BZPopMax(Vec<String>, f64),
+    BZMPop(f64, Vec<String>, ZSetEnd, usize),
+}
+
+/// What FUNCTION RESTORE does with the libraries already loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestorePolicy {
+    /// Fail if a restored library already exists.
+    Append,
+    /// Replace the loaded libraries of the same names.
+    Replace,
+    /// Delete every loaded library first.
+    Flush,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ZSetEnd {
+    Min,
+    Max,
+}
+
+/// The time argument of the HEXPIRE family, as given by the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FieldExpiry {
+    Seconds(i64),
+    Millis(i64),
+    AtSeconds(i64),
+    AtMillis(i64),
+}
+
+impl FieldExpiry {
+    /// The command this time argument came with.
+    pub fn command_name(&self) -> &'static str {
+        match self {
+            FieldExpiry::Seconds(_) => "HEXPIRE",
+            FieldExpiry::Millis(_) => "HPEXPIRE",
+            FieldExpiry::AtSeconds(_) => "HEXPIREAT",
+            FieldExpiry::AtMillis(_) => "HPEXPIREAT",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExpireCondition {
+    Nx,
+    Xx,
+    Gt,
+    Lt,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ZAddOptions {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+    pub ch: bool,
+    pub incr: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ScoreBound {
+    Inclusive(f64),
+    Exclusive(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ScoreRange {
+    pub min: ScoreBound,
+    pub max: ScoreBound,
+}
+
+impl ScoreRange {
+    pub fn above_min(&self, score: f64) -> bool {
+        match self.min {
+            ScoreBound::Inclusive(min) => score >= min,
+            ScoreBound::Exclusive(min) => score > min,
+        }
+    }
+
+    pub fn below_max(&self, score: f64) -> bool {
+        match self.max {
+            ScoreBound::Inclusive(max) => score <= max,
+            ScoreBound::Exclusive(max) => score < max,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match (self.min, self.max) {
+            (ScoreBound::Inclusive(min), ScoreBound::Inclusive(max)) => min > max,
+            (ScoreBound::Inclusive(min) | ScoreBound::Exclusive(min),
+             ScoreBound::Inclusive(max) | ScoreBound::Exclusive(max)) => min >= max,
+        }
+    }
+}
+
+/// A ZRANGEBYLEX bound: `-`, `+`, `[member` or `(member`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LexBound {
+    NegInf,
+    PosInf,
+    Inclusive(Vec<u8>),
+    Exclusive(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LexRange {
+    pub min: LexBound,
+    pub max: LexBound,
+}
+
+impl LexRange {
+    pub fn above_min(&self, member: &[u8]) -> bool {
+        match &self.min {
+            LexBound::NegInf => true,
+            LexBound::PosInf => false,
+            LexBound::Inclusive(min) => member >= min.as_slice(),
+            LexBound::Exclusive(min) => member > min.as_slice(),
+        }
+    }
+
+    pub fn below_max(&self, member: &[u8]) -> bool {
+        match &self.max {
+            LexBound::NegInf => false,
+            LexBound::PosInf => true,
+            LexBound::Inclusive(max) => member <= max.as_slice(),
+            LexBound::Exclusive(max) => member < max.as_slice(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match (&self.min, &self.max) {
+            (LexBound::PosInf, _) | (_, LexBound::NegInf) => true,
+            (LexBound::NegInf, _) | (_, LexBound::PosInf) => false,
+            (LexBound::Inclusive(min), LexBound::Inclusive(max)) => min > max,
+            (LexBound::Inclusive(min) | LexBound::Exclusive(min),
+             LexBound::Inclusive(max) | LexBound::Exclusive(max)) => min >= max,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ZRangeBy {
+    Rank(i64, i64),
+    Score(ScoreRange),
+    Lex(LexRange),
+}
+
+/// A parsed ZRANGE request. The legacy ZREVRANGE and ZRANGEBYSCORE/LEX forms
+/// parse into the same shape.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ZRangeOptions {
+    pub by: ZRangeBy,
+    pub rev: bool,
+    /// `LIMIT offset count`; a negative count returns everything past the offset.
+    pub limit: Option<(i64, i64)>,
+    pub with_scores: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+/// Options shared by ZUNION, ZINTER, ZDIFF and their STORE variants.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ZAlgebraOptions {
+    /// One multiplier per input key; None means every weight is 1.
+    pub weights: Option<Vec<f64>>,
+    pub aggregate: Aggregate,
+    pub with_scores: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InsertPosition {
+    Before,
+    After,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LPosOptions {
+    pub rank: i64,
+    pub count: Option<usize>,
+    pub maxlen: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LcsOptions {
+    pub len: bool,
+    pub idx: bool,
+    pub min_match_len: usize,
+    pub with_match_len: bool,
 }
 
 impl TryFrom<Frame> for Command {
@@ -95,6 +399,191 @@ impl TryFrom<Frame> for Command {
 
         match cmd_name.as_str() {
             "PING" => Ok(Command::Ping),
+
+            // Transactions
+            "MULTI" | "EXEC" | "DISCARD" => {
+                if arr.len() != 1 {
+                    return Err(RedisError::Other(format!(
+                        "ERR wrong number of arguments for '{}'",
+                        cmd_name
+                    )));
+                }
+                Ok(match cmd_name.as_str() {
+                    "MULTI" => Command::Multi,
+                    "EXEC" => Command::Exec,
+                    _ => Command::Discard,
+                })
+            }
+            "WATCH" => {
+                if arr.len() < 2 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'WATCH'".into()));
+                }
+                let keys = arr[1..].iter().map(frame_to_string).collect::<Result<_, _>>()?;
+                Ok(Command::Watch(keys))
+            }
+            "UNWATCH" => {
+                if arr.len() != 1 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'UNWATCH'".into()));
+                }
+                Ok(Command::Unwatch)
+            }
+
+            // Scripting
+            "EVAL" | "EVALSHA" => {
+                let (body, keys, args) = parse_script_call(&arr, &cmd_name)?;
+                if cmd_name == "EVAL" {
+                    Ok(Command::Eval(body, keys, args))
+                } else {
+                    Ok(Command::EvalSha(body, keys, args))
+                }
+            }
+            "FCALL" | "FCALL_RO" => {
+                let (name, keys, args) = parse_script_call(&arr, &cmd_name)?;
+                if cmd_name == "FCALL" {
+                    Ok(Command::FCall(name, keys, args))
+                } else {
+                    Ok(Command::FCallRo(name, keys, args))
+                }
+            }
+            "FUNCTION" => {
+                if arr.len() < 2 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'FUNCTION'".into()));
+                }
+                let sub = frame_to_string(&arr[1])?;
+                match sub.to_uppercase().as_str() {
+                    "LOAD" => {
+                        let (replace, code) = match arr.len() {
+                            3 => (false, &arr[2]),
+                            4 if frame_to_string(&arr[2])?.eq_ignore_ascii_case("REPLACE") => (true, &arr[3]),
+                            4 => return Err(RedisError::Other(format!("ERR Unknown option given: {}", frame_to_string(&arr[2])?))),
+                            _ => return Err(RedisError::Other("ERR wrong number of arguments for 'FUNCTION|LOAD'".into())),
+                        };
+                        Ok(Command::FunctionLoad(frame_to_string(code)?, replace))
+                    }
+                    "DELETE" => {
+                        if arr.len() != 3 {
+                            return Err(RedisError::Other("ERR wrong number of arguments for 'FUNCTION|DELETE'".into()));
+                        }
+                        Ok(Command::FunctionDelete(frame_to_string(&arr[2])?))
+                    }
+                    "FLUSH" => {
+                        // ASYNC and SYNC are accepted, but libraries are
+                        // always dropped in place.
+                        parse_flush_mode(&arr[1..])?;
+                        Ok(Command::FunctionFlush)
+                    }
+                    "LIST" => {
+                        let mut pattern = None;
+                        let mut with_code = false;
+                        let mut i = 2;
+                        while i < arr.len() {
+                            match frame_to_string(&arr[i])?.to_uppercase().as_str() {
+                                "WITHCODE" if !with_code => with_code = true,
+                                "LIBRARYNAME" if pattern.is_none() && i + 1 < arr.len() => {
+                                    pattern = Some(frame_to_string(&arr[i + 1])?);
+                                    i += 1;
+                                }
+                                _ => return Err(RedisError::Other("ERR syntax error".into())),
+                            }
+                            i += 1;
+                        }
+                        Ok(Command::FunctionList(pattern, with_code))
+                    }
+                    "DUMP" => {
+                        if arr.len() != 2 {
+                            return Err(RedisError::Other("ERR wrong number of arguments for 'FUNCTION|DUMP'".into()));
+                        }
+                        Ok(Command::FunctionDump)
+                    }
+                    "RESTORE" => {
+                        let policy = match arr.len() {
+                            3 => RestorePolicy::Append,
+                            4 => match frame_to_string(&arr[3])?.to_uppercase().as_str() {
+                                "APPEND" => RestorePolicy::Append,
+                                "REPLACE" => RestorePolicy::Replace,
+                                "FLUSH" => RestorePolicy::Flush,
+                                _ => return Err(RedisError::Other("ERR Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE.".into())),
+                            },
+                            _ => return Err(RedisError::Other("ERR wrong number of arguments for 'FUNCTION|RESTORE'".into())),
+                        };
+                        Ok(Command::FunctionRestore(frame_to_bytes(&arr[2])?, policy))
+                    }
+                    _ => Err(RedisError::Other(format!("ERR unknown subcommand '{}'. Try FUNCTION HELP.", sub))),
+                }
+            }
+            "SCRIPT" => {
+                if arr.len() < 2 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'SCRIPT'".into()));
+                }
+                let sub = frame_to_string(&arr[1])?;
+                match sub.to_uppercase().as_str() {
+                    "LOAD" => {
+                        if arr.len() != 3 {
+                            return Err(RedisError::Other("ERR wrong number of arguments for 'SCRIPT|LOAD'".into()));
+                        }
+                        Ok(Command::ScriptLoad(frame_to_string(&arr[2])?))
+                    }
+                    "EXISTS" => {
+                        if arr.len() < 3 {
+                            return Err(RedisError::Other("ERR wrong number of arguments for 'SCRIPT|EXISTS'".into()));
+                        }
+                        let shas = arr[2..].iter().map(frame_to_string).collect::<Result<_, _>>()?;
+                        Ok(Command::ScriptExists(shas))
+                    }
+                    "FLUSH" => {
+                        // ASYNC and SYNC are accepted, but the cache is small
+                        // enough to always drop in place.
+                        parse_flush_mode(&arr[1..])?;
+                        Ok(Command::ScriptFlush)
+                    }
+                    "KILL" => {
+                        if arr.len() != 2 {
+                            return Err(RedisError::Other("ERR wrong number of arguments for 'SCRIPT|KILL'".into()));
+                        }
+                        Ok(Command::ScriptKill)
+                    }
+                    _ => Err(RedisError::Other(format!("ERR unknown subcommand '{}'. Try SCRIPT HELP.", sub))),
+                }
+            }
+            
+            // Pub/Sub
+            "SUBSCRIBE" => {
+                if arr.len() < 2 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'SUBSCRIBE'".into()));
+                }
+                let channels = arr[1..].iter().map(frame_to_string).collect::<Result<_, _>>()?;
+                Ok(Command::Subscribe(channels))
+            }
+            "UNSUBSCRIBE" => {
+                let channels = arr[1..].iter().map(frame_to_string).collect::<Result<_, _>>()?;
+                Ok(Command::Unsubscribe(channels))
+            }
+            "PUBLISH" => {
+                if arr.len() != 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'PUBLISH'".into()));
+                }
+                Ok(Command::Publish(frame_to_string(&arr[1])?, frame_to_bytes(&arr[2])?))
+            }
+            "PUBSUB" => {
+                if arr.len() < 2 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'PUBSUB'".into()));
+                }
+                let sub = frame_to_string(&arr[1])?;
+                match sub.to_uppercase().as_str() {
+                    "CHANNELS" => {
+                        if arr.len() > 3 {
+                            return Err(RedisError::Other("ERR wrong number of arguments for 'PUBSUB|CHANNELS'".into()));
+                        }
+                        let pattern = arr.get(2).map(frame_to_string).transpose()?;
+                        Ok(Command::PubSubChannels(pattern))
+                    }
+                    "NUMSUB" => {
+                        let channels = arr[2..].iter().map(frame_to_string).collect::<Result<_, _>>()?;
+                        Ok(Command::PubSubNumSub(channels))
+                    }
+                    _ => Err(RedisError::Other(format!("ERR unknown subcommand '{}'. Try PUBSUB HELP.", sub))),
+                }
+            }
             
             // Keyspace commands
             "EXISTS" => {
@@ -114,6 +603,70 @@ impl TryFrom<Frame> for Command {
                 let key = frame_to_string(&arr[1])?;
                 Ok(Command::Type(key))
             }
+            "OBJECT" => {
+                if arr.len() < 2 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'OBJECT'".into()));
+                }
+                let sub = frame_to_string(&arr[1])?;
+                match sub.to_uppercase().as_str() {
+                    "ENCODING" => {
+                        if arr.len() != 3 {
+                            return Err(RedisError::Other("ERR wrong number of arguments for 'OBJECT|ENCODING'".into()));
+                        }
+                        Ok(Command::ObjectEncoding(frame_to_string(&arr[2])?))
+                    }
+                    "FREQ" => {
+                        if arr.len() != 3 {
+                            return Err(RedisError::Other("ERR wrong number of arguments for 'OBJECT|FREQ'".into()));
+                        }
+                        Ok(Command::ObjectFreq(frame_to_string(&arr[2])?))
+                    }
+                    "IDLETIME" => {
+                        if arr.len() != 3 {
+                            return Err(RedisError::Other("ERR wrong number of arguments for 'OBJECT|IDLETIME'".into()));
+                        }
+                        Ok(Command::ObjectIdleTime(frame_to_string(&arr[2])?))
+                    }
+                    "REFCOUNT" => {
+                        if arr.len() != 3 {
+                            return Err(RedisError::Other("ERR wrong number of arguments for 'OBJECT|REFCOUNT'".into()));
+                        }
+                        Ok(Command::ObjectRefCount(frame_to_string(&arr[2])?))
+                    }
+                    _ => Err(RedisError::Other(format!("ERR unknown subcommand '{}'. Try OBJECT HELP.", sub))),
+                }
+            }
+            "MEMORY" => {
+                if arr.len() < 2 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'MEMORY'".into()));
+                }
+                let sub = frame_to_string(&arr[1])?;
+                match sub.to_uppercase().as_str() {
+                    "USAGE" => {
+                        if arr.len() != 3 && arr.len() != 5 {
+                            return Err(RedisError::Other("ERR wrong number of arguments for 'MEMORY|USAGE'".into()));
+                        }
+                        let key = frame_to_string(&arr[2])?;
+                        let mut samples = DEFAULT_MEM_SAMPLES;
+                        if arr.len() == 5 {
+                            if !frame_to_string(&arr[3])?.eq_ignore_ascii_case("SAMPLES") {
+                                return Err(RedisError::Other("ERR syntax error".into()));
+                            }
+                            samples = frame_to_string(&arr[4])?
+                                .parse()
+                                .map_err(|_| RedisError::Other("ERR value is out of range, must be positive".into()))?;
+                        }
+                        Ok(Command::MemoryUsage(key, samples))
+                    }
+                    "STATS" => {
+                        if arr.len() != 2 {
+                            return Err(RedisError::Other("ERR wrong number of arguments for 'MEMORY|STATS'".into()));
+                        }
+                        Ok(Command::MemoryStats)
+                    }
+                    _ => Err(RedisError::Other(format!("ERR unknown subcommand '{}'. Try MEMORY HELP.", sub))),
+                }
+            }
             "KEYS" => {
                 if arr.len() != 2 {
                     return Err(RedisError::Other("ERR wrong number of arguments for 'KEYS'".into()));
@@ -144,6 +697,36 @@ impl TryFrom<Frame> for Command {
                 let key = frame_to_string(&arr[1])?;
                 Ok(Command::Ttl(key))
             }
+            "SELECT" => {
+                if arr.len() != 2 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'SELECT'".into()));
+                }
+                Ok(Command::Select(parse_db_index(&arr[1], "ERR value is not an integer or out of range")?))
+            }
+            "SWAPDB" => {
+                if arr.len() != 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'SWAPDB'".into()));
+                }
+                Ok(Command::SwapDb(
+                    parse_db_index(&arr[1], "ERR invalid first DB index")?,
+                    parse_db_index(&arr[2], "ERR invalid second DB index")?,
+                ))
+            }
+            "MOVE" => {
+                if arr.len() != 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'MOVE'".into()));
+                }
+                Ok(Command::Move(
+                    frame_to_string(&arr[1])?,
+                    parse_db_index(&arr[2], "ERR value is not an integer or out of range")?,
+                ))
+            }
+            "DBSIZE" => {
+                if arr.len() != 1 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'DBSIZE'".into()));
+                }
+                Ok(Command::DbSize)
+            }
 
             // String commands
             "GET" => {
@@ -168,6 +751,15 @@ impl TryFrom<Frame> for Command {
                 let key = frame_to_string(&arr[1])?;
                 Ok(Command::Del(key))
             }
+            "UNLINK" => {
+                if arr.len() < 2 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'UNLINK'".into()));
+                }
+                let keys = arr[1..].iter().map(frame_to_string).collect::<Result<_, _>>()?;
+                Ok(Command::Unlink(keys))
+            }
+            "FLUSHALL" => Ok(Command::FlushAll(parse_flush_mode(&arr)?)),
+            "FLUSHDB" => Ok(Command::FlushDb(parse_flush_mode(&arr)?)),
             "APPEND" => {
                 if arr.len() != 3 {
                     return Err(RedisError::Other("ERR wrong number of arguments for 'APPEND'".into()));
@@ -230,6 +822,37 @@ impl TryFrom<Frame> for Command {
                 }
                 Ok(Command::MGet(keys))
             }
+            "LCS" => {
+                if arr.len() < 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'LCS'".into()));
+                }
+                let key1 = frame_to_string(&arr[1])?;
+                let key2 = frame_to_string(&arr[2])?;
+                let mut opts = LcsOptions::default();
+                let mut i = 3;
+                while i < arr.len() {
+                    match frame_to_string(&arr[i])?.to_uppercase().as_str() {
+                        "LEN" => opts.len = true,
+                        "IDX" => opts.idx = true,
+                        "WITHMATCHLEN" => opts.with_match_len = true,
+                        "MINMATCHLEN" if i + 1 < arr.len() => {
+                            let n = frame_to_string(&arr[i + 1])?
+                                .parse::<i64>()
+                                .map_err(|_| RedisError::Other("ERR value is not an integer or out of range".into()))?;
+                            opts.min_match_len = n.max(0) as usize;
+                            i += 1;
+                        }
+                        _ => return Err(RedisError::Other("ERR syntax error".into())),
+                    }
+                    i += 1;
+                }
+                if opts.len && opts.idx {
+                    return Err(RedisError::Other(
+                        "ERR If you want both the length and indexes, please just use IDX.".into(),
+                    ));
+                }
+                Ok(Command::Lcs(key1, key2, opts))
+            }
 
             // List commands
             "LPUSH" => {
@@ -243,11 +866,27 @@ impl TryFrom<Frame> for Command {
                 }
                 Ok(Command::LPush(key, vals))
             }
+            "LPUSHX" => {
+                if arr.len() < 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'LPUSHX'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let mut vals = Vec::new();
+                for f in &arr[2..] {
+                    vals.push(frame_to_bytes(f)?);
+                }
+                Ok(Command::LPushX(key, vals))
+            }
             "LPOP" => {
-                if arr.len() != 2 {
+                if arr.len() != 2 && arr.len() != 3 {
                     return Err(RedisError::Other("ERR wrong number of arguments for 'LPOP'".into()));
                 }
-                Ok(Command::LPop(frame_to_string(&arr[1])?))
+                let key = frame_to_string(&arr[1])?;
+                let count = match arr.get(2) {
+                    Some(f) => Some(parse_positive_count(f)?),
+                    None => None,
+                };
+                Ok(Command::LPop(key, count))
             }
             "RPUSH" => {
                 if arr.len() < 3 {
@@ -260,12 +899,27 @@ impl TryFrom<Frame> for Command {
                 }
                 Ok(Command::RPush(key, vals))
             }
+            "RPUSHX" => {
+                if arr.len() < 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'RPUSHX'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let mut vals = Vec::new();
+                for f in &arr[2..] {
+                    vals.push(frame_to_bytes(f)?);
+                }
+                Ok(Command::RPushX(key, vals))
+            }
             "RPOP" => {
-                if arr.len() != 2 {
+                if arr.len() != 2 && arr.len() != 3 {
                     return Err(RedisError::Other("ERR wrong number of arguments for 'RPOP'".into()));
                 }
                 let key = frame_to_string(&arr[1])?;
-                Ok(Command::RPop(key))
+                let count = match arr.get(2) {
+                    Some(f) => Some(parse_positive_count(f)?),
+                    None => None,
+                };
+                Ok(Command::RPop(key, count))
             }
             "LLEN" => {
                 if arr.len() != 2 {
@@ -314,34 +968,168 @@ impl TryFrom<Frame> for Command {
                     .map_err(|_| RedisError::Other("ERR value is not an integer".into()))?;
                 Ok(Command::LTrim(key, start, stop))
             }
-            "BRPOP" => {
-                if arr.len() != 3 {
-                    return Err(RedisError::Other("ERR wrong number of arguments for 'BRPOP'".into()));
+            "LINSERT" => {
+                if arr.len() != 5 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'LINSERT'".into()));
                 }
                 let key = frame_to_string(&arr[1])?;
-                let timeout_str = frame_to_string(&arr[2])?;
-                let timeout: usize = timeout_str.parse().map_err(|_| {
-                    RedisError::Other("ERR timeout must be integer".into())
-                })?;
-                Ok(Command::BRPop(key, timeout))
+                let position = match frame_to_string(&arr[2])?.to_uppercase().as_str() {
+                    "BEFORE" => InsertPosition::Before,
+                    "AFTER" => InsertPosition::After,
+                    _ => return Err(RedisError::Other("ERR syntax error".into())),
+                };
+                let pivot = frame_to_bytes(&arr[3])?;
+                let element = frame_to_bytes(&arr[4])?;
+                Ok(Command::LInsert(key, position, pivot, element))
+            }
+            "LREM" => {
+                if arr.len() != 4 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'LREM'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let count = frame_to_string(&arr[2])?.parse::<i64>()
+                    .map_err(|_| RedisError::Other("ERR value is not an integer".into()))?;
+                let element = frame_to_bytes(&arr[3])?;
+                Ok(Command::LRem(key, count, element))
+            }
+            "LPOS" => {
+                if arr.len() < 3 || arr.len() % 2 == 0 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'LPOS'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let element = frame_to_bytes(&arr[2])?;
+                let mut opts = LPosOptions { rank: 1, count: None, maxlen: 0 };
+                for pair in arr[3..].chunks(2) {
+                    let n = frame_to_string(&pair[1])?.parse::<i64>()
+                        .map_err(|_| RedisError::Other("ERR value is not an integer".into()))?;
+                    match frame_to_string(&pair[0])?.to_uppercase().as_str() {
+                        "RANK" => {
+                            if n == 0 {
+                                return Err(RedisError::Other(
+                                    "ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list".into(),
+                                ));
+                            }
+                            if n == i64::MIN {
+                                return Err(RedisError::Other("ERR value is out of range".into()));
+                            }
+                            opts.rank = n;
+                        }
+                        "COUNT" => {
+                            if n < 0 {
+                                return Err(RedisError::Other("ERR COUNT can't be negative".into()));
+                            }
+                            opts.count = Some(n as usize);
+                        }
+                        "MAXLEN" => {
+                            if n < 0 {
+                                return Err(RedisError::Other("ERR MAXLEN can't be negative".into()));
+                            }
+                            opts.maxlen = n as usize;
+                        }
+                        _ => return Err(RedisError::Other("ERR syntax error".into())),
+                    }
+                }
+                Ok(Command::LPos(key, element, opts))
+            }
+            "LMOVE" => {
+                if arr.len() != 5 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'LMOVE'".into()));
+                }
+                let source = frame_to_string(&arr[1])?;
+                let destination = frame_to_string(&arr[2])?;
+                let from = parse_list_end(&arr[3])?;
+                let to = parse_list_end(&arr[4])?;
+                Ok(Command::LMove(source, destination, from, to))
+            }
+            "RPOPLPUSH" => {
+                if arr.len() != 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'RPOPLPUSH'".into()));
+                }
+                let source = frame_to_string(&arr[1])?;
+                let destination = frame_to_string(&arr[2])?;
+                Ok(Command::LMove(source, destination, ListEnd::Right, ListEnd::Left))
+            }
+            "LMPOP" => {
+                if arr.len() < 4 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'LMPOP'".into()));
+                }
+                let (keys, end, count) = parse_mpop_args(&arr[1..], parse_list_end)?;
+                Ok(Command::LMPop(keys, end, count))
+            }
+            "BLPOP" | "BRPOP" => {
+                if arr.len() < 3 {
+                    return Err(RedisError::Other(format!("ERR wrong number of arguments for '{}'", cmd_name)));
+                }
+                let keys = arr[1..arr.len() - 1].iter()
+                    .map(frame_to_string)
+                    .collect::<Result<Vec<_>, _>>()?;
+                let timeout = parse_timeout(&arr[arr.len() - 1])?;
+                if cmd_name == "BLPOP" {
+                    Ok(Command::BLPop(keys, timeout))
+                } else {
+                    Ok(Command::BRPop(keys, timeout))
+                }
+            }
+            "BLMOVE" => {
+                if arr.len() != 6 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'BLMOVE'".into()));
+                }
+                let source = frame_to_string(&arr[1])?;
+                let destination = frame_to_string(&arr[2])?;
+                let from = parse_list_end(&arr[3])?;
+                let to = parse_list_end(&arr[4])?;
+                let timeout = parse_timeout(&arr[5])?;
+                Ok(Command::BLMove(source, destination, from, to, timeout))
+            }
+            "BRPOPLPUSH" => {
+                if arr.len() != 4 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'BRPOPLPUSH'".into()));
+                }
+                let source = frame_to_string(&arr[1])?;
+                let destination = frame_to_string(&arr[2])?;
+                let timeout = parse_timeout(&arr[3])?;
+                Ok(Command::BLMove(source, destination, ListEnd::Right, ListEnd::Left, timeout))
+            }
+            "BLMPOP" => {
+                if arr.len() < 5 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'BLMPOP'".into()));
+                }
+                let timeout = parse_timeout(&arr[1])?;
+                let (keys, end, count) = parse_mpop_args(&arr[2..], parse_list_end)?;
+                Ok(Command::BLMPop(timeout, keys, end, count))
             }
 
             // Hash commands
-            "HSET" => {
+            "HSET" | "HMSET" => {
+                if arr.len() < 4 || arr.len() % 2 != 0 {
+                    return Err(RedisError::Other(format!("ERR wrong number of arguments for '{}'", cmd_name)));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let mut pairs = Vec::new();
+                for pair in arr[2..].chunks(2) {
+                    pairs.push((frame_to_bytes(&pair[0])?, frame_to_bytes(&pair[1])?));
+                }
+                if cmd_name == "HSET" {
+                    Ok(Command::HSet(key, pairs))
+                } else {
+                    Ok(Command::HMSet(key, pairs))
+                }
+            }
+            "HSETNX" => {
                 if arr.len() != 4 {
-                    return Err(RedisError::Other("ERR wrong number of arguments for 'HSET'".into()));
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'HSETNX'".into()));
                 }
                 let key = frame_to_string(&arr[1])?;
-                let field = frame_to_string(&arr[2])?;
+                let field = frame_to_bytes(&arr[2])?;
                 let value = frame_to_bytes(&arr[3])?;
-                Ok(Command::HSet(key, field, value))
+                Ok(Command::HSetNx(key, field, value))
             }
             "HGET" => {
                 if arr.len() != 3 {
                     return Err(RedisError::Other("ERR wrong number of arguments for 'HGET'".into()));
                 }
                 let key = frame_to_string(&arr[1])?;
-                let field = frame_to_string(&arr[2])?;
+                let field = frame_to_bytes(&arr[2])?;
                 Ok(Command::HGet(key, field))
             }
             "HDEL" => {
@@ -351,7 +1139,7 @@ impl TryFrom<Frame> for Command {
                 let key = frame_to_string(&arr[1])?;
                 let mut fields = Vec::new();
                 for f in &arr[2..] {
-                    fields.push(frame_to_string(f)?);
+                    fields.push(frame_to_bytes(f)?);
                 }
                 Ok(Command::HDel(key, fields))
             }
@@ -369,7 +1157,7 @@ impl TryFrom<Frame> for Command {
                 let key = frame_to_string(&arr[1])?;
                 let mut fields = Vec::new();
                 for f in &arr[2..] {
-                    fields.push(frame_to_string(f)?);
+                    fields.push(frame_to_bytes(f)?);
                 }
                 Ok(Command::HMGet(key, fields))
             }
@@ -378,7 +1166,7 @@ impl TryFrom<Frame> for Command {
                     return Err(RedisError::Other("ERR wrong number of arguments for 'HEXISTS'".into()));
                 }
                 let key = frame_to_string(&arr[1])?;
-                let field = frame_to_string(&arr[2])?;
+                let field = frame_to_bytes(&arr[2])?;
                 Ok(Command::HExists(key, field))
             }
             "HLEN" => {
@@ -402,6 +1190,94 @@ impl TryFrom<Frame> for Command {
                 let key = frame_to_string(&arr[1])?;
                 Ok(Command::HVals(key))
             }
+            "HSTRLEN" => {
+                if arr.len() != 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'HSTRLEN'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let field = frame_to_bytes(&arr[2])?;
+                Ok(Command::HStrLen(key, field))
+            }
+            "HINCRBY" => {
+                if arr.len() != 4 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'HINCRBY'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let field = frame_to_bytes(&arr[2])?;
+                let amt = frame_to_string(&arr[3])?.parse::<i64>()
+                    .map_err(|_| RedisError::Other("ERR value is not an integer or out of range".into()))?;
+                Ok(Command::HIncrBy(key, field, amt))
+            }
+            "HINCRBYFLOAT" => {
+                if arr.len() != 4 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'HINCRBYFLOAT'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let field = frame_to_bytes(&arr[2])?;
+                let amt = frame_to_string(&arr[3])?.parse::<f64>()
+                    .ok()
+                    .filter(|f| f.is_finite())
+                    .ok_or_else(|| RedisError::Other("ERR value is not a valid float".into()))?;
+                Ok(Command::HIncrByFloat(key, field, amt))
+            }
+            "HRANDFIELD" => {
+                if arr.len() < 2 || arr.len() > 4 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'HRANDFIELD'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let count = match arr.get(2) {
+                    Some(f) => Some(parse_random_count(f)?),
+                    None => None,
+                };
+                let with_values = match arr.get(3) {
+                    Some(f) if frame_to_string(f)?.eq_ignore_ascii_case("WITHVALUES") => true,
+                    Some(_) => return Err(RedisError::Other("ERR syntax error".into())),
+                    None => false,
+                };
+                Ok(Command::HRandField(key, count, with_values))
+            }
+            "HEXPIRE" | "HPEXPIRE" | "HEXPIREAT" | "HPEXPIREAT" => {
+                if arr.len() < 6 {
+                    return Err(RedisError::Other(format!("ERR wrong number of arguments for '{}'", cmd_name)));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let time = frame_to_string(&arr[2])?
+                    .parse::<i64>()
+                    .map_err(|_| RedisError::Other("ERR value is not an integer or out of range".into()))?;
+                let when = match cmd_name.as_str() {
+                    "HEXPIRE" => FieldExpiry::Seconds(time),
+                    "HPEXPIRE" => FieldExpiry::Millis(time),
+                    "HEXPIREAT" => FieldExpiry::AtSeconds(time),
+                    _ => FieldExpiry::AtMillis(time),
+                };
+                let mut rest = &arr[3..];
+                let cond = match frame_to_string(&rest[0])?.to_uppercase().as_str() {
+                    "NX" => Some(ExpireCondition::Nx),
+                    "XX" => Some(ExpireCondition::Xx),
+                    "GT" => Some(ExpireCondition::Gt),
+                    "LT" => Some(ExpireCondition::Lt),
+                    _ => None,
+                };
+                if cond.is_some() {
+                    rest = &rest[1..];
+                }
+                let fields = parse_fields_arg(rest)?;
+                Ok(Command::HExpire(key, when, cond, fields))
+            }
+            "HTTL" | "HPTTL" | "HEXPIRETIME" | "HPEXPIRETIME" | "HPERSIST" => {
+                if arr.len() < 5 {
+                    return Err(RedisError::Other(format!("ERR wrong number of arguments for '{}'", cmd_name)));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let fields = parse_fields_arg(&arr[2..])?;
+                Ok(match cmd_name.as_str() {
+                    "HTTL" => Command::HTtl(key, fields),
+                    "HPTTL" => Command::HPTtl(key, fields),
+                    "HEXPIRETIME" => Command::HExpireTime(key, fields),
+                    "HPEXPIRETIME" => Command::HPExpireTime(key, fields),
+                    _ => Command::HPersist(key, fields),
+                })
+            }
 
             // Set commands
             "SADD" => {
@@ -449,7 +1325,7 @@ impl TryFrom<Frame> for Command {
                     return Err(RedisError::Other("ERR wrong number of arguments for 'SUNION'".into()));
                 }
                 let keys = arr[1..].iter()
-                    .map(|f| frame_to_string(f))
+                    .map(frame_to_string)
                     .collect::<Result<Vec<_>, _>>()?;
                 Ok(Command::SUnion(keys))
             }
@@ -458,7 +1334,7 @@ impl TryFrom<Frame> for Command {
                     return Err(RedisError::Other("ERR wrong number of arguments for 'SINTER'".into()));
                 }
                 let keys = arr[1..].iter()
-                    .map(|f| frame_to_string(f))
+                    .map(frame_to_string)
                     .collect::<Result<Vec<_>, _>>()?;
                 Ok(Command::SInter(keys))
             }
@@ -467,60 +1343,188 @@ impl TryFrom<Frame> for Command {
                     return Err(RedisError::Other("ERR wrong number of arguments for 'SDIFF'".into()));
                 }
                 let keys = arr[1..].iter()
-                    .map(|f| frame_to_string(f))
+                    .map(frame_to_string)
                     .collect::<Result<Vec<_>, _>>()?;
                 Ok(Command::SDiff(keys))
             }
+            "SUNIONSTORE" | "SINTERSTORE" | "SDIFFSTORE" => {
+                if arr.len() < 3 {
+                    return Err(RedisError::Other(format!("ERR wrong number of arguments for '{}'", cmd_name)));
+                }
+                let dest = frame_to_string(&arr[1])?;
+                let keys = arr[2..].iter()
+                    .map(frame_to_string)
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(match cmd_name.as_str() {
+                    "SUNIONSTORE" => Command::SUnionStore(dest, keys),
+                    "SINTERSTORE" => Command::SInterStore(dest, keys),
+                    _ => Command::SDiffStore(dest, keys),
+                })
+            }
+            "SINTERCARD" => {
+                if arr.len() < 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'SINTERCARD'".into()));
+                }
+                let numkeys = frame_to_string(&arr[1])?
+                    .parse::<i64>()
+                    .map_err(|_| RedisError::Other("ERR numkeys should be greater than 0".into()))?;
+                if numkeys <= 0 {
+                    return Err(RedisError::Other("ERR numkeys should be greater than 0".into()));
+                }
+                let numkeys = numkeys as usize;
+                if arr.len() < numkeys + 2 {
+                    return Err(RedisError::Other("ERR Number of keys can't be greater than number of args".into()));
+                }
+                let keys = arr[2..numkeys + 2].iter()
+                    .map(frame_to_string)
+                    .collect::<Result<Vec<_>, _>>()?;
+                let mut limit = 0;
+                match &arr[numkeys + 2..] {
+                    [] => {}
+                    [opt, n] if frame_to_string(opt)?.eq_ignore_ascii_case("LIMIT") => {
+                        let n = frame_to_string(n)?
+                            .parse::<i64>()
+                            .map_err(|_| RedisError::Other("ERR LIMIT can't be negative".into()))?;
+                        if n < 0 {
+                            return Err(RedisError::Other("ERR LIMIT can't be negative".into()));
+                        }
+                        limit = n as usize;
+                    }
+                    _ => return Err(RedisError::Other("ERR syntax error".into())),
+                }
+                Ok(Command::SInterCard(keys, limit))
+            }
+            "SPOP" => {
+                if arr.len() < 2 || arr.len() > 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'SPOP'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let count = match arr.get(2) {
+                    Some(f) => Some(parse_positive_count(f)?),
+                    None => None,
+                };
+                Ok(Command::SPop(key, count))
+            }
+            "SRANDMEMBER" => {
+                if arr.len() < 2 || arr.len() > 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'SRANDMEMBER'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let count = match arr.get(2) {
+                    Some(f) => Some(parse_random_count(f)?),
+                    None => None,
+                };
+                Ok(Command::SRandMember(key, count))
+            }
+            "SMOVE" => {
+                if arr.len() != 4 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'SMOVE'".into()));
+                }
+                let src = frame_to_string(&arr[1])?;
+                let dst = frame_to_string(&arr[2])?;
+                let member = frame_to_bytes(&arr[3])?;
+                Ok(Command::SMove(src, dst, member))
+            }
+            "SMISMEMBER" => {
+                if arr.len() < 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'SMISMEMBER'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let mut members = Vec::new();
+                for f in &arr[2..] {
+                    members.push(frame_to_bytes(f)?);
+                }
+                Ok(Command::SMIsMember(key, members))
+            }
             
             // Sorted Set commands
             "ZADD" => {
-                if arr.len() != 4 {
+                if arr.len() < 4 {
                     return Err(RedisError::Other("ERR wrong number of arguments for 'ZADD'".into()));
                 }
                 let key = frame_to_string(&arr[1])?;
-                let score: f64 = frame_to_string(&arr[2])?
-                    .parse()
-                    .map_err(|_| RedisError::Other("ERR score must be a float".into()))?;
+
+                let mut opts = ZAddOptions::default();
+                let mut idx = 2;
+                while idx < arr.len() {
+                    match frame_to_string(&arr[idx])?.to_uppercase().as_str() {
+                        "NX" => opts.nx = true,
+                        "XX" => opts.xx = true,
+                        "GT" => opts.gt = true,
+                        "LT" => opts.lt = true,
+                        "CH" => opts.ch = true,
+                        "INCR" => opts.incr = true,
+                        _ => break,
+                    }
+                    idx += 1;
+                }
+
+                let rest = &arr[idx..];
+                if rest.is_empty() || rest.len() % 2 != 0 {
+                    return Err(RedisError::Other("ERR syntax error".into()));
+                }
+                if opts.nx && opts.xx {
+                    return Err(RedisError::Other("ERR XX and NX options at the same time are not compatible".into()));
+                }
+                if (opts.gt && opts.lt) || (opts.nx && (opts.gt || opts.lt)) {
+                    return Err(RedisError::Other("ERR GT, LT, and/or NX options at the same time are not compatible".into()));
+                }
+                if opts.incr && rest.len() != 2 {
+                    return Err(RedisError::Other("ERR INCR option supports a single increment-element pair".into()));
+                }
+
+                let mut pairs = Vec::with_capacity(rest.len() / 2);
+                for pair in rest.chunks(2) {
+                    pairs.push((parse_score(&pair[0])?, frame_to_bytes(&pair[1])?));
+                }
+                Ok(Command::ZAdd(key, opts, pairs))
+            }
+            "ZINCRBY" => {
+                if arr.len() != 4 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'ZINCRBY'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let incr = parse_score(&arr[2])?;
                 let member = frame_to_bytes(&arr[3])?;
-                Ok(Command::ZAdd(key, score, member))
+                Ok(Command::ZIncrBy(key, incr, member))
+            }
+            "ZMSCORE" => {
+                if arr.len() < 3 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'ZMSCORE'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let members = arr[2..].iter()
+                    .map(frame_to_bytes)
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Command::ZMScore(key, members))
             }
             "ZREM" => {
-                if arr.len() != 3 {
+                if arr.len() < 3 {
                     return Err(RedisError::Other("ERR wrong number of arguments for 'ZREM'".into()));
                 }
                 let key = frame_to_string(&arr[1])?;
-                let member = frame_to_bytes(&arr[2])?;
-                Ok(Command::ZRem(key, member))
+                let mut members = Vec::new();
+                for f in &arr[2..] {
+                    members.push(frame_to_bytes(f)?);
+                }
+                Ok(Command::ZRem(key, members))
             }
-            "ZRANGE" => {
-                if arr.len() != 4 {
-                    return Err(RedisError::Other(
-                        "ERR wrong number of arguments for 'ZRANGE'".into(),
-                    ));
+            "ZRANGE" | "ZREVRANGE" | "ZRANGEBYSCORE" | "ZREVRANGEBYSCORE" | "ZRANGEBYLEX" | "ZREVRANGEBYLEX" => {
+                if arr.len() < 4 {
+                    return Err(RedisError::Other(format!("ERR wrong number of arguments for '{}'", cmd_name)));
                 }
                 let key = frame_to_string(&arr[1])?;
-                let start = frame_to_string(&arr[2])?
-                    .parse::<i64>()
-                    .map_err(|_| RedisError::Other("ERR start must be an integer".into()))?;
-                let end = frame_to_string(&arr[3])?
-                    .parse::<i64>()
-                    .map_err(|_| RedisError::Other("ERR stop must be an integer".into()))?;
-                Ok(Command::ZRange(key, start, end))
+                let opts = parse_zrange(&cmd_name, &arr[2], &arr[3], &arr[4..])?;
+                Ok(Command::ZRange(key, opts))
             }
-            "ZREVRANGE" => {
-                if arr.len() != 4 {
-                    return Err(RedisError::Other(
-                        "ERR wrong number of arguments for 'ZREVRANGE'".into(),
-                    ));
+            "ZRANGESTORE" => {
+                if arr.len() < 5 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'ZRANGESTORE'".into()));
                 }
-                let key = frame_to_string(&arr[1])?;
-                let start = frame_to_string(&arr[2])?
-                    .parse::<i64>()
-                    .map_err(|_| RedisError::Other("ERR start must be an integer".into()))?;
-                let end = frame_to_string(&arr[3])?
-                    .parse::<i64>()
-                    .map_err(|_| RedisError::Other("ERR start must be an integer".into()))?;
-                Ok(Command::ZRevRange(key, start, end))
+                let dst = frame_to_string(&arr[1])?;
+                let src = frame_to_string(&arr[2])?;
+                let opts = parse_zrange(&cmd_name, &arr[3], &arr[4], &arr[5..])?;
+                Ok(Command::ZRangeStore(dst, src, opts))
             }
             "ZCARD" => {
                 if arr.len() != 2 {
@@ -538,64 +1542,148 @@ impl TryFrom<Frame> for Command {
                 let member = frame_to_bytes(&arr[2])?;
                 Ok(Command::ZScore(key, member))
             }
-            "ZRANGEBYSCORE" => {
+            "ZREMRANGEBYSCORE" => {
                 if arr.len() != 4 {
                     return Err(RedisError::Other(
-                        "ERR wrong number of arguments for 'ZRANGEBYSCORE'".into(),
+                        "ERR wrong number of arguments for 'ZREMRANGEBYSCORE'".into(),
                     ));
                 }
                 let key = frame_to_string(&arr[1])?;
-                let min: f64 = frame_to_string(&arr[2])?
-                    .parse()
-                    .map_err(|_| RedisError::Other("ERR min must be a float".into()))?;
-                let max: f64 = frame_to_string(&arr[3])?
-                    .parse()
-                    .map_err(|_| RedisError::Other("ERR max must be a float".into()))?;
-                Ok(Command::ZRangeByScore(key, min, max))
+                let range = parse_score_range(&arr[2], &arr[3])?;
+                Ok(Command::ZRemRangeByScore(key, range))
             }
-            "ZREMRANGEBYSCORE" => {
+            "ZREMRANGEBYLEX" => {
                 if arr.len() != 4 {
-                    return Err(RedisError::Other(
-                        "ERR wrong number of arguments for 'ZREMRANGEBYSCORE'".into(),
-                    ));
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'ZREMRANGEBYLEX'".into()));
                 }
                 let key = frame_to_string(&arr[1])?;
-                let min: f64 = frame_to_string(&arr[2])?
-                    .parse()
-                    .map_err(|_| RedisError::Other("ERR min must be a float".into()))?;
-                let max: f64 = frame_to_string(&arr[3])?
-                    .parse()
-                    .map_err(|_| RedisError::Other("ERR max must be a float".into()))?;
-                Ok(Command::ZRemRangeByScore(key, min, max))
+                let range = parse_lex_range(&arr[2], &arr[3])?;
+                Ok(Command::ZRemRangeByLex(key, range))
             }
-            "ZRANK" => {
-                if arr.len() != 3 {
-                    return Err(RedisError::Other("ERR wrong number of arguments for 'ZRANK'".into()));
+            "ZREMRANGEBYRANK" => {
+                if arr.len() != 4 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'ZREMRANGEBYRANK'".into()));
                 }
                 let key = frame_to_string(&arr[1])?;
-                let member = frame_to_bytes(&arr[2])?;
-                Ok(Command::ZRank(key, member))
+                let start = parse_rank_index(&arr[2])?;
+                let stop = parse_rank_index(&arr[3])?;
+                Ok(Command::ZRemRangeByRank(key, start, stop))
             }
-            "ZREVRANK" => {
-                if arr.len() != 3 {
-                    return Err(RedisError::Other("ERR wrong number of arguments for 'ZREVRANK'".into()));
+            "ZRANK" | "ZREVRANK" => {
+                if arr.len() != 3 && arr.len() != 4 {
+                    return Err(RedisError::Other(format!("ERR wrong number of arguments for '{}'", cmd_name)));
                 }
                 let key = frame_to_string(&arr[1])?;
                 let member = frame_to_bytes(&arr[2])?;
-                Ok(Command::ZRevRank(key, member))
+                let with_score = match arr.get(3) {
+                    Some(f) if frame_to_string(f)?.eq_ignore_ascii_case("WITHSCORE") => true,
+                    Some(_) => return Err(RedisError::Other("ERR syntax error".into())),
+                    None => false,
+                };
+                if cmd_name == "ZRANK" {
+                    Ok(Command::ZRank(key, member, with_score))
+                } else {
+                    Ok(Command::ZRevRank(key, member, with_score))
+                }
+            }
+            "ZRANDMEMBER" => {
+                if arr.len() < 2 || arr.len() > 4 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'ZRANDMEMBER'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let count = match arr.get(2) {
+                    Some(f) => Some(parse_random_count(f)?),
+                    None => None,
+                };
+                let with_scores = match arr.get(3) {
+                    Some(f) if frame_to_string(f)?.eq_ignore_ascii_case("WITHSCORES") => true,
+                    Some(_) => return Err(RedisError::Other("ERR syntax error".into())),
+                    None => false,
+                };
+                Ok(Command::ZRandMember(key, count, with_scores))
+            }
+            "ZMPOP" => {
+                if arr.len() < 4 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'ZMPOP'".into()));
+                }
+                let (keys, end, count) = parse_mpop_args(&arr[1..], parse_zset_end)?;
+                Ok(Command::ZMPop(keys, end, count))
             }
             "ZCOUNT" => {
                 if arr.len() != 4 {
                     return Err(RedisError::Other("ERR wrong number of arguments for 'ZCOUNT'".into()));
                 }
                 let key = frame_to_string(&arr[1])?;
-                let min = frame_to_string(&arr[2])?
-                    .parse::<f64>()
-                    .map_err(|_| RedisError::Other("ERR start must be a float".into()))?;
-                let max = frame_to_string(&arr[3])?
-                    .parse::<f64>()
-                    .map_err(|_| RedisError::Other("ERR start must be a float".into()))?;
-                Ok(Command::ZCount(key, min, max))
+                let range = parse_score_range(&arr[2], &arr[3])?;
+                Ok(Command::ZCount(key, range))
+            }
+            "ZUNION" | "ZINTER" | "ZDIFF" => {
+                if arr.len() < 3 {
+                    return Err(RedisError::Other(format!("ERR wrong number of arguments for '{}'", cmd_name)));
+                }
+                let (keys, opts) = parse_zalgebra(&cmd_name, &arr[1..])?;
+                Ok(match cmd_name.as_str() {
+                    "ZUNION" => Command::ZUnion(keys, opts),
+                    "ZINTER" => Command::ZInter(keys, opts),
+                    _ => Command::ZDiff(keys, opts),
+                })
+            }
+            "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" => {
+                if arr.len() < 4 {
+                    return Err(RedisError::Other(format!("ERR wrong number of arguments for '{}'", cmd_name)));
+                }
+                let dest = frame_to_string(&arr[1])?;
+                let (keys, opts) = parse_zalgebra(&cmd_name, &arr[2..])?;
+                Ok(match cmd_name.as_str() {
+                    "ZUNIONSTORE" => Command::ZUnionStore(dest, keys, opts),
+                    "ZINTERSTORE" => Command::ZInterStore(dest, keys, opts),
+                    _ => Command::ZDiffStore(dest, keys, opts),
+                })
+            }
+            "ZLEXCOUNT" => {
+                if arr.len() != 4 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'ZLEXCOUNT'".into()));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let range = parse_lex_range(&arr[2], &arr[3])?;
+                Ok(Command::ZLexCount(key, range))
+            }
+            "ZPOPMIN" | "ZPOPMAX" => {
+                if arr.len() != 2 && arr.len() != 3 {
+                    return Err(RedisError::Other(format!("ERR wrong number of arguments for '{}'", cmd_name)));
+                }
+                let key = frame_to_string(&arr[1])?;
+                let count = match arr.get(2) {
+                    Some(f) => Some(parse_positive_count(f)?),
+                    None => None,
+                };
+                if cmd_name == "ZPOPMIN" {
+                    Ok(Command::ZPopMin(key, count))
+                } else {
+                    Ok(Command::ZPopMax(key, count))
+                }
+            }
+            "BZPOPMIN" | "BZPOPMAX" => {
+                if arr.len() < 3 {
+                    return Err(RedisError::Other(format!("ERR wrong number of arguments for '{}'", cmd_name)));
+                }
+                let keys = arr[1..arr.len() - 1].iter()
+                    .map(frame_to_string)
+                    .collect::<Result<Vec<_>, _>>()?;
+                let timeout = parse_timeout(&arr[arr.len() - 1])?;
+                if cmd_name == "BZPOPMIN" {
+                    Ok(Command::BZPopMin(keys, timeout))
+                } else {
+                    Ok(Command::BZPopMax(keys, timeout))
+                }
+            }
+            "BZMPOP" => {
+                if arr.len() < 5 {
+                    return Err(RedisError::Other("ERR wrong number of arguments for 'BZMPOP'".into()));
+                }
+                let timeout = parse_timeout(&arr[1])?;
+                let (keys, end, count) = parse_mpop_args(&arr[2..], parse_zset_end)?;
+                Ok(Command::BZMPop(timeout, keys, end, count))
             }
             _ => Err(RedisError::UnknownCommand),
         }
@@ -609,8 +1697,30 @@ impl Command {
         match self {
             Expire(_, _) => true,
 
+            // SELECT is not logged as such: the log records one whenever a
+            // write goes to another database than the previous one.
+            SwapDb(_, _) | Move(_, _) => true,
+            Select(_) | DbSize => false,
+
+            // The queued commands are logged between MULTI and EXEC when the
+            // transaction runs.
+            Multi | Exec | Discard | Watch(_) | Unwatch => false,
+
+            // Scripts are logged as the effects of the commands they call.
+            Eval(_, _, _) | EvalSha(_, _, _) => false,
+            ScriptLoad(_) | ScriptExists(_) | ScriptFlush | ScriptKill => false,
+            FCall(_, _, _) | FCallRo(_, _, _) => false,
+
+            // Libraries are only persisted through the log, so every change
+            // to them is logged.
+            FunctionLoad(_, _) | FunctionDelete(_) | FunctionFlush | FunctionRestore(_, _) => true,
+            FunctionList(_, _) | FunctionDump => false,
+
             Set(_, _)
             | Del(_)
+            | Unlink(_)
+            | FlushAll(_)
+            | FlushDb(_)
             | Append(_, _)
             | GetSet(_, _)
             | Incr(_)
@@ -618,25 +1728,186 @@ impl Command {
             | MSet(_) => true,
 
             LPush(_, _)
-            | LPop(_)
+            | LPushX(_, _)
+            | LPop(_, _)
             | RPush(_, _)
-            | RPop(_)
+            | RPushX(_, _)
+            | RPop(_, _)
             | LSet(_, _, _)
-            | LTrim(_, _, _) => true,
+            | LTrim(_, _, _)
+            | LInsert(_, _, _, _)
+            | LRem(_, _, _)
+            | LMove(_, _, _, _) => true,
+
+            // Pops performed by these are propagated as concrete LPOP/RPOP/LMOVE effects.
+            LMPop(_, _, _)
+            | BLPop(_, _)
+            | BRPop(_, _)
+            | BLMove(_, _, _, _, _)
+            | BLMPop(_, _, _, _) => false,
 
-            BRPop(_, _) => false,
+            HSet(_, _)
+            | HMSet(_, _)
+            | HSetNx(_, _, _)
+            | HDel(_, _)
+            | HIncrBy(_, _, _) => true,
 
-            HSet(_, _, _) | HDel(_, _) => true,
+            // Propagated as an HSET of the resulting value so replay cannot drift.
+            HIncrByFloat(_, _, _) => false,
 
-            SAdd(_, _) | SRem(_, _) => true,
+            // Relative times are propagated as HPEXPIREAT with the absolute deadline.
+            HExpire(_, _, _, _) => false,
+            HPersist(_, _) => true,
+
+            SAdd(_, _)
+            | SRem(_, _)
+            | SMove(_, _, _)
+            | SUnionStore(_, _)
+            | SInterStore(_, _)
+            | SDiffStore(_, _) => true,
+
+            // The randomly chosen members are propagated as an SREM.
+            SPop(_, _) => false,
+
+            // Pops are propagated as concrete ZREM effects.
+            ZPopMin(_, _)
+            | ZMPop(_, _, _)
+            | ZPopMax(_, _)
+            | BZPopMin(_, _)
+            | BZPopMax(_, _)
+            | BZMPop(_, _, _, _) => false,
 
             ZAdd(_, _, _)
+            | ZIncrBy(_, _, _)
             | ZRem(_, _)
-            | ZRemRangeByScore(_, _, _) => true,
+            | ZRemRangeByScore(_, _)
+            | ZRemRangeByLex(_, _)
+            | ZRemRangeByRank(_, _, _)
+            | ZRangeStore(_, _, _)
+            | ZUnionStore(_, _, _)
+            | ZInterStore(_, _, _)
+            | ZDiffStore(_, _, _) => true,
 
             _ => false,
         }
     }
+
+    /// Whether the command may grow the dataset, and so must be refused when
+    /// `maxmemory` is reached and no key can be evicted. Matches the commands
+    /// Redis flags `denyoom`; deletions and pops stay allowed since they
+    /// free memory.
+    pub fn is_denyoom(&self) -> bool {
+        use Command::*;
+        matches!(
+            self,
+            Eval(_, _, _)
+                | EvalSha(_, _, _)
+                | FCall(_, _, _)
+                | FunctionLoad(_, _)
+                | FunctionRestore(_, _)
+                | Set(_, _)
+                | Append(_, _)
+                | GetSet(_, _)
+                | Incr(_)
+                | IncrBy(_, _)
+                | MSet(_)
+                | LPush(_, _)
+                | LPushX(_, _)
+                | RPush(_, _)
+                | RPushX(_, _)
+                | LSet(_, _, _)
+                | LInsert(_, _, _, _)
+                | LMove(_, _, _, _)
+                | BLMove(_, _, _, _, _)
+                | HSet(_, _)
+                | HMSet(_, _)
+                | HSetNx(_, _, _)
+                | HIncrBy(_, _, _)
+                | HIncrByFloat(_, _, _)
+                | SAdd(_, _)
+                | SUnionStore(_, _)
+                | SInterStore(_, _)
+                | SDiffStore(_, _)
+                | ZAdd(_, _, _)
+                | ZIncrBy(_, _, _)
+                | ZRangeStore(_, _, _)
+                | ZUnionStore(_, _, _)
+                | ZInterStore(_, _, _)
+                | ZDiffStore(_, _, _)
+        )
+    }
+
+    /// Whether this is HRANDFIELD or one of its kin with a negative count,
+    /// whose picks may repeat, making the reply as large as the client asks.
+    pub fn picks_with_repetition(&self) -> bool {
+        matches!(
+            self,
+            Command::HRandField(_, Some(n), _)
+                | Command::SRandMember(_, Some(n))
+                | Command::ZRandMember(_, Some(n), _) if *n < 0
+        )
+    }
+
+    /// Whether the command runs a script or a function.
+    pub fn is_script(&self) -> bool {
+        use Command::*;
+        matches!(self, Eval(_, _, _) | EvalSha(_, _, _) | FCall(_, _, _) | FCallRo(_, _, _))
+    }
+
+    /// Whether scripts are refused the command, as for the commands Redis
+    /// flags `noscript`.
+    pub fn is_noscript(&self) -> bool {
+        use Command::*;
+        matches!(
+            self,
+            Multi
+                | Exec
+                | Discard
+                | Watch(_)
+                | Unwatch
+                | Eval(_, _, _)
+                | EvalSha(_, _, _)
+                | ScriptLoad(_)
+                | ScriptExists(_)
+                | ScriptFlush
+                | ScriptKill
+                | FCall(_, _, _)
+                | FCallRo(_, _, _)
+                | FunctionLoad(_, _)
+                | FunctionDelete(_)
+                | FunctionFlush
+                | FunctionList(_, _)
+                | FunctionDump
+                | FunctionRestore(_, _)
+                | Subscribe(_)
+                | Unsubscribe(_)
+        )
+    }
+
+    /// Whether the command may change the dataset, which read-only scripts
+    /// must not do. Unlike [`Command::is_write_for_aof`], this includes the
+    /// commands logged as their effects.
+    pub fn is_write(&self) -> bool {
+        use Command::*;
+        self.is_write_for_aof()
+            || matches!(
+                self,
+                LMPop(_, _, _)
+                    | BLPop(_, _)
+                    | BRPop(_, _)
+                    | BLMove(_, _, _, _, _)
+                    | BLMPop(_, _, _, _)
+                    | HIncrByFloat(_, _, _)
+                    | HExpire(_, _, _, _)
+                    | SPop(_, _)
+                    | ZPopMin(_, _)
+                    | ZMPop(_, _, _)
+                    | ZPopMax(_, _)
+                    | BZPopMin(_, _)
+                    | BZPopMax(_, _)
+                    | BZMPop(_, _, _, _)
+            )
+    }
 }
 
 fn frame_to_string(f: &Frame) -> Result<String, RedisError> {
@@ -647,6 +1918,29 @@ fn frame_to_string(f: &Frame) -> Result<String, RedisError> {
     }
 }
 
+/// The script or function to run, its keys and its other arguments.
+type ScriptCall = (String, Vec<String>, Vec<Vec<u8>>);
+
+/// Parses the arguments shared by EVAL and FCALL: the script or function,
+/// the number of keys, then the keys and the other arguments.
+fn parse_script_call(arr: &[Frame], cmd_name: &str) -> Result<ScriptCall, RedisError> {
+    if arr.len() < 3 {
+        return Err(RedisError::Other(format!("ERR wrong number of arguments for '{}'", cmd_name)));
+    }
+    let target = frame_to_string(&arr[1])?;
+    let numkeys = frame_to_string(&arr[2])?
+        .parse::<i64>()
+        .map_err(|_| RedisError::Other("ERR value is not an integer or out of range".into()))?;
+    let numkeys = usize::try_from(numkeys)
+        .map_err(|_| RedisError::Other("ERR Number of keys can't be negative".into()))?;
+    if numkeys > arr.len() - 3 {
+        return Err(RedisError::Other("ERR Number of keys can't be greater than number of args".into()));
+    }
+    let keys = arr[3..3 + numkeys].iter().map(frame_to_string).collect::<Result<_, _>>()?;
+    let args = arr[3 + numkeys..].iter().map(frame_to_bytes).collect::<Result<_, _>>()?;
+    Ok((target, keys, args))
+}
+
 fn frame_to_bytes(f: &Frame) -> Result<Vec<u8>, RedisError> {
     match f {
         Frame::Bulk(b) => Ok(b.clone()),
@@ -654,3 +1948,299 @@ fn frame_to_bytes(f: &Frame) -> Result<Vec<u8>, RedisError> {
         _ => Err(RedisError::Other("expected bulk or simple string".into())),
     }
 }
+
+/// Parses a database index. Non-integers fail with `err`, negative indexes
+/// the way out of range ones do once the number of databases is known.
+fn parse_db_index(f: &Frame, err: &str) -> Result<usize, RedisError> {
+    let idx = frame_to_string(f)?
+        .parse::<i64>()
+        .map_err(|_| RedisError::Other(err.into()))?;
+    usize::try_from(idx).map_err(|_| RedisError::Other("ERR DB index is out of range".into()))
+}
+
+/// Parses the optional ASYNC or SYNC argument of FLUSHALL and FLUSHDB.
+fn parse_flush_mode(arr: &[Frame]) -> Result<Option<bool>, RedisError> {
+    match arr.len() {
+        1 => Ok(None),
+        2 => match frame_to_string(&arr[1])?.to_uppercase().as_str() {
+            "ASYNC" => Ok(Some(true)),
+            "SYNC" => Ok(Some(false)),
+            _ => Err(RedisError::Other("ERR syntax error".into())),
+        },
+        _ => Err(RedisError::Other("ERR syntax error".into())),
+    }
+}
+
+fn parse_positive_count(f: &Frame) -> Result<usize, RedisError> {
+    let n = frame_to_string(f)?
+        .parse::<i64>()
+        .map_err(|_| RedisError::Other("ERR value is out of range, must be positive".into()))?;
+    if n < 0 {
+        return Err(RedisError::Other("ERR value is out of range, must be positive".into()));
+    }
+    Ok(n as usize)
+}
+
+/// Parses the count of HRANDFIELD and its kin. Like Redis, refuses counts
+/// beyond half the range of a long, whose replies could never be sized.
+fn parse_random_count(f: &Frame) -> Result<i64, RedisError> {
+    let n = frame_to_string(f)?
+        .parse::<i64>()
+        .map_err(|_| RedisError::Other("ERR value is not an integer or out of range".into()))?;
+    if !(-(i64::MAX / 2)..=i64::MAX / 2).contains(&n) {
+        return Err(RedisError::Other("ERR value is out of range".into()));
+    }
+    Ok(n)
+}
+
+/// Parses a sorted set score, accepting `inf`/`-inf` but rejecting NaN.
+fn parse_score(f: &Frame) -> Result<f64, RedisError> {
+    frame_to_string(f)?
+        .parse::<f64>()
+        .ok()
+        .filter(|s| !s.is_nan())
+        .ok_or_else(|| RedisError::Other("ERR value is not a valid float".into()))
+}
+
+fn parse_rank_index(f: &Frame) -> Result<i64, RedisError> {
+    frame_to_string(f)?
+        .parse::<i64>()
+        .map_err(|_| RedisError::Other("ERR value is not an integer or out of range".into()))
+}
+
+/// Parses a score bound such as `1.5`, `(1.5`, `-inf` or `+inf`.
+fn parse_score_bound(f: &Frame) -> Result<ScoreBound, RedisError> {
+    let s = frame_to_string(f)?;
+    let (exclusive, num) = match s.strip_prefix('(') {
+        Some(rest) => (true, rest),
+        None => (false, s.as_str()),
+    };
+    let score = num.parse::<f64>()
+        .ok()
+        .filter(|n| !n.is_nan())
+        .ok_or_else(|| RedisError::Other("ERR min or max is not a float".into()))?;
+    Ok(if exclusive { ScoreBound::Exclusive(score) } else { ScoreBound::Inclusive(score) })
+}
+
+fn parse_score_range(min: &Frame, max: &Frame) -> Result<ScoreRange, RedisError> {
+    Ok(ScoreRange { min: parse_score_bound(min)?, max: parse_score_bound(max)? })
+}
+
+fn parse_lex_bound(f: &Frame) -> Result<LexBound, RedisError> {
+    let b = frame_to_bytes(f)?;
+    match b.first() {
+        Some(b'-') if b.len() == 1 => Ok(LexBound::NegInf),
+        Some(b'+') if b.len() == 1 => Ok(LexBound::PosInf),
+        Some(b'[') => Ok(LexBound::Inclusive(b[1..].to_vec())),
+        Some(b'(') => Ok(LexBound::Exclusive(b[1..].to_vec())),
+        _ => Err(RedisError::Other("ERR min or max not valid string range item".into())),
+    }
+}
+
+fn parse_lex_range(min: &Frame, max: &Frame) -> Result<LexRange, RedisError> {
+    Ok(LexRange { min: parse_lex_bound(min)?, max: parse_lex_bound(max)? })
+}
+
+/// Parses the `start stop [options]` part of every ZRANGE flavour. Only ZRANGE
+/// and ZRANGESTORE accept BYSCORE, BYLEX and REV; the legacy commands imply them.
+fn parse_zrange(cmd: &str, start: &Frame, stop: &Frame, flags: &[Frame]) -> Result<ZRangeOptions, RedisError> {
+    #[derive(PartialEq)]
+    enum Kind { Rank, Score, Lex }
+
+    let unified = cmd == "ZRANGE" || cmd == "ZRANGESTORE";
+    let mut kind = match cmd {
+        "ZRANGEBYSCORE" | "ZREVRANGEBYSCORE" => Kind::Score,
+        "ZRANGEBYLEX" | "ZREVRANGEBYLEX" => Kind::Lex,
+        _ => Kind::Rank,
+    };
+    let mut rev = cmd.starts_with("ZREV");
+    let mut limit = None;
+    let mut with_scores = false;
+
+    let mut idx = 0;
+    while idx < flags.len() {
+        match frame_to_string(&flags[idx])?.to_uppercase().as_str() {
+            "BYSCORE" if unified => kind = Kind::Score,
+            "BYLEX" if unified => kind = Kind::Lex,
+            "REV" if unified => rev = true,
+            "WITHSCORES" if cmd != "ZRANGESTORE" => with_scores = true,
+            "LIMIT" if idx + 2 < flags.len() => {
+                let offset = parse_rank_index(&flags[idx + 1])?;
+                let count = parse_rank_index(&flags[idx + 2])?;
+                limit = Some((offset, count));
+                idx += 2;
+            }
+            _ => return Err(RedisError::Other("ERR syntax error".into())),
+        }
+        idx += 1;
+    }
+
+    if limit.is_some() && kind == Kind::Rank {
+        return Err(RedisError::Other(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX".into(),
+        ));
+    }
+    if with_scores && kind == Kind::Lex {
+        return Err(RedisError::Other("ERR syntax error, WITHSCORES not supported in combination with BYLEX".into()));
+    }
+
+    // Reversed score and lex ranges are written `max min`.
+    let (lo, hi) = if rev && kind != Kind::Rank { (stop, start) } else { (start, stop) };
+    let by = match kind {
+        Kind::Rank => ZRangeBy::Rank(parse_rank_index(start)?, parse_rank_index(stop)?),
+        Kind::Score => ZRangeBy::Score(parse_score_range(lo, hi)?),
+        Kind::Lex => ZRangeBy::Lex(parse_lex_range(lo, hi)?),
+    };
+
+    Ok(ZRangeOptions { by, rev, limit, with_scores })
+}
+
+/// Parses `numkeys key [key ...] [WEIGHTS ...] [AGGREGATE ...] [WITHSCORES]`.
+/// ZDIFF takes no WEIGHTS or AGGREGATE, and the STORE variants no WITHSCORES.
+fn parse_zalgebra(cmd: &str, args: &[Frame]) -> Result<(Vec<String>, ZAlgebraOptions), RedisError> {
+    let numkeys = frame_to_string(&args[0])?
+        .parse::<i64>()
+        .map_err(|_| RedisError::Other("ERR value is not an integer or out of range".into()))?;
+    if numkeys <= 0 {
+        return Err(RedisError::Other(format!(
+            "ERR at least 1 input key is needed for '{}' command",
+            cmd.to_lowercase()
+        )));
+    }
+    let numkeys = numkeys as usize;
+    if args.len() < numkeys + 1 {
+        return Err(RedisError::Other("ERR Number of keys can't be greater than number of args".into()));
+    }
+
+    let keys = args[1..=numkeys].iter()
+        .map(frame_to_string)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let is_diff = cmd.starts_with("ZDIFF");
+    let is_store = cmd.ends_with("STORE");
+    let mut opts = ZAlgebraOptions { weights: None, aggregate: Aggregate::Sum, with_scores: false };
+
+    let rest = &args[numkeys + 1..];
+    let mut idx = 0;
+    while idx < rest.len() {
+        match frame_to_string(&rest[idx])?.to_uppercase().as_str() {
+            "WEIGHTS" if !is_diff && idx + numkeys < rest.len() => {
+                let weights = rest[idx + 1..=idx + numkeys].iter()
+                    .map(|f| {
+                        frame_to_string(f)?
+                            .parse::<f64>()
+                            .ok()
+                            .filter(|w| !w.is_nan())
+                            .ok_or_else(|| RedisError::Other("ERR weight value is not a float".into()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                opts.weights = Some(weights);
+                idx += numkeys;
+            }
+            "AGGREGATE" if !is_diff && idx + 1 < rest.len() => {
+                opts.aggregate = match frame_to_string(&rest[idx + 1])?.to_uppercase().as_str() {
+                    "SUM" => Aggregate::Sum,
+                    "MIN" => Aggregate::Min,
+                    "MAX" => Aggregate::Max,
+                    _ => return Err(RedisError::Other("ERR syntax error".into())),
+                };
+                idx += 1;
+            }
+            "WITHSCORES" if !is_store => opts.with_scores = true,
+            _ => return Err(RedisError::Other("ERR syntax error".into())),
+        }
+        idx += 1;
+    }
+
+    Ok((keys, opts))
+}
+
+fn parse_list_end(f: &Frame) -> Result<ListEnd, RedisError> {
+    match frame_to_string(f)?.to_uppercase().as_str() {
+        "LEFT" => Ok(ListEnd::Left),
+        "RIGHT" => Ok(ListEnd::Right),
+        _ => Err(RedisError::Other("ERR syntax error".into())),
+    }
+}
+
+fn parse_zset_end(f: &Frame) -> Result<ZSetEnd, RedisError> {
+    match frame_to_string(f)?.to_uppercase().as_str() {
+        "MIN" => Ok(ZSetEnd::Min),
+        "MAX" => Ok(ZSetEnd::Max),
+        _ => Err(RedisError::Other("ERR syntax error".into())),
+    }
+}
+
+/// Parses a blocking timeout in (possibly fractional) seconds; 0 blocks forever.
+fn parse_timeout(f: &Frame) -> Result<f64, RedisError> {
+    let timeout = frame_to_string(f)?
+        .parse::<f64>()
+        .ok()
+        .filter(|t| t.is_finite())
+        .ok_or_else(|| RedisError::Other("ERR timeout is not a float or out of range".into()))?;
+    if timeout < 0.0 {
+        return Err(RedisError::Other("ERR timeout is negative".into()));
+    }
+    // Redis keeps timeouts as milliseconds in a long long.
+    if timeout * 1000.0 >= i64::MAX as f64 {
+        return Err(RedisError::Other("ERR timeout is out of range".into()));
+    }
+    Ok(timeout)
+}
+
+/// Parses the `numkeys key [key ...] <end> [COUNT count]` tail shared by the
+/// LMPOP and ZMPOP families, using `parse_end` for the LEFT|RIGHT or MIN|MAX word.
+fn parse_mpop_args<E>(
+    args: &[Frame],
+    parse_end: fn(&Frame) -> Result<E, RedisError>,
+) -> Result<(Vec<String>, E, usize), RedisError> {
+    let numkeys = frame_to_string(&args[0])?
+        .parse::<i64>()
+        .map_err(|_| RedisError::Other("ERR value is not an integer or out of range".into()))?;
+    if numkeys <= 0 {
+        return Err(RedisError::Other("ERR numkeys should be greater than 0".into()));
+    }
+    let numkeys = numkeys as usize;
+    if args.len() < numkeys + 2 {
+        return Err(RedisError::Other("ERR syntax error".into()));
+    }
+
+    let keys = args[1..=numkeys].iter()
+        .map(frame_to_string)
+        .collect::<Result<Vec<_>, _>>()?;
+    let end = parse_end(&args[numkeys + 1])?;
+
+    let mut count = 1;
+    match &args[numkeys + 2..] {
+        [] => {}
+        [opt, n] if frame_to_string(opt)?.eq_ignore_ascii_case("COUNT") => {
+            let n = frame_to_string(n)?
+                .parse::<i64>()
+                .map_err(|_| RedisError::Other("ERR count should be greater than 0".into()))?;
+            if n <= 0 {
+                return Err(RedisError::Other("ERR count should be greater than 0".into()));
+            }
+            count = n as usize;
+        }
+        _ => return Err(RedisError::Other("ERR syntax error".into())),
+    }
+
+    Ok((keys, end, count))
+}
+
+/// Parses the `FIELDS numfields field [field ...]` tail of the HEXPIRE family.
+fn parse_fields_arg(args: &[Frame]) -> Result<Vec<Vec<u8>>, RedisError> {
+    if args.len() < 2 || !frame_to_string(&args[0])?.eq_ignore_ascii_case("FIELDS") {
+        return Err(RedisError::Other("ERR Mandatory argument FIELDS is missing or not at the right position".into()));
+    }
+    let numfields = frame_to_string(&args[1])?
+        .parse::<i64>()
+        .map_err(|_| RedisError::Other("ERR Number of fields must be a positive integer".into()))?;
+    if numfields <= 0 {
+        return Err(RedisError::Other("ERR Number of fields must be a positive integer".into()));
+    }
+    if args.len() - 2 != numfields as usize {
+        return Err(RedisError::Other("ERR The `numfields` parameter must match the number of arguments".into()));
+    }
+    args[2..].iter().map(frame_to_bytes).collect()
+}
@@ -1,56 +1,234 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, watch, Notify};
 
+use crate::pubsub::CONFIG;
+use crate::resp::parser::ParseError;
 use crate::resp::{Frame, parse_frame, encode_frame};
 
+/// Bytes of replies a client may leave unread before the commands it sends
+/// after them are left unread too. Published messages are queued regardless
+/// and limited by [`CONFIG`] instead.
+pub const MAX_UNREAD_REPLIES: usize = 64 * 1024;
+
+/// A client connection. Frames are read here while replies and published
+/// messages are written by a task of their own, through the connection's
+/// [`Output`], so that a client slow to read its messages holds up nobody
+/// but itself.
 pub struct Connection {
-    reader: BufReader<TcpStream>,
+    reader: BufReader<OwnedReadHalf>,
+    /// Bytes read but not parsed yet, which may hold several pipelined
+    /// commands.
+    buf: Vec<u8>,
+    output: Arc<Output>,
+    killed: watch::Receiver<bool>,
 }
 
 impl Connection {
     pub fn new(stream: TcpStream) -> Self {
+        let (reader, writer) = stream.into_split();
+        let (queue, rx) = mpsc::unbounded_channel();
+        let (kill, killed) = watch::channel(false);
+        let output = Arc::new(Output {
+            queue: Mutex::new(Some(queue)),
+            pending: AtomicUsize::new(0),
+            written: Notify::new(),
+            soft_limit_since: Mutex::new(None),
+            kill,
+        });
+        tokio::spawn(write_loop(BufWriter::new(writer), rx, output.clone(), killed.clone()));
         Self {
-            reader: BufReader::new(stream),
+            reader: BufReader::new(reader),
+            buf: Vec::new(),
+            output,
+            killed,
         }
     }
 
+    /// Reads the next frame, or None once the client disconnected or was
+    /// disconnected by [`Output::kill`]. Waits first for the client to read
+    /// its replies down to [`MAX_UNREAD_REPLIES`], so that one that never
+    /// reads them stops being served instead of growing its output forever.
     pub async fn read_frame(&mut self) -> std::io::Result<Option<Frame>> {
-        let mut first = [0u8; 1];
-        let n = self.reader.read(&mut first).await?;
-        if n == 0 {
-            return Ok(None);
-        }
-
-        let mut buf = vec![first[0]];
-
+        self.output.writable().await;
         loop {
-            if let Ok((frame, _used)) = parse_frame(&buf) {
-                return Ok(Some(frame));
+            match parse_frame(&self.buf) {
+                Ok((frame, used)) => {
+                    self.buf.drain(..used);
+                    return Ok(Some(frame));
+                }
+                Err(ParseError::Incomplete) => {}
+                Err(e) => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()));
+                }
             }
 
             let mut chunk = [0u8; 1024];
-            let n = self.reader.read(&mut chunk).await?;
+            let n = tokio::select! {
+                n = self.reader.read(&mut chunk) => n?,
+                _ = self.killed.wait_for(|killed| *killed) => return Ok(None),
+            };
             if n == 0 {
-                if buf.is_empty() {
+                if self.buf.is_empty() {
                     return Ok(None);
-                } else {
-                    if let Ok((frame, _used)) = parse_frame(&buf) {
-                        return Ok(Some(frame));
-                    }
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::UnexpectedEof,
-                        "incomplete frame",
-                    ));
                 }
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "incomplete frame",
+                ));
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    /// Resolves once the client disconnects or is disconnected, keeping
+    /// whatever it sends meanwhile for [`Connection::read_frame`]. Lets a
+    /// command that waits, such as BLPOP, notice the client is gone.
+    pub async fn closed(&mut self) {
+        loop {
+            let mut chunk = [0u8; 1024];
+            tokio::select! {
+                n = self.reader.read(&mut chunk) => match n {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                },
+                _ = self.killed.wait_for(|killed| *killed) => return,
             }
-            buf.extend_from_slice(&chunk[..n]);
         }
     }
 
-    pub async fn write_frame(&mut self, frame: &Frame) -> std::io::Result<()> {
+    /// Queues a reply. Fails once the connection can no longer be written to.
+    pub fn write_frame(&self, frame: &Frame) -> std::io::Result<()> {
+        if self.output.send(encode_frame(frame)) {
+            Ok(())
+        } else {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    /// Queues part of a reply that is already encoded.
+    pub fn write_bytes(&self, bytes: Vec<u8>) -> std::io::Result<()> {
+        if self.output.send(bytes) {
+            Ok(())
+        } else {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    pub fn output(&self) -> &Arc<Output> {
+        &self.output
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Lets the writer finish the replies already queued, then stop.
+        self.output.queue.lock().unwrap().take();
+    }
+}
+
+/// The bytes queued for a client and not written yet.
+#[derive(Debug)]
+pub struct Output {
+    /// None once the connection is closing.
+    queue: Mutex<Option<mpsc::UnboundedSender<Vec<u8>>>>,
+    pending: AtomicUsize,
+    /// Notified whenever pending bytes were written, or will never be.
+    written: Notify,
+    /// When the pending bytes last went over the soft limit, if they still
+    /// are.
+    soft_limit_since: Mutex<Option<Instant>>,
+    kill: watch::Sender<bool>,
+}
+
+impl Output {
+    fn send(&self, bytes: Vec<u8>) -> bool {
+        let queue = self.queue.lock().unwrap();
+        let Some(queue) = queue.as_ref() else {
+            return false;
+        };
+        self.pending.fetch_add(bytes.len(), Ordering::Relaxed);
+        queue.send(bytes).is_ok()
+    }
+
+    /// Queues a published message, unless the client is too far behind on
+    /// its output: past the hard limit, or past the soft limit for longer
+    /// than allowed. Such a client is disconnected instead, as in Redis.
+    pub fn push(&self, frame: &Frame) -> bool {
         let bytes = encode_frame(frame);
-        let stream = self.reader.get_mut();
-        stream.write_all(&bytes).await?;
-        stream.flush().await
+        let pending = self.pending.load(Ordering::Relaxed) + bytes.len();
+        let limits = CONFIG.output_buffer_limits();
+
+        let over = if limits.hard > 0 && pending > limits.hard {
+            true
+        } else if limits.soft > 0 && pending > limits.soft {
+            let mut since = self.soft_limit_since.lock().unwrap();
+            since.get_or_insert_with(Instant::now).elapsed() >= limits.soft_time
+        } else {
+            *self.soft_limit_since.lock().unwrap() = None;
+            false
+        };
+        if over {
+            eprintln!("disconnecting subscriber over its output buffer limits ({} bytes pending)", pending);
+            self.kill();
+            return false;
+        }
+        self.send(bytes)
+    }
+
+    /// Waits until at most [`MAX_UNREAD_REPLIES`] bytes are left to write,
+    /// or the connection is closing.
+    pub async fn writable(&self) {
+        loop {
+            let written = self.written.notified();
+            if self.pending.load(Ordering::Relaxed) <= MAX_UNREAD_REPLIES || self.queue.lock().unwrap().is_none() {
+                return;
+            }
+            written.await;
+        }
     }
-}
\ No newline at end of file
+
+    /// Disconnects the client without writing what is still queued.
+    pub fn kill(&self) {
+        self.queue.lock().unwrap().take();
+        self.kill.send_replace(true);
+        self.written.notify_waiters();
+    }
+}
+
+async fn write_loop(
+    mut writer: BufWriter<OwnedWriteHalf>,
+    mut rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    output: Arc<Output>,
+    mut killed: watch::Receiver<bool>,
+) {
+    let result: std::io::Result<()> = async {
+        while let Some(bytes) = rx.recv().await {
+            tokio::select! {
+                res = writer.write_all(&bytes) => res?,
+                _ = killed.wait_for(|killed| *killed) => return Ok(()),
+            }
+            output.pending.fetch_sub(bytes.len(), Ordering::Relaxed);
+            output.written.notify_waiters();
+            // Flushed once nothing else is queued, so that pipelined replies
+            // go out together.
+            if rx.is_empty() {
+                tokio::select! {
+                    res = writer.flush() => res?,
+                    _ = killed.wait_for(|killed| *killed) => return Ok(()),
+                }
+            }
+        }
+        writer.shutdown().await
+    }
+    .await;
+    if let Err(e) = result {
+        eprintln!("error writing response: {:?}", e);
+        output.kill();
+    }
+}
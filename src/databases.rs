@@ -0,0 +1,422 @@
+use std::future::Future;
+use std::sync::Mutex;
+
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::aof;
+use crate::blocking::BlockRequest;
+use crate::command::Command;
+use crate::db::{Db, RandomPicks};
+use crate::eviction::{self, EvictionPool};
+use crate::functions::Functions;
+use crate::lazyfree;
+use crate::pubsub::PubSub;
+use crate::resp::Frame;
+use crate::scripting::{Scripts, BUSY_ERROR};
+
+/// What WATCH saw of a key, so that EXEC can tell whether it changed since.
+#[derive(Debug)]
+pub struct Watch {
+    db: usize,
+    key: String,
+    version: u64,
+    /// Whether the key had already expired. One that expires only later
+    /// counts as changed even if nothing removed it yet.
+    expired: bool,
+}
+
+impl Watch {
+    pub fn is(&self, db: usize, key: &str) -> bool {
+        self.db == db && self.key == key
+    }
+}
+
+/// Number of databases used by [`Databases::default`], as in Redis.
+pub const DEFAULT_DATABASES: usize = 16;
+
+const OUT_OF_RANGE: &str = "ERR DB index is out of range";
+
+/// The numbered databases clients switch between with SELECT.
+///
+/// Every database has its own keyspace and blocked clients. The keyspaces are
+/// siblings, so memory is accounted and evicted across all of them and
+/// SWAPDB can exchange their contents in place. Commands that involve more
+/// than one database lock them in index order.
+#[derive(Debug)]
+pub struct Databases {
+    dbs: Box<[Db]>,
+    eviction: Mutex<EvictionPool>,
+    /// Held shared by every command while it runs and exclusively by EXEC
+    /// and scripts, so that nothing runs in the middle of either.
+    gate: RwLock<()>,
+    scripts: Scripts,
+    functions: Functions,
+    pubsub: PubSub,
+}
+
+impl Default for Databases {
+    fn default() -> Self {
+        Self::new(DEFAULT_DATABASES, crate::keyspace::DEFAULT_SHARDS)
+    }
+}
+
+impl Databases {
+    pub fn new(count: usize, shards: usize) -> Self {
+        let first = Db::with_shards(shards);
+        let rest: Vec<Db> = (1..count.max(1))
+            .map(|_| Db::with_keyspace(first.keyspace().sibling()))
+            .collect();
+        Self {
+            dbs: std::iter::once(first).chain(rest).collect(),
+            eviction: Mutex::new(EvictionPool::default()),
+            gate: RwLock::new(()),
+            scripts: Scripts::default(),
+            functions: Functions::default(),
+            pubsub: PubSub::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.dbs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dbs.is_empty()
+    }
+
+    pub fn get(&self, idx: usize) -> &Db {
+        &self.dbs[idx]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Db> {
+        self.dbs.iter()
+    }
+
+    pub fn scripts(&self) -> &Scripts {
+        &self.scripts
+    }
+
+    pub fn pubsub(&self) -> &PubSub {
+        &self.pubsub
+    }
+
+    /// Evicts keys if `cmd` may grow the dataset and memory is over
+    /// `maxmemory`. Returns false if the command must be refused.
+    ///
+    /// Kept apart from [`Databases::apply`] so that the evictions can be
+    /// logged before the command they made room for.
+    pub async fn make_room(&self, cmd: &Command) -> bool {
+        if !cmd.is_denyoom() {
+            return true;
+        }
+        // A busy script gets the command refused by apply instead.
+        let Ok(_gate) = self.shared().await else {
+            return true;
+        };
+        eviction::free_memory(&self.dbs, &self.eviction).await
+    }
+
+    /// Runs `cmd` for a client that has database `selected` selected; SELECT
+    /// changes it. Also used by AOF replay, which never evicts or refuses
+    /// writes that were already accepted.
+    ///
+    /// Blocking commands only hold off transactions until they are served or
+    /// registered as blocked, not while they wait.
+    pub async fn apply(&self, selected: &mut usize, cmd: Command) -> Frame {
+        self.apply_until(selected, cmd, std::future::pending()).await
+    }
+
+    /// Like [`Databases::apply`], but a blocked client stops waiting once
+    /// `closed` resolves, so that nothing is handed to a client that is gone.
+    pub async fn apply_until(&self, selected: &mut usize, cmd: Command, closed: impl Future<Output = ()>) -> Frame {
+        if cmd.is_script() {
+            return match self.exclusive().await {
+                Ok(tx) => {
+                    let reply = tx.apply(selected, cmd).await;
+                    tx.serve_blocked().await;
+                    reply
+                }
+                Err(busy) => busy,
+            };
+        }
+        let Some(req) = BlockRequest::from_command(&cmd) else {
+            return match self.shared().await {
+                Ok(_gate) => self.run(selected, cmd).await,
+                Err(busy) => busy,
+            };
+        };
+
+        let db = &self.dbs[*selected];
+        let registration = {
+            let _gate = match self.shared().await {
+                Ok(gate) => gate,
+                Err(busy) => return busy,
+            };
+            match db.block_start(&req, true).await {
+                Ok(reply) => return reply,
+                Err(registration) => registration,
+            }
+        };
+        db.block_wait(&req.keys, registration, req.timeout, closed).await
+    }
+
+    /// Gathers what `cmd` picks from in database `selected`, for a command
+    /// whose reply is written as it is picked. See [`Db::random_picks`].
+    pub async fn random_picks(&self, selected: usize, cmd: &Command) -> Result<RandomPicks, Frame> {
+        let _gate = self.shared().await?;
+        self.dbs[selected].random_picks(cmd).await
+    }
+
+    /// Starts tracking changes to `key` in database `selected`.
+    pub async fn watch(&self, selected: usize, key: String) -> Watch {
+        let mut guard = self.dbs[selected].keyspace().write([&key]).await;
+        let version = guard.watch(&key);
+        let expired = guard.is_expired(&key);
+        drop(guard);
+        Watch { db: selected, key, version, expired }
+    }
+
+    /// Stops tracking the keys behind `watches`.
+    pub async fn unwatch(&self, watches: Vec<Watch>) {
+        for w in watches {
+            self.dbs[w.db].keyspace().write([&w.key]).await.unwatch(&w.key);
+        }
+    }
+
+    /// Holds off every other client's commands for as long as the returned
+    /// guard lives. The commands of a transaction then run through it.
+    ///
+    /// Fails with a BUSY error if a script runs for too long to wait for.
+    pub async fn exclusive(&self) -> Result<Exclusive<'_>, Frame> {
+        let busy = self.scripts.busy_notified();
+        if self.scripts.is_busy() {
+            return Err(Frame::Error(BUSY_ERROR.into()));
+        }
+        let gate = tokio::select! {
+            gate = self.gate.write() => gate,
+            _ = busy => return Err(Frame::Error(BUSY_ERROR.into())),
+        };
+        for db in self.dbs.iter() {
+            db.defer_wakeups();
+        }
+        Ok(Exclusive { dbs: self, _gate: gate })
+    }
+
+    /// Holds off transactions and scripts while a command runs, or fails
+    /// like [`Databases::exclusive`].
+    async fn shared(&self) -> Result<RwLockReadGuard<'_, ()>, Frame> {
+        let busy = self.scripts.busy_notified();
+        if self.scripts.is_busy() {
+            return Err(Frame::Error(BUSY_ERROR.into()));
+        }
+        tokio::select! {
+            gate = self.gate.read() => Ok(gate),
+            _ = busy => Err(Frame::Error(BUSY_ERROR.into())),
+        }
+    }
+
+    async fn run(&self, selected: &mut usize, cmd: Command) -> Frame {
+        match cmd {
+            Command::Select(idx) => {
+                if idx >= self.dbs.len() {
+                    return Frame::Error(OUT_OF_RANGE.into());
+                }
+                *selected = idx;
+                Frame::Simple("OK".into())
+            }
+            Command::SwapDb(a, b) => self.swapdb(a, b).await,
+            Command::Move(key, to) => self.r#move(*selected, key, to).await,
+            Command::FlushAll(lazy) => {
+                let lazy = lazyfree::user_flush(lazy);
+                for db in self.dbs.iter() {
+                    db.flush(lazy).await;
+                }
+                Frame::Simple("OK".into())
+            }
+            Command::MemoryStats => self.memory_stats().await,
+            Command::ScriptLoad(script) => Frame::Bulk(self.scripts.load(script).into_bytes()),
+            Command::ScriptExists(shas) => self.scripts.exists(&shas),
+            Command::ScriptFlush => {
+                self.scripts.flush();
+                Frame::Simple("OK".into())
+            }
+            Command::ScriptKill => self.scripts.kill(),
+            Command::FunctionLoad(code, replace) => self.functions.load(code, replace),
+            Command::FunctionDelete(name) => self.functions.delete(&name),
+            Command::FunctionFlush => {
+                self.functions.flush();
+                Frame::Simple("OK".into())
+            }
+            Command::FunctionList(pattern, with_code) => self.functions.list(pattern.as_deref(), with_code),
+            Command::FunctionDump => self.functions.dump(),
+            Command::FunctionRestore(payload, policy) => self.functions.restore(&payload, policy),
+            Command::Publish(channel, message) => Frame::Integer(self.pubsub.publish(&channel, message) as i64),
+            Command::PubSubChannels(pattern) => self.pubsub.channels(pattern.as_deref()),
+            Command::PubSubNumSub(channels) => self.pubsub.numsub(&channels),
+            cmd => self.dbs[*selected].apply(cmd).await,
+        }
+    }
+
+    async fn swapdb(&self, a: usize, b: usize) -> Frame {
+        if a >= self.dbs.len() || b >= self.dbs.len() {
+            return Frame::Error(OUT_OF_RANGE.into());
+        }
+        if a == b {
+            return Frame::Simple("OK".into());
+        }
+
+        let (lo, hi) = (a.min(b), a.max(b));
+        let mut lo_guard = self.dbs[lo].keyspace().write_all().await;
+        let mut hi_guard = self.dbs[hi].keyspace().write_all().await;
+        lo_guard.swap_with(&mut hi_guard);
+
+        // Clients blocked in either database may now find their keys filled.
+        aof::redirect(lo, async { self.dbs[lo].serve_all_blocked(&mut lo_guard) }).await;
+        aof::redirect(hi, async { self.dbs[hi].serve_all_blocked(&mut hi_guard) }).await;
+        Frame::Simple("OK".into())
+    }
+
+    async fn r#move(&self, from: usize, key: String, to: usize) -> Frame {
+        if to >= self.dbs.len() {
+            return Frame::Error(OUT_OF_RANGE.into());
+        }
+        if from == to {
+            return Frame::Error("ERR source and destination objects are the same".into());
+        }
+
+        let (src_db, dst_db) = (&self.dbs[from], &self.dbs[to]);
+        let (mut src, mut dst) = if from < to {
+            let src = src_db.keyspace().write([&key]).await;
+            (src, dst_db.keyspace().write([&key]).await)
+        } else {
+            let dst = dst_db.keyspace().write([&key]).await;
+            (src_db.keyspace().write([&key]).await, dst)
+        };
+
+        if dst.contains_key(&key) {
+            return Frame::Integer(0);
+        }
+        let Some(entry) = src.take(&key) else {
+            return Frame::Integer(0);
+        };
+        dst.put(key.clone(), entry);
+        drop(src);
+
+        aof::redirect(to, dst_db.wake_blocked(dst, &key)).await;
+        Frame::Integer(1)
+    }
+
+    async fn memory_stats(&self) -> Frame {
+        let field = |name: &str| Frame::Bulk(name.as_bytes().to_vec());
+        let percent = |part: usize, whole: usize| {
+            let pct = if whole == 0 { 0.0 } else { part as f64 * 100.0 / whole as f64 };
+            Frame::Bulk(pct.to_string().into_bytes())
+        };
+
+        let mut used = 0;
+        let mut peak = 0;
+        let mut keys = 0;
+        let mut overhead = 0;
+        let mut per_db = Vec::new();
+        for (idx, db) in self.dbs.iter().enumerate() {
+            let stats = db.keyspace().memory_stats().await;
+            // Siblings share one account, so any of them has the totals.
+            used = stats.used;
+            peak = stats.peak;
+            keys += stats.keys;
+            overhead += stats.overhead_main + stats.overhead_expires;
+            if stats.keys > 0 {
+                per_db.push(field(&format!("db.{}", idx)));
+                per_db.push(Frame::Array(vec![
+                    field("overhead.hashtable.main"),
+                    Frame::Integer(stats.overhead_main as i64),
+                    field("overhead.hashtable.expires"),
+                    Frame::Integer(stats.overhead_expires as i64),
+                ]));
+            }
+        }
+        let dataset = used.saturating_sub(overhead);
+
+        let mut out = vec![
+            field("peak.allocated"),
+            Frame::Integer(peak as i64),
+            field("total.allocated"),
+            Frame::Integer(used as i64),
+        ];
+        out.extend(per_db);
+        out.extend([
+            field("overhead.total"),
+            Frame::Integer(overhead as i64),
+            field("keys.count"),
+            Frame::Integer(keys as i64),
+            field("keys.bytes-per-key"),
+            Frame::Integer(used.checked_div(keys).unwrap_or(0) as i64),
+            field("dataset.bytes"),
+            Frame::Integer(dataset as i64),
+            field("dataset.percentage"),
+            percent(dataset, used),
+            field("peak.percentage"),
+            percent(used, peak),
+            field("lazyfree.pending_objects"),
+            Frame::Integer(lazyfree::pending() as i64),
+        ]);
+        Frame::Array(out)
+    }
+}
+
+/// Exclusive access to the databases, for running a transaction.
+#[derive(Debug)]
+pub struct Exclusive<'a> {
+    dbs: &'a Databases,
+    _gate: RwLockWriteGuard<'a, ()>,
+}
+
+impl Exclusive<'_> {
+    /// Like [`Databases::apply`]. Blocking commands never wait: they reply as
+    /// though they had timed out if nothing can serve them right away.
+    ///
+    /// Scripts run here too, calling back into this for their commands.
+    pub async fn apply(&self, selected: &mut usize, cmd: Command) -> Frame {
+        match cmd {
+            Command::Eval(..) | Command::EvalSha(..) => return self.dbs.scripts.eval(self, *selected, cmd),
+            Command::FCall(..) | Command::FCallRo(..) => {
+                return self.dbs.scripts.fcall(self, *selected, &self.dbs.functions, cmd)
+            }
+            _ => {}
+        }
+        match BlockRequest::from_command(&cmd) {
+            Some(req) => self.dbs.dbs[*selected].block_start(&req, false).await.unwrap_or(Frame::Null),
+            None => self.dbs.run(selected, cmd).await,
+        }
+    }
+
+    /// Whether any of the watched keys changed since it was watched, in which
+    /// case the transaction must not run.
+    pub async fn changed(&self, watches: &[Watch]) -> bool {
+        for w in watches {
+            let guard = self.dbs.dbs[w.db].keyspace().read([&w.key]).await;
+            if guard.version(&w.key) != w.version || (!w.expired && guard.is_expired(&w.key)) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Serves the clients blocked on keys that received data during the
+    /// transaction, which must be done before releasing it.
+    pub async fn serve_blocked(&self) {
+        for (idx, db) in self.dbs.dbs.iter().enumerate() {
+            aof::redirect(idx, db.serve_deferred()).await;
+        }
+    }
+}
+
+impl Drop for Exclusive<'_> {
+    fn drop(&mut self) {
+        // Stop deferring even if the deferred clients were never served;
+        // they are then served by the next write to their keys.
+        for db in self.dbs.dbs.iter() {
+            db.stop_deferring();
+        }
+    }
+}
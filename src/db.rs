@@ -1,83 +1,160 @@
-use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::Mutex;
 use std::time::{Instant, Duration};
 
 use rand::Rng;
 
-use tokio::sync::RwLock;
 use tokio::time;
 
-use crate::command::Command;
+use crate::aof::{command_frame, propagate};
+use crate::blocking::{BlockKind, BlockOp, BlockRequest, Blocking, Registration};
+use crate::eviction;
+use crate::command::{
+    Aggregate, Command, ExpireCondition, FieldExpiry, InsertPosition, LPosOptions, LcsOptions, ListEnd,
+    ZAddOptions, ZAlgebraOptions, ZRangeBy, ZRangeOptions, ZSetEnd,
+};
+use crate::hash::{now_ms, HashState};
+use crate::lazyfree;
+use crate::keyspace::{Guard, Keyspace, ShardLock, WriteGuard, DEFAULT_SHARDS};
 use crate::resp::Frame;
-use crate::value::Value;
+use crate::value::{StringValue, Value};
 use crate::list::ListState;
-use crate::skiplist::SkipList;
-use crate::errors::RedisError;
+use crate::set::SetState;
+use crate::zset::ZSetState;
 
+/// Largest field deadline accepted by the HEXPIRE family, in unix milliseconds.
+const FIELD_EXPIRE_MAX_MS: i64 = i64::MAX / 2;
+
+/// Upper bound on the transient DP table allocated by LCS, mirroring Redis'
+/// default `proto-max-bulk-len` of 512MB.
+const LCS_MAX_TABLE_BYTES: usize = 512 * 1024 * 1024;
+
+/// Sorted set members with their scores.
+type ZEntries = Vec<(Vec<u8>, f64)>;
+
+#[derive(Debug, Clone, Copy)]
+enum SetOp {
+    Union,
+    Inter,
+    Diff,
+}
+
+/// What a negative count of HRANDFIELD and its kin picks from: `count`
+/// picks, which may repeat, among `entries`, each holding the elements one
+/// pick adds to the reply.
 #[derive(Debug)]
-pub struct Db {
-    inner: RwLock<HashMap<String, Value>>,
-    ttl: RwLock<HashMap<String, Instant>>,
+pub struct RandomPicks {
+    pub entries: Vec<Vec<Frame>>,
+    pub count: u64,
 }
 
-impl Db {
-    pub fn new() -> Self {
-        Self {
-            inner: RwLock::new(HashMap::new()),
-            ttl: RwLock::new(HashMap::new()),
+impl RandomPicks {
+    /// The whole reply at once, as needed inside transactions and scripts.
+    /// Clients are otherwise written the picks as they are made.
+    pub fn into_frame(self) -> Frame {
+        let mut rng = rand::thread_rng();
+        let mut arr = Vec::new();
+        for _ in 0..self.count {
+            arr.extend_from_slice(&self.entries[rng.gen_range(0..self.entries.len())]);
         }
+        Frame::Array(arr)
     }
+}
 
-    pub async fn get_inner(&self) -> tokio::sync::RwLockReadGuard<'_, HashMap<String, Value>> {
-        self.inner.read().await
-    }
+#[derive(Debug)]
+pub struct Db {
+    keyspace: Keyspace,
+    /// Clients blocked on list and sorted set keys. Only locked while holding
+    /// the shards of the keys being registered or served.
+    blocking: Mutex<Blocking>,
+    /// While a transaction runs, the keys that received data for blocked
+    /// clients. They are served once it has completed.
+    deferred: Mutex<Option<Vec<String>>>,
+}
 
-    pub async fn get_inner_mut(&self) -> tokio::sync::RwLockWriteGuard<'_, HashMap<String, Value>> {
-        self.inner.write().await
+impl Default for Db {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    pub async fn get_ttl(&self) -> tokio::sync::RwLockReadGuard<'_, HashMap<String, Instant>> {
-        self.ttl.read().await
+impl Db {
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
     }
 
-    pub async fn get_ttl_mut(&self) -> tokio::sync::RwLockWriteGuard<'_, HashMap<String, Instant>> {
-        self.ttl.write().await
+    pub fn with_shards(shards: usize) -> Self {
+        Self::with_keyspace(Keyspace::new(shards))
     }
 
-    async fn is_expired(&self, key: &str) -> bool {
-        let ttl = self.ttl.read().await;
-        if let Some(exp_at) = ttl.get(key) {
-            if Instant::now() >= *exp_at {
-                return true;
-            }
+    pub fn with_keyspace(keyspace: Keyspace) -> Self {
+        Self {
+            keyspace,
+            blocking: Mutex::new(Blocking::default()),
+            deferred: Mutex::new(None),
         }
-        false
     }
 
-    async fn purge_expired(&self, key: &str) {
-        let mut ttl = self.ttl.write().await;
-        let mut inner = self.inner.write().await;
+    pub fn keyspace(&self) -> &Keyspace {
+        &self.keyspace
+    }
 
-        ttl.remove(key);
-        inner.remove(key);
+    /// Drops `key` if its deadline has passed. Returns true if it did.
+    async fn purge_expired(&self, key: &str) -> bool {
+        if !self.keyspace.read([key]).await.is_expired(key) {
+            return false;
+        }
+        self.keyspace.write([key]).await.purge(key)
     }
 
     async fn check_and_purge(&self, key: &str) -> bool {
-        if self.is_expired(key).await {
-            self.purge_expired(key).await;
-            true
-        } else {
-            false
+        self.purge_expired(key).await || self.purge_expired_fields(key).await
+    }
+
+    /// Drops hash fields whose deadline has passed, removing the key if it
+    /// ends up empty. Returns true if the key is gone as a result.
+    async fn purge_expired_fields(&self, key: &str) -> bool {
+        let now = now_ms();
+
+        let inner = self.keyspace.read([key]).await;
+        match inner.get(key) {
+            Some(Value::Hash(map)) if map.has_expired(now) => {}
+            _ => return false,
+        }
+        drop(inner);
+
+        let mut inner = self.keyspace.write([key]).await;
+        if let Some(Value::Hash(map)) = inner.get_mut(key) {
+            if map.purge_expired(now) > 0 {
+                inner.touch(key);
+            }
         }
+        let had_key = inner.contains_key(key);
+        remove_if_empty_hash(&mut inner, key);
+        had_key && !inner.contains_key(key)
     }
 
+    /// Runs a command against this database alone. Commands that span
+    /// databases are run by [`Databases::apply`](crate::databases::Databases::apply),
+    /// which hands everything else to the selected database.
     pub async fn apply(&self, cmd: Command) -> Frame {
+        if let Some(req) = BlockRequest::from_command(&cmd) {
+            return self.block_on_keys(req).await;
+        }
+
         match cmd {
             Command::Ping => Frame::Simple("PONG".to_string()),
             
             // Keyspace commands
             Command::Exists(keys) => self.exists(keys).await,
             Command::Type(key) => self.r#type(key).await,
+            Command::ObjectEncoding(key) => self.object_encoding(key).await,
+            Command::ObjectFreq(key) => self.object_freq(key).await,
+            Command::ObjectIdleTime(key) => self.object_idletime(key).await,
+            Command::ObjectRefCount(key) => self.object_refcount(key).await,
+            Command::MemoryUsage(key, samples) => self.memory_usage(key, samples).await,
             Command::Keys(pattern) => self.keys(pattern).await,
             Command::RandomKey => self.randomkey().await,
             Command::Expire(key, secs) => self.expire(key, secs).await,
@@ -87,6 +164,43 @@ impl Db {
             Command::Get(key) => self.get(&key).await,
             Command::Set(key, val) => self.set(key, val).await,
             Command::Del(key) => self.del(&key).await,
+            Command::Unlink(keys) => self.unlink(keys).await,
+            Command::FlushDb(lazy) | Command::FlushAll(lazy) => {
+                self.flush(lazyfree::user_flush(lazy)).await;
+                Frame::Simple("OK".into())
+            }
+            Command::DbSize => Frame::Integer(self.keyspace.len().await as i64),
+            Command::Select(_) | Command::SwapDb(_, _) | Command::Move(_, _) | Command::MemoryStats => {
+                Frame::Error("ERR command spans databases".into())
+            }
+            // Scripts may call into any database.
+            Command::Eval(_, _, _)
+            | Command::EvalSha(_, _, _)
+            | Command::ScriptLoad(_)
+            | Command::ScriptExists(_)
+            | Command::ScriptFlush
+            | Command::ScriptKill
+            | Command::FCall(_, _, _)
+            | Command::FCallRo(_, _, _)
+            | Command::FunctionLoad(_, _)
+            | Command::FunctionDelete(_)
+            | Command::FunctionFlush
+            | Command::FunctionList(_, _)
+            | Command::FunctionDump
+            | Command::FunctionRestore(_, _) => Frame::Error("ERR command spans databases".into()),
+            // Channels are shared by all databases.
+            Command::Publish(_, _) | Command::PubSubChannels(_) | Command::PubSubNumSub(_) => {
+                Frame::Error("ERR command spans databases".into())
+            }
+            Command::Subscribe(_) | Command::Unsubscribe(_) => {
+                Frame::Error("ERR subscriptions are kept by the connection".into())
+            }
+            Command::Multi | Command::Exec | Command::Discard | Command::Watch(_) => {
+                Frame::Error("ERR transactions are run by the connection".into())
+            }
+            // Only reached when queued in a transaction, after which EXEC
+            // unwatches everything anyway.
+            Command::Unwatch => Frame::Simple("OK".into()),
             Command::Append(key, val) => self.append(key, val).await,
             Command::StrLen(key) => self.strlen(key).await,
             Command::GetSet(key, val) => self.getset(key, val).await,
@@ -94,21 +208,30 @@ impl Db {
             Command::IncrBy(key, amt) => self.incrby(key, amt).await,
             Command::MSet(kvs) => self.mset(kvs).await,
             Command::MGet(keys) => self.mget(keys).await,
+            Command::Lcs(key1, key2, opts) => self.lcs(key1, key2, opts).await,
 
             // List commands
             Command::LPush(key, vals) => self.lpush(key, vals).await,
-            Command::LPop(key) => self.lpop(key).await,
+            Command::LPushX(key, vals) => self.pushx(key, ListEnd::Left, vals).await,
+            Command::LPop(key, count) => self.list_pop(&key, ListEnd::Left, count).await,
             Command::RPush(key, vals) => self.rpush(key, vals).await,
-            Command::RPop(key) => self.rpop(&key).await,
+            Command::RPushX(key, vals) => self.pushx(key, ListEnd::Right, vals).await,
+            Command::RPop(key, count) => self.list_pop(&key, ListEnd::Right, count).await,
             Command::LLen(key) => self.llen(key).await,
             Command::LRange(key, s, e) => self.lrange(key, s, e).await,
             Command::LIndex(key, idx) => self.lindex(key, idx).await,
             Command::LSet(key, idx, val) => self.lset(key, idx, val).await,
             Command::LTrim(key, s, e) => self.ltrim(key, s, e).await,
-            Command::BRPop(key, timeout) => self.brpop(key, timeout).await,
+            Command::LInsert(key, pos, pivot, elem) => self.linsert(key, pos, pivot, elem).await,
+            Command::LRem(key, count, elem) => self.lrem(key, count, elem).await,
+            Command::LPos(key, elem, opts) => self.lpos(key, elem, opts).await,
+            Command::LMove(src, dst, from, to) => self.lmove(src, dst, from, to).await,
+            Command::LMPop(keys, end, count) => self.lmpop(keys, end, count).await,
 
             // Hash commands
-            Command::HSet(key, field, value) => self.hset(key, field, value).await,
+            Command::HSet(key, pairs) => self.hset(key, pairs).await,
+            Command::HMSet(key, pairs) => self.hmset(key, pairs).await,
+            Command::HSetNx(key, field, value) => self.hsetnx(key, field, value).await,
             Command::HGet(key, field) => self.hget(key, field).await,
             Command::HDel(key, fields) => self.hdel(key, fields).await,
             Command::HGetAll(key) => self.hgetall(key).await,
@@ -117,6 +240,20 @@ impl Db {
             Command::HLen(key) => self.hlen(key).await,
             Command::HKeys(key) => self.hkeys(key).await,
             Command::HVals(key) => self.hvals(key).await,
+            Command::HStrLen(key, field) => self.hstrlen(key, field).await,
+            Command::HIncrBy(key, field, amt) => self.hincrby(key, field, amt).await,
+            Command::HIncrByFloat(key, field, amt) => self.hincrbyfloat(key, field, amt).await,
+            Command::HRandField(key, count, with_values) => self.hrandfield(key, count, with_values).await,
+            Command::HExpire(key, when, cond, fields) => self.hexpire(key, when, cond, fields).await,
+            Command::HTtl(key, fields) => {
+                self.hfield_ttl(key, fields, |at| (at.saturating_sub(now_ms()) as i64 + 500) / 1000).await
+            }
+            Command::HPTtl(key, fields) => {
+                self.hfield_ttl(key, fields, |at| at.saturating_sub(now_ms()) as i64).await
+            }
+            Command::HExpireTime(key, fields) => self.hfield_ttl(key, fields, |at| at as i64 / 1000).await,
+            Command::HPExpireTime(key, fields) => self.hfield_ttl(key, fields, |at| at as i64).await,
+            Command::HPersist(key, fields) => self.hpersist(key, fields).await,
 
             // Set commands
             Command::SAdd(key, members) => self.sadd(key, members).await,
@@ -124,22 +261,64 @@ impl Db {
             Command::SMembers(key) => self.smembers(key).await,
             Command::SIsMember(key, member) => self.sismember(key, member).await,
             Command::SCard(key) => self.scard(key).await,
-            Command::SUnion(keys) => self.sunion(keys).await,
-            Command::SInter(keys) => self.sinter(keys).await,
-            Command::SDiff(keys) => self.sdiff(keys).await,
+            Command::SUnion(keys) => self.set_algebra(SetOp::Union, keys).await,
+            Command::SInter(keys) => self.set_algebra(SetOp::Inter, keys).await,
+            Command::SDiff(keys) => self.set_algebra(SetOp::Diff, keys).await,
+            Command::SUnionStore(dest, keys) => self.set_algebra_store(SetOp::Union, dest, keys).await,
+            Command::SInterStore(dest, keys) => self.set_algebra_store(SetOp::Inter, dest, keys).await,
+            Command::SDiffStore(dest, keys) => self.set_algebra_store(SetOp::Diff, dest, keys).await,
+            Command::SInterCard(keys, limit) => self.sintercard(keys, limit).await,
+            Command::SPop(key, count) => self.spop(key, count).await,
+            Command::SRandMember(key, count) => self.srandmember(key, count).await,
+            Command::SMove(src, dst, member) => self.smove(src, dst, member).await,
+            Command::SMIsMember(key, members) => self.smismember(key, members).await,
             
             // Sorted Set commands
-            Command::ZAdd(key, score, member) => self.zadd(key, score, member).await,
-            Command::ZRem(key, member) => self.zrem(key, member).await,
-            Command::ZRange(key, start, end) => self.zrange(key, start, end).await,
-            Command::ZRevRange(key, start, end) => self.zrevrange(key, start, end).await,
+            Command::ZAdd(key, opts, pairs) => self.zadd(key, opts, pairs).await,
+            Command::ZIncrBy(key, incr, member) => {
+                let opts = ZAddOptions { incr: true, ..ZAddOptions::default() };
+                self.zadd(key, opts, vec![(incr, member)]).await
+            }
+            Command::ZMScore(key, members) => self.zmscore(key, members).await,
+            Command::ZRem(key, members) => self.zrem(key, members).await,
+            Command::ZRange(key, opts) => self.zrange(key, opts).await,
+            Command::ZRangeStore(dst, src, opts) => self.zrangestore(dst, src, opts).await,
             Command::ZCard(key) => self.zcard(key).await,
             Command::ZScore(key, member) => self.zscore(key, member).await,
-            Command::ZRangeByScore(key, min, max) => self.zrange_by_score(key, min, max).await,
-            Command::ZRemRangeByScore(key, min, max) => self.zremrangebyscore(key, min, max).await,
-            Command::ZRank(key, member) => self.zrank(key, member).await,
-            Command::ZRevRank(key, member) => self.zrevrank(key, member).await,
-            Command::ZCount(key, min, max) => self.zcount(key, min, max).await,
+            Command::ZRemRangeByScore(key, range) => {
+                self.zremrange(key, |zset| zset.remove_range_by_score(&range)).await
+            }
+            Command::ZRemRangeByLex(key, range) => {
+                self.zremrange(key, |zset| zset.remove_range_by_lex(&range)).await
+            }
+            Command::ZRemRangeByRank(key, start, stop) => {
+                self.zremrange(key, |zset| match normalize_rank_range(start, stop, zset.len()) {
+                    Some((s, e)) => zset.remove_range_by_rank(s, e),
+                    None => 0,
+                }).await
+            }
+            Command::ZRank(key, member, with_score) => self.zrank(key, member, false, with_score).await,
+            Command::ZRevRank(key, member, with_score) => self.zrank(key, member, true, with_score).await,
+            Command::ZRandMember(key, count, with_scores) => self.zrandmember(key, count, with_scores).await,
+            Command::ZMPop(keys, end, count) => self.zmpop(keys, end, count).await,
+            Command::ZUnion(keys, opts) => self.zalgebra(SetOp::Union, keys, opts).await,
+            Command::ZInter(keys, opts) => self.zalgebra(SetOp::Inter, keys, opts).await,
+            Command::ZDiff(keys, opts) => self.zalgebra(SetOp::Diff, keys, opts).await,
+            Command::ZUnionStore(dest, keys, opts) => self.zalgebra_store(SetOp::Union, dest, keys, opts).await,
+            Command::ZInterStore(dest, keys, opts) => self.zalgebra_store(SetOp::Inter, dest, keys, opts).await,
+            Command::ZDiffStore(dest, keys, opts) => self.zalgebra_store(SetOp::Diff, dest, keys, opts).await,
+            Command::ZCount(key, range) => self.zcount(key, |zset| zset.range_by_score(&range).len()).await,
+            Command::ZLexCount(key, range) => self.zcount(key, |zset| zset.range_by_lex(&range).len()).await,
+            Command::ZPopMin(key, count) => self.zpop(key, ZSetEnd::Min, count).await,
+            Command::ZPopMax(key, count) => self.zpop(key, ZSetEnd::Max, count).await,
+
+            Command::BLPop(..)
+            | Command::BRPop(..)
+            | Command::BLMove(..)
+            | Command::BLMPop(..)
+            | Command::BZPopMin(..)
+            | Command::BZPopMax(..)
+            | Command::BZMPop(..) => unreachable!("blocking commands are split off above"),
         }
     }
 
@@ -147,9 +326,9 @@ impl Db {
         if self.check_and_purge(key).await {
             return Frame::Null;
         }
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
         match inner.get(key) {
-            Some(Value::String(v)) => Frame::Bulk(v.clone()),
+            Some(Value::String(v)) => Frame::Bulk(v.to_vec()),
             Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
             None => Frame::Null,
         }
@@ -157,8 +336,8 @@ impl Db {
 
     async fn set(&self, key: String, val: Vec<u8>) -> Frame {
         self.check_and_purge(&key).await;
-        let mut inner = self.inner.write().await;
-        inner.insert(key, Value::String(val));
+        let mut inner = self.keyspace.write([&key]).await;
+        inner.insert(key, Value::string(val));
         Frame::Simple("OK".into())
     }
 
@@ -167,15 +346,24 @@ impl Db {
             return Frame::Integer(0);
         }
         
-        let mut inner = self.inner.write().await;
-        let removed = inner.remove(key).is_some();
+        let mut inner = self.keyspace.write([key]).await;
+        let removed = inner.free(key, lazyfree::enabled(&lazyfree::CONFIG.lazy_user_del));
 
-        let mut ttl = self.ttl.write().await;
-        ttl.remove(key);
+        Frame::Integer(removed as i64)
+    }
 
+    /// Like DEL, but large values are always freed in the background.
+    async fn unlink(&self, keys: Vec<String>) -> Frame {
+        let mut inner = self.keyspace.write(&keys).await;
+        let removed = keys.iter().filter(|k| inner.free(k, true)).count();
         Frame::Integer(removed as i64)
     }
 
+    /// Removes every key, freeing large values in the background if `lazy`.
+    pub async fn flush(&self, lazy: bool) {
+        self.keyspace.write_all().await.clear(lazy);
+    }
+
     // ------- KEYSPACE ------- //
 
     async fn exists(&self, keys: Vec<String>) -> Frame {
@@ -186,7 +374,7 @@ impl Db {
                 continue;
             }
 
-            let inner = self.inner.read().await;
+            let inner = self.keyspace.read([&key]).await;
             if inner.contains_key(&key) {
                 count += 1;
             }
@@ -200,7 +388,7 @@ impl Db {
             return Frame::Simple("none".into());
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         let t = match inner.get(&key) {
             None => "none",
@@ -214,6 +402,63 @@ impl Db {
         Frame::Simple(t.into())
     }
 
+    async fn object_encoding(&self, key: String) -> Frame {
+        if self.check_and_purge(&key).await {
+            return Frame::Null;
+        }
+
+        let inner = self.keyspace.read([&key]).await;
+
+        match inner.peek(&key) {
+            Some(e) => Frame::Bulk(e.value.encoding().as_bytes().to_vec()),
+            None => Frame::Null,
+        }
+    }
+
+    async fn object_freq(&self, key: String) -> Frame {
+        if !eviction::CONFIG.policy().is_lfu() {
+            return Frame::Error(
+                "ERR An LFU maxmemory policy is not selected, access frequency not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.".into(),
+            );
+        }
+        let inner = self.keyspace.read([&key]).await;
+        match inner.peek(&key) {
+            Some(e) => Frame::Integer(e.access().frequency() as i64),
+            None => Frame::Null,
+        }
+    }
+
+    async fn object_idletime(&self, key: String) -> Frame {
+        if eviction::CONFIG.policy().is_lfu() {
+            return Frame::Error(
+                "ERR An LFU maxmemory policy is selected, idle time not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.".into(),
+            );
+        }
+        let inner = self.keyspace.read([&key]).await;
+        match inner.peek(&key) {
+            Some(e) => Frame::Integer((e.access().idle_ms() / 1000) as i64),
+            None => Frame::Null,
+        }
+    }
+
+    /// Values are never shared between keys, so every live key has exactly
+    /// one reference.
+    async fn object_refcount(&self, key: String) -> Frame {
+        let inner = self.keyspace.read([&key]).await;
+        match inner.peek(&key) {
+            Some(_) => Frame::Integer(1),
+            None => Frame::Null,
+        }
+    }
+
+    async fn memory_usage(&self, key: String, samples: usize) -> Frame {
+        let inner = self.keyspace.read([&key]).await;
+        match inner.peek(&key) {
+            Some(e) => Frame::Integer(e.mem_usage(&key, samples) as i64),
+            None => Frame::Null,
+        }
+    }
+
     async fn keys(&self, pattern: String) -> Frame {
         // Currently only works for exact matches
         fn key_matches(pattern: &str, key: &str) -> bool {
@@ -224,8 +469,7 @@ impl Db {
             }
         }
 
-        // Currently not using TTL checks to avoid complications
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read_all().await;
         let mut arr = Vec::new();
         for k in inner.keys() {
             if key_matches(&pattern, k) {
@@ -236,53 +480,41 @@ impl Db {
         Frame::Array(arr)
     }
 
+    /// A random live key. Expired keys it comes across are dropped and
+    /// another is drawn, so each draw either answers or shrinks the keyspace.
     async fn randomkey(&self) -> Frame {
-        let inner = self.inner.read().await;
-
-        if inner.is_empty() {
-            return Frame::Null;
-        }
-
-        let mut rng = rand::thread_rng();
-        let idx = rng.gen_range(0..inner.len());
-
-        if let Some(key) = inner.keys().nth(idx) {
-            Frame::Bulk(key.as_bytes().to_vec())
-        } else {
-            Frame::Null
+        loop {
+            let Some(idx) = self.keyspace.random_shard().await else {
+                return Frame::Null;
+            };
+            let (key, expired) = {
+                let shard = self.keyspace.read_shard(idx).await;
+                match shard.sample(1, false).first() {
+                    Some((key, entry)) => (key.to_string(), entry.is_expired(Instant::now())),
+                    None => continue,
+                }
+            };
+            if !expired {
+                return Frame::Bulk(key.into_bytes());
+            }
+            self.purge_expired(&key).await;
         }
     }
 
     async fn expire(&self, key: String, secs: usize) -> Frame {
-        let inner = self.inner.read().await;
-        if !inner.contains_key(&key) {
-            return Frame::Integer(0);
-        }
-
-        drop(inner);
-
-        let mut ttl = self.ttl.write().await;
-        ttl.insert(key, Instant::now() + Duration::from_secs(secs as u64));
-        Frame::Integer(1)
+        let mut inner = self.keyspace.write([&key]).await;
+        let set = inner.set_expires_at(&key, Instant::now() + Duration::from_secs(secs as u64));
+        Frame::Integer(set as i64)
     }
 
     async fn ttl(&self, key: &str) -> Frame {
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([key]).await;
         if !inner.contains_key(key) {
             return Frame::Integer(-2);
         }
 
-        drop(inner);
-
-        let ttl = self.ttl.read().await;
-        if let Some(exp_at) = ttl.get(key) {
-            let now = Instant::now();
-
-            if now >= *exp_at {
-                return Frame::Integer(-2);
-            }
-
-            let remaining = (*exp_at - now).as_secs() as i64;
+        if let Some(exp_at) = inner.expires_at(key) {
+            let remaining = exp_at.saturating_duration_since(Instant::now()).as_secs() as i64;
             Frame::Integer(remaining)
         } else {
             Frame::Integer(-1)
@@ -293,16 +525,17 @@ impl Db {
 
     async fn append(&self, key: String, val: Vec<u8>) -> Frame {
         self.check_and_purge(&key).await;
-        let mut inner = self.inner.write().await;
+        let mut inner = self.keyspace.write([&key]).await;
 
         match inner.get_mut(&key) {
             Some(Value::String(s)) => {
-                s.extend_from_slice(&val);
-                Frame::Integer(s.len() as i64)
+                let len = s.append(&val);
+                inner.touch(&key);
+                Frame::Integer(len as i64)
             }
             Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
             None => {
-                inner.insert(key.clone(), Value::String(val.clone()));
+                inner.insert(key.clone(), Value::string(val.clone()));
                 Frame::Integer(val.len() as i64)
             }
         }
@@ -313,7 +546,7 @@ impl Db {
             return Frame::Integer(0);
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::String(s)) => Frame::Integer(s.len() as i64),
@@ -325,34 +558,28 @@ impl Db {
     async fn getset(&self, key: String, val: Vec<u8>) -> Frame {
         self.check_and_purge(&key).await;
 
-        let mut inner = self.inner.write().await;
+        let mut inner = self.keyspace.write([&key]).await;
 
         let old = match inner.get(&key) {
-            Some(Value::String(s)) => Frame::Bulk(s.clone()),
+            Some(Value::String(s)) => Frame::Bulk(s.to_vec()),
             Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
             None => Frame::Null,
         };
 
-        inner.insert(key, Value::String(val));
+        inner.insert(key, Value::string(val));
         old
     }
 
     async fn incr(&self, key: String) -> Frame {
         self.check_and_purge(&key).await;
 
-        let mut inner = self.inner.write().await;
+        let mut inner = self.keyspace.write([&key]).await;
 
         let curr = match inner.get(&key) {
             Some(Value::String(s)) => {
-                let s = match std::str::from_utf8(s) {
-                    Ok(v) => v,
-                    Err(_) => {
-                        return Frame::Error("ERR value is not an integer".into());
-                    }
-                };
-                match s.parse::<i64>() {
-                    Ok(v) => v,
-                    Err(_) => {
+                match s.parse_int() {
+                    Some(v) => v,
+                    None => {
                         return Frame::Error("ERR value is not an integer".into());
                     }
                 }
@@ -362,26 +589,20 @@ impl Db {
         };
 
         let new_val = curr + 1;
-        inner.insert(key, Value::String(new_val.to_string().into_bytes()));
+        store_counter(&mut inner, key, new_val);
         Frame::Integer(new_val)
     }
 
     async fn incrby(&self, key: String, amt: i64) -> Frame {
         self.check_and_purge(&key).await;
 
-        let mut inner = self.inner.write().await;
+        let mut inner = self.keyspace.write([&key]).await;
 
         let curr = match inner.get(&key) {
             Some(Value::String(s)) => {
-                let s = match std::str::from_utf8(s) {
-                    Ok(v) => v,
-                    Err(_) => {
-                        return Frame::Error("ERR value is not an integer".into());
-                    }
-                };
-                match s.parse::<i64>() {
-                    Ok(v) => v,
-                    Err(_) => {
+                match s.parse_int() {
+                    Some(v) => v,
+                    None => {
                         return Frame::Error("ERR value is not an integer".into());
                     }
                 }
@@ -391,28 +612,28 @@ impl Db {
         };
 
         let new_val = curr + amt;
-        inner.insert(key, Value::String(new_val.to_string().into_bytes()));
+        store_counter(&mut inner, key, new_val);
         Frame::Integer(new_val)
     }
 
     async fn mset(&self, kvs: Vec<(String, Vec<u8>)>) -> Frame {
-        let mut inner = self.inner.write().await;
+        let mut inner = self.keyspace.write(kvs.iter().map(|(k, _)| k)).await;
 
         for (k, v) in kvs {
-            inner.insert(k, Value::String(v));
+            inner.insert(k, Value::string(v));
         }
 
         Frame::Simple("OK".into())
     }
 
     async fn mget(&self, keys: Vec<String>) -> Frame {
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read(&keys).await;
 
         let mut arr = Vec::new();
 
         for k in keys {
             match inner.get(&k) {
-                Some(Value::String(s)) => arr.push(Frame::Bulk(s.clone())),
+                Some(Value::String(s)) => arr.push(Frame::Bulk(s.to_vec())),
                 Some(_) => arr.push(Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())),
                 None => arr.push(Frame::Null),
             }
@@ -421,81 +642,267 @@ impl Db {
         Frame::Array(arr)
     }
 
-    async fn lpush(&self, key: String, vals: Vec<Vec<u8>>) -> Frame {
-        self.check_and_purge(&key).await;
-        let mut inner = self.inner.write().await;
-        let entry = inner.entry(key).or_insert_with(|| Value::List(ListState::new()));
-
-        match entry {
-            Value::List(list) => {
-                for v in vals {
-                    list.data.push_front(v);
+    async fn lcs(&self, key1: String, key2: String, opts: LcsOptions) -> Frame {
+        self.check_and_purge(&key1).await;
+        self.check_and_purge(&key2).await;
+
+        // Copy both operands out so the quadratic work below does not hold the lock.
+        let (a, b) = {
+            let inner = self.keyspace.read([&key1, &key2]).await;
+            let mut operands = Vec::with_capacity(2);
+            for key in [&key1, &key2] {
+                match inner.get(key) {
+                    Some(v) => match v.as_string() {
+                        Some(s) => operands.push(s.to_vec()),
+                        None => {
+                            return Frame::Error(
+                                "ERR The specified keys must contain string values".into(),
+                            );
+                        }
+                    },
+                    None => operands.push(Vec::new()),
                 }
-                list.notify.notify_one();
-                Frame::Integer(list.data.len() as i64)
             }
-            _ => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            let b = operands.pop().unwrap();
+            let a = operands.pop().unwrap();
+            (a, b)
+        };
+
+        if a.len() >= u32::MAX as usize - 1 || b.len() >= u32::MAX as usize - 1 {
+            return Frame::Error("ERR String too long for LCS".into());
         }
-    }
 
-    async fn lpop(&self, key: String) -> Frame {
-        if self.check_and_purge(&key).await {
-            return Frame::Null;
+        let alen = a.len();
+        let blen = b.len();
+
+        let cells = (alen + 1).checked_mul(blen + 1);
+        let bytes = cells.and_then(|c| c.checked_mul(std::mem::size_of::<u32>()));
+        let (cells, bytes) = match (cells, bytes) {
+            (Some(c), Some(b)) => (c, b),
+            _ => {
+                return Frame::Error(
+                    "ERR Insufficient memory, failed allocating transient memory for LCS".into(),
+                );
+            }
+        };
+        if bytes > LCS_MAX_TABLE_BYTES {
+            return Frame::Error(
+                "ERR Insufficient memory, transient memory for LCS exceeds proto-max-bulk-len"
+                    .into(),
+            );
+        }
+
+        // table[i * (blen + 1) + j] holds the LCS length of a[..i] and b[..j].
+        let at = |i: usize, j: usize| i * (blen + 1) + j;
+
+        // Filling up to LCS_MAX_TABLE_BYTES of table takes a while; as with
+        // scripts, block_in_place lets the runtime move other tasks off this
+        // worker in the meantime.
+        let table = tokio::task::block_in_place(|| {
+            let mut table: Vec<u32> = Vec::new();
+            table.try_reserve_exact(cells).ok()?;
+            table.resize(cells, 0);
+            for i in 1..=alen {
+                for j in 1..=blen {
+                    table[at(i, j)] = if a[i - 1] == b[j - 1] {
+                        table[at(i - 1, j - 1)] + 1
+                    } else {
+                        table[at(i - 1, j)].max(table[at(i, j - 1)])
+                    };
+                }
+            }
+            Some(table)
+        });
+        let Some(table) = table else {
+            return Frame::Error(
+                "ERR Insufficient memory, failed allocating transient memory for LCS".into(),
+            );
+        };
+
+        let total = table[at(alen, blen)];
+
+        if opts.len {
+            return Frame::Integer(total as i64);
         }
 
-        let mut inner = self.inner.write().await;
+        // Walk the table backwards, rebuilding the LCS and the matching ranges.
+        let mut result = vec![0u8; total as usize];
+        let mut matches = Vec::new();
+        let mut remaining = total as usize;
+        let mut range: Option<(usize, usize, usize, usize)> = None;
+        let (mut i, mut j) = (alen, blen);
 
-        match inner.get_mut(&key) {
-            Some(Value::List(list)) => {
-                if let Some(v) = list.data.pop_front() {
-                    Frame::Bulk(v)
+        while i > 0 && j > 0 {
+            let mut emit = false;
+
+            if a[i - 1] == b[j - 1] {
+                result[remaining - 1] = a[i - 1];
+
+                match range.as_mut() {
+                    None => range = Some((i - 1, i - 1, j - 1, j - 1)),
+                    Some((a_start, _, b_start, _)) => {
+                        if *a_start == i && *b_start == j {
+                            *a_start -= 1;
+                            *b_start -= 1;
+                        } else {
+                            emit = true;
+                        }
+                    }
+                }
+
+                if let Some((a_start, _, b_start, _)) = range {
+                    if a_start == 0 || b_start == 0 {
+                        emit = true;
+                    }
+                }
+
+                remaining -= 1;
+                i -= 1;
+                j -= 1;
+            } else {
+                if table[at(i - 1, j)] > table[at(i, j - 1)] {
+                    i -= 1;
                 } else {
-                    Frame::Null
+                    j -= 1;
+                }
+                if range.is_some() {
+                    emit = true;
                 }
             }
-            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
-            None => Frame::Null,
+
+            if emit {
+                if let Some((a_start, a_end, b_start, b_end)) = range.take() {
+                    let match_len = a_end - a_start + 1;
+                    if opts.idx && (opts.min_match_len == 0 || match_len >= opts.min_match_len) {
+                        let mut item = vec![
+                            Frame::Array(vec![
+                                Frame::Integer(a_start as i64),
+                                Frame::Integer(a_end as i64),
+                            ]),
+                            Frame::Array(vec![
+                                Frame::Integer(b_start as i64),
+                                Frame::Integer(b_end as i64),
+                            ]),
+                        ];
+                        if opts.with_match_len {
+                            item.push(Frame::Integer(match_len as i64));
+                        }
+                        matches.push(Frame::Array(item));
+                    }
+                }
+            }
+        }
+
+        if opts.idx {
+            Frame::Array(vec![
+                Frame::Bulk(b"matches".to_vec()),
+                Frame::Array(matches),
+                Frame::Bulk(b"len".to_vec()),
+                Frame::Integer(total as i64),
+            ])
+        } else {
+            Frame::Bulk(result)
         }
     }
 
-    async fn rpush(&self, key: String, vals: Vec<Vec<u8>>) -> Frame {
+    async fn lpush(&self, key: String, vals: Vec<Vec<u8>>) -> Frame {
         self.check_and_purge(&key).await;
+        let mut inner = self.keyspace.write([&key]).await;
+        let entry = inner.get_or_insert_with(key.clone(), || Value::List(ListState::new()));
 
-        let mut inner = self.inner.write().await;
-        let entry = inner.entry(key).or_insert_with(|| Value::List(ListState::new()));
-
-        match entry {
+        let len = match entry {
             Value::List(list) => {
                 for v in vals {
-                    list.data.push_back(v);
+                    list.push(ListEnd::Left, v);
                 }
-                list.notify.notify_one();
-                Frame::Integer(list.data.len() as i64)
+                list.len()
             }
-            _ => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            _ => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+        };
+
+        inner.touch(&key);
+        self.wake_blocked(inner, &key).await;
+        Frame::Integer(len as i64)
+    }
+
+    async fn pushx(&self, key: String, end: ListEnd, vals: Vec<Vec<u8>>) -> Frame {
+        if self.check_and_purge(&key).await {
+            return Frame::Integer(0);
         }
+
+        let mut inner = self.keyspace.write([&key]).await;
+
+        let len = match inner.get_mut(&key) {
+            Some(Value::List(list)) => {
+                for v in vals {
+                    list.push(end, v);
+                }
+                list.len()
+            }
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return Frame::Integer(0),
+        };
+
+        inner.touch(&key);
+        self.wake_blocked(inner, &key).await;
+        Frame::Integer(len as i64)
     }
 
-    async fn rpop(&self, key: &str) -> Frame {
+    async fn list_pop(&self, key: &str, end: ListEnd, count: Option<usize>) -> Frame {
         if self.check_and_purge(key).await {
             return Frame::Null;
         }
 
-        let mut inner = self.inner.write().await;
+        let mut inner = self.keyspace.write([&key]).await;
 
-        let value_opt = inner.get_mut(key);
-        if let Some(value) = value_opt {
-            if let Some(list) = value.as_list_mut() {
-                if let Some(v) = list.data.pop_back() {
-                    return Frame::Bulk(v);
-                } else {
-                    return Frame::Null;
+        let reply = match inner.get_mut(key) {
+            Some(Value::List(list)) => match count {
+                None => match list.pop(end) {
+                    Some(v) => Frame::Bulk(v),
+                    None => Frame::Null,
+                },
+                Some(n) => {
+                    let mut arr = Vec::with_capacity(n.min(list.len()));
+                    while arr.len() < n {
+                        match list.pop(end) {
+                            Some(v) => arr.push(Frame::Bulk(v)),
+                            None => break,
+                        }
+                    }
+                    Frame::Array(arr)
                 }
-            } else {
-                return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into());
-            }
+            },
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return Frame::Null,
+        };
+
+        // Stored lists are never empty, so only a count of 0 pops nothing.
+        if count != Some(0) {
+            inner.touch(key);
         }
-        Frame::Null
+        remove_if_empty_list(&mut inner, key);
+        reply
+    }
+
+    async fn rpush(&self, key: String, vals: Vec<Vec<u8>>) -> Frame {
+        self.check_and_purge(&key).await;
+
+        let mut inner = self.keyspace.write([&key]).await;
+        let entry = inner.get_or_insert_with(key.clone(), || Value::List(ListState::new()));
+
+        let len = match entry {
+            Value::List(list) => {
+                for v in vals {
+                    list.push(ListEnd::Right, v);
+                }
+                list.len()
+            }
+            _ => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+        };
+
+        inner.touch(&key);
+        self.wake_blocked(inner, &key).await;
+        Frame::Integer(len as i64)
     }
 
     async fn llen(&self, key: String) -> Frame {
@@ -503,10 +910,10 @@ impl Db {
             return Frame::Integer(0);
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
-            Some(Value::List(list)) => Frame::Integer(list.data.len() as i64),
+            Some(Value::List(list)) => Frame::Integer(list.len() as i64),
             Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
             None => Frame::Integer(0),
         }
@@ -517,11 +924,11 @@ impl Db {
             return Frame::Null;
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::List(list)) => {
-                let len = list.data.len() as i64;
+                let len = list.len() as i64;
 
                 let idx = if index < 0 {
                     len + index
@@ -532,7 +939,7 @@ impl Db {
                 if idx < 0 || idx >= len {
                     Frame::Null
                 } else {
-                    Frame::Bulk(list.data[idx as usize].clone())
+                    list.get(idx as usize).map_or(Frame::Null, Frame::Bulk)
                 }
             }
             Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
@@ -545,11 +952,11 @@ impl Db {
             return Frame::Error("ERR no such key".into());
         }
 
-        let mut inner = self.inner.write().await;
+        let mut inner = self.keyspace.write([&key]).await;
 
         match inner.get_mut(&key) {
             Some(Value::List(list)) => {
-                let len = list.data.len() as i64;
+                let len = list.len() as i64;
 
                 let idx = if index < 0 {
                     len + index
@@ -561,7 +968,8 @@ impl Db {
                     return Frame::Error("ERR index out of range".into());
                 }
 
-                list.data[idx as usize] = val;
+                list.set(idx as usize, val);
+                inner.touch(&key);
                 Frame::Simple("OK".into())
             }
             Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
@@ -574,11 +982,11 @@ impl Db {
             return Frame::Array(vec![]);
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::List(list)) => {
-                let len = list.data.len() as i64;
+                let len = list.len() as i64;
 
                 let s = if start < 0 { len + start } else { start }.max(0);
                 let e = if end < 0 { len + end } else { end }.max(0);
@@ -589,12 +997,11 @@ impl Db {
 
                 let e = e.min(len - 1);
 
-                let result = list.data
+                let result = list
                     .iter()
                     .skip(s as usize)
                     .take((e - s + 1) as usize)
-                    .cloned()
-                    .map(Frame::Bulk)
+                    .map(|v| Frame::Bulk(v.to_vec()))
                     .collect::<Vec<_>>();
 
                 Frame::Array(result)
@@ -609,135 +1016,426 @@ impl Db {
             return Frame::Simple("OK".into());
         }
 
-        let mut inner = self.inner.write().await;
+        let mut inner = self.keyspace.write([&key]).await;
 
         match inner.get_mut(&key) {
             Some(Value::List(list)) => {
-                let len = list.data.len() as i64;
+                let len = list.len() as i64;
 
                 let s = if start < 0 { len + start } else { start }.max(0);
                 let e = if end < 0 { len + end } else { end }.max(0);
 
                 if s > e || s >= len {
-                    list.data.clear();
-                    return Frame::Simple("OK".into());
+                    list.clear();
+                } else {
+                    list.trim(s as usize, e.min(len - 1) as usize);
+                }
+                if list.len() as i64 != len {
+                    inner.touch(&key);
                 }
-
-                let e = e.min(len - 1);
-
-                list.data = list.data
-                    .iter()
-                    .skip(s as usize)
-                    .take((e - s + 1) as usize)
-                    .cloned()
-                    .collect();
-
-                Frame::Simple("OK".into())
             }
-            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
-            None => Frame::Simple("OK".into()),
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return Frame::Simple("OK".into()),
         }
+
+        remove_if_empty_list(&mut inner, &key);
+        Frame::Simple("OK".into())
     }
 
-    async fn brpop(&self, key: String, timeout_secs: usize) -> Frame {
+    async fn linsert(&self, key: String, position: InsertPosition, pivot: Vec<u8>, element: Vec<u8>) -> Frame {
         if self.check_and_purge(&key).await {
-            return Frame::Null;
+            return Frame::Integer(0);
         }
 
-        let timeout = Duration::from_secs(timeout_secs as u64);
-        let deadline = time::Instant::now() + timeout;
-
-        loop {
-            let notify_opt = {
-                let mut inner = self.inner.write().await;
-
-                match inner.get_mut(&key) {
-                    Some(Value::List(list)) => {
-                        if let Some(v) = list.data.pop_back() {
-                            return Frame::Array(vec![
-                                Frame::Bulk(key.as_bytes().to_vec()),
-                                Frame::Bulk(v),
-                            ]);
-                        }
-                        Some(list.notify.clone())
-                    }
-                    Some(_) => {
-                        return Frame::Error(
-                            "WRONGTYPE Operation against a key holding the wrong kind of value"
-                                .into(),
-                        );
-                    }
-                    None => {
-                        None
-                    }
-                }
-            };
+        let mut inner = self.keyspace.write([&key]).await;
 
-            if let Some(notify) = notify_opt {
-                let now = time::Instant::now();
-                if now >= deadline {
-                    return Frame::Null;
-                }
+        let len = match inner.get_mut(&key) {
+            Some(Value::List(list)) => {
+                let Some(idx) = list.iter().position(|v| v == pivot.as_slice()) else {
+                    return Frame::Integer(-1);
+                };
+                let at = match position {
+                    InsertPosition::Before => idx,
+                    InsertPosition::After => idx + 1,
+                };
+                list.insert(at, element);
+                list.len()
+            }
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return Frame::Integer(0),
+        };
 
-                let remaining = deadline - now;
+        inner.touch(&key);
+        self.wake_blocked(inner, &key).await;
+        Frame::Integer(len as i64)
+    }
 
-                if time::timeout(remaining, notify.notified()).await.is_err() {
-                    return Frame::Null;
-                }
+    async fn lrem(&self, key: String, count: i64, element: Vec<u8>) -> Frame {
+        if self.check_and_purge(&key).await {
+            return Frame::Integer(0);
+        }
 
-                if self.check_and_purge(&key).await {
-                    return Frame::Null;
-                }
+        let mut inner = self.keyspace.write([&key]).await;
 
-                continue;
-            }
+        let removed = match inner.get_mut(&key) {
+            Some(Value::List(list)) => {
+                let limit = if count == 0 { usize::MAX } else { count.unsigned_abs() as usize };
+                let matches = list.iter()
+                    .enumerate()
+                    .filter(|(_, v)| *v == element.as_slice())
+                    .map(|(i, _)| i);
+                let mut doomed: Vec<usize> = if count >= 0 {
+                    matches.take(limit).collect()
+                } else {
+                    let all: Vec<usize> = matches.collect();
+                    all.into_iter().rev().take(limit).collect()
+                };
 
-            let now = time::Instant::now();
-            if now >= deadline {
-                return Frame::Null;
+                // Remove back to front so earlier indexes stay valid.
+                doomed.sort_unstable_by(|a, b| b.cmp(a));
+                for &i in &doomed {
+                    list.remove(i);
+                }
+                doomed.len()
             }
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return Frame::Integer(0),
+        };
 
-            let remaining = deadline - now;
-            let sleep_dur = remaining.min(Duration::from_millis(10));
-            time::sleep(sleep_dur).await;
-
-            if self.check_and_purge(&key).await {
-                return Frame::Null;
-            }
+        if removed > 0 {
+            inner.touch(&key);
         }
+        remove_if_empty_list(&mut inner, &key);
+        Frame::Integer(removed as i64)
     }
 
-    // ------- HASH ------- //
+    async fn lpos(&self, key: String, element: Vec<u8>, opts: LPosOptions) -> Frame {
+        let empty = || match opts.count {
+            Some(_) => Frame::Array(vec![]),
+            None => Frame::Null,
+        };
+
+        if self.check_and_purge(&key).await {
+            return empty();
+        }
+
+        let inner = self.keyspace.read([&key]).await;
+
+        let list = match inner.get(&key) {
+            Some(Value::List(list)) => list,
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return empty(),
+        };
+
+        let items: Vec<&[u8]> = list.iter().collect();
+        let len = items.len();
+        let scan_limit = if opts.maxlen == 0 { len } else { opts.maxlen.min(len) };
+        let wanted = match opts.count {
+            Some(0) => usize::MAX,
+            Some(n) => n,
+            None => 1,
+        };
+        let mut skip = opts.rank.unsigned_abs() as usize - 1;
+
+        let indexes: Box<dyn Iterator<Item = usize>> = if opts.rank > 0 {
+            Box::new(0..scan_limit)
+        } else {
+            Box::new((len - scan_limit..len).rev())
+        };
+
+        let mut found = Vec::new();
+        for i in indexes {
+            if items[i] != element.as_slice() {
+                continue;
+            }
+            if skip > 0 {
+                skip -= 1;
+                continue;
+            }
+            found.push(i);
+            if found.len() >= wanted {
+                break;
+            }
+        }
+
+        match opts.count {
+            Some(_) => Frame::Array(found.into_iter().map(|i| Frame::Integer(i as i64)).collect()),
+            None => match found.first() {
+                Some(i) => Frame::Integer(*i as i64),
+                None => Frame::Null,
+            },
+        }
+    }
+
+    async fn lmove(&self, source: String, destination: String, from: ListEnd, to: ListEnd) -> Frame {
+        if self.check_and_purge(&source).await {
+            return Frame::Null;
+        }
+        self.check_and_purge(&destination).await;
+
+        let mut inner = self.keyspace.write([&source, &destination]).await;
+
+        match inner.get(&source) {
+            Some(Value::List(_)) => {}
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return Frame::Null,
+        }
+
+        if !matches!(inner.get(&destination), Some(Value::List(_)) | None) {
+            return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into());
+        }
+
+        let (reply, _) = move_element(&mut inner, &source, &destination, from, to);
+        self.wake_blocked(inner, &destination).await;
+        reply
+    }
+
+    async fn lmpop(&self, keys: Vec<String>, end: ListEnd, count: usize) -> Frame {
+        for key in &keys {
+            self.check_and_purge(key).await;
+        }
+
+        let mut inner = self.keyspace.write(&keys).await;
+        match self.try_block_op(&mut inner, &keys, &BlockOp::MPop(end, count)) {
+            Some((reply, _)) => reply,
+            None => Frame::Null,
+        }
+    }
+
+    /// Blocks until one of the requested keys holds data its op can be served
+    /// from, then performs the op on it.
+    async fn block_on_keys(&self, req: BlockRequest) -> Frame {
+        match self.block_start(&req, true).await {
+            Ok(reply) => reply,
+            Err(registration) => self.block_wait(&req.keys, registration, req.timeout, std::future::pending()).await,
+        }
+    }
+
+    /// Performs the requested op right away if one of its keys holds data
+    /// for it. Otherwise registers the client as blocked if `wait` is set,
+    /// and replies as though the timeout had passed if not, which is how
+    /// blocking commands behave inside a transaction.
+    pub async fn block_start(&self, req: &BlockRequest, wait: bool) -> Result<Frame, Registration> {
+        let BlockRequest { keys, op, .. } = req;
+        for key in keys {
+            self.check_and_purge(key).await;
+        }
+
+        let mut locked: Vec<&str> = keys.iter().map(String::as_str).collect();
+        if let BlockOp::Move(destination, _, _) = op {
+            locked.push(destination);
+        }
+        let mut inner = self.keyspace.write(locked).await;
+        if let Some((reply, moved_to)) = self.try_block_op(&mut inner, keys, op) {
+            if let Some(dest) = moved_to {
+                self.wake_blocked(inner, &dest).await;
+            }
+            return Ok(reply);
+        }
+        if !wait {
+            return Ok(Frame::Null);
+        }
+        Err(self.blocking.lock().unwrap().register(keys.clone(), op.clone()))
+    }
+
+    /// Waits until a client registered by [`Db::block_start`] is served, or
+    /// gives up once `closed` resolves because the client went away. A
+    /// timeout of zero waits forever.
+    pub async fn block_wait(
+        &self,
+        keys: &[String],
+        registration: Registration,
+        timeout: f64,
+        closed: impl Future<Output = ()>,
+    ) -> Frame {
+        let (id, mut rx) = registration;
+        let wait = async {
+            if timeout > 0.0 {
+                time::timeout(Duration::from_secs_f64(timeout), &mut rx).await.ok()
+            } else {
+                Some((&mut rx).await)
+            }
+        };
+        let served = tokio::select! {
+            served = wait => served,
+            () = closed => None,
+        };
+
+        if let Some(Ok(reply)) = served {
+            return reply;
+        }
+
+        // Deregister under our keys' shard locks so that no pusher can serve
+        // us concurrently, then pick up anything that was handed over meanwhile.
+        let _inner = self.keyspace.write(keys).await;
+        self.blocking.lock().unwrap().remove(id);
+        rx.try_recv().unwrap_or(Frame::Null)
+    }
+
+    /// Performs `op` on the first of `keys` holding data for it, if any,
+    /// returning the reply and the destination of a move. `inner` must also
+    /// cover the destination of a [`BlockOp::Move`].
+    fn try_block_op(&self, inner: &mut WriteGuard, keys: &[String], op: &BlockOp) -> Option<(Frame, Option<String>)> {
+        for key in keys {
+            let Some(value) = inner.get(key) else {
+                continue;
+            };
+            match ready_kind(value) {
+                Some(kind) if kind == op.kind() => {
+                    return Some(run_block_op(inner, key, op));
+                }
+                _ if block_kind(value) == Some(op.kind()) => {}
+                _ => {
+                    return Some((
+                        Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+                        None,
+                    ));
+                }
+            }
+        }
+        None
+    }
+
+    /// Wakes clients blocked on `key` after data was added to it. Serving one
+    /// may move data into keys on any other shard, so if anyone is waiting
+    /// this releases `inner` and serves with the whole keyspace locked.
+    pub async fn wake_blocked(&self, inner: WriteGuard<'_>, key: &str) {
+        if !self.blocking.lock().unwrap().has_waiters(key) {
+            return;
+        }
+        if let Some(deferred) = self.deferred.lock().unwrap().as_mut() {
+            deferred.push(key.to_string());
+            return;
+        }
+        drop(inner);
+
+        let mut all = self.keyspace.write_all().await;
+        self.serve_blocked(&mut all, key);
+    }
+
+    /// Serves clients blocked on any key, as needed after the whole keyspace
+    /// was replaced. Must be called with every shard write-locked.
+    pub fn serve_all_blocked(&self, inner: &mut WriteGuard) {
+        let keys = self.blocking.lock().unwrap().keys();
+        if let Some(deferred) = self.deferred.lock().unwrap().as_mut() {
+            deferred.extend(keys);
+            return;
+        }
+        for key in keys {
+            self.serve_blocked(inner, &key);
+        }
+    }
+
+    /// Holds back serving blocked clients until [`Db::serve_deferred`], so
+    /// that none of them runs in the middle of a transaction.
+    pub fn defer_wakeups(&self) {
+        *self.deferred.lock().unwrap() = Some(Vec::new());
+    }
+
+    /// Serves the clients held back since [`Db::defer_wakeups`].
+    pub async fn serve_deferred(&self) {
+        let Some(keys) = self.deferred.lock().unwrap().take() else {
+            return;
+        };
+        if keys.is_empty() {
+            return;
+        }
+        let mut all = self.keyspace.write_all().await;
+        for key in keys {
+            self.serve_blocked(&mut all, &key);
+        }
+    }
+
+    pub fn stop_deferring(&self) {
+        *self.deferred.lock().unwrap() = None;
+    }
+
+    /// Hands data at `key` to clients blocked on it, oldest first, for as long
+    /// as both remain. Must be called with every shard write-locked.
+    fn serve_blocked(&self, inner: &mut WriteGuard, key: &str) {
+        let mut blocking = self.blocking.lock().unwrap();
+        let mut ready = VecDeque::from([key.to_string()]);
+
+        while let Some(key) = ready.pop_front() {
+            while let Some(kind) = inner.get(&key).and_then(ready_kind) {
+                let Some(waiter) = blocking.take_next(&key, kind) else {
+                    break;
+                };
+                let (reply, moved_to) = run_block_op(inner, &key, &waiter.op);
+                if let Some(dest) = moved_to {
+                    ready.push_back(dest);
+                }
+                let _ = waiter.tx.send(reply);
+            }
+        }
+    }
+
+    // ------- HASH ------- //
+
+    async fn hset(&self, key: String, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Frame {
+        self.check_and_purge(&key).await;
+
+        let mut inner = self.keyspace.write([&key]).await;
+
+        let entry = inner.get_or_insert_with(key.clone(), || {
+            Value::Hash(HashState::new())
+        });
+
+        match entry {
+            Value::Hash(map) => {
+                let mut added = 0;
+                for (field, value) in pairs {
+                    if map.insert(field, value).is_none() {
+                        added += 1;
+                    }
+                }
+                inner.touch(&key);
+                Frame::Integer(added)
+            }
+            _ => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
+        }
+    }
 
-    async fn hset(&self, key: String, field: String, value: Vec<u8>) -> Frame {
+    async fn hmset(&self, key: String, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Frame {
+        match self.hset(key, pairs).await {
+            Frame::Integer(_) => Frame::Simple("OK".into()),
+            other => other,
+        }
+    }
+
+    async fn hsetnx(&self, key: String, field: Vec<u8>, value: Vec<u8>) -> Frame {
         self.check_and_purge(&key).await;
 
-        let mut inner = self.inner.write().await;
+        let mut inner = self.keyspace.write([&key]).await;
 
-        let entry = inner.entry(key).or_insert_with(|| {
-            Value::Hash(HashMap::new())
+        let entry = inner.get_or_insert_with(key.clone(), || {
+            Value::Hash(HashState::new())
         });
 
         match entry {
             Value::Hash(map) => {
-                let existed = map.insert(field, value).is_some();
-                Frame::Integer(if existed { 0 } else { 1 })
+                if map.contains_key(&field) {
+                    return Frame::Integer(0);
+                }
+                map.insert(field, value);
+                inner.touch(&key);
+                Frame::Integer(1)
             }
             _ => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())
         }
     }
 
-    async fn hget(&self, key: String, field: String) -> Frame {
+    async fn hget(&self, key: String, field: Vec<u8>) -> Frame {
         if self.check_and_purge(&key).await {
             return Frame::Null;
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::Hash(map)) => {
                 match map.get(&field) {
-                    Some(val) => Frame::Bulk(val.clone()),
+                    Some(val) => Frame::Bulk(val.to_vec()),
                     None => Frame::Null,
                 }
             }
@@ -746,14 +1444,14 @@ impl Db {
         }
     }
 
-    async fn hdel(&self, key: String, fields: Vec<String>) -> Frame {
+    async fn hdel(&self, key: String, fields: Vec<Vec<u8>>) -> Frame {
         if self.check_and_purge(&key).await {
             return Frame::Integer(0);
         }
 
-        let mut inner = self.inner.write().await;
+        let mut inner = self.keyspace.write([&key]).await;
 
-        match inner.get_mut(&key) {
+        let removed = match inner.get_mut(&key) {
             Some(Value::Hash(map)) => {
                 let mut removed = 0;
                 for f in fields {
@@ -761,11 +1459,17 @@ impl Db {
                         removed += 1;
                     }
                 }
-                Frame::Integer(removed)
+                removed
             }
-            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
-            None => Frame::Integer(0),
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return Frame::Integer(0),
+        };
+
+        if removed > 0 {
+            inner.touch(&key);
         }
+        remove_if_empty_hash(&mut inner, &key);
+        Frame::Integer(removed)
     }
 
     async fn hgetall(&self, key: String) -> Frame {
@@ -773,14 +1477,14 @@ impl Db {
             return Frame::Array(vec![]);
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::Hash(map)) => {
                 let mut arr = Vec::new();
-                for (k, v) in map {
-                    arr.push(Frame::Bulk(k.as_bytes().to_vec()));
-                    arr.push(Frame::Bulk(v.clone()));
+                for (k, v) in map.iter() {
+                    arr.push(Frame::Bulk(k.to_vec()));
+                    arr.push(Frame::Bulk(v.to_vec()));
                 }
                 Frame::Array(arr)
             }
@@ -789,19 +1493,19 @@ impl Db {
         }
     }
 
-    async fn hmget(&self, key: String, fields: Vec<String>) -> Frame {
+    async fn hmget(&self, key: String, fields: Vec<Vec<u8>>) -> Frame {
         if self.check_and_purge(&key).await {
             return Frame::Array(vec![Frame::Null; fields.len()])
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::Hash(map)) => {
                 let mut arr = Vec::new();
                 for f in fields {
                     match map.get(&f) {
-                        Some(v) => arr.push(Frame::Bulk(v.clone())),
+                        Some(v) => arr.push(Frame::Bulk(v.to_vec())),
                         None => arr.push(Frame::Null),
                     }
                 }
@@ -812,12 +1516,12 @@ impl Db {
         }
     }
 
-    async fn hexists(&self, key: String, field: String) -> Frame {
+    async fn hexists(&self, key: String, field: Vec<u8>) -> Frame {
         if self.check_and_purge(&key).await {
             return Frame::Integer(0);
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::Hash(map)) =>
@@ -832,7 +1536,7 @@ impl Db {
             return Frame::Integer(0);
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::Hash(map)) => Frame::Integer(map.len() as i64),
@@ -846,12 +1550,12 @@ impl Db {
             return Frame::Array(vec![]);
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::Hash(map)) => {
                 let arr = map.keys()
-                    .map(|k| Frame::Bulk(k.as_bytes().to_vec()))
+                    .map(|k| Frame::Bulk(k.to_vec()))
                     .collect();
                 Frame::Array(arr)
             }
@@ -865,12 +1569,12 @@ impl Db {
             return Frame::Array(vec![]);
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::Hash(map)) => {
                 let arr = map.values()
-                    .map(|v| Frame::Bulk(v.clone()))
+                    .map(|v| Frame::Bulk(v.to_vec()))
                     .collect();
                 Frame::Array(arr)
             }
@@ -879,303 +1583,787 @@ impl Db {
         }
     }
 
-    // ------- SET ------- //
-
-    async fn sadd(&self, key: String, members: Vec<Vec<u8>>) -> Frame {
-        self.check_and_purge(&key).await;
-
-        let mut inner = self.inner.write().await;
-
-        let entry = inner.entry(key).or_insert_with(|| Value::Set(HashSet::new()));
-
-        match entry {
-            Value::Set(set) => {
-                let mut added = 0;
-                for m in members {
-                    if set.insert(m) {
-                        added += 1;
-                    }
-                }
-                Frame::Integer(added)
-            }
-            _ => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
-        }
-    }
-
-    async fn srem(&self, key: String, members: Vec<Vec<u8>>) -> Frame {
+    async fn hstrlen(&self, key: String, field: Vec<u8>) -> Frame {
         if self.check_and_purge(&key).await {
             return Frame::Integer(0);
         }
 
-        let mut inner = self.inner.write().await;
+        let inner = self.keyspace.read([&key]).await;
 
-        match inner.get_mut(&key) {
-            Some(Value::Set(set)) => {
-                let mut removed = 0;
-                for m in members {
-                    if set.remove(&m) {
-                        removed += 1;
-                    }
-                }
-                Frame::Integer(removed)
+        match inner.get(&key) {
+            Some(Value::Hash(map)) => {
+                Frame::Integer(map.get(&field).map_or(0, |v| v.len()) as i64)
             }
             Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
             None => Frame::Integer(0),
         }
     }
 
-    async fn smembers(&self, key: String) -> Frame {
-        if self.check_and_purge(&key).await {
-            return Frame::Array(vec![]);
-        }
+    async fn hincrby(&self, key: String, field: Vec<u8>, amt: i64) -> Frame {
+        self.check_and_purge(&key).await;
 
-        let inner = self.inner.read().await;
+        let mut inner = self.keyspace.write([&key]).await;
 
-        match inner.get(&key) {
-            Some(Value::Set(set)) => {
-                let arr = set.iter()
-                    .map(|v| Frame::Bulk(v.clone()))
-                    .collect();
-                Frame::Array(arr)
-            }
-            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
-            None => Frame::Array(vec![]),
-        }
-    }
+        let entry = inner.get_or_insert_with(key.clone(), || {
+            Value::Hash(HashState::new())
+        });
 
-    async fn sismember(&self, key: String, member: Vec<u8>) -> Frame {
-        if self.check_and_purge(&key).await {
-            return Frame::Integer(0);
-        }
+        let Value::Hash(map) = entry else {
+            return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into());
+        };
 
-        let inner = self.inner.read().await;
+        let curr = match map.get(&field) {
+            Some(v) => match std::str::from_utf8(v).ok().and_then(|s| s.parse::<i64>().ok()) {
+                Some(n) => n,
+                None => return Frame::Error("ERR hash value is not an integer".into()),
+            },
+            None => 0,
+        };
 
-        match inner.get(&key) {
-            Some(Value::Set(set)) => 
-                Frame::Integer(if set.contains(&member) { 1 } else { 0 }),
-            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
-            None => Frame::Integer(0),
-        }
-    }
+        let Some(new_val) = curr.checked_add(amt) else {
+            return Frame::Error("ERR increment or decrement would overflow".into());
+        };
 
-    async fn scard(&self, key: String) -> Frame {
-        if self.check_and_purge(&key).await {
-            return Frame::Integer(0);
-        }
+        map.update(field, new_val.to_string().into_bytes());
+        inner.touch(&key);
+        Frame::Integer(new_val)
+    }
 
-        let inner = self.inner.read().await;
+    async fn hincrbyfloat(&self, key: String, field: Vec<u8>, amt: f64) -> Frame {
+        self.check_and_purge(&key).await;
 
-        match inner.get(&key) {
-            Some(Value::Set(set)) => Frame::Integer(set.len() as i64),
-            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
-            None => Frame::Integer(0),
-        }
-    }
+        let mut inner = self.keyspace.write([&key]).await;
 
-    async fn sunion(&self, keys: Vec<String>) -> Frame {
-        let inner = self.inner.read().await;
+        let entry = inner.get_or_insert_with(key.clone(), || {
+            Value::Hash(HashState::new())
+        });
 
-        let mut result = HashSet::new();
+        let Value::Hash(map) = entry else {
+            return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into());
+        };
 
-        for k in keys {
-            if self.is_expired(&k).await {
-                continue;
-            }
+        let curr = match map.get(&field) {
+            Some(v) => match std::str::from_utf8(v).ok().and_then(|s| s.parse::<f64>().ok()) {
+                Some(n) => n,
+                None => return Frame::Error("ERR hash value is not a float".into()),
+            },
+            None => 0.0,
+        };
 
-            if let Some(Value::Set(set)) = inner.get(&k) {
-                for v in set {
-                    result.insert(v.clone());
-                }
-            }
+        let new_val = curr + amt;
+        if !new_val.is_finite() {
+            return Frame::Error("ERR increment would produce NaN or Infinity".into());
         }
 
-        let arr = result.into_iter().map(Frame::Bulk).collect();
-        Frame::Array(arr)
+        let encoded = new_val.to_string().into_bytes();
+        map.update(field.clone(), encoded.clone());
+        let expire_at = map.expire_at(&field);
+        inner.touch(&key);
+        propagate(command_frame([b"HSET".to_vec(), key.clone().into_bytes(), field.clone(), encoded.clone()]));
+        // The HSET above clears the field's deadline on replay, so restore it.
+        if let Some(at) = expire_at {
+            propagate(hpexpireat_frame(&key, at, vec![field]));
+        }
+        Frame::Bulk(encoded)
     }
 
-    async fn sinter(&self, keys: Vec<String>) -> Frame {
-        let inner = self.inner.read().await;
-
-        if keys.is_empty() {
-            return Frame::Array(vec![]);
+    async fn hrandfield(&self, key: String, count: Option<i64>, with_values: bool) -> Frame {
+        if let Some(n) = count.filter(|n| *n < 0) {
+            return match self.hash_picks(&key, n.unsigned_abs(), with_values).await {
+                Ok(picks) => picks.into_frame(),
+                Err(reply) => reply,
+            };
         }
 
-        let mut iter = keys.iter();
+        if self.check_and_purge(&key).await {
+            return match count {
+                Some(_) => Frame::Array(vec![]),
+                None => Frame::Null,
+            };
+        }
 
-        let mut base: Option<HashSet<Vec<u8>>> = None;
+        let inner = self.keyspace.read([&key]).await;
 
-        for k in &keys {
-            if self.is_expired(&k).await {
-                continue;
+        let map = match inner.get(&key) {
+            Some(Value::Hash(map)) => map,
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => {
+                return match count {
+                    Some(_) => Frame::Array(vec![]),
+                    None => Frame::Null,
+                };
             }
+        };
 
-            if let Some(Value::Set(set)) = inner.get(k) {
-                base = Some(set.clone());
-                break;
-            }
-        }
+        let mut rng = rand::thread_rng();
 
-        let Some(mut acc) = base else {
-            return Frame::Array(vec![]);
+        let Some(count) = count else {
+            let idx = rng.gen_range(0..map.len());
+            return match map.keys().nth(idx) {
+                Some(field) => Frame::Bulk(field.to_vec()),
+                None => Frame::Null,
+            };
         };
 
-        for k in &keys {
-            if let Some(Value::Set(set)) = inner.get(k) {
-                acc = acc.intersection(set).cloned().collect();
+        // Distinct fields, at most the whole hash.
+        let entries: Vec<(&[u8], &[u8])> = map.iter().collect();
+        let n = (count as usize).min(entries.len());
+        let mut arr = Vec::with_capacity(n * if with_values { 2 } else { 1 });
+        for i in rand::seq::index::sample(&mut rng, entries.len(), n) {
+            let (field, value) = entries[i];
+            arr.push(Frame::Bulk(field.to_vec()));
+            if with_values {
+                arr.push(Frame::Bulk(value.to_vec()));
             }
         }
-
-        let arr = acc.into_iter().map(Frame::Bulk).collect();
         Frame::Array(arr)
     }
 
-    async fn sdiff(&self, keys: Vec<String>) -> Frame {
-        let inner = self.inner.read().await;
-
-        if keys.is_empty() {
-            return Frame::Array(vec![]);
+    /// The fields HRANDFIELD with a negative count picks `count` of, allowing
+    /// the same field more than once. Fails with the whole reply if there is
+    /// nothing to pick.
+    async fn hash_picks(&self, key: &str, count: u64, with_values: bool) -> Result<RandomPicks, Frame> {
+        if self.check_and_purge(key).await {
+            return Err(Frame::Array(vec![]));
         }
 
-        let first = &keys[0];
+        let inner = self.keyspace.read([key]).await;
+        let entries: Vec<Vec<Frame>> = match inner.get(key) {
+            Some(Value::Hash(map)) => map
+                .iter()
+                .map(|(field, value)| {
+                    let mut entry = vec![Frame::Bulk(field.to_vec())];
+                    if with_values {
+                        entry.push(Frame::Bulk(value.to_vec()));
+                    }
+                    entry
+                })
+                .collect(),
+            Some(_) => return Err(Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())),
+            None => return Err(Frame::Array(vec![])),
+        };
+        if entries.is_empty() {
+            return Err(Frame::Array(vec![]));
+        }
+        Ok(RandomPicks { entries, count })
+    }
 
-        if self.is_expired(first).await {
-            return Frame::Array(vec![]);
+    /// Gathers what `cmd` picks from when [`Command::picks_with_repetition`],
+    /// for its reply to be written as it is picked. Fails with the whole
+    /// reply instead when that is known right away, as when there is
+    /// nothing to pick.
+    pub async fn random_picks(&self, cmd: &Command) -> Result<RandomPicks, Frame> {
+        match cmd {
+            Command::HRandField(key, Some(n), with_values) if *n < 0 => {
+                self.hash_picks(key, n.unsigned_abs(), *with_values).await
+            }
+            Command::SRandMember(key, Some(n)) if *n < 0 => self.set_picks(key, n.unsigned_abs()).await,
+            Command::ZRandMember(key, Some(n), with_scores) if *n < 0 => {
+                self.zset_picks(key, n.unsigned_abs(), *with_scores).await
+            }
+            _ => Err(Frame::Error("ERR command does not pick with repetition".into())),
         }
+    }
 
-        let base = match inner.get(first) {
-            Some(Value::Set(set)) => set.clone(),
-            _ => HashSet::new(),
+    async fn hexpire(
+        &self,
+        key: String,
+        when: FieldExpiry,
+        cond: Option<ExpireCondition>,
+        fields: Vec<Vec<u8>>,
+    ) -> Frame {
+        let now = now_ms();
+        // Negative times are refused as given, before a relative one could
+        // turn into a deadline in the past.
+        let at = match when {
+            FieldExpiry::Seconds(secs) if secs >= 0 => secs.checked_mul(1000).and_then(|ms| ms.checked_add(now as i64)),
+            FieldExpiry::Millis(ms) if ms >= 0 => ms.checked_add(now as i64),
+            FieldExpiry::AtSeconds(secs) if secs >= 0 => secs.checked_mul(1000),
+            FieldExpiry::AtMillis(ms) if ms >= 0 => Some(ms),
+            _ => None,
+        };
+        let at = match at {
+            Some(at) if at <= FIELD_EXPIRE_MAX_MS => at as u64,
+            _ => return Frame::Error(format!("ERR invalid expire time in '{}' command", when.command_name())),
         };
 
-        let mut result = base;
+        if self.check_and_purge(&key).await {
+            return Frame::Array(vec![Frame::Integer(-2); fields.len()]);
+        }
 
-        for k in keys.iter().skip(1) {
-            if let Some(Value::Set(set)) = inner.get(k) {
-                for m in set {
-                    result.remove(m);
-                }
+        let mut inner = self.keyspace.write([&key]).await;
+
+        let map = match inner.get_mut(&key) {
+            Some(Value::Hash(map)) => map,
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return Frame::Array(vec![Frame::Integer(-2); fields.len()]),
+        };
+
+        let mut replies = Vec::with_capacity(fields.len());
+        let mut changed = Vec::new();
+        for field in fields {
+            if !map.contains_key(&field) {
+                replies.push(Frame::Integer(-2));
+                continue;
+            }
+
+            let current = map.expire_at(&field);
+            // A field without a deadline counts as living forever for GT/LT.
+            let allowed = match cond {
+                None => true,
+                Some(ExpireCondition::Nx) => current.is_none(),
+                Some(ExpireCondition::Xx) => current.is_some(),
+                Some(ExpireCondition::Gt) => current.is_some_and(|c| at > c),
+                Some(ExpireCondition::Lt) => current.is_none_or(|c| at < c),
+            };
+            if !allowed {
+                replies.push(Frame::Integer(0));
+                continue;
+            }
+
+            if at <= now {
+                map.remove(&field);
+                replies.push(Frame::Integer(2));
+            } else {
+                map.set_expire_at(&field, at);
+                replies.push(Frame::Integer(1));
             }
+            changed.push(field);
         }
 
-        let arr = result.into_iter().map(Frame::Bulk).collect();
-        Frame::Array(arr)
+        if !changed.is_empty() {
+            inner.touch(&key);
+            propagate(hpexpireat_frame(&key, at, changed));
+        }
+        remove_if_empty_hash(&mut inner, &key);
+        Frame::Array(replies)
     }
 
-    // ------- SORTED SET ------- //
+    /// Shared body of HTTL, HPTTL, HEXPIRETIME and HPEXPIRETIME; `reply` maps
+    /// a field's deadline to the integer sent back.
+    async fn hfield_ttl(&self, key: String, fields: Vec<Vec<u8>>, reply: fn(u64) -> i64) -> Frame {
+        if self.check_and_purge(&key).await {
+            return Frame::Array(vec![Frame::Integer(-2); fields.len()]);
+        }
+
+        let inner = self.keyspace.read([&key]).await;
+
+        match inner.get(&key) {
+            Some(Value::Hash(map)) => {
+                let arr = fields.iter()
+                    .map(|f| match (map.contains_key(f), map.expire_at(f)) {
+                        (false, _) => Frame::Integer(-2),
+                        (true, None) => Frame::Integer(-1),
+                        (true, Some(at)) => Frame::Integer(reply(at)),
+                    })
+                    .collect();
+                Frame::Array(arr)
+            }
+            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => Frame::Array(vec![Frame::Integer(-2); fields.len()]),
+        }
+    }
+
+    async fn hpersist(&self, key: String, fields: Vec<Vec<u8>>) -> Frame {
+        if self.check_and_purge(&key).await {
+            return Frame::Array(vec![Frame::Integer(-2); fields.len()]);
+        }
+
+        let mut inner = self.keyspace.write([&key]).await;
 
-    async fn zadd(&self, key: String, score: f64, member: Vec<u8>) -> Frame {
+        match inner.get_mut(&key) {
+            Some(Value::Hash(map)) => {
+                let arr: Vec<Frame> = fields.iter()
+                    .map(|f| {
+                        if !map.contains_key(f) {
+                            Frame::Integer(-2)
+                        } else if map.persist(f) {
+                            Frame::Integer(1)
+                        } else {
+                            Frame::Integer(-1)
+                        }
+                    })
+                    .collect();
+                if arr.iter().any(|r| matches!(r, Frame::Integer(1))) {
+                    inner.touch(&key);
+                }
+                Frame::Array(arr)
+            }
+            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => Frame::Array(vec![Frame::Integer(-2); fields.len()]),
+        }
+    }
+
+    // ------- SET ------- //
+
+    async fn sadd(&self, key: String, members: Vec<Vec<u8>>) -> Frame {
         self.check_and_purge(&key).await;
-        let mut inner = self.get_inner_mut().await;
-        let entry = inner
-            .entry(key)
-            .or_insert_with(|| Value::ZSet(SkipList::new()));
+
+        let mut inner = self.keyspace.write([&key]).await;
+
+        let entry = inner.get_or_insert_with(key.clone(), || Value::Set(SetState::new()));
 
         match entry {
-            Value::ZSet(zset) => {
-                zset.insert(score, member);
-                Frame::Integer(1)
+            Value::Set(set) => {
+                let mut added = 0;
+                for m in members {
+                    if set.insert(m) {
+                        added += 1;
+                    }
+                }
+                if added > 0 {
+                    inner.touch(&key);
+                }
+                Frame::Integer(added)
             }
-            _ => Frame::Error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value".into(),
-            ),
+            _ => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
         }
     }
-    
-    async fn zrem(&self, key: String, member: Vec<u8>) -> Frame {
-        self.check_and_purge(&key).await;
-        let mut inner = self.get_inner_mut().await;
-        let Some(value) = inner.get_mut(&key) else {
+
+    async fn srem(&self, key: String, members: Vec<Vec<u8>>) -> Frame {
+        if self.check_and_purge(&key).await {
             return Frame::Integer(0);
-        };
+        }
 
-        match value {
-            Value::ZSet(zset) => {
-                let removed = zset.remove_member(&member);
-                Frame::Integer(if removed { 1 } else { 0 })
+        let mut inner = self.keyspace.write([&key]).await;
+
+        match inner.get_mut(&key) {
+            Some(Value::Set(set)) => {
+                let mut removed = 0;
+                for m in members {
+                    if set.remove(&m) {
+                        removed += 1;
+                    }
+                }
+                if removed > 0 {
+                    inner.touch(&key);
+                }
+                remove_if_empty_set(&mut inner, &key);
+                Frame::Integer(removed)
             }
-            _ => Frame::Error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value".into(),
-            ),
+            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => Frame::Integer(0),
         }
     }
 
-    async fn zrange(&self, key: String, start: i64, end: i64) -> Frame {
+    async fn smembers(&self, key: String) -> Frame {
         if self.check_and_purge(&key).await {
             return Frame::Array(vec![]);
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
-            Some(Value::ZSet(zset)) => {
-                let len = zset.len() as i64;
-                if len == 0 {
-                    return Frame::Array(vec![]);
+            Some(Value::Set(set)) => {
+                let arr = set.iter()
+                    .map(|v| Frame::Bulk(v.into_owned()))
+                    .collect();
+                Frame::Array(arr)
+            }
+            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => Frame::Array(vec![]),
+        }
+    }
+
+    async fn sismember(&self, key: String, member: Vec<u8>) -> Frame {
+        if self.check_and_purge(&key).await {
+            return Frame::Integer(0);
+        }
+
+        let inner = self.keyspace.read([&key]).await;
+
+        match inner.get(&key) {
+            Some(Value::Set(set)) => 
+                Frame::Integer(if set.contains(&member) { 1 } else { 0 }),
+            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => Frame::Integer(0),
+        }
+    }
+
+    async fn scard(&self, key: String) -> Frame {
+        if self.check_and_purge(&key).await {
+            return Frame::Integer(0);
+        }
+
+        let inner = self.keyspace.read([&key]).await;
+
+        match inner.get(&key) {
+            Some(Value::Set(set)) => Frame::Integer(set.len() as i64),
+            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => Frame::Integer(0),
+        }
+    }
+
+    async fn set_algebra(&self, op: SetOp, keys: Vec<String>) -> Frame {
+        let inner = self.keyspace.read(&keys).await;
+
+        match read_sets(&inner, &keys) {
+            Ok(sets) => {
+                let arr = combine_sets(op, &sets).into_iter().map(Frame::Bulk).collect();
+                Frame::Array(arr)
+            }
+            Err(e) => e,
+        }
+    }
+
+    async fn set_algebra_store(&self, op: SetOp, dest: String, keys: Vec<String>) -> Frame {
+        let mut inner = self.keyspace.write(keys.iter().chain([&dest])).await;
+
+        let result = match read_sets(&inner, &keys) {
+            Ok(sets) => combine_sets(op, &sets),
+            Err(e) => return e,
+        };
+
+        let card = result.len() as i64;
+        if result.is_empty() {
+            inner.remove(&dest);
+        } else {
+            // The destination is replaced outright, including any TTL it had.
+            inner.insert(dest.clone(), Value::Set(result.into_iter().collect()));
+        }
+
+        Frame::Integer(card)
+    }
+
+    async fn sintercard(&self, keys: Vec<String>, limit: usize) -> Frame {
+        let inner = self.keyspace.read(&keys).await;
+
+        let sets = match read_sets(&inner, &keys) {
+            Ok(sets) => sets,
+            Err(e) => return e,
+        };
+
+        let Some(mut sets) = sets.into_iter().collect::<Option<Vec<_>>>() else {
+            return Frame::Integer(0);
+        };
+        sets.sort_by_key(|s| s.len());
+
+        let mut card = 0;
+        for m in sets[0].iter() {
+            if sets[1..].iter().all(|s| s.contains(&m)) {
+                card += 1;
+                if card == limit {
+                    break;
                 }
+            }
+        }
+        Frame::Integer(card as i64)
+    }
 
-                let mut s = if start < 0 { len + start } else { start };
-                let mut e = if end < 0 { len + end } else { end };
+    async fn spop(&self, key: String, count: Option<usize>) -> Frame {
+        let empty = || match count {
+            Some(_) => Frame::Array(vec![]),
+            None => Frame::Null,
+        };
 
-                if s < 0 { s = 0; }
-                if e < 0 { e = 0; }
-                if e >= len { e = len - 1; }
+        if self.check_and_purge(&key).await {
+            return empty();
+        }
 
-                if s > e || s >= len {
-                    return Frame::Array(vec![]);
+        let mut inner = self.keyspace.write([&key]).await;
+
+        let set = match inner.get_mut(&key) {
+            Some(Value::Set(set)) => set,
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return empty(),
+        };
+
+        let picked = set.pop_random(count.unwrap_or(1));
+
+        if !picked.is_empty() {
+            inner.touch(&key);
+            let mut args = vec![b"SREM".to_vec(), key.clone().into_bytes()];
+            args.extend(picked.iter().cloned());
+            propagate(command_frame(args));
+        }
+        remove_if_empty_set(&mut inner, &key);
+
+        match count {
+            Some(_) => Frame::Array(picked.into_iter().map(Frame::Bulk).collect()),
+            None => picked.into_iter().next().map_or(Frame::Null, Frame::Bulk),
+        }
+    }
+
+    async fn srandmember(&self, key: String, count: Option<i64>) -> Frame {
+        if let Some(n) = count.filter(|n| *n < 0) {
+            return match self.set_picks(&key, n.unsigned_abs()).await {
+                Ok(picks) => picks.into_frame(),
+                Err(reply) => reply,
+            };
+        }
+
+        let empty = || match count {
+            Some(_) => Frame::Array(vec![]),
+            None => Frame::Null,
+        };
+
+        if self.check_and_purge(&key).await {
+            return empty();
+        }
+
+        let inner = self.keyspace.read([&key]).await;
+
+        let set = match inner.get(&key) {
+            Some(Value::Set(set)) => set,
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return empty(),
+        };
+
+        match count {
+            Some(count) => Frame::Array(
+                set.random_members(count as usize)
+                    .into_iter()
+                    .map(|m| Frame::Bulk(m.into_owned()))
+                    .collect(),
+            ),
+            None => set.random_member().map_or(Frame::Null, |m| Frame::Bulk(m.into_owned())),
+        }
+    }
+
+    /// The members SRANDMEMBER with a negative count picks `count` of,
+    /// allowing the same member more than once. No more picks than members
+    /// are drawn right away, returning the whole reply.
+    async fn set_picks(&self, key: &str, count: u64) -> Result<RandomPicks, Frame> {
+        if self.check_and_purge(key).await {
+            return Err(Frame::Array(vec![]));
+        }
+
+        let inner = self.keyspace.read([key]).await;
+        let set = match inner.get(key) {
+            Some(Value::Set(set)) => set,
+            Some(_) => return Err(Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())),
+            None => return Err(Frame::Array(vec![])),
+        };
+        if count <= set.len() as u64 {
+            let picked = (0..count).filter_map(|_| set.random_member()).map(|m| Frame::Bulk(m.into_owned()));
+            return Err(Frame::Array(picked.collect()));
+        }
+        let entries: Vec<Vec<Frame>> = set.iter().map(|m| vec![Frame::Bulk(m.into_owned())]).collect();
+        if entries.is_empty() {
+            return Err(Frame::Array(vec![]));
+        }
+        Ok(RandomPicks { entries, count })
+    }
+
+    async fn smove(&self, src: String, dst: String, member: Vec<u8>) -> Frame {
+        if self.check_and_purge(&src).await {
+            return Frame::Integer(0);
+        }
+        self.check_and_purge(&dst).await;
+
+        let mut inner = self.keyspace.write([&src, &dst]).await;
+
+        match inner.get(&dst) {
+            Some(Value::Set(_)) | None => {}
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+        }
+
+        let removed = match inner.get_mut(&src) {
+            Some(Value::Set(set)) => {
+                if src == dst {
+                    return Frame::Integer(set.contains(&member) as i64);
                 }
+                set.remove(&member)
+            }
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return Frame::Integer(0),
+        };
 
-                let vals = zset.range_by_rank(s, e);
-                Frame::Array(vals.into_iter().map(Frame::Bulk).collect())
+        if !removed {
+            return Frame::Integer(0);
+        }
+        inner.touch(&src);
+        remove_if_empty_set(&mut inner, &src);
+
+        if let Value::Set(set) = inner.get_or_insert_with(dst.clone(), || Value::Set(SetState::new())) {
+            if set.insert(member) {
+                inner.touch(&dst);
             }
-            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
-            None => Frame::Array(vec![]),
         }
+        Frame::Integer(1)
     }
 
-    async fn zrevrange(&self, key: String, start: i64, end: i64) -> Frame {
+    async fn smismember(&self, key: String, members: Vec<Vec<u8>>) -> Frame {
         if self.check_and_purge(&key).await {
-            return Frame::Array(vec![]);
+            return Frame::Array(vec![Frame::Integer(0); members.len()]);
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
-            Some(Value::ZSet(zset)) => {
-                let len = zset.len() as i64;
-                if len == 0 {
-                    return Frame::Array(vec![]);
-                }
+            Some(Value::Set(set)) => {
+                let arr = members.iter()
+                    .map(|m| Frame::Integer(set.contains(m) as i64))
+                    .collect();
+                Frame::Array(arr)
+            }
+            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => Frame::Array(vec![Frame::Integer(0); members.len()]),
+        }
+    }
 
-                let mut s = if start < 0 { len + start } else { start };
-                let mut e = if end < 0 { len + end } else { end };
+    // ------- SORTED SET ------- //
 
-                if s < 0 { s = 0; }
-                if e < 0 { e = 0; }
-                if e >= len { e = len - 1; }
+    async fn zadd(&self, key: String, opts: ZAddOptions, pairs: Vec<(f64, Vec<u8>)>) -> Frame {
+        self.check_and_purge(&key).await;
+        let mut inner = self.keyspace.write([&key]).await;
+        let entry = inner
+            .get_or_insert_with(key.clone(), || Value::ZSet(ZSetState::new()));
 
-                if s > e || s >= len {
-                    return Frame::Array(vec![]);
+        let Value::ZSet(zset) = entry else {
+            return Frame::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".into(),
+            );
+        };
+
+        let mut added = 0;
+        let mut changed = 0;
+        // With INCR, the score the single member ended up with, if it was touched.
+        let mut incr_result = None;
+
+        for (score, member) in pairs {
+            match zset.get_score(&member) {
+                Some(current) => {
+                    if opts.nx {
+                        continue;
+                    }
+                    let new_score = if opts.incr { current + score } else { score };
+                    if new_score.is_nan() {
+                        remove_if_empty_zset(&mut inner, &key);
+                        return Frame::Error("ERR resulting score is not a number (NaN)".into());
+                    }
+                    if (opts.gt && new_score <= current) || (opts.lt && new_score >= current) {
+                        continue;
+                    }
+                    if new_score != current {
+                        zset.insert(new_score, member);
+                        changed += 1;
+                    }
+                    incr_result = Some(new_score);
+                }
+                None => {
+                    if opts.xx {
+                        continue;
+                    }
+                    zset.insert(score, member);
+                    added += 1;
+                    incr_result = Some(score);
                 }
+            }
+        }
+
+        if added + changed > 0 {
+            inner.touch(&key);
+        }
+        remove_if_empty_zset(&mut inner, &key);
+        if added > 0 {
+            self.wake_blocked(inner, &key).await;
+        }
+
+        if opts.incr {
+            return incr_result.map_or(Frame::Null, score_frame);
+        }
+        Frame::Integer(if opts.ch { added + changed } else { added })
+    }
+
+    async fn zmscore(&self, key: String, members: Vec<Vec<u8>>) -> Frame {
+        if self.check_and_purge(&key).await {
+            return Frame::Array(vec![Frame::Null; members.len()]);
+        }
+
+        let inner = self.keyspace.read([&key]).await;
+
+        match inner.get(&key) {
+            Some(Value::ZSet(zset)) => {
+                let arr = members.iter()
+                    .map(|m| zset.get_score(m).map_or(Frame::Null, score_frame))
+                    .collect();
+                Frame::Array(arr)
+            }
+            Some(_) => Frame::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".into(),
+            ),
+            None => Frame::Array(vec![Frame::Null; members.len()]),
+        }
+    }
+    
+    async fn zrem(&self, key: String, members: Vec<Vec<u8>>) -> Frame {
+        self.check_and_purge(&key).await;
+        let mut inner = self.keyspace.write([&key]).await;
+        let Some(value) = inner.get_mut(&key) else {
+            return Frame::Integer(0);
+        };
+
+        let removed = match value {
+            Value::ZSet(zset) => members.iter().filter(|m| zset.remove_member(m)).count(),
+            _ => return Frame::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".into(),
+            ),
+        };
+
+        if removed > 0 {
+            inner.touch(&key);
+        }
+        remove_if_empty_zset(&mut inner, &key);
+        Frame::Integer(removed as i64)
+    }
 
-                let real_start = len - 1 - s;
-                let real_end   = len - 1 - e;
+    async fn zrange(&self, key: String, opts: ZRangeOptions) -> Frame {
+        if self.check_and_purge(&key).await {
+            return Frame::Array(vec![]);
+        }
 
-                let vals = zset.range_by_rank(real_end, real_start);
-                let rev = vals.into_iter().rev().collect::<Vec<_>>();
+        let inner = self.keyspace.read([&key]).await;
 
-                Frame::Array(rev.into_iter().map(Frame::Bulk).collect())
+        match inner.get(&key) {
+            Some(Value::ZSet(zset)) => {
+                let entries = zrange_entries(zset, &opts);
+                let mut arr = Vec::with_capacity(entries.len() * if opts.with_scores { 2 } else { 1 });
+                for (member, score) in entries {
+                    arr.push(Frame::Bulk(member));
+                    if opts.with_scores {
+                        arr.push(score_frame(score));
+                    }
+                }
+                Frame::Array(arr)
             }
             Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
             None => Frame::Array(vec![]),
         }
     }
 
+    async fn zrangestore(&self, dst: String, src: String, opts: ZRangeOptions) -> Frame {
+        self.check_and_purge(&src).await;
+
+        let mut inner = self.keyspace.write([&src, &dst]).await;
+
+        let entries = match inner.get(&src) {
+            Some(Value::ZSet(zset)) => zrange_entries(zset, &opts),
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => vec![],
+        };
+
+        let count = entries.len() as i64;
+        if entries.is_empty() {
+            inner.remove(&dst);
+        } else {
+            let mut zset = ZSetState::new();
+            for (member, score) in entries {
+                zset.insert(score, member);
+            }
+            inner.insert(dst.clone(), Value::ZSet(zset));
+            self.wake_blocked(inner, &dst).await;
+        }
+
+        Frame::Integer(count)
+    }
+
     async fn zcard(&self, key: String) -> Frame {
         if self.check_and_purge(&key).await {
             return Frame::Integer(0);
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::ZSet(zset)) => Frame::Integer(zset.len() as i64),   
@@ -1189,7 +2377,7 @@ impl Db {
             return Frame::Null;
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::ZSet(zset)) => {
@@ -1202,100 +2390,622 @@ impl Db {
             None => Frame::Null,
         }
     }
- 
-    async fn zrange_by_score(&self, key: String, min: f64, max: f64) -> Frame {
-        self.check_and_purge(&key).await;
-        let inner = self.get_inner().await;
-        let Some(value) = inner.get(&key) else {
-            return Frame::Array(vec![]);
-        };
 
-        match value {
-            Value::ZSet(zset) => {
-                let members = zset.range_by_score(min, max);
-                let frames = members
-                    .into_iter()
-                    .map(Frame::Bulk)
-                    .collect::<Vec<_>>();
-                Frame::Array(frames)
-            }
-            _ => Frame::Error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value".into(),
-            ),
-        }
-    }
-
-    async fn zremrangebyscore(&self, key: String, min: f64, max: f64) -> Frame {
+    /// Shared body of the ZREMRANGEBY* commands; `remove` returns how many
+    /// members it dropped.
+    async fn zremrange(&self, key: String, remove: impl FnOnce(&mut ZSetState) -> usize) -> Frame {
         if self.check_and_purge(&key).await {
             return Frame::Integer(0);
         }
 
-        let mut inner = self.inner.write().await;
+        let mut inner = self.keyspace.write([&key]).await;
 
-        match inner.get_mut(&key) {
-            Some(Value::ZSet(zset)) => {
-                let removed = zset.remove_range_by_score(min, max);
-                Frame::Integer(removed as i64)
-            }
-            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
-            None => Frame::Integer(0),
+        let removed = match inner.get_mut(&key) {
+            Some(Value::ZSet(zset)) => remove(zset),
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return Frame::Integer(0),
+        };
+
+        if removed > 0 {
+            inner.touch(&key);
         }
+        remove_if_empty_zset(&mut inner, &key);
+        Frame::Integer(removed as i64)
     }
 
-    async fn zrank(&self, key: String, member: Vec<u8>) -> Frame {
+    async fn zrank(&self, key: String, member: Vec<u8>, rev: bool, with_score: bool) -> Frame {
         if self.check_and_purge(&key).await {
             return Frame::Null;
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
             Some(Value::ZSet(zset)) => {
-                match zset.rank(&member) {
-                    Some(r) => Frame::Integer(r as i64),
-                    None => Frame::Null,
+                let Some(rank) = zset.rank(&member) else {
+                    return Frame::Null;
+                };
+                let rank = if rev { zset.len() - 1 - rank } else { rank };
+                if !with_score {
+                    return Frame::Integer(rank as i64);
                 }
+                let score = zset.get_score(&member).unwrap_or_default();
+                Frame::Array(vec![Frame::Integer(rank as i64), score_frame(score)])
             }
             Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
             None => Frame::Null,
         }
     }
 
-    async fn zrevrank(&self, key: String, member: Vec<u8>) -> Frame {
+    async fn zrandmember(&self, key: String, count: Option<i64>, with_scores: bool) -> Frame {
+        if let Some(n) = count.filter(|n| *n < 0) {
+            return match self.zset_picks(&key, n.unsigned_abs(), with_scores).await {
+                Ok(picks) => picks.into_frame(),
+                Err(reply) => reply,
+            };
+        }
+
+        let empty = || match count {
+            Some(_) => Frame::Array(vec![]),
+            None => Frame::Null,
+        };
+
         if self.check_and_purge(&key).await {
-            return Frame::Null;
+            return empty();
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
-        match inner.get(&key) {
-            Some(Value::ZSet(zset)) => {
-                if let Some(r) = zset.rank(&member) {
-                    let rev = zset.len() - 1 - r;
-                    Frame::Integer(rev as i64)
-                } else {
-                    Frame::Null
-                }
+        let zset = match inner.get(&key) {
+            Some(Value::ZSet(zset)) => zset,
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return empty(),
+        };
+
+        let mut rng = rand::thread_rng();
+        let len = zset.len();
+
+        let Some(count) = count else {
+            return match zset.get_by_rank(rng.gen_range(0..len)) {
+                Some((member, _)) => Frame::Bulk(member),
+                None => Frame::Null,
+            };
+        };
+
+        // Distinct members, at most the whole set.
+        let ranks = rand::seq::index::sample(&mut rng, len, (count as usize).min(len));
+        let mut arr = Vec::with_capacity(ranks.len() * if with_scores { 2 } else { 1 });
+        for (member, score) in ranks.into_iter().filter_map(|r| zset.get_by_rank(r)) {
+            arr.push(Frame::Bulk(member));
+            if with_scores {
+                arr.push(score_frame(score));
             }
-            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+        }
+        Frame::Array(arr)
+    }
+
+    /// The members ZRANDMEMBER with a negative count picks `count` of,
+    /// allowing the same member more than once. No more picks than members
+    /// are drawn right away, returning the whole reply.
+    async fn zset_picks(&self, key: &str, count: u64, with_scores: bool) -> Result<RandomPicks, Frame> {
+        if self.check_and_purge(key).await {
+            return Err(Frame::Array(vec![]));
+        }
+
+        let inner = self.keyspace.read([key]).await;
+        let zset = match inner.get(key) {
+            Some(Value::ZSet(zset)) => zset,
+            Some(_) => return Err(Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())),
+            None => return Err(Frame::Array(vec![])),
+        };
+        let entry = |(member, score): (Vec<u8>, f64)| {
+            let mut entry = vec![Frame::Bulk(member)];
+            if with_scores {
+                entry.push(score_frame(score));
+            }
+            entry
+        };
+
+        let len = zset.len();
+        if len == 0 {
+            return Err(Frame::Array(vec![]));
+        }
+        if count <= len as u64 {
+            let mut rng = rand::thread_rng();
+            let picked = (0..count).filter_map(|_| zset.get_by_rank(rng.gen_range(0..len))).flat_map(entry);
+            return Err(Frame::Array(picked.collect()));
+        }
+        let entries = zset.entries().into_iter().map(entry).collect();
+        Ok(RandomPicks { entries, count })
+    }
+
+    async fn zmpop(&self, keys: Vec<String>, end: ZSetEnd, count: usize) -> Frame {
+        for key in &keys {
+            self.check_and_purge(key).await;
+        }
+
+        let mut inner = self.keyspace.write(&keys).await;
+        match self.try_block_op(&mut inner, &keys, &BlockOp::ZMPop(end, count)) {
+            Some((reply, _)) => reply,
             None => Frame::Null,
         }
     }
 
-    async fn zcount(&self, key: String, min: f64, max: f64) -> Frame {
+    async fn zcount(&self, key: String, count: impl FnOnce(&ZSetState) -> usize) -> Frame {
         if self.check_and_purge(&key).await {
             return Frame::Integer(0);
         }
 
-        let inner = self.inner.read().await;
+        let inner = self.keyspace.read([&key]).await;
 
         match inner.get(&key) {
-            Some(Value::ZSet(zset)) => {
-                let items = zset.range_by_score(min, max);
-                Frame::Integer(items.len() as i64)
+            Some(Value::ZSet(zset)) => Frame::Integer(count(zset) as i64),
+            Some(_) => Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => Frame::Integer(0),
+        }
+    }
+
+    async fn zalgebra(&self, op: SetOp, keys: Vec<String>, opts: ZAlgebraOptions) -> Frame {
+        let inner = self.keyspace.read(&keys).await;
+
+        let inputs = match read_zalgebra_inputs(&inner, &keys) {
+            Ok(inputs) => inputs,
+            Err(e) => return e,
+        };
+
+        let entries = combine_zsets(op, inputs, &opts);
+        let mut arr = Vec::with_capacity(entries.len() * if opts.with_scores { 2 } else { 1 });
+        for (member, score) in entries {
+            arr.push(Frame::Bulk(member));
+            if opts.with_scores {
+                arr.push(score_frame(score));
             }
-            Some(_) => Frame::Error("WRONGTYPE Operation against a keyholding the wrong kind of value".into()),
-            None => Frame::Null,
+        }
+        Frame::Array(arr)
+    }
+
+    async fn zalgebra_store(&self, op: SetOp, dest: String, keys: Vec<String>, opts: ZAlgebraOptions) -> Frame {
+        let mut inner = self.keyspace.write(keys.iter().chain([&dest])).await;
+
+        let inputs = match read_zalgebra_inputs(&inner, &keys) {
+            Ok(inputs) => inputs,
+            Err(e) => return e,
+        };
+
+        let entries = combine_zsets(op, inputs, &opts);
+        let count = entries.len() as i64;
+        if entries.is_empty() {
+            inner.remove(&dest);
+        } else {
+            let mut zset = ZSetState::new();
+            for (member, score) in entries {
+                zset.insert(score, member);
+            }
+            // The destination is replaced outright, including any TTL it had.
+            inner.insert(dest.clone(), Value::ZSet(zset));
+            self.wake_blocked(inner, &dest).await;
+        }
+
+        Frame::Integer(count)
+    }
+
+    async fn zpop(&self, key: String, end: ZSetEnd, count: Option<usize>) -> Frame {
+        if self.check_and_purge(&key).await {
+            return Frame::Array(vec![]);
+        }
+
+        let mut inner = self.keyspace.write([&key]).await;
+
+        match inner.get(&key) {
+            Some(Value::ZSet(_)) => {}
+            Some(_) => return Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+            None => return Frame::Array(vec![]),
+        }
+
+        let popped = pop_zset(&mut inner, &key, end, count.unwrap_or(1));
+        let mut arr = Vec::with_capacity(popped.len() * 2);
+        for (member, score) in popped {
+            arr.push(Frame::Bulk(member));
+            arr.push(score_frame(score));
+        }
+        Frame::Array(arr)
+    }
+}
+
+/// Writes the result of INCR/INCRBY, keeping any deadline the key had.
+fn store_counter(inner: &mut WriteGuard, key: String, value: i64) {
+    match inner.get_mut(&key) {
+        Some(v) => {
+            *v = Value::String(StringValue::Int(value));
+            inner.touch(&key);
+        }
+        None => {
+            inner.insert(key, Value::String(StringValue::Int(value)));
+        }
+    }
+}
+
+fn remove_if_empty_list(inner: &mut WriteGuard, key: &str) {
+    if matches!(inner.get(key), Some(Value::List(list)) if list.is_empty()) {
+        inner.remove(key);
+    }
+}
+
+fn remove_if_empty_hash(inner: &mut WriteGuard, key: &str) {
+    if matches!(inner.get(key), Some(Value::Hash(map)) if map.is_empty()) {
+        inner.remove(key);
+    }
+}
+
+fn hpexpireat_frame(key: &str, at_ms: u64, fields: Vec<Vec<u8>>) -> Frame {
+    let mut args = vec![
+        b"HPEXPIREAT".to_vec(),
+        key.as_bytes().to_vec(),
+        at_ms.to_string().into_bytes(),
+        b"FIELDS".to_vec(),
+        fields.len().to_string().into_bytes(),
+    ];
+    args.extend(fields);
+    command_frame(args)
+}
+
+fn remove_if_empty_set(inner: &mut WriteGuard, key: &str) {
+    if matches!(inner.get(key), Some(Value::Set(set)) if set.is_empty()) {
+        inner.remove(key);
+    }
+}
+
+/// Looks up the sets named by `keys`, treating missing keys as `None`. Fails with WRONGTYPE if any key holds another type.
+fn read_sets<'a, G: ShardLock>(
+    inner: &'a Guard<'_, G>,
+    keys: &[String],
+) -> Result<Vec<Option<&'a SetState>>, Frame> {
+    let mut sets = Vec::with_capacity(keys.len());
+    for k in keys {
+        match inner.get(k) {
+            Some(Value::Set(set)) => sets.push(Some(set)),
+            Some(_) => return Err(Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())),
+            None => sets.push(None),
+        }
+    }
+    Ok(sets)
+}
+
+/// Reads the inputs of ZUNION, ZINTER and ZDIFF. Plain sets count as
+/// sorted sets with every score 1, and missing keys are `None`.
+fn read_zalgebra_inputs<G: ShardLock>(
+    inner: &Guard<'_, G>,
+    keys: &[String],
+) -> Result<Vec<Option<ZEntries>>, Frame> {
+    let mut inputs = Vec::with_capacity(keys.len());
+    for k in keys {
+        match inner.get(k) {
+            Some(Value::ZSet(zset)) => inputs.push(Some(zset.entries())),
+            Some(Value::Set(set)) => inputs.push(Some(set.iter().map(|m| (m.into_owned(), 1.0)).collect())),
+            Some(_) => return Err(Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into())),
+            None => inputs.push(None),
+        }
+    }
+    Ok(inputs)
+}
+
+/// Applies a set operation across `sets`, where `None` stands for a missing key.
+fn combine_sets(op: SetOp, sets: &[Option<&SetState>]) -> HashSet<Vec<u8>> {
+    match op {
+        SetOp::Union => sets.iter()
+            .flatten()
+            .flat_map(|s| s.iter().map(Cow::into_owned))
+            .collect(),
+        SetOp::Inter => {
+            // Any missing key makes the intersection empty.
+            let Some(mut sets) = sets.iter().copied().collect::<Option<Vec<_>>>() else {
+                return HashSet::new();
+            };
+            sets.sort_by_key(|s| s.len());
+            sets[0].iter()
+                .filter(|m| sets[1..].iter().all(|s| s.contains(m)))
+                .map(Cow::into_owned)
+                .collect()
+        }
+        SetOp::Diff => {
+            let mut result: HashSet<Vec<u8>> = sets[0]
+                .map(|s| s.iter().map(Cow::into_owned).collect())
+                .unwrap_or_default();
+            for s in sets[1..].iter().flatten() {
+                result.retain(|m| !s.contains(m));
+            }
+            result
+        }
+    }
+}
+
+fn remove_if_empty_zset(inner: &mut WriteGuard, key: &str) {
+    if matches!(inner.get(key), Some(Value::ZSet(zset)) if zset.is_empty()) {
+        inner.remove(key);
+    }
+}
+
+fn score_frame(score: f64) -> Frame {
+    Frame::Bulk(score.to_string().into_bytes())
+}
+
+/// The kind of blocked client `value` could serve, regardless of whether it
+/// currently holds any data.
+fn block_kind(value: &Value) -> Option<BlockKind> {
+    match value {
+        Value::List(_) => Some(BlockKind::List),
+        Value::ZSet(_) => Some(BlockKind::ZSet),
+        _ => None,
+    }
+}
+
+/// Like [`block_kind`], but only if `value` has data to hand out right now.
+fn ready_kind(value: &Value) -> Option<BlockKind> {
+    match value {
+        Value::List(list) if !list.is_empty() => Some(BlockKind::List),
+        Value::ZSet(zset) if !zset.is_empty() => Some(BlockKind::ZSet),
+        _ => None,
+    }
+}
+
+/// Resolves `start`/`stop` ranks against `len` members, None if the range is empty.
+fn normalize_rank_range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let s = if start < 0 { (len + start).max(0) } else { start };
+    let e = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if s > e || s >= len || e < 0 {
+        return None;
+    }
+    Some((s as usize, e as usize))
+}
+
+fn zrange_entries(zset: &ZSetState, opts: &ZRangeOptions) -> Vec<(Vec<u8>, f64)> {
+    let mut entries = match &opts.by {
+        ZRangeBy::Rank(start, stop) => {
+            let Some((s, e)) = normalize_rank_range(*start, *stop, zset.len()) else {
+                return vec![];
+            };
+            if !opts.rev {
+                return zset.range_by_rank(s, e);
+            }
+            // Reversed ranks count down from the highest score.
+            let last = zset.len() - 1;
+            zset.range_by_rank(last - e, last - s)
+        }
+        ZRangeBy::Score(range) => zset.range_by_score(range),
+        ZRangeBy::Lex(range) => zset.range_by_lex(range),
+    };
+
+    if opts.rev {
+        entries.reverse();
+    }
+
+    if let Some((offset, count)) = opts.limit {
+        if offset < 0 {
+            return vec![];
+        }
+        let rest = entries.into_iter().skip(offset as usize);
+        entries = if count < 0 { rest.collect() } else { rest.take(count as usize).collect() };
+    }
+    entries
+}
+
+/// Applies a sorted set operation to `inputs` (`None` for a missing key) and
+/// returns the result in (score, member) order. ZDIFF keeps the first input's
+/// scores; the others apply the weights and aggregate function.
+fn combine_zsets(
+    op: SetOp,
+    inputs: Vec<Option<ZEntries>>,
+    opts: &ZAlgebraOptions,
+) -> ZEntries {
+    let weight = |i: usize| opts.weights.as_ref().map_or(1.0, |w| w[i]);
+    // inf * 0 and inf + -inf are defined as 0, as in Redis.
+    let weighted = |score: f64, w: f64| {
+        let s = score * w;
+        if s.is_nan() { 0.0 } else { s }
+    };
+    let aggregate = |a: f64, b: f64| match opts.aggregate {
+        Aggregate::Sum => {
+            let s = a + b;
+            if s.is_nan() { 0.0 } else { s }
+        }
+        Aggregate::Min => a.min(b),
+        Aggregate::Max => a.max(b),
+    };
+
+    let mut result: HashMap<Vec<u8>, f64> = HashMap::new();
+    match op {
+        SetOp::Union => {
+            for (i, input) in inputs.into_iter().enumerate() {
+                for (member, score) in input.into_iter().flatten() {
+                    let score = weighted(score, weight(i));
+                    result.entry(member)
+                        .and_modify(|cur| *cur = aggregate(*cur, score))
+                        .or_insert(score);
+                }
+            }
+        }
+        SetOp::Inter => {
+            // Any missing key makes the intersection empty.
+            let Some(inputs) = inputs.into_iter().collect::<Option<Vec<_>>>() else {
+                return vec![];
+            };
+            let mut inputs = inputs.into_iter().enumerate();
+            if let Some((i, first)) = inputs.next() {
+                result = first.into_iter()
+                    .map(|(member, score)| (member, weighted(score, weight(i))))
+                    .collect();
+            }
+            for (i, input) in inputs {
+                let other: HashMap<Vec<u8>, f64> = input.into_iter().collect();
+                result.retain(|member, cur| match other.get(member) {
+                    Some(score) => {
+                        *cur = aggregate(*cur, weighted(*score, weight(i)));
+                        true
+                    }
+                    None => false,
+                });
+            }
+        }
+        SetOp::Diff => {
+            let mut inputs = inputs.into_iter();
+            result = inputs.next().flatten().unwrap_or_default().into_iter().collect();
+            for (member, _) in inputs.flatten().flatten() {
+                result.remove(&member);
+            }
+        }
+    }
+
+    let mut entries: Vec<(Vec<u8>, f64)> = result.into_iter().collect();
+    entries.sort_by(|(am, a), (bm, b)| a.total_cmp(b).then_with(|| am.cmp(bm)));
+    entries
+}
+
+/// Pops up to `count` members from the `end` of the sorted set at `key` and
+/// propagates the removal as a ZREM.
+fn pop_zset(inner: &mut WriteGuard, key: &str, end: ZSetEnd, count: usize) -> Vec<(Vec<u8>, f64)> {
+    let mut popped = Vec::new();
+
+    if let Some(Value::ZSet(zset)) = inner.get_mut(key) {
+        while popped.len() < count {
+            let next = match end {
+                ZSetEnd::Min => zset.first(),
+                ZSetEnd::Max => zset.last(),
+            };
+            let Some((member, score)) = next else {
+                break;
+            };
+            zset.remove_member(&member);
+            popped.push((member, score));
+        }
+    }
+    if !popped.is_empty() {
+        inner.touch(key);
+    }
+    remove_if_empty_zset(inner, key);
+
+    if !popped.is_empty() {
+        let mut args: Vec<Vec<u8>> = vec![b"ZREM".to_vec(), key.as_bytes().to_vec()];
+        args.extend(popped.iter().map(|(m, _)| m.clone()));
+        propagate(command_frame(args));
+    }
+
+    popped
+}
+
+fn list_end_name(end: ListEnd) -> &'static str {
+    match end {
+        ListEnd::Left => "LEFT",
+        ListEnd::Right => "RIGHT",
+    }
+}
+
+/// Pops from `source` and pushes onto `destination`. Both keys must already be
+/// known to hold lists (or, for `destination`, to be absent).
+fn move_element(
+    inner: &mut WriteGuard,
+    source: &str,
+    destination: &str,
+    from: ListEnd,
+    to: ListEnd,
+) -> (Frame, bool) {
+    let Some(v) = inner.get_mut(source).and_then(|v| v.as_list_mut()).and_then(|l| l.pop(from)) else {
+        return (Frame::Null, false);
+    };
+    inner.touch(source);
+    remove_if_empty_list(inner, source);
+
+    let entry = inner
+        .get_or_insert_with(destination.to_string(), || Value::List(ListState::new()));
+    if let Value::List(list) = entry {
+        list.push(to, v.clone());
+    }
+    inner.touch(destination);
+
+    (Frame::Bulk(v), true)
+}
+
+/// Runs a blocked client's operation against the non-empty value at `key` and
+/// propagates what it did. Returns the reply along with the destination key of
+/// a move, which may now unblock other clients.
+fn run_block_op(inner: &mut WriteGuard, key: &str, op: &BlockOp) -> (Frame, Option<String>) {
+    match op {
+        BlockOp::Pop(end) => {
+            let Some(v) = inner.get_mut(key).and_then(|v| v.as_list_mut()).and_then(|l| l.pop(*end)) else {
+                return (Frame::Null, None);
+            };
+            inner.touch(key);
+            remove_if_empty_list(inner, key);
+
+            let cmd = match end {
+                ListEnd::Left => "LPOP",
+                ListEnd::Right => "RPOP",
+            };
+            propagate(command_frame([cmd, key]));
+
+            (Frame::Array(vec![Frame::Bulk(key.as_bytes().to_vec()), Frame::Bulk(v)]), None)
+        }
+        BlockOp::MPop(end, count) => {
+            let mut popped = Vec::new();
+            if let Some(list) = inner.get_mut(key).and_then(|v| v.as_list_mut()) {
+                while popped.len() < *count {
+                    match list.pop(*end) {
+                        Some(v) => popped.push(Frame::Bulk(v)),
+                        None => break,
+                    }
+                }
+            }
+            if !popped.is_empty() {
+                inner.touch(key);
+            }
+            remove_if_empty_list(inner, key);
+
+            let cmd = match end {
+                ListEnd::Left => "LPOP",
+                ListEnd::Right => "RPOP",
+            };
+            propagate(command_frame([cmd, key, &popped.len().to_string()]));
+
+            (Frame::Array(vec![Frame::Bulk(key.as_bytes().to_vec()), Frame::Array(popped)]), None)
+        }
+        BlockOp::Move(destination, from, to) => {
+            if !matches!(inner.get(destination), Some(Value::List(_)) | None) {
+                return (
+                    Frame::Error("WRONGTYPE Operation against a key holding the wrong kind of value".into()),
+                    None,
+                );
+            }
+
+            let (reply, moved) = move_element(inner, key, destination, *from, *to);
+            if !moved {
+                return (reply, None);
+            }
+            propagate(command_frame([
+                "LMOVE",
+                key,
+                destination,
+                list_end_name(*from),
+                list_end_name(*to),
+            ]));
+
+            (reply, Some(destination.clone()))
+        }
+        BlockOp::ZPop(end) => {
+            let popped = pop_zset(inner, key, *end, 1);
+            let Some((member, score)) = popped.into_iter().next() else {
+                return (Frame::Null, None);
+            };
+            (
+                Frame::Array(vec![Frame::Bulk(key.as_bytes().to_vec()), Frame::Bulk(member), score_frame(score)]),
+                None,
+            )
+        }
+        BlockOp::ZMPop(end, count) => {
+            let popped = pop_zset(inner, key, *end, *count)
+                .into_iter()
+                .map(|(member, score)| Frame::Array(vec![Frame::Bulk(member), score_frame(score)]))
+                .collect();
+            (Frame::Array(vec![Frame::Bulk(key.as_bytes().to_vec()), Frame::Array(popped)]), None)
         }
     }
 }
@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+
+/// Thresholds below which values keep their compact encoding. Names and
+/// defaults follow the matching `redis.conf` directives.
+#[derive(Debug)]
+pub struct EncodingLimits {
+    pub hash_max_listpack_entries: AtomicUsize,
+    pub hash_max_listpack_value: AtomicUsize,
+    pub set_max_intset_entries: AtomicUsize,
+    pub set_max_listpack_entries: AtomicUsize,
+    pub set_max_listpack_value: AtomicUsize,
+    pub zset_max_listpack_entries: AtomicUsize,
+    pub zset_max_listpack_value: AtomicUsize,
+    /// Positive values cap the entry count; -1 to -5 cap the packed size at
+    /// 4, 8, 16, 32 or 64 KB, as in Redis.
+    pub list_max_listpack_size: AtomicI64,
+}
+
+/// Every limit accepted by [`EncodingLimits::set`].
+pub const LIMIT_NAMES: &[&str] = &[
+    "hash-max-listpack-entries",
+    "hash-max-listpack-value",
+    "set-max-intset-entries",
+    "set-max-listpack-entries",
+    "set-max-listpack-value",
+    "zset-max-listpack-entries",
+    "zset-max-listpack-value",
+    "list-max-listpack-size",
+];
+
+pub static LIMITS: EncodingLimits = EncodingLimits {
+    hash_max_listpack_entries: AtomicUsize::new(128),
+    hash_max_listpack_value: AtomicUsize::new(64),
+    set_max_intset_entries: AtomicUsize::new(512),
+    set_max_listpack_entries: AtomicUsize::new(128),
+    set_max_listpack_value: AtomicUsize::new(64),
+    zset_max_listpack_entries: AtomicUsize::new(128),
+    zset_max_listpack_value: AtomicUsize::new(64),
+    list_max_listpack_size: AtomicI64::new(-2),
+};
+
+impl EncodingLimits {
+    /// Sets a limit by its `redis.conf` name, e.g. `hash-max-listpack-entries`.
+    pub fn set(&self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value for {}: {}", name, value);
+
+        if name == "list-max-listpack-size" {
+            let v = value.parse::<i64>().map_err(|_| invalid())?;
+            if v == 0 || v < -5 {
+                return Err(invalid());
+            }
+            self.list_max_listpack_size.store(v, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        let limit = match name {
+            "hash-max-listpack-entries" => &self.hash_max_listpack_entries,
+            "hash-max-listpack-value" => &self.hash_max_listpack_value,
+            "set-max-intset-entries" => &self.set_max_intset_entries,
+            "set-max-listpack-entries" => &self.set_max_listpack_entries,
+            "set-max-listpack-value" => &self.set_max_listpack_value,
+            "zset-max-listpack-entries" => &self.zset_max_listpack_entries,
+            "zset-max-listpack-value" => &self.zset_max_listpack_value,
+            _ => return Err(format!("unknown encoding limit: {}", name)),
+        };
+        limit.store(value.parse::<usize>().map_err(|_| invalid())?, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether a packed list of `entries` entries taking `bytes` bytes is
+    /// still within `list-max-listpack-size`.
+    pub fn list_fits(&self, entries: usize, bytes: usize) -> bool {
+        match self.list_max_listpack_size.load(Ordering::Relaxed) {
+            n if n > 0 => entries <= n as usize,
+            n => bytes <= 4096 << (-n - 1),
+        }
+    }
+}
+
+pub fn limit(value: &AtomicUsize) -> usize {
+    value.load(Ordering::Relaxed)
+}
@@ -18,4 +18,17 @@ pub enum RedisError {
 
     #[error("{0}")]
     Other(String),
-}
\ No newline at end of file
+}
+impl RedisError {
+    /// The error reply for a client. Messages that already start with an
+    /// error code, such as `ERR` or `WRONGTYPE`, are sent as they are.
+    pub fn reply(&self) -> String {
+        let msg = self.to_string();
+        let code = msg.split(' ').next().unwrap_or_default();
+        if !code.is_empty() && code.bytes().all(|b| b.is_ascii_uppercase()) {
+            msg
+        } else {
+            format!("ERR {}", msg)
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use std::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use rand::Rng;
+
+use crate::aof::{command_frame, propagate_to};
+use crate::db::Db;
+use crate::keyspace::Entry;
+use crate::lazyfree;
+
+/// Reply to commands refused because memory could not be freed.
+pub const OOM_ERROR: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+/// Candidates kept between evictions, as in Redis' eviction pool.
+const POOL_SIZE: usize = 16;
+/// Frequency counter given to new keys under an LFU policy, so they are not
+/// evicted before they had a chance to be accessed.
+const LFU_INIT_VAL: u8 = 5;
+
+/// What to do when `maxmemory` is reached. Names follow `maxmemory-policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    AllKeysRandom,
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl Policy {
+    const ALL: [Policy; 8] = [
+        Policy::NoEviction,
+        Policy::AllKeysLru,
+        Policy::AllKeysLfu,
+        Policy::AllKeysRandom,
+        Policy::VolatileLru,
+        Policy::VolatileLfu,
+        Policy::VolatileRandom,
+        Policy::VolatileTtl,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Policy::NoEviction => "noeviction",
+            Policy::AllKeysLru => "allkeys-lru",
+            Policy::AllKeysLfu => "allkeys-lfu",
+            Policy::AllKeysRandom => "allkeys-random",
+            Policy::VolatileLru => "volatile-lru",
+            Policy::VolatileLfu => "volatile-lfu",
+            Policy::VolatileRandom => "volatile-random",
+            Policy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.name().eq_ignore_ascii_case(s))
+    }
+
+    /// Whether only keys with a deadline may be evicted.
+    pub fn is_volatile(self) -> bool {
+        matches!(
+            self,
+            Policy::VolatileLru | Policy::VolatileLfu | Policy::VolatileRandom | Policy::VolatileTtl
+        )
+    }
+
+    pub fn is_lfu(self) -> bool {
+        matches!(self, Policy::AllKeysLfu | Policy::VolatileLfu)
+    }
+
+    fn is_random(self) -> bool {
+        matches!(self, Policy::AllKeysRandom | Policy::VolatileRandom)
+    }
+}
+
+/// Memory limit and eviction settings. Names and defaults follow the
+/// matching `redis.conf` directives.
+#[derive(Debug)]
+pub struct MemoryConfig {
+    /// Bytes; 0 means no limit.
+    pub maxmemory: AtomicUsize,
+    policy: AtomicU8,
+    pub maxmemory_samples: AtomicUsize,
+    pub lfu_log_factor: AtomicUsize,
+    /// Idle minutes per decrement of a key's frequency counter; 0 disables
+    /// decay.
+    pub lfu_decay_time: AtomicUsize,
+}
+
+/// Every setting accepted by [`MemoryConfig::set`].
+pub const CONFIG_NAMES: &[&str] = &[
+    "maxmemory",
+    "maxmemory-policy",
+    "maxmemory-samples",
+    "lfu-log-factor",
+    "lfu-decay-time",
+];
+
+pub static CONFIG: MemoryConfig = MemoryConfig {
+    maxmemory: AtomicUsize::new(0),
+    policy: AtomicU8::new(0),
+    maxmemory_samples: AtomicUsize::new(5),
+    lfu_log_factor: AtomicUsize::new(10),
+    lfu_decay_time: AtomicUsize::new(1),
+};
+
+impl MemoryConfig {
+    /// Sets a value by its `redis.conf` name, e.g. `maxmemory-policy`.
+    pub fn set(&self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value for {}: {}", name, value);
+
+        match name {
+            "maxmemory" => {
+                let bytes = parse_memory(value).ok_or_else(invalid)?;
+                self.maxmemory.store(bytes, Ordering::Relaxed);
+            }
+            "maxmemory-policy" => {
+                let policy = Policy::parse(value).ok_or_else(invalid)?;
+                let idx = Policy::ALL.iter().position(|p| *p == policy).expect("listed policy");
+                self.policy.store(idx as u8, Ordering::Relaxed);
+            }
+            "maxmemory-samples" | "lfu-log-factor" | "lfu-decay-time" => {
+                let v = value.parse::<usize>().map_err(|_| invalid())?;
+                let setting = match name {
+                    "maxmemory-samples" if v > 0 => &self.maxmemory_samples,
+                    "lfu-log-factor" => &self.lfu_log_factor,
+                    "lfu-decay-time" => &self.lfu_decay_time,
+                    _ => return Err(invalid()),
+                };
+                setting.store(v, Ordering::Relaxed);
+            }
+            _ => return Err(format!("unknown memory setting: {}", name)),
+        }
+        Ok(())
+    }
+
+    pub fn maxmemory(&self) -> usize {
+        self.maxmemory.load(Ordering::Relaxed)
+    }
+
+    pub fn policy(&self) -> Policy {
+        Policy::ALL[self.policy.load(Ordering::Relaxed) as usize]
+    }
+}
+
+/// Parses a byte count with an optional unit, as in `redis.conf`: `k`, `m`
+/// and `g` are powers of 1000, `kb`, `mb` and `gb` powers of 1024.
+pub fn parse_memory(s: &str) -> Option<usize> {
+    let s = s.to_ascii_lowercase();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(digits);
+    let mul: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    num.parse::<usize>().ok()?.checked_mul(mul)
+}
+
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+/// Milliseconds since startup, wrapping after about 49 days.
+fn clock_ms() -> u32 {
+    epoch().elapsed().as_millis() as u32
+}
+
+/// Minutes since startup, wrapping after about 45 days.
+fn clock_minutes() -> u16 {
+    (epoch().elapsed().as_secs() / 60) as u16
+}
+
+/// Access bookkeeping kept on every entry. Like Redis' `lru` field it holds
+/// either the last access time or, under an LFU policy, a logarithmic access
+/// counter next to the minute it was last decremented.
+#[derive(Debug)]
+pub struct Access(AtomicU32);
+
+impl Default for Access {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Access {
+    pub fn new() -> Self {
+        if CONFIG.policy().is_lfu() {
+            Self(AtomicU32::new(pack_lfu(clock_minutes(), LFU_INIT_VAL)))
+        } else {
+            Self(AtomicU32::new(clock_ms()))
+        }
+    }
+
+    /// Records an access. Runs under a read lock, hence the atomic.
+    pub fn touch(&self) {
+        if CONFIG.policy().is_lfu() {
+            let counter = log_incr(self.frequency());
+            self.0.store(pack_lfu(clock_minutes(), counter), Ordering::Relaxed);
+        } else {
+            self.0.store(clock_ms(), Ordering::Relaxed);
+        }
+    }
+
+    /// Milliseconds since the last access. Meaningless under LFU policies.
+    pub fn idle_ms(&self) -> u64 {
+        clock_ms().wrapping_sub(self.0.load(Ordering::Relaxed)) as u64
+    }
+
+    /// The access counter after decay. Meaningless outside LFU policies.
+    pub fn frequency(&self) -> u8 {
+        let packed = self.0.load(Ordering::Relaxed);
+        let (last, counter) = ((packed >> 8) as u16, packed as u8);
+        let decay = CONFIG.lfu_decay_time.load(Ordering::Relaxed);
+        if decay == 0 {
+            return counter;
+        }
+        let periods = clock_minutes().wrapping_sub(last) as usize / decay;
+        counter.saturating_sub(periods.min(u8::MAX as usize) as u8)
+    }
+}
+
+fn pack_lfu(minutes: u16, counter: u8) -> u32 {
+    ((minutes as u32) << 8) | counter as u32
+}
+
+/// Increments `counter` with a probability that falls as it grows, so that
+/// eight bits can tell apart keys hit millions of times.
+fn log_incr(counter: u8) -> u8 {
+    if counter == u8::MAX {
+        return counter;
+    }
+    let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+    let factor = CONFIG.lfu_log_factor.load(Ordering::Relaxed) as f64;
+    if rand::thread_rng().gen::<f64>() < 1.0 / (base * factor + 1.0) {
+        counter + 1
+    } else {
+        counter
+    }
+}
+
+/// How good a candidate `entry` is for eviction; higher is evicted first.
+fn score(policy: Policy, entry: &Entry, now: Instant) -> u64 {
+    match policy {
+        Policy::AllKeysLfu | Policy::VolatileLfu => (u8::MAX - entry.access().frequency()) as u64,
+        Policy::VolatileTtl => {
+            let ttl = entry.expires_at().map_or(u64::MAX, |at| at.saturating_duration_since(now).as_millis() as u64);
+            u64::MAX - ttl
+        }
+        _ => entry.access().idle_ms(),
+    }
+}
+
+/// The best candidates seen by earlier samples across all databases, ordered
+/// by ascending score. Keeping them around lets each eviction draw on more
+/// than one sample, which brings the approximation much closer to true
+/// LRU/LFU.
+#[derive(Debug, Default)]
+pub struct EvictionPool {
+    candidates: Vec<(u64, usize, String)>,
+}
+
+impl EvictionPool {
+    fn offer(&mut self, score: u64, db: usize, key: &str) {
+        if self.candidates.iter().any(|(_, d, k)| *d == db && k == key) {
+            return;
+        }
+        if self.candidates.len() >= POOL_SIZE && score <= self.candidates[0].0 {
+            return;
+        }
+        let pos = self.candidates.partition_point(|(s, _, _)| *s < score);
+        self.candidates.insert(pos, (score, db, key.to_string()));
+        if self.candidates.len() > POOL_SIZE {
+            self.candidates.remove(0);
+        }
+    }
+
+    fn pop_best(&mut self) -> Option<(usize, String)> {
+        self.candidates.pop().map(|(_, db, k)| (db, k))
+    }
+}
+
+/// Evicts keys from any of `dbs` under the configured policy until used
+/// memory is back within `maxmemory`, propagating each eviction as a DEL in
+/// its database. The databases must share one memory account. Returns false
+/// if that was not possible, in which case commands that may grow the
+/// dataset must be refused.
+pub async fn free_memory(dbs: &[Db], pool: &Mutex<EvictionPool>) -> bool {
+    let limit = CONFIG.maxmemory();
+    if limit == 0 || dbs.is_empty() {
+        return true;
+    }
+    let policy = CONFIG.policy();
+
+    while dbs[0].keyspace().used_memory() > limit {
+        if policy == Policy::NoEviction {
+            return false;
+        }
+        let Some((db, key)) = pick_victim(dbs, pool, policy).await else {
+            return false;
+        };
+        let mut guard = dbs[db].keyspace().write([&key]).await;
+        if guard.free(&key, lazyfree::enabled(&lazyfree::CONFIG.lazy_eviction)) {
+            propagate_to(db, command_frame(["DEL".to_string(), key]));
+        }
+    }
+    true
+}
+
+/// Samples `maxmemory-samples` keys from the same random shard of every
+/// database, as Redis samples every database, feeding them to the pool, and
+/// returns the best candidate seen so far. Shards with nothing to sample in
+/// any database are skipped.
+async fn pick_victim(dbs: &[Db], pool: &Mutex<EvictionPool>, policy: Policy) -> Option<(usize, String)> {
+    let shards = dbs[0].keyspace().shard_count();
+    let (start, first_db) = {
+        let mut rng = rand::thread_rng();
+        (rng.gen_range(0..shards), rng.gen_range(0..dbs.len()))
+    };
+    let samples = CONFIG.maxmemory_samples.load(Ordering::Relaxed);
+
+    for n in 0..shards {
+        let idx = (start + n) % shards;
+        let mut sampled = false;
+        for m in 0..dbs.len() {
+            let db = (first_db + m) % dbs.len();
+            let shard = dbs[db].keyspace().read_shard(idx).await;
+            let sample = shard.sample(samples, policy.is_volatile());
+            if sample.is_empty() {
+                continue;
+            }
+            if policy.is_random() {
+                return sample.first().map(|(k, _)| (db, k.to_string()));
+            }
+
+            let now = Instant::now();
+            let mut pool = pool.lock().unwrap();
+            for (key, entry) in sample {
+                pool.offer(score(policy, entry, now), db, key);
+            }
+            sampled = true;
+        }
+        if sampled {
+            return pool.lock().unwrap().pop_best();
+        }
+    }
+    pool.lock().unwrap().pop_best()
+}
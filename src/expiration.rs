@@ -1,41 +1,115 @@
 use std::sync::Arc;
 use std::time::Instant;
 
-use tokio::time::{self, Duration};
+use tokio::time::{self, Duration, MissedTickBehavior};
 
-use crate::db::Db;
+use crate::databases::Databases;
+use crate::hash::now_ms;
 
-pub async fn run(db: Arc<Db>) {
-    let mut interval = time::interval(Duration::from_secs(1));
+/// Keys sampled per batch at effort 1.
+const KEYS_PER_LOOP: usize = 20;
+/// Share of the CPU a cycle may use at effort 1, in percent.
+const CYCLE_CPU_PERCENT: u64 = 25;
+/// A batch in which at most this percentage of keys had expired ends the
+/// shard's turn: the remaining expired keys are too sparse to be worth the
+/// sampling.
+const ACCEPTABLE_STALE_PERCENT: usize = 25;
 
-    loop {
-        interval.tick().await;
-        cleanup(&db).await;
+/// Settings for the active expiry cycle, named after their `redis.conf`
+/// counterparts.
+#[derive(Debug, Clone, Copy)]
+pub struct ExpireConfig {
+    /// Cycles per second.
+    pub hz: u32,
+    /// 1..=10; higher values sample more keys per batch and allow each cycle
+    /// a bigger time budget, trading CPU for memory held by expired keys.
+    pub effort: u32,
+}
+
+impl Default for ExpireConfig {
+    fn default() -> Self {
+        Self { hz: 10, effort: 1 }
     }
 }
 
-async fn cleanup(db: &Db) {
-    let mut expired_keys = vec![];
-    
-    {
-        let ttl = db.get_ttl().await;
-        let now = Instant::now();
-        for (k, exp) in ttl.iter() {
-            if now >= *exp {
-                expired_keys.push(k.clone());
-            }
+impl ExpireConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=500).contains(&self.hz) {
+            return Err(format!("hz must be between 1 and 500, got {}", self.hz));
+        }
+        if !(1..=10).contains(&self.effort) {
+            return Err(format!("active-expire-effort must be between 1 and 10, got {}", self.effort));
         }
+        Ok(())
     }
 
-    if expired_keys.is_empty() {
-        return;
+    fn keys_per_loop(&self) -> usize {
+        KEYS_PER_LOOP + KEYS_PER_LOOP / 4 * (self.effort as usize - 1)
+    }
+
+    /// Wall-clock time one cycle may spend before yielding to the next tick.
+    fn time_budget(&self) -> Duration {
+        let percent = CYCLE_CPU_PERCENT + 2 * (self.effort as u64 - 1);
+        Duration::from_micros(1_000_000 * percent / 100 / self.hz as u64)
+    }
+}
+
+/// Runs the active expiry cycle forever.
+///
+/// Each tick samples random keys with a deadline and random hashes with
+/// field deadlines, shard by shard across all databases, and drops what has
+/// expired. A shard is sampled again for as long as more than a quarter of
+/// either sample turned out expired, so cleanup speeds up while many keys
+/// are expiring and costs little otherwise. No shard is locked for
+/// longer than one batch, and a cycle stops once its time budget is spent;
+/// the next one resumes at the shard where it stopped.
+pub async fn run(dbs: Arc<Databases>, cfg: ExpireConfig) {
+    let mut interval = time::interval(Duration::from_secs(1) / cfg.hz);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut next_shard = 0;
+
+    loop {
+        interval.tick().await;
+        next_shard = expire_cycle(&dbs, &cfg, next_shard).await;
     }
+}
+
+/// Runs one cycle starting at shard `start`, counting the shards of all
+/// databases one after another. Returns the shard the next cycle should
+/// start at.
+async fn expire_cycle(dbs: &Databases, cfg: &ExpireConfig, start: usize) -> usize {
+    let per_db = dbs.get(0).keyspace().shard_count();
+    let shards = dbs.len() * per_db;
+    let per_loop = cfg.keys_per_loop();
+    let budget = cfg.time_budget();
+    let started = Instant::now();
 
-    let mut inner = db.get_inner_mut().await;
-    let mut ttl_mut = db.get_ttl_mut().await;
+    for n in 0..shards {
+        let idx = (start + n) % shards;
+        loop {
+            let (keys, fields) = {
+                let db = dbs.get(idx / per_db);
+                let mut shard = db.keyspace().write_shard(idx % per_db).await;
+                (
+                    shard.expire_sample(per_loop, Instant::now()),
+                    shard.expire_fields_sample(per_loop, now_ms()),
+                )
+            };
 
-    for key in expired_keys{
-        inner.remove(&key);
-        ttl_mut.remove(&key);
+            if started.elapsed() >= budget {
+                return idx;
+            }
+            if !mostly_stale(keys) && !mostly_stale(fields) {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
     }
-}
\ No newline at end of file
+    start
+}
+
+/// Whether a batch that checked `sampled` keys and found `expired` of them
+/// stale calls for another batch on the same shard.
+fn mostly_stale((sampled, expired): (usize, usize)) -> bool {
+    sampled > 0 && expired * 100 > sampled * ACCEPTABLE_STALE_PERCENT
+}
@@ -0,0 +1,334 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mlua::{Function, HookTriggers, Lua, RegistryKey, Table, Value as LuaValue};
+
+use crate::command::RestorePolicy;
+use crate::resp::Frame;
+use crate::scripting;
+
+/// Flags a function may be registered with. Only `no-writes` changes
+/// anything here: it lets FCALL_RO call the function.
+const FLAGS: &[&str] = &["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+/// How long the code of a library may run when loaded, as in Redis.
+const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Version tag at the start of FUNCTION DUMP payloads.
+const DUMP_VERSION: u8 = 1;
+
+/// Registers the functions of a library in a table passed as the chunk's
+/// argument, for the library loader to read back.
+const REGISTER: &str = r#"
+local registered = ...
+function redis.register_function(name, callback)
+    local flags = {}
+    if type(name) == 'table' then
+        name, callback, flags = name.function_name, name.callback, name.flags or {}
+    end
+    if type(name) ~= 'string' then
+        error('function name argument given to redis.register_function must be a string', 2)
+    end
+    if type(callback) ~= 'function' then
+        error('callback argument given to redis.register_function must be a function', 2)
+    end
+    if type(flags) ~= 'table' then
+        error('flags argument given to redis.register_function must be a table', 2)
+    end
+    table.insert(registered, {name = name, callback = callback, flags = flags})
+end
+"#;
+
+#[derive(Debug, Clone)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub flags: Vec<String>,
+}
+
+impl FunctionInfo {
+    pub fn no_writes(&self) -> bool {
+        self.flags.iter().any(|f| f == "no-writes")
+    }
+}
+
+/// A library loaded with FUNCTION LOAD.
+#[derive(Debug, Clone)]
+pub struct Library {
+    pub name: String,
+    /// The code as given, metadata line included.
+    pub code: String,
+    pub functions: Vec<FunctionInfo>,
+    engine: Arc<Mutex<Engine>>,
+}
+
+/// The interpreter a library's code ran in when it was loaded, which keeps
+/// the functions it registered along with whatever state the library set up.
+#[derive(Debug)]
+pub struct Engine {
+    pub lua: Lua,
+    callbacks: HashMap<String, RegistryKey>,
+}
+
+impl Engine {
+    /// The callback registered as function `name`.
+    pub fn callback(&self, name: &str) -> mlua::Result<Option<Function<'_>>> {
+        self.callbacks.get(name).map(|key| self.lua.registry_value(key)).transpose()
+    }
+}
+
+/// A function registered while running a library's code.
+struct Registered<'lua> {
+    name: String,
+    callback: Function<'lua>,
+    flags: Vec<String>,
+}
+
+/// The libraries loaded with FUNCTION LOAD, by name.
+///
+/// Each library's code runs once, when it is loaded or restored, in an
+/// interpreter of its own that then serves every call to its functions.
+/// Libraries survive restarts through the AOF, which logs the FUNCTION
+/// commands that changed them, so replaying it runs their code again.
+#[derive(Debug, Default)]
+pub struct Functions {
+    libraries: Mutex<BTreeMap<String, Library>>,
+}
+
+impl Functions {
+    /// Loads the library in `code`, returning its name.
+    pub fn load(&self, code: String, replace: bool) -> Frame {
+        let library = match compile(code) {
+            Ok(library) => library,
+            Err(e) => return Frame::Error(e),
+        };
+        let mut libraries = self.libraries.lock().unwrap();
+        if let Err(e) = check_conflicts(&libraries, &library, replace) {
+            return Frame::Error(e);
+        }
+        let name = library.name.clone();
+        libraries.insert(name.clone(), library);
+        Frame::Bulk(name.into_bytes())
+    }
+
+    pub fn delete(&self, name: &str) -> Frame {
+        match self.libraries.lock().unwrap().remove(name) {
+            Some(_) => Frame::Simple("OK".into()),
+            None => Frame::Error("ERR Library not found".into()),
+        }
+    }
+
+    pub fn flush(&self) {
+        self.libraries.lock().unwrap().clear();
+    }
+
+    /// The interpreter of the library that registered function `name`, and
+    /// the function's details.
+    pub fn find(&self, name: &str) -> Option<(Arc<Mutex<Engine>>, FunctionInfo)> {
+        let libraries = self.libraries.lock().unwrap();
+        libraries.values().find_map(|lib| {
+            let info = lib.functions.iter().find(|f| f.name == name)?;
+            Some((lib.engine.clone(), info.clone()))
+        })
+    }
+
+    pub fn list(&self, pattern: Option<&str>, with_code: bool) -> Frame {
+        // Like KEYS, only matches every name or exactly one.
+        let matches = |name: &str| pattern.is_none_or(|p| p == "*" || p == name);
+        let field = |name: &str| Frame::Bulk(name.as_bytes().to_vec());
+
+        let libraries = self.libraries.lock().unwrap();
+        let mut out = Vec::new();
+        for lib in libraries.values().filter(|lib| matches(&lib.name)) {
+            let functions = lib
+                .functions
+                .iter()
+                .map(|f| {
+                    Frame::Array(vec![
+                        field("name"),
+                        field(&f.name),
+                        field("description"),
+                        Frame::Null,
+                        field("flags"),
+                        Frame::Array(f.flags.iter().map(|flag| field(flag)).collect()),
+                    ])
+                })
+                .collect();
+            let mut entry = vec![
+                field("library_name"),
+                field(&lib.name),
+                field("engine"),
+                field("LUA"),
+                field("functions"),
+                Frame::Array(functions),
+            ];
+            if with_code {
+                entry.push(field("library_code"));
+                entry.push(field(&lib.code));
+            }
+            out.push(Frame::Array(entry));
+        }
+        Frame::Array(out)
+    }
+
+    /// Serializes every library for FUNCTION RESTORE.
+    pub fn dump(&self) -> Frame {
+        let codes: Vec<String> = self.libraries.lock().unwrap().values().map(|lib| lib.code.clone()).collect();
+        let mut payload = vec![DUMP_VERSION];
+        payload.extend(serde_json::to_vec(&codes).expect("library code serializes"));
+        Frame::Bulk(payload)
+    }
+
+    /// Loads the libraries of a FUNCTION DUMP payload, all or none of them.
+    pub fn restore(&self, payload: &[u8], policy: RestorePolicy) -> Frame {
+        let codes: Vec<String> = match payload.split_first() {
+            Some((&DUMP_VERSION, body)) => match serde_json::from_slice(body) {
+                Ok(codes) => codes,
+                Err(_) => return Frame::Error("ERR payload version or checksum are wrong".into()),
+            },
+            _ => return Frame::Error("ERR payload version or checksum are wrong".into()),
+        };
+        let mut restored = Vec::with_capacity(codes.len());
+        for code in codes {
+            match compile(code) {
+                Ok(library) => restored.push(library),
+                Err(e) => return Frame::Error(e),
+            }
+        }
+
+        let mut libraries = self.libraries.lock().unwrap();
+        let mut updated = match policy {
+            RestorePolicy::Flush => BTreeMap::new(),
+            RestorePolicy::Append | RestorePolicy::Replace => libraries.clone(),
+        };
+        for library in restored {
+            if let Err(e) = check_conflicts(&updated, &library, policy == RestorePolicy::Replace) {
+                return Frame::Error(e);
+            }
+            updated.insert(library.name.clone(), library);
+        }
+        *libraries = updated;
+        Frame::Simple("OK".into())
+    }
+}
+
+/// Fails if `library` exists and may not be replaced, or registers a
+/// function that another library already has.
+fn check_conflicts(libraries: &BTreeMap<String, Library>, library: &Library, replace: bool) -> Result<(), String> {
+    if !replace && libraries.contains_key(&library.name) {
+        return Err(format!("ERR Library '{}' already exists", library.name));
+    }
+    for other in libraries.values().filter(|other| other.name != library.name) {
+        if let Some(f) = library.functions.iter().find(|f| other.functions.iter().any(|o| o.name == f.name)) {
+            return Err(format!("ERR Function {} already exists", f.name));
+        }
+    }
+    Ok(())
+}
+
+/// Runs the code of a library in a new interpreter to learn its name and
+/// the functions it registers, and keeps the interpreter to call them in.
+fn compile(code: String) -> Result<Library, String> {
+    let (name, _) = parse_metadata(&code)?;
+    let lua = scripting::sandbox().map_err(|e| format!("ERR Error creating interpreter: {}", e))?;
+    let started = Instant::now();
+    lua.set_hook(HookTriggers::new().every_nth_instruction(scripting::HOOK_INTERVAL), move |_, _| {
+        if started.elapsed() > LOAD_TIMEOUT {
+            return Err(mlua::Error::RuntimeError("FUNCTION LOAD timeout".into()));
+        }
+        Ok(())
+    });
+    let functions = register(&lua, &code).map_err(|e| match e {
+        mlua::Error::SyntaxError { message, .. } => format!("ERR Error compiling function: {}", message),
+        e => format!("ERR Error registering functions: {}", scripting::error_message(&e)),
+    })?;
+
+    if functions.is_empty() {
+        return Err("ERR No functions registered".into());
+    }
+    let mut infos: Vec<FunctionInfo> = Vec::with_capacity(functions.len());
+    let mut callbacks = HashMap::with_capacity(functions.len());
+    for f in functions {
+        if !valid_name(&f.name) {
+            return Err(
+                "ERR Function names can only contain letters, numbers, or underscores(_) and must be at least one character long"
+                    .into(),
+            );
+        }
+        if infos.iter().any(|i| i.name == f.name) {
+            return Err(format!("ERR Function {} already exists", f.name));
+        }
+        if let Some(flag) = f.flags.iter().find(|flag| !FLAGS.contains(&flag.as_str())) {
+            return Err(format!("ERR Unknown flag given: {}", flag));
+        }
+        let key = lua.create_registry_value(f.callback).map_err(|e| e.to_string())?;
+        callbacks.insert(f.name.clone(), key);
+        infos.push(FunctionInfo { name: f.name, flags: f.flags });
+    }
+    lua.remove_hook();
+
+    let engine = Engine { lua, callbacks };
+    Ok(Library { name, code, functions: infos, engine: Arc::new(Mutex::new(engine)) })
+}
+
+/// Runs the code of a library in `lua`, whose `redis` table must already be
+/// set, returning the functions it registered. `redis.register_function` is
+/// gone again afterwards, as functions may only be registered while loading.
+fn register<'lua>(lua: &'lua Lua, code: &str) -> mlua::Result<Vec<Registered<'lua>>> {
+    let (name, body) = parse_metadata(code).map_err(mlua::Error::RuntimeError)?;
+    let registered = lua.create_table()?;
+    lua.load(REGISTER).set_name("=register").call::<_, ()>(registered.clone())?;
+    lua.load(body).set_name(format!("@{}", name)).call::<_, ()>(())?;
+    lua.globals().get::<_, Table>("redis")?.set("register_function", LuaValue::Nil)?;
+
+    let mut functions = Vec::new();
+    for entry in registered.sequence_values::<Table>() {
+        let entry = entry?;
+        let flags = entry
+            .get::<_, Table>("flags")?
+            .sequence_values::<String>()
+            .collect::<mlua::Result<_>>()?;
+        functions.push(Registered { name: entry.get("name")?, callback: entry.get("callback")?, flags });
+    }
+    Ok(functions)
+}
+
+/// Splits the code of a library into its name, given on the `#!lua name=...`
+/// line it starts with, and the Lua code that follows. The code keeps the
+/// newline ending that line so that errors report the right line numbers.
+fn parse_metadata(code: &str) -> Result<(String, &str), String> {
+    let first = code.split('\n').next().unwrap_or_default();
+    let body = &code[first.len()..];
+    let Some(shebang) = first.strip_prefix("#!") else {
+        return Err("ERR Missing library metadata".into());
+    };
+    let mut parts = shebang.split_whitespace();
+    let engine = parts.next().unwrap_or_default();
+    if !engine.eq_ignore_ascii_case("lua") {
+        return Err(format!("ERR Engine '{}' not found", engine));
+    }
+    let mut name = None;
+    for part in parts {
+        match part.split_once('=') {
+            Some(("name", value)) if name.is_none() => name = Some(value.to_string()),
+            Some(("name", _)) => {
+                return Err("ERR Invalid metadata value, name argument was given multiple times".into())
+            }
+            _ => return Err(format!("ERR Invalid metadata value given: {}", part)),
+        }
+    }
+    let Some(name) = name else {
+        return Err("ERR Library name was not given".into());
+    };
+    if !valid_name(&name) {
+        return Err(
+            "ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long"
+                .into(),
+        );
+    }
+    Ok((name, body))
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
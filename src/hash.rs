@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::encoding::{limit, LIMITS};
+use crate::listpack::ListPack;
+use crate::value::sampled_size;
+
+#[derive(Debug)]
+enum Fields {
+    /// Field and value entries alternating.
+    ListPack(ListPack),
+    Table(HashMap<Vec<u8>, Vec<u8>>),
+}
+
+/// A hash value along with optional per-field deadlines.
+///
+/// Small hashes keep their fields in a listpack and move to a table once
+/// they pass `hash-max-listpack-entries` or `hash-max-listpack-value`.
+/// Deadlines are absolute unix times in milliseconds so they survive an AOF
+/// replay unchanged.
+#[derive(Debug)]
+pub struct HashState {
+    fields: Fields,
+    expires: HashMap<Vec<u8>, u64>,
+    /// The earliest deadline in `expires`.
+    next_expire: Option<u64>,
+}
+
+impl Default for HashState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HashState {
+    pub fn new() -> Self {
+        Self {
+            fields: Fields::ListPack(ListPack::new()),
+            expires: HashMap::new(),
+            next_expire: None,
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self.fields {
+            Fields::ListPack(_) => "listpack",
+            Fields::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.fields {
+            Fields::ListPack(lp) => lp.len() / 2,
+            Fields::Table(t) => t.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Estimated heap bytes held by the fields and their deadlines.
+    pub fn mem_usage(&self, samples: usize) -> usize {
+        let fields = match &self.fields {
+            Fields::ListPack(lp) => lp.mem_usage(),
+            Fields::Table(t) => sampled_size(
+                t.len(),
+                2 * size_of::<Vec<u8>>() + size_of::<usize>(),
+                samples,
+                t.iter().map(|(f, v)| f.capacity() + v.capacity()),
+            ),
+        };
+        let expires = sampled_size(
+            self.expires.len(),
+            size_of::<(Vec<u8>, u64)>() + size_of::<usize>(),
+            samples,
+            self.expires.keys().map(|f| f.capacity()),
+        );
+        fields + expires
+    }
+
+    /// Position of `field` among the packed pairs.
+    fn packed_position(lp: &ListPack, field: &[u8]) -> Option<usize> {
+        lp.iter().step_by(2).position(|f| f == field)
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&[u8]> {
+        match &self.fields {
+            Fields::ListPack(lp) => {
+                let pos = Self::packed_position(lp, field)?;
+                lp.get(pos * 2 + 1)
+            }
+            Fields::Table(t) => t.get(field).map(|v| v.as_slice()),
+        }
+    }
+
+    pub fn contains_key(&self, field: &[u8]) -> bool {
+        match &self.fields {
+            Fields::ListPack(lp) => Self::packed_position(lp, field).is_some(),
+            Fields::Table(t) => t.contains_key(field),
+        }
+    }
+
+    /// Sets a field, discarding any deadline it had (HSET semantics).
+    pub fn insert(&mut self, field: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.forget_deadline(&field);
+        self.store(field, value)
+    }
+
+    /// Overwrites a field's value but keeps its deadline (HINCRBY semantics).
+    pub fn update(&mut self, field: Vec<u8>, value: Vec<u8>) {
+        self.store(field, value);
+    }
+
+    fn store(&mut self, field: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>> {
+        let max_value = limit(&LIMITS.hash_max_listpack_value);
+        if let Fields::ListPack(lp) = &self.fields {
+            let fits = field.len() <= max_value && value.len() <= max_value;
+            let grows = !self.contains_key(&field);
+            if !fits || (grows && lp.len() / 2 >= limit(&LIMITS.hash_max_listpack_entries)) {
+                self.convert_to_table();
+            }
+        }
+
+        match &mut self.fields {
+            Fields::ListPack(lp) => match Self::packed_position(lp, &field) {
+                Some(pos) => {
+                    let old = lp.get(pos * 2 + 1).map(|v| v.to_vec());
+                    lp.replace(pos * 2 + 1, &value);
+                    old
+                }
+                None => {
+                    lp.push_back(&field);
+                    lp.push_back(&value);
+                    None
+                }
+            },
+            Fields::Table(t) => t.insert(field, value),
+        }
+    }
+
+    fn convert_to_table(&mut self) {
+        if let Fields::ListPack(_) = self.fields {
+            let table = self.iter().map(|(f, v)| (f.to_vec(), v.to_vec())).collect();
+            self.fields = Fields::Table(table);
+        }
+    }
+
+    pub fn remove(&mut self, field: &[u8]) -> Option<Vec<u8>> {
+        self.forget_deadline(field);
+        match &mut self.fields {
+            Fields::ListPack(lp) => {
+                let pos = Self::packed_position(lp, field)?;
+                lp.remove(pos * 2);
+                Some(lp.remove(pos * 2))
+            }
+            Fields::Table(t) => t.remove(field),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&[u8], &[u8])> + '_> {
+        match &self.fields {
+            Fields::ListPack(lp) => Box::new(lp.iter().step_by(2).zip(lp.iter().skip(1).step_by(2))),
+            Fields::Table(t) => Box::new(t.iter().map(|(f, v)| (f.as_slice(), v.as_slice()))),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.iter().map(|(f, _)| f)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &[u8]> {
+        self.iter().map(|(_, v)| v)
+    }
+
+    pub fn expire_at(&self, field: &[u8]) -> Option<u64> {
+        self.expires.get(field).copied()
+    }
+
+    /// Sets a deadline on an existing field. Returns false if the field is missing.
+    pub fn set_expire_at(&mut self, field: &[u8], at_ms: u64) -> bool {
+        if !self.contains_key(field) {
+            return false;
+        }
+        if let Some(old) = self.expires.insert(field.to_vec(), at_ms) {
+            if Some(old) == self.next_expire && at_ms > old {
+                self.next_expire = self.expires.values().min().copied();
+                return true;
+            }
+        }
+        self.next_expire = Some(self.next_expire.map_or(at_ms, |next| next.min(at_ms)));
+        true
+    }
+
+    pub fn persist(&mut self, field: &[u8]) -> bool {
+        self.forget_deadline(field)
+    }
+
+    /// Drops the deadline of `field`, if any, keeping `next_expire` exact.
+    /// Only losing the earliest deadline costs a pass over the others.
+    fn forget_deadline(&mut self, field: &[u8]) -> bool {
+        let Some(at) = self.expires.remove(field) else {
+            return false;
+        };
+        if Some(at) == self.next_expire {
+            self.next_expire = self.expires.values().min().copied();
+        }
+        true
+    }
+
+    /// Whether any field carries a deadline.
+    pub fn has_field_ttls(&self) -> bool {
+        !self.expires.is_empty()
+    }
+
+    pub fn has_expired(&self, now_ms: u64) -> bool {
+        self.next_expire.is_some_and(|at| at <= now_ms)
+    }
+
+    /// Drops every field whose deadline has passed, returning how many went.
+    pub fn purge_expired(&mut self, now_ms: u64) -> usize {
+        if !self.has_expired(now_ms) {
+            return 0;
+        }
+        let expired: Vec<Vec<u8>> = self.expires.iter()
+            .filter(|(_, at)| **at <= now_ms)
+            .map(|(f, _)| f.clone())
+            .collect();
+
+        for f in &expired {
+            self.expires.remove(f);
+            self.remove(f);
+        }
+        self.next_expire = self.expires.values().min().copied();
+        expired.len()
+    }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    fn field(rng: &mut StdRng) -> Vec<u8> {
+        format!("f{}", rng.gen_range(0..200)).into_bytes()
+    }
+
+    fn value(rng: &mut StdRng) -> Vec<u8> {
+        let len = match rng.gen_range(0..40) {
+            0 => limit(&LIMITS.hash_max_listpack_value) + 1,
+            1 => limit(&LIMITS.hash_max_listpack_value),
+            _ => rng.gen_range(0..10),
+        };
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    fn assert_matches(hash: &HashState, model: &HashMap<Vec<u8>, Vec<u8>>) {
+        assert_eq!(hash.len(), model.len());
+        let pairs: HashMap<Vec<u8>, Vec<u8>> = hash.iter().map(|(f, v)| (f.to_vec(), v.to_vec())).collect();
+        assert_eq!(&pairs, model);
+        for (f, v) in model {
+            assert_eq!(hash.get(f), Some(v.as_slice()));
+        }
+    }
+
+    #[test]
+    fn converts_at_the_limits() {
+        let value_max = limit(&LIMITS.hash_max_listpack_value);
+        let mut hash = HashState::new();
+        hash.insert(vec![b'f'; value_max], vec![b'v'; value_max]);
+        assert_eq!(hash.encoding(), "listpack");
+        hash.insert(b"f".to_vec(), vec![b'v'; value_max + 1]);
+        assert_eq!(hash.encoding(), "hashtable");
+
+        let mut hash = HashState::new();
+        hash.insert(vec![b'f'; value_max + 1], b"v".to_vec());
+        assert_eq!(hash.encoding(), "hashtable");
+
+        let entries_max = limit(&LIMITS.hash_max_listpack_entries);
+        let mut hash = HashState::new();
+        for i in 0..entries_max {
+            hash.insert(format!("f{}", i).into_bytes(), b"v".to_vec());
+        }
+        assert_eq!(hash.insert(b"f0".to_vec(), b"w".to_vec()), Some(b"v".to_vec()));
+        assert_eq!(hash.encoding(), "listpack");
+        hash.insert(b"new".to_vec(), b"v".to_vec());
+        assert_eq!(hash.encoding(), "hashtable");
+        assert_eq!(hash.len(), entries_max + 1);
+        assert_eq!(hash.get(b"f0"), Some(&b"w"[..]));
+    }
+
+    #[test]
+    fn matches_hashmap_across_conversion() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..20 {
+            let mut hash = HashState::new();
+            let mut model = HashMap::new();
+            for round in 0..600 {
+                let f = field(&mut rng);
+                if rng.gen_range(0..4) == 0 {
+                    assert_eq!(hash.remove(&f), model.remove(&f));
+                } else {
+                    let v = value(&mut rng);
+                    let expected = if hash.encoding() == "hashtable"
+                        || v.len() > limit(&LIMITS.hash_max_listpack_value)
+                        || (!model.contains_key(&f) && model.len() >= limit(&LIMITS.hash_max_listpack_entries))
+                    {
+                        "hashtable"
+                    } else {
+                        "listpack"
+                    };
+                    if rng.gen() {
+                        assert_eq!(hash.insert(f.clone(), v.clone()), model.insert(f.clone(), v));
+                    } else {
+                        hash.update(f.clone(), v.clone());
+                        model.insert(f.clone(), v);
+                    }
+                    assert_eq!(hash.encoding(), expected);
+                }
+                assert_eq!(hash.contains_key(&f), model.contains_key(&f));
+                if round % 50 == 0 {
+                    assert_matches(&hash, &model);
+                }
+            }
+            assert_matches(&hash, &model);
+        }
+    }
+
+    /// The cached earliest deadline must always be the true minimum.
+    fn assert_next_expire(hash: &HashState) {
+        assert_eq!(hash.next_expire, hash.expires.values().min().copied());
+    }
+
+    fn with_fields(n: usize) -> HashState {
+        let mut hash = HashState::new();
+        for i in 0..n {
+            hash.insert(format!("f{}", i).into_bytes(), b"v".to_vec());
+        }
+        hash
+    }
+
+    #[test]
+    fn next_expire_follows_each_change() {
+        let mut hash = with_fields(4);
+        assert!(!hash.set_expire_at(b"missing", 10));
+        assert_next_expire(&hash);
+
+        hash.set_expire_at(b"f0", 300);
+        hash.set_expire_at(b"f1", 100);
+        hash.set_expire_at(b"f2", 200);
+        assert_eq!(hash.next_expire, Some(100));
+
+        // Moving the earliest deadline later hands the lead to the next one.
+        hash.set_expire_at(b"f1", 400);
+        assert_eq!(hash.next_expire, Some(200));
+        hash.set_expire_at(b"f0", 50);
+        assert_eq!(hash.next_expire, Some(50));
+        hash.set_expire_at(b"f0", 50);
+        assert_eq!(hash.next_expire, Some(50));
+
+        assert!(hash.persist(b"f0"));
+        assert_eq!(hash.next_expire, Some(200));
+        assert!(!hash.persist(b"f0"));
+
+        // HSET drops the deadline, HINCRBY-style updates keep it.
+        hash.update(b"f2".to_vec(), b"w".to_vec());
+        assert_eq!(hash.next_expire, Some(200));
+        hash.insert(b"f2".to_vec(), b"w".to_vec());
+        assert_eq!(hash.next_expire, Some(400));
+
+        hash.remove(b"f1");
+        assert_eq!(hash.next_expire, None);
+        assert!(!hash.has_field_ttls());
+
+        hash.set_expire_at(b"f3", 500);
+        hash.set_expire_at(b"f2", 600);
+        assert_eq!(hash.purge_expired(499), 0);
+        assert_eq!(hash.purge_expired(500), 1);
+        assert_eq!(hash.next_expire, Some(600));
+        assert!(!hash.contains_key(b"f3"));
+        assert!(!hash.has_expired(599));
+        assert!(hash.has_expired(600));
+        assert_eq!(hash.purge_expired(1000), 1);
+        assert_eq!(hash.next_expire, None);
+        assert_eq!(hash.len(), 1);
+    }
+
+    #[test]
+    fn next_expire_stays_exact_under_random_operations() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut hash = with_fields(0);
+        let mut now = 0;
+        for _ in 0..5000 {
+            let f = format!("f{}", rng.gen_range(0..150)).into_bytes();
+            match rng.gen_range(0..10) {
+                0 | 1 => {
+                    hash.insert(f, b"v".to_vec());
+                }
+                2 => hash.update(f, b"w".to_vec()),
+                3..=5 => {
+                    hash.set_expire_at(&f, now + rng.gen_range(0..50));
+                }
+                6 => {
+                    hash.persist(&f);
+                }
+                7 => {
+                    hash.remove(&f);
+                }
+                _ => {
+                    now += rng.gen_range(0..5);
+                    let before = hash.len();
+                    let expired = hash.expires.values().filter(|at| **at <= now).count();
+                    assert_eq!(hash.purge_expired(now), expired);
+                    assert_eq!(hash.len(), before - expired);
+                    assert!(!hash.has_expired(now));
+                }
+            }
+            assert_next_expire(&hash);
+            assert!(hash.expires.keys().all(|f| hash.contains_key(f)));
+        }
+    }
+}
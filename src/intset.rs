@@ -0,0 +1,135 @@
+/// A set of integers kept as a sorted vector, used for sets whose members
+/// all parse as canonical 64-bit integers.
+#[derive(Debug, Clone, Default)]
+pub struct IntSet {
+    values: Vec<i64>,
+}
+
+impl IntSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn contains(&self, value: i64) -> bool {
+        self.values.binary_search(&value).is_ok()
+    }
+
+    pub fn insert(&mut self, value: i64) -> bool {
+        match self.values.binary_search(&value) {
+            Ok(_) => false,
+            Err(pos) => {
+                self.values.insert(pos, value);
+                true
+            }
+        }
+    }
+
+    pub fn remove(&mut self, value: i64) -> bool {
+        match self.values.binary_search(&value) {
+            Ok(pos) => {
+                self.values.remove(pos);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> Option<i64> {
+        self.values.get(idx).copied()
+    }
+
+    pub fn remove_at(&mut self, idx: usize) -> i64 {
+        self.values.remove(idx)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = i64> + '_ {
+        self.values.iter().copied()
+    }
+}
+
+/// Parses `bytes` as an integer only if formatting it back gives the same
+/// bytes, so that "007" or "+1" keep their exact spelling.
+pub fn parse_canonical(bytes: &[u8]) -> Option<i64> {
+    if bytes.len() > 20 {
+        return None;
+    }
+    let n = std::str::from_utf8(bytes).ok()?.parse::<i64>().ok()?;
+    (n.to_string().as_bytes() == bytes).then_some(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    fn assert_matches(set: &IntSet, model: &BTreeSet<i64>) {
+        assert_eq!(set.len(), model.len());
+        assert_eq!(set.iter().collect::<Vec<_>>(), model.iter().copied().collect::<Vec<_>>());
+        for (i, n) in model.iter().enumerate() {
+            assert_eq!(set.get(i), Some(*n));
+        }
+        assert_eq!(set.get(model.len()), None);
+    }
+
+    #[test]
+    fn matches_btreeset_under_random_operations() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut set = IntSet::new();
+        let mut model = BTreeSet::new();
+
+        for round in 0..3000 {
+            let n = match rng.gen_range(0..10) {
+                0 => [i64::MIN, i64::MAX, 0, -1][rng.gen_range(0..4)],
+                _ => rng.gen_range(-200..200),
+            };
+            match rng.gen_range(0..10) {
+                0..=4 => assert_eq!(set.insert(n), model.insert(n)),
+                5..=7 => assert_eq!(set.remove(n), model.remove(&n)),
+                8 if !model.is_empty() => {
+                    let idx = rng.gen_range(0..model.len());
+                    let expected = *model.iter().nth(idx).unwrap();
+                    model.remove(&expected);
+                    assert_eq!(set.remove_at(idx), expected);
+                }
+                _ => {}
+            }
+            assert_eq!(set.contains(n), model.contains(&n));
+            if round % 50 == 0 {
+                assert_matches(&set, &model);
+            }
+        }
+        assert_matches(&set, &model);
+    }
+
+    #[test]
+    fn parses_only_canonical_integers() {
+        for (input, expected) in [
+            (&b"0"[..], Some(0)),
+            (b"-1", Some(-1)),
+            (b"9223372036854775807", Some(i64::MAX)),
+            (b"-9223372036854775808", Some(i64::MIN)),
+            (b"9223372036854775808", None),
+            (b"007", None),
+            (b"+1", None),
+            (b"-0", None),
+            (b" 1", None),
+            (b"1.0", None),
+            (b"", None),
+            (b"000000000000000000001", None),
+        ] {
+            assert_eq!(parse_canonical(input), expected, "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+}
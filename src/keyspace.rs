@@ -0,0 +1,849 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::mem::size_of;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use indexmap::{IndexMap, IndexSet};
+use rand::Rng;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::eviction::Access;
+use crate::lazyfree::{self, CONFIG as LAZYFREE};
+use crate::value::{Value, DEFAULT_MEM_SAMPLES};
+
+/// Number of shards used by [`Keyspace::default`].
+pub const DEFAULT_SHARDS: usize = 64;
+
+/// Bookkeeping bytes charged to every key on top of its name and value: the
+/// map slot, the entry itself and the index the map keeps alongside.
+const ENTRY_OVERHEAD: usize = size_of::<(String, Entry)>() + 2 * size_of::<usize>();
+/// Extra bytes charged to keys with a deadline for their place in the
+/// volatile set.
+const VOLATILE_OVERHEAD: usize = size_of::<String>() + 2 * size_of::<usize>();
+
+/// A stored value along with its deadline, if it has one.
+#[derive(Debug)]
+pub struct Entry {
+    pub value: Value,
+    expires_at: Option<Instant>,
+    /// Estimated bytes held by this entry, as last accounted in the shard.
+    size: usize,
+    access: Access,
+}
+
+impl Entry {
+    fn new(value: Value) -> Self {
+        Self {
+            value,
+            expires_at: None,
+            size: 0,
+            access: Access::new(),
+        }
+    }
+
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.expires_at
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| now >= at)
+    }
+
+    /// Estimated bytes held by the key, its value and its bookkeeping.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn access(&self) -> &Access {
+        &self.access
+    }
+
+    /// Estimated bytes held by `key` stored with this entry, measuring
+    /// `samples` elements of large values (all if 0).
+    pub fn mem_usage(&self, key: &str, samples: usize) -> usize {
+        let volatile = match self.expires_at {
+            Some(_) => VOLATILE_OVERHEAD + key.len(),
+            None => 0,
+        };
+        ENTRY_OVERHEAD + key.len() + self.value.mem_usage(samples) + volatile
+    }
+
+    fn footprint(&self, key: &str) -> usize {
+        self.mem_usage(key, DEFAULT_MEM_SAMPLES)
+    }
+}
+
+/// Estimated bytes held by the keyspace, shared by all shards.
+#[derive(Debug, Default)]
+struct Usage {
+    used: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl Usage {
+    fn add(&self, bytes: usize) {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.peak.fetch_max(used, Ordering::Relaxed);
+    }
+
+    fn sub(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Point-in-time figures behind `MEMORY STATS`.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryStats {
+    pub used: usize,
+    pub peak: usize,
+    pub keys: usize,
+    pub volatile_keys: usize,
+    /// Bookkeeping bytes of the main maps, excluding key names.
+    pub overhead_main: usize,
+    /// Bookkeeping bytes of the volatile sets, excluding key names.
+    pub overhead_expires: usize,
+}
+
+/// A key some clients are WATCHing.
+#[derive(Debug, Default)]
+struct Watched {
+    clients: usize,
+    /// Bumped by every change to the key while it is watched.
+    version: u64,
+}
+
+/// One partition of the keyspace.
+///
+/// Keys with a deadline are also kept in an indexed set so that active
+/// expiry can sample them uniformly; both maps support O(1) random access
+/// for the same reason. Hashes with field deadlines are tracked the same
+/// way, in a set kept up to date whenever such a key is stored, resized or
+/// removed. Deadlines can only be changed through these methods
+/// so that the set always matches the entries that carry one, and every
+/// change is reflected in the keyspace's memory estimate and in the version
+/// of the key if it is watched.
+#[derive(Debug)]
+pub struct Shard {
+    entries: IndexMap<String, Entry>,
+    volatile: IndexSet<String>,
+    /// Keys holding hashes with at least one field deadline.
+    volatile_hashes: IndexSet<String>,
+    watched: HashMap<String, Watched>,
+    usage: Arc<Usage>,
+    /// This shard's share of `usage`.
+    used: usize,
+}
+
+impl Shard {
+    fn new(usage: Arc<Usage>) -> Self {
+        Self {
+            entries: IndexMap::new(),
+            volatile: IndexSet::new(),
+            volatile_hashes: IndexSet::new(),
+            watched: HashMap::new(),
+            usage,
+            used: 0,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Entry> {
+        self.entries.get(key)
+    }
+
+    /// Callers that change the value must call [`Shard::resize`] and
+    /// [`Shard::touch`] afterwards.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.entries.get_mut(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Entry)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Re-estimates the memory held by `key` after its value changed in place.
+    pub fn resize(&mut self, key: &str) {
+        self.track_field_ttls(key);
+        let Some(entry) = self.entries.get_mut(key) else {
+            return;
+        };
+        let size = entry.footprint(key);
+        let old = std::mem::replace(&mut entry.size, size);
+        self.used = self.used + size - old;
+        if size >= old {
+            self.usage.add(size - old);
+        } else {
+            self.usage.sub(old - size);
+        }
+    }
+
+    /// Adds `key` to or drops it from the hashes with field deadlines.
+    fn track_field_ttls(&mut self, key: &str) {
+        match self.entries.get(key).map(|e| &e.value) {
+            Some(Value::Hash(map)) if map.has_field_ttls() => {
+                if !self.volatile_hashes.contains(key) {
+                    self.volatile_hashes.insert(key.to_string());
+                }
+            }
+            _ => {
+                self.volatile_hashes.swap_remove(key);
+            }
+        }
+    }
+
+    fn charge(&mut self, key: &str, entry: &mut Entry) {
+        entry.size = entry.footprint(key);
+        self.used += entry.size;
+        self.usage.add(entry.size);
+    }
+
+    /// Stores `value` at `key` without a deadline, returning the old entry.
+    pub fn insert(&mut self, key: String, value: Value) -> Option<Entry> {
+        self.insert_entry(key, Entry::new(value))
+    }
+
+    /// Stores `entry` at `key` as is, deadline included, returning the old
+    /// entry.
+    pub fn insert_entry(&mut self, key: String, mut entry: Entry) -> Option<Entry> {
+        let old = self.remove(&key);
+        if entry.expires_at.is_some() {
+            self.volatile.insert(key.clone());
+        }
+        if matches!(&entry.value, Value::Hash(map) if map.has_field_ttls()) {
+            self.volatile_hashes.insert(key.clone());
+        }
+        self.touch(&key);
+        self.charge(&key, &mut entry);
+        self.entries.insert(key, entry);
+        old
+    }
+
+    /// Callers that change the value must call [`Shard::resize`] and
+    /// [`Shard::touch`] afterwards. Storing the default alone is no change.
+    pub fn get_or_insert_with(&mut self, key: String, default: impl FnOnce() -> Value) -> &mut Entry {
+        if !self.entries.contains_key(&key) {
+            let mut entry = Entry::new(default());
+            self.charge(&key, &mut entry);
+            return self.entries.entry(key).or_insert(entry);
+        }
+        self.entries.get_mut(&key).expect("checked above")
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.swap_remove(key)?;
+        if entry.expires_at.is_some() {
+            self.volatile.swap_remove(key);
+        }
+        self.volatile_hashes.swap_remove(key);
+        self.touch(key);
+        self.used -= entry.size;
+        self.usage.sub(entry.size);
+        Some(entry)
+    }
+
+    /// Sets a deadline on an existing key. Returns false if the key is absent.
+    pub fn set_expires_at(&mut self, key: &str, at: Instant) -> bool {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return false;
+        };
+        if entry.expires_at.replace(at).is_none() {
+            self.volatile.insert(key.to_string());
+            self.resize(key);
+        }
+        self.touch(key);
+        true
+    }
+
+    /// Clears the deadline of `key`. Returns whether it had one.
+    pub fn persist(&mut self, key: &str) -> bool {
+        let had = self.entries.get_mut(key).is_some_and(|e| e.expires_at.take().is_some());
+        if had {
+            self.volatile.swap_remove(key);
+            self.resize(key);
+            self.touch(key);
+        }
+        had
+    }
+
+    /// Exchanges contents with a shard of a sibling keyspace.
+    pub fn swap_contents(&mut self, other: &mut Shard) {
+        std::mem::swap(&mut self.entries, &mut other.entries);
+        std::mem::swap(&mut self.volatile, &mut other.volatile);
+        std::mem::swap(&mut self.volatile_hashes, &mut other.volatile_hashes);
+        std::mem::swap(&mut self.used, &mut other.used);
+        self.touch_all();
+        other.touch_all();
+    }
+
+    /// Empties the shard, handing back everything it held.
+    pub fn clear(&mut self) -> IndexMap<String, Entry> {
+        self.touch_all();
+        self.volatile.clear();
+        self.volatile_hashes.clear();
+        self.usage.sub(std::mem::take(&mut self.used));
+        std::mem::take(&mut self.entries)
+    }
+
+    /// Starts tracking changes to `key` for one more client, returning its
+    /// current version.
+    pub fn watch(&mut self, key: &str) -> u64 {
+        let watched = self.watched.entry(key.to_string()).or_default();
+        watched.clients += 1;
+        watched.version
+    }
+
+    /// Undoes one [`Shard::watch`] of `key`.
+    pub fn unwatch(&mut self, key: &str) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.clients -= 1;
+            if watched.clients == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    /// How many times `key` changed since it was first watched; 0 if it is
+    /// not watched.
+    pub fn version(&self, key: &str) -> u64 {
+        self.watched.get(key).map_or(0, |w| w.version)
+    }
+
+    /// Records a change to `key`, bumping its version if it is watched.
+    pub fn touch(&mut self, key: &str) {
+        if self.watched.is_empty() {
+            return;
+        }
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.version += 1;
+        }
+    }
+
+    fn touch_all(&mut self) {
+        for watched in self.watched.values_mut() {
+            watched.version += 1;
+        }
+    }
+
+    /// Number of keys with a deadline.
+    pub fn volatile_len(&self) -> usize {
+        self.volatile.len()
+    }
+
+    /// Up to `count` randomly chosen entries, from the keys with a deadline
+    /// only if `volatile` is set. May repeat entries.
+    pub fn sample(&self, count: usize, volatile: bool) -> Vec<(&str, &Entry)> {
+        let mut rng = rand::thread_rng();
+        let len = if volatile { self.volatile.len() } else { self.entries.len() };
+        if len == 0 {
+            return Vec::new();
+        }
+
+        (0..count)
+            .filter_map(|_| {
+                let idx = rng.gen_range(0..len);
+                if volatile {
+                    self.entries.get_key_value(self.volatile.get_index(idx)?.as_str())
+                } else {
+                    self.entries.get_index(idx)
+                }
+            })
+            .map(|(k, e)| (k.as_str(), e))
+            .collect()
+    }
+
+    /// Checks up to `count` randomly chosen keys with a deadline and removes
+    /// the ones that have expired. Returns how many were checked and how many
+    /// removed.
+    pub fn expire_sample(&mut self, count: usize, now: Instant) -> (usize, usize) {
+        let mut rng = rand::thread_rng();
+        let mut sampled = 0;
+        let mut expired = 0;
+
+        while sampled < count && !self.volatile.is_empty() {
+            let pos = rng.gen_range(0..self.volatile.len());
+            sampled += 1;
+
+            let key = &self.volatile[pos];
+            if self.entries.get(key).is_some_and(|e| e.is_expired(now)) {
+                let key = key.clone();
+                if let Some(entry) = self.remove(&key) {
+                    lazyfree::dispose(entry.value, lazyfree::enabled(&LAZYFREE.lazy_expire));
+                }
+                expired += 1;
+            }
+        }
+        (sampled, expired)
+    }
+
+    /// Checks up to `count` randomly chosen hashes with field deadlines and
+    /// drops their expired fields, removing hashes left empty. Returns how
+    /// many were checked and how many had expired fields.
+    pub fn expire_fields_sample(&mut self, count: usize, now_ms: u64) -> (usize, usize) {
+        let mut rng = rand::thread_rng();
+        let mut sampled = 0;
+        let mut expired = 0;
+
+        while sampled < count && !self.volatile_hashes.is_empty() {
+            let pos = rng.gen_range(0..self.volatile_hashes.len());
+            sampled += 1;
+
+            let key = &self.volatile_hashes[pos];
+            let due = self.entries.get(key)
+                .is_some_and(|e| matches!(&e.value, Value::Hash(map) if map.has_expired(now_ms)));
+            if !due {
+                continue;
+            }
+            let key = key.clone();
+            let emptied = match self.entries.get_mut(&key).map(|e| &mut e.value) {
+                Some(Value::Hash(map)) => {
+                    map.purge_expired(now_ms);
+                    map.is_empty()
+                }
+                _ => false,
+            };
+            if emptied {
+                self.remove(&key);
+            } else {
+                self.resize(&key);
+                self.touch(&key);
+            }
+            expired += 1;
+        }
+        (sampled, expired)
+    }
+}
+
+/// Lock guards a [`Guard`] can hold. Only write guards hand out the shard
+/// mutably, which a guard needs to settle memory accounting when dropped.
+pub trait ShardLock: Deref<Target = Shard> {
+    fn shard_mut(&mut self) -> Option<&mut Shard>;
+}
+
+impl ShardLock for RwLockReadGuard<'_, Shard> {
+    fn shard_mut(&mut self) -> Option<&mut Shard> {
+        None
+    }
+}
+
+impl ShardLock for RwLockWriteGuard<'_, Shard> {
+    fn shard_mut(&mut self) -> Option<&mut Shard> {
+        Some(self)
+    }
+}
+
+/// The keyspace, split into independently locked shards by key hash.
+///
+/// Commands lock only the shards their keys live in. Multi-key commands lock
+/// every shard they need up front, always in ascending shard order, so two
+/// commands can never each hold a shard the other is waiting for. Callers
+/// must not acquire a second guard while holding one.
+#[derive(Debug)]
+pub struct Keyspace {
+    shards: Box<[RwLock<Shard>]>,
+    hasher: RandomState,
+    usage: Arc<Usage>,
+}
+
+impl Default for Keyspace {
+    fn default() -> Self {
+        Self::new(DEFAULT_SHARDS)
+    }
+}
+
+impl Keyspace {
+    pub fn new(shards: usize) -> Self {
+        Self::with_parts(shards, RandomState::new(), Arc::new(Usage::default()))
+    }
+
+    fn with_parts(shards: usize, hasher: RandomState, usage: Arc<Usage>) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| RwLock::new(Shard::new(usage.clone()))).collect(),
+            hasher,
+            usage,
+        }
+    }
+
+    /// An empty keyspace that places keys in the same shards as this one and
+    /// shares its memory accounting. Only siblings can swap contents.
+    pub fn sibling(&self) -> Self {
+        Self::with_parts(self.shards.len(), self.hasher.clone(), self.usage.clone())
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Estimated bytes held by all keys and values, here and in siblings.
+    pub fn used_memory(&self) -> usize {
+        self.usage.used.load(Ordering::Relaxed)
+    }
+
+    /// Number of stored keys, including expired ones not yet swept. Counted
+    /// shard by shard, so not an atomic snapshot.
+    pub async fn len(&self) -> usize {
+        let mut len = 0;
+        for shard in self.shards.iter() {
+            len += shard.read().await.len();
+        }
+        len
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        for shard in self.shards.iter() {
+            if !shard.read().await.is_empty() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// A shard picked with probability proportional to its number of keys,
+    /// so that sampling it gives every key the same chance; None if all are
+    /// empty. Sizes are read shard by shard, so not an atomic snapshot.
+    pub async fn random_shard(&self) -> Option<usize> {
+        let mut lens = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            lens.push(shard.read().await.len());
+        }
+        let total: usize = lens.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let mut pick = rand::thread_rng().gen_range(0..total);
+        lens.iter().position(|&len| {
+            if pick < len {
+                return true;
+            }
+            pick -= len;
+            false
+        })
+    }
+
+    /// Counts keys shard by shard, so the figures are not an atomic snapshot.
+    pub async fn memory_stats(&self) -> MemoryStats {
+        let mut keys = 0;
+        let mut volatile_keys = 0;
+        for shard in self.shards.iter() {
+            let shard = shard.read().await;
+            keys += shard.len();
+            volatile_keys += shard.volatile_len();
+        }
+        MemoryStats {
+            used: self.used_memory(),
+            peak: self.usage.peak.load(Ordering::Relaxed),
+            keys,
+            volatile_keys,
+            overhead_main: keys * ENTRY_OVERHEAD,
+            overhead_expires: volatile_keys * VOLATILE_OVERHEAD,
+        }
+    }
+
+    pub fn shard_of(&self, key: &str) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    /// Sorted, deduplicated shard indexes for `keys`.
+    fn shard_indexes<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> Vec<usize> {
+        let mut idxs: Vec<usize> = keys.into_iter().map(|k| self.shard_of(k.as_ref())).collect();
+        idxs.sort_unstable();
+        idxs.dedup();
+        idxs
+    }
+
+    /// Read-locks the shards holding `keys`.
+    pub async fn read<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> ReadGuard<'_> {
+        let mut shards = Vec::new();
+        for idx in self.shard_indexes(keys) {
+            shards.push((idx, self.shards[idx].read().await));
+        }
+        Guard::new(self, shards)
+    }
+
+    /// Write-locks the shards holding `keys`.
+    pub async fn write<K: AsRef<str>>(&self, keys: impl IntoIterator<Item = K>) -> WriteGuard<'_> {
+        let mut shards = Vec::new();
+        for idx in self.shard_indexes(keys) {
+            shards.push((idx, self.shards[idx].write().await));
+        }
+        Guard::new(self, shards)
+    }
+
+    pub async fn read_all(&self) -> ReadGuard<'_> {
+        let mut shards = Vec::with_capacity(self.shards.len());
+        for (idx, shard) in self.shards.iter().enumerate() {
+            shards.push((idx, shard.read().await));
+        }
+        Guard::new(self, shards)
+    }
+
+    pub async fn write_all(&self) -> WriteGuard<'_> {
+        let mut shards = Vec::with_capacity(self.shards.len());
+        for (idx, shard) in self.shards.iter().enumerate() {
+            shards.push((idx, shard.write().await));
+        }
+        Guard::new(self, shards)
+    }
+
+    /// Write-locks a single shard by index, for background work that walks
+    /// the keyspace one shard at a time.
+    pub async fn write_shard(&self, idx: usize) -> RwLockWriteGuard<'_, Shard> {
+        self.shards[idx].write().await
+    }
+
+    pub async fn read_shard(&self, idx: usize) -> RwLockReadGuard<'_, Shard> {
+        self.shards[idx].read().await
+    }
+}
+
+/// A set of locked shards, addressed by key. This is the only way commands
+/// reach stored values.
+///
+/// Keys whose deadline has passed read as absent even before the background
+/// sweep gets to them, and write access drops them on the spot. Looking up a
+/// key whose shard was not locked is a bug and panics.
+pub struct Guard<'a, G: ShardLock> {
+    keyspace: &'a Keyspace,
+    shards: Vec<(usize, G)>,
+    /// Keys whose values were handed out mutably, re-measured on drop.
+    dirty: Vec<String>,
+}
+
+pub type ReadGuard<'a> = Guard<'a, RwLockReadGuard<'a, Shard>>;
+pub type WriteGuard<'a> = Guard<'a, RwLockWriteGuard<'a, Shard>>;
+
+impl<'a, G: ShardLock> Guard<'a, G> {
+    fn new(keyspace: &'a Keyspace, shards: Vec<(usize, G)>) -> Self {
+        Self { keyspace, shards, dirty: Vec::new() }
+    }
+
+    fn position(&self, key: &str) -> usize {
+        let idx = self.keyspace.shard_of(key);
+        self.shards
+            .iter()
+            .position(|(i, _)| *i == idx)
+            .unwrap_or_else(|| panic!("shard for key {:?} is not locked", key))
+    }
+
+    fn shard(&self, key: &str) -> &Shard {
+        &self.shards[self.position(key)].1
+    }
+
+    /// The live entry for `key`, if any.
+    fn live(&self, key: &str) -> Option<&Entry> {
+        let now = Instant::now();
+        self.shard(key).get(key).filter(|e| !e.is_expired(now))
+    }
+
+    /// The live entry for `key`, without counting this as an access.
+    pub fn peek(&self, key: &str) -> Option<&Entry> {
+        self.live(key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.live(key).map(|e| {
+            e.access.touch();
+            &e.value
+        })
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.live(key).is_some()
+    }
+
+    pub fn expires_at(&self, key: &str) -> Option<Instant> {
+        self.live(key).and_then(|e| e.expires_at)
+    }
+
+    /// Whether `key` is stored but its deadline has passed.
+    pub fn is_expired(&self, key: &str) -> bool {
+        let now = Instant::now();
+        self.shard(key).get(key).is_some_and(|e| e.is_expired(now))
+    }
+
+    /// Live keys in every locked shard.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        let now = Instant::now();
+        self.shards
+            .iter()
+            .flat_map(|(_, s)| s.iter())
+            .filter(move |(_, e)| !e.is_expired(now))
+            .map(|(k, _)| k)
+    }
+
+    /// Number of stored keys in the locked shards, including expired ones
+    /// not yet swept.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|(_, s)| s.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// See [`Shard::version`].
+    pub fn version(&self, key: &str) -> u64 {
+        self.shard(key).version(key)
+    }
+}
+
+impl<G: ShardLock + DerefMut<Target = Shard>> Guard<'_, G> {
+    fn shard_mut(&mut self, key: &str) -> &mut Shard {
+        let pos = self.position(key);
+        &mut self.shards[pos].1
+    }
+
+    fn mark_dirty(&mut self, key: &str) {
+        if !self.dirty.iter().any(|k| k == key) {
+            self.dirty.push(key.to_string());
+        }
+    }
+
+    /// The live entry for `key`, dropping it first if it has expired.
+    fn live_mut(&mut self, key: &str) -> Option<&mut Entry> {
+        self.purge(key);
+        self.shard_mut(key).get_mut(key)
+    }
+
+    /// See [`Shard::watch`].
+    pub fn watch(&mut self, key: &str) -> u64 {
+        self.shard_mut(key).watch(key)
+    }
+
+    pub fn unwatch(&mut self, key: &str) {
+        self.shard_mut(key).unwatch(key)
+    }
+
+    /// Drops `key` if its deadline has passed. Returns whether it did.
+    pub fn purge(&mut self, key: &str) -> bool {
+        if !self.is_expired(key) {
+            return false;
+        }
+        if let Some(entry) = self.shard_mut(key).remove(key) {
+            lazyfree::dispose(entry.value, lazyfree::enabled(&LAZYFREE.lazy_expire));
+        }
+        true
+    }
+
+    /// The value at `key`, to change in place. Callers must [`Guard::touch`]
+    /// the key if they do change it, and only then, so that a failed or
+    /// no-op command does not abort transactions watching the key.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.mark_dirty(key);
+        let entry = self.live_mut(key)?;
+        entry.access.touch();
+        Some(&mut entry.value)
+    }
+
+    /// The value at `key`, storing the result of `default` first if the key is
+    /// absent. As with [`Guard::get_mut`], callers must [`Guard::touch`] the
+    /// key once they change the value.
+    pub fn get_or_insert_with(&mut self, key: String, default: impl FnOnce() -> Value) -> &mut Value {
+        self.purge(&key);
+        self.mark_dirty(&key);
+        let entry = self.shard_mut(&key).get_or_insert_with(key, default);
+        entry.access.touch();
+        &mut entry.value
+    }
+
+    /// Records that the value at `key` changed in place.
+    pub fn touch(&mut self, key: &str) {
+        self.shard_mut(key).touch(key);
+    }
+
+    /// Stores `value` at `key`, discarding whatever was there along with its
+    /// deadline.
+    pub fn insert(&mut self, key: String, value: Value) {
+        self.purge(&key);
+        if let Some(old) = self.shard_mut(&key).insert(key, value) {
+            lazyfree::dispose(old.value, lazyfree::enabled(&LAZYFREE.lazy_server_del));
+        }
+    }
+
+    /// Deletes `key` as a side effect of a command, such as a store command
+    /// replacing its destination. Returns whether the key existed.
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.free(key, lazyfree::enabled(&LAZYFREE.lazy_server_del))
+    }
+
+    /// Deletes `key`, freeing a large value on the background thread if
+    /// `lazy` is set. Returns whether the key existed.
+    pub fn free(&mut self, key: &str, lazy: bool) -> bool {
+        self.purge(key);
+        match self.shard_mut(key).remove(key) {
+            Some(entry) => {
+                lazyfree::dispose(entry.value, lazy);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes `key` along with its deadline, for storing elsewhere.
+    pub fn take(&mut self, key: &str) -> Option<Entry> {
+        self.purge(key);
+        self.shard_mut(key).remove(key)
+    }
+
+    /// Stores a taken entry at `key`, replacing whatever was there.
+    pub fn put(&mut self, key: String, entry: Entry) {
+        self.purge(&key);
+        if let Some(old) = self.shard_mut(&key).insert_entry(key, entry) {
+            lazyfree::dispose(old.value, lazyfree::enabled(&LAZYFREE.lazy_server_del));
+        }
+    }
+
+    /// Exchanges the contents of every shard with `other`. Both guards must
+    /// hold all shards of sibling keyspaces.
+    pub fn swap_with(&mut self, other: &mut Self) {
+        assert_eq!(self.shards.len(), self.keyspace.shard_count(), "swap needs every shard locked");
+        assert_eq!(other.shards.len(), other.keyspace.shard_count(), "swap needs every shard locked");
+        for ((_, a), (_, b)) in self.shards.iter_mut().zip(other.shards.iter_mut()) {
+            a.swap_contents(b);
+        }
+    }
+
+    /// Empties every locked shard. With `lazy` the contents are freed on the
+    /// background thread.
+    pub fn clear(&mut self, lazy: bool) {
+        for (_, shard) in self.shards.iter_mut() {
+            let entries = shard.clear();
+            if lazy && !entries.is_empty() {
+                lazyfree::drop_in_background(entries);
+            }
+        }
+    }
+
+    /// Sets a deadline on an existing key. Returns false if the key is absent.
+    pub fn set_expires_at(&mut self, key: &str, at: Instant) -> bool {
+        self.purge(key);
+        self.shard_mut(key).set_expires_at(key, at)
+    }
+
+    /// Clears the deadline of `key`. Returns whether it had one.
+    pub fn persist(&mut self, key: &str) -> bool {
+        self.purge(key);
+        self.shard_mut(key).persist(key)
+    }
+}
+
+impl<G: ShardLock> Drop for Guard<'_, G> {
+    fn drop(&mut self) {
+        for key in std::mem::take(&mut self.dirty) {
+            let pos = self.position(&key);
+            if let Some(shard) = self.shards[pos].1.shard_mut() {
+                shard.resize(&key);
+            }
+        }
+    }
+}
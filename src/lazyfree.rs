@@ -0,0 +1,122 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::OnceLock;
+use std::thread;
+
+use crate::value::Value;
+
+/// Values that take more allocations than this to free are handed to the
+/// background thread when lazy freeing applies; smaller ones are cheaper to
+/// drop in place than to send. Same cutoff as Redis.
+const LAZYFREE_THRESHOLD: usize = 64;
+
+/// When deletions free memory on the background thread instead of while
+/// holding the keyspace locks. Names follow the `lazyfree-lazy-*` directives
+/// of `redis.conf`, and like there everything is off by default. UNLINK and
+/// FLUSHALL ASYNC are always lazy.
+#[derive(Debug)]
+pub struct LazyFreeConfig {
+    pub lazy_eviction: AtomicBool,
+    pub lazy_expire: AtomicBool,
+    /// Deletions that are a side effect of a command, such as the old value
+    /// of an overwritten key.
+    pub lazy_server_del: AtomicBool,
+    /// Makes DEL behave like UNLINK.
+    pub lazy_user_del: AtomicBool,
+    /// Makes FLUSHALL without a mode behave like FLUSHALL ASYNC.
+    pub lazy_user_flush: AtomicBool,
+}
+
+/// Every setting accepted by [`LazyFreeConfig::set`].
+pub const CONFIG_NAMES: &[&str] = &[
+    "lazyfree-lazy-eviction",
+    "lazyfree-lazy-expire",
+    "lazyfree-lazy-server-del",
+    "lazyfree-lazy-user-del",
+    "lazyfree-lazy-user-flush",
+];
+
+pub static CONFIG: LazyFreeConfig = LazyFreeConfig {
+    lazy_eviction: AtomicBool::new(false),
+    lazy_expire: AtomicBool::new(false),
+    lazy_server_del: AtomicBool::new(false),
+    lazy_user_del: AtomicBool::new(false),
+    lazy_user_flush: AtomicBool::new(false),
+};
+
+impl LazyFreeConfig {
+    /// Sets a toggle by its `redis.conf` name to `yes` or `no`.
+    pub fn set(&self, name: &str, value: &str) -> Result<(), String> {
+        let on = match value.to_ascii_lowercase().as_str() {
+            "yes" => true,
+            "no" => false,
+            _ => return Err(format!("invalid value for {}: {}", name, value)),
+        };
+        let toggle = match name {
+            "lazyfree-lazy-eviction" => &self.lazy_eviction,
+            "lazyfree-lazy-expire" => &self.lazy_expire,
+            "lazyfree-lazy-server-del" => &self.lazy_server_del,
+            "lazyfree-lazy-user-del" => &self.lazy_user_del,
+            "lazyfree-lazy-user-flush" => &self.lazy_user_flush,
+            _ => return Err(format!("unknown lazyfree setting: {}", name)),
+        };
+        toggle.store(on, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+pub fn enabled(toggle: &AtomicBool) -> bool {
+    toggle.load(Ordering::Relaxed)
+}
+
+/// Whether a flush is lazy: as asked with ASYNC or SYNC, or per
+/// `lazyfree-lazy-user-flush` if neither was given.
+pub fn user_flush(requested: Option<bool>) -> bool {
+    requested.unwrap_or_else(|| enabled(&CONFIG.lazy_user_flush))
+}
+
+type Garbage = Box<dyn Send>;
+
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// The background thread's queue, started on first use. Dropping is plain
+/// CPU work, so it runs on its own OS thread rather than on the runtime.
+fn queue() -> &'static Sender<Garbage> {
+    static QUEUE: OnceLock<Sender<Garbage>> = OnceLock::new();
+    QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Garbage>();
+        thread::Builder::new()
+            .name("lazyfree".into())
+            .spawn(move || {
+                for garbage in rx {
+                    drop(garbage);
+                    PENDING.fetch_sub(1, Ordering::Relaxed);
+                }
+            })
+            .expect("failed to spawn lazyfree thread");
+        tx
+    })
+}
+
+/// Drops `garbage` on the background thread.
+pub fn drop_in_background<T: Send + 'static>(garbage: T) {
+    PENDING.fetch_add(1, Ordering::Relaxed);
+    if let Err(mpsc::SendError(garbage)) = queue().send(Box::new(garbage)) {
+        PENDING.fetch_sub(1, Ordering::Relaxed);
+        drop(garbage);
+    }
+}
+
+/// Frees a value removed from the keyspace: on the background thread if
+/// `lazy` is set and the value is large enough to be worth it, in place
+/// otherwise.
+pub fn dispose(value: Value, lazy: bool) {
+    if lazy && value.free_effort() > LAZYFREE_THRESHOLD {
+        drop_in_background(value);
+    }
+}
+
+/// Objects queued for the background thread but not freed yet.
+pub fn pending() -> usize {
+    PENDING.load(Ordering::Relaxed)
+}
@@ -0,0 +1,26 @@
+pub mod server;
+pub mod connection;
+pub mod resp;
+pub mod command;
+pub mod errors;
+pub mod db;
+pub mod databases;
+pub mod keyspace;
+pub mod value;
+pub mod encoding;
+pub mod list;
+pub mod listpack;
+pub mod hash;
+pub mod intset;
+pub mod set;
+pub mod eviction;
+pub mod expiration;
+pub mod lazyfree;
+pub mod skiplist;
+pub mod zset;
+pub mod aof;
+pub mod blocking;
+pub mod scripting;
+pub mod lualib;
+pub mod functions;
+pub mod pubsub;
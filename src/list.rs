@@ -1,18 +1,251 @@
 use std::collections::VecDeque;
-use std::sync::Arc;
-use tokio::sync::Notify;
+use std::mem::size_of;
 
+use crate::command::ListEnd;
+use crate::encoding::LIMITS;
+use crate::listpack::ListPack;
+use crate::value::sampled_size;
+
+#[derive(Debug)]
+enum Encoding {
+    ListPack(ListPack),
+    Deque(VecDeque<Vec<u8>>),
+}
+
+/// A list value, packed into a listpack until it outgrows
+/// `list-max-listpack-size`.
 #[derive(Debug)]
 pub struct ListState {
-    pub data: VecDeque<Vec<u8>>,
-    pub notify: Arc<Notify>,
+    data: Encoding,
+}
+
+impl Default for ListState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ListState {
     pub fn new() -> Self {
         Self {
-            data: VecDeque::new(),
-            notify: Arc::new(Notify::new()),
+            data: Encoding::ListPack(ListPack::new()),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self.data {
+            Encoding::ListPack(_) => "listpack",
+            Encoding::Deque(_) => "quicklist",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.data {
+            Encoding::ListPack(lp) => lp.len(),
+            Encoding::Deque(d) => d.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Estimated heap bytes held by the list.
+    pub fn mem_usage(&self, samples: usize) -> usize {
+        match &self.data {
+            Encoding::ListPack(lp) => lp.mem_usage(),
+            Encoding::Deque(d) => sampled_size(d.len(), size_of::<Vec<u8>>(), samples, d.iter().map(|v| v.capacity())),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+        match &self.data {
+            Encoding::ListPack(lp) => Box::new(lp.iter()),
+            Encoding::Deque(d) => Box::new(d.iter().map(|v| v.as_slice())),
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> Option<Vec<u8>> {
+        match &self.data {
+            Encoding::ListPack(lp) => lp.get(idx).map(|v| v.to_vec()),
+            Encoding::Deque(d) => d.get(idx).cloned(),
+        }
+    }
+
+    pub fn set(&mut self, idx: usize, value: Vec<u8>) {
+        match &mut self.data {
+            Encoding::ListPack(lp) => lp.replace(idx, &value),
+            Encoding::Deque(d) => d[idx] = value,
+        }
+        self.convert_if_needed();
+    }
+
+    pub fn push(&mut self, end: ListEnd, value: Vec<u8>) {
+        let idx = match end {
+            ListEnd::Left => 0,
+            ListEnd::Right => self.len(),
+        };
+        self.insert(idx, value);
+    }
+
+    pub fn insert(&mut self, idx: usize, value: Vec<u8>) {
+        match &mut self.data {
+            Encoding::ListPack(lp) => lp.insert(idx, &value),
+            Encoding::Deque(d) => d.insert(idx, value),
         }
+        self.convert_if_needed();
     }
-}
\ No newline at end of file
+
+    pub fn pop(&mut self, end: ListEnd) -> Option<Vec<u8>> {
+        if self.is_empty() {
+            return None;
+        }
+        let idx = match end {
+            ListEnd::Left => 0,
+            ListEnd::Right => self.len() - 1,
+        };
+        Some(self.remove(idx))
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Vec<u8> {
+        match &mut self.data {
+            Encoding::ListPack(lp) => lp.remove(idx),
+            Encoding::Deque(d) => d.remove(idx).expect("index in bounds"),
+        }
+    }
+
+    /// Keeps only the elements in `start..=end`.
+    pub fn trim(&mut self, start: usize, end: usize) {
+        match &mut self.data {
+            Encoding::ListPack(lp) => {
+                *lp = lp.iter().skip(start).take(end + 1 - start).collect();
+            }
+            Encoding::Deque(d) => {
+                d.truncate(end + 1);
+                d.drain(..start);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.data = Encoding::ListPack(ListPack::new());
+    }
+
+    fn convert_if_needed(&mut self) {
+        if let Encoding::ListPack(lp) = &self.data {
+            if !LIMITS.list_fits(lp.len(), lp.byte_len()) {
+                self.data = Encoding::Deque(lp.iter().map(|v| v.to_vec()).collect());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    /// Whether the list should have left the listpack after holding `model`.
+    fn outgrew_listpack(model: &[Vec<u8>]) -> bool {
+        let lp: ListPack = model.iter().map(Vec::as_slice).collect();
+        !LIMITS.list_fits(lp.len(), lp.byte_len())
+    }
+
+    fn value(rng: &mut StdRng) -> Vec<u8> {
+        let len = rng.gen_range(0..300);
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    fn assert_matches(list: &ListState, model: &[Vec<u8>]) {
+        assert_eq!(list.len(), model.len());
+        assert_eq!(list.iter().collect::<Vec<_>>(), model.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        for (i, v) in model.iter().enumerate() {
+            assert_eq!(list.get(i).as_ref(), Some(v));
+        }
+        assert_eq!(list.get(model.len()), None);
+    }
+
+    #[test]
+    fn converts_once_past_the_size_limit() {
+        let mut list = ListState::new();
+        let mut model = Vec::new();
+        let chunk = vec![b'x'; 100];
+        while !outgrew_listpack(&model) {
+            assert_eq!(list.encoding(), "listpack");
+            list.push(ListEnd::Right, chunk.clone());
+            model.push(chunk.clone());
+        }
+        assert_eq!(list.encoding(), "quicklist");
+        assert_matches(&list, &model);
+
+        list.clear();
+        assert_eq!(list.encoding(), "listpack");
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn matches_vec_across_conversion() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..20 {
+            let mut list = ListState::new();
+            let mut model: Vec<Vec<u8>> = Vec::new();
+            let mut converted = false;
+            for round in 0..300 {
+                match rng.gen_range(0..10) {
+                    0..=2 => {
+                        let v = value(&mut rng);
+                        if rng.gen() {
+                            list.push(ListEnd::Left, v.clone());
+                            model.insert(0, v);
+                        } else {
+                            list.push(ListEnd::Right, v.clone());
+                            model.push(v);
+                        }
+                    }
+                    3 | 4 => {
+                        let idx = rng.gen_range(0..=model.len());
+                        let v = value(&mut rng);
+                        list.insert(idx, v.clone());
+                        model.insert(idx, v);
+                    }
+                    5 if !model.is_empty() => {
+                        let idx = rng.gen_range(0..model.len());
+                        let v = value(&mut rng);
+                        list.set(idx, v.clone());
+                        model[idx] = v;
+                    }
+                    6 => {
+                        let end = if rng.gen() { ListEnd::Left } else { ListEnd::Right };
+                        let expected = match end {
+                            ListEnd::Left if !model.is_empty() => Some(model.remove(0)),
+                            ListEnd::Left => None,
+                            ListEnd::Right => model.pop(),
+                        };
+                        assert_eq!(list.pop(end), expected);
+                    }
+                    7 if !model.is_empty() => {
+                        let idx = rng.gen_range(0..model.len());
+                        assert_eq!(list.remove(idx), model.remove(idx));
+                    }
+                    8 if round % 10 == 0 && !model.is_empty() => {
+                        let start = rng.gen_range(0..model.len());
+                        let end = rng.gen_range(start..model.len());
+                        list.trim(start, end);
+                        model.truncate(end + 1);
+                        model.drain(..start);
+                    }
+                    _ => {}
+                }
+                // Shrinking never converts back.
+                converted |= outgrew_listpack(&model);
+                assert_eq!(list.encoding(), if converted { "quicklist" } else { "listpack" });
+                if round % 25 == 0 {
+                    assert_matches(&list, &model);
+                }
+            }
+            assert_matches(&list, &model);
+        }
+    }
+}
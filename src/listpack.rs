@@ -0,0 +1,243 @@
+/// A compact sequence of byte strings stored back to back in one buffer,
+/// each prefixed with its length as a LEB128 varint.
+///
+/// This is the small-value encoding for lists, hashes, sets and sorted sets.
+/// Every lookup is a linear walk, which is cheap while entries stay few and
+/// short; the owning types convert to a full structure past the limits in
+/// [`crate::encoding`].
+#[derive(Debug, Clone, Default)]
+pub struct ListPack {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl ListPack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bytes used by the packed entries.
+    pub fn byte_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Heap bytes held by the buffer.
+    pub fn mem_usage(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { buf: &self.buf }
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&[u8]> {
+        self.iter().nth(idx)
+    }
+
+    pub fn push_back(&mut self, entry: &[u8]) {
+        write_entry(&mut self.buf, entry);
+        self.len += 1;
+    }
+
+    /// Inserts `entry` so that it ends up at position `idx`.
+    pub fn insert(&mut self, idx: usize, entry: &[u8]) {
+        let at = self.offset_of(idx);
+        let mut encoded = Vec::with_capacity(entry.len() + 5);
+        write_entry(&mut encoded, entry);
+        self.buf.splice(at..at, encoded);
+        self.len += 1;
+    }
+
+    pub fn remove(&mut self, idx: usize) -> Vec<u8> {
+        let start = self.offset_of(idx);
+        let (entry, used) = read_entry(&self.buf[start..]);
+        let entry = entry.to_vec();
+        self.buf.drain(start..start + used);
+        self.len -= 1;
+        entry
+    }
+
+    pub fn replace(&mut self, idx: usize, entry: &[u8]) {
+        let start = self.offset_of(idx);
+        let (_, used) = read_entry(&self.buf[start..]);
+        let mut encoded = Vec::with_capacity(entry.len() + 5);
+        write_entry(&mut encoded, entry);
+        self.buf.splice(start..start + used, encoded);
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.len = 0;
+    }
+
+    /// Byte offset at which entry `idx` starts, or the end of the buffer.
+    fn offset_of(&self, idx: usize) -> usize {
+        let mut offset = 0;
+        for _ in 0..idx.min(self.len) {
+            let (_, used) = read_entry(&self.buf[offset..]);
+            offset += used;
+        }
+        offset
+    }
+}
+
+impl<'a> FromIterator<&'a [u8]> for ListPack {
+    fn from_iter<I: IntoIterator<Item = &'a [u8]>>(iter: I) -> Self {
+        let mut lp = ListPack::new();
+        for entry in iter {
+            lp.push_back(entry);
+        }
+        lp
+    }
+}
+
+pub struct Iter<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let (entry, used) = read_entry(self.buf);
+        self.buf = &self.buf[used..];
+        Some(entry)
+    }
+}
+
+fn write_entry(buf: &mut Vec<u8>, entry: &[u8]) {
+    let mut n = entry.len();
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+    buf.extend_from_slice(entry);
+}
+
+/// Decodes the entry at the start of `buf`, returning it and the total number
+/// of bytes (prefix included) it occupies.
+fn read_entry(buf: &[u8]) -> (&[u8], usize) {
+    let mut len = 0usize;
+    let mut shift = 0;
+    let mut pos = 0;
+    loop {
+        let byte = buf[pos];
+        len |= ((byte & 0x7f) as usize) << shift;
+        pos += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    (&buf[pos..pos + len], pos + len)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    /// Lengths around each point where the varint prefix gains a byte.
+    const LENGTHS: [usize; 8] = [0, 1, 127, 128, 129, 16383, 16384, 16385];
+
+    fn entry(rng: &mut StdRng) -> Vec<u8> {
+        let len = if rng.gen_range(0..4) == 0 {
+            LENGTHS[rng.gen_range(0..LENGTHS.len())]
+        } else {
+            rng.gen_range(0..40)
+        };
+        (0..len).map(|_| rng.gen()).collect()
+    }
+
+    fn prefix_len(len: usize) -> usize {
+        match len {
+            0..=127 => 1,
+            128..=16383 => 2,
+            _ => 3,
+        }
+    }
+
+    fn assert_matches(lp: &ListPack, model: &[Vec<u8>]) {
+        assert_eq!(lp.len(), model.len());
+        assert_eq!(lp.is_empty(), model.is_empty());
+        assert_eq!(lp.iter().collect::<Vec<_>>(), model.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        let bytes: usize = model.iter().map(|e| prefix_len(e.len()) + e.len()).sum();
+        assert_eq!(lp.byte_len(), bytes);
+        for (i, e) in model.iter().enumerate() {
+            assert_eq!(lp.get(i), Some(e.as_slice()));
+        }
+        assert_eq!(lp.get(model.len()), None);
+    }
+
+    #[test]
+    fn prefix_grows_at_varint_boundaries() {
+        for len in LENGTHS {
+            let e = vec![b'x'; len];
+            let lp: ListPack = [e.as_slice(), b"next"].into_iter().collect();
+            assert_eq!(lp.byte_len(), prefix_len(len) + len + 5);
+            assert_eq!(lp.get(0), Some(e.as_slice()));
+            assert_eq!(lp.get(1), Some(&b"next"[..]));
+        }
+    }
+
+    #[test]
+    fn matches_vec_under_random_operations() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut lp = ListPack::new();
+        let mut model: Vec<Vec<u8>> = Vec::new();
+
+        for round in 0..3000 {
+            match rng.gen_range(0..10) {
+                0..=2 => {
+                    let e = entry(&mut rng);
+                    lp.push_back(&e);
+                    model.push(e);
+                }
+                3 | 4 => {
+                    let idx = rng.gen_range(0..=model.len());
+                    let e = entry(&mut rng);
+                    lp.insert(idx, &e);
+                    model.insert(idx, e);
+                }
+                5..=7 if !model.is_empty() => {
+                    let idx = rng.gen_range(0..model.len());
+                    assert_eq!(lp.remove(idx), model.remove(idx));
+                }
+                8 if !model.is_empty() => {
+                    // Replacing with a longer or shorter entry moves everything after it.
+                    let idx = rng.gen_range(0..model.len());
+                    let e = entry(&mut rng);
+                    lp.replace(idx, &e);
+                    model[idx] = e;
+                }
+                _ => {}
+            }
+            if round % 25 == 0 {
+                assert_matches(&lp, &model);
+            }
+            if round % 1000 == 999 {
+                lp.clear();
+                model.clear();
+            }
+        }
+        assert_matches(&lp, &model);
+    }
+}
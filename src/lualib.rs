@@ -0,0 +1,234 @@
+use mlua::{LightUserData, Lua, Table, Value as LuaValue, Variadic};
+
+/// Deepest nesting `cjson.encode` follows before giving up. lua-cjson allows
+/// 1000, but the encoder recurses on a runtime worker's stack, so this stays
+/// at the 128 levels serde_json allows `cjson.decode`.
+const MAX_ENCODE_DEPTH: usize = 128;
+
+/// Loads the libraries Redis gives every script on top of Lua's own: `cjson`
+/// and `bit`. Their behaviour follows lua-cjson and LuaBitOp as bundled with
+/// Redis; `struct`, `cmsgpack` and `redis.setresp` are not provided.
+pub fn load(lua: &Lua) -> mlua::Result<()> {
+    let globals = lua.globals();
+    globals.set("cjson", cjson(lua)?)?;
+    globals.set("bit", bit(lua)?)?;
+    Ok(())
+}
+
+/// `cjson.null`, the value JSON nulls decode to.
+fn json_null() -> LuaValue<'static> {
+    LuaValue::LightUserData(LightUserData(std::ptr::null_mut()))
+}
+
+fn cjson(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let cjson = lua.create_table()?;
+    cjson.set("null", json_null())?;
+    cjson.set(
+        "encode",
+        lua.create_function(|lua, value: LuaValue| {
+            let mut out = Vec::new();
+            encode(&mut out, &value, 1).map_err(mlua::Error::RuntimeError)?;
+            lua.create_string(&out)
+        })?,
+    )?;
+    cjson.set(
+        "decode",
+        lua.create_function(|lua, text: mlua::String| {
+            let json: serde_json::Value = serde_json::from_slice(text.as_bytes())
+                .map_err(|e| mlua::Error::RuntimeError(format!("Expected valid JSON: {}", e)))?;
+            decode(lua, json)
+        })?,
+    )?;
+    Ok(cjson)
+}
+
+fn encode(out: &mut Vec<u8>, value: &LuaValue, depth: usize) -> Result<(), String> {
+    match value {
+        LuaValue::Nil => out.extend_from_slice(b"null"),
+        LuaValue::LightUserData(ud) if ud.0.is_null() => out.extend_from_slice(b"null"),
+        LuaValue::Boolean(b) => out.extend_from_slice(if *b { b"true" } else { b"false" }),
+        LuaValue::Integer(i) => out.extend_from_slice(i.to_string().as_bytes()),
+        LuaValue::Number(n) => out.extend_from_slice(format_number(*n)?.as_bytes()),
+        LuaValue::String(s) => encode_string(out, s.as_bytes()),
+        LuaValue::Table(table) => {
+            if depth > MAX_ENCODE_DEPTH {
+                return Err(format!("Cannot serialise, excessive nesting ({})", depth));
+            }
+            match array_len(table)? {
+                Some(len) => {
+                    out.push(b'[');
+                    for i in 1..=len {
+                        if i > 1 {
+                            out.push(b',');
+                        }
+                        let item: LuaValue = table.raw_get(i).map_err(|e| e.to_string())?;
+                        encode(out, &item, depth + 1)?;
+                    }
+                    out.push(b']');
+                }
+                None => {
+                    out.push(b'{');
+                    for (n, pair) in table.clone().pairs::<LuaValue, LuaValue>().enumerate() {
+                        let (key, item) = pair.map_err(|e| e.to_string())?;
+                        if n > 0 {
+                            out.push(b',');
+                        }
+                        match &key {
+                            LuaValue::String(s) => encode_string(out, s.as_bytes()),
+                            LuaValue::Integer(i) => encode_string(out, i.to_string().as_bytes()),
+                            LuaValue::Number(k) => encode_string(out, format_number(*k)?.as_bytes()),
+                            _ => return Err("Cannot serialise table: table key must be a number or string".into()),
+                        }
+                        out.push(b':');
+                        encode(out, &item, depth + 1)?;
+                    }
+                    out.push(b'}');
+                }
+            }
+        }
+        other => return Err(format!("Cannot serialise {}: type not supported", other.type_name())),
+    }
+    Ok(())
+}
+
+/// The length of `table` as a JSON array, or None if it is to be an object:
+/// it has a key that is not a positive integer, or no keys at all. Arrays
+/// with far more holes than entries are refused rather than padded with
+/// nulls.
+fn array_len(table: &Table) -> Result<Option<usize>, String> {
+    let mut max = 0;
+    let mut count = 0;
+    for pair in table.clone().pairs::<LuaValue, LuaValue>() {
+        let (key, _) = pair.map_err(|e| e.to_string())?;
+        let index = match key {
+            LuaValue::Integer(i) if i >= 1 => i as f64,
+            LuaValue::Number(n) if n >= 1.0 && n.fract() == 0.0 => n,
+            _ => return Ok(None),
+        };
+        max = max.max(index as usize);
+        count += 1;
+    }
+    if count == 0 {
+        return Ok(None);
+    }
+    if max > 10 && max > count * 2 {
+        return Err("Cannot serialise table: excessively sparse array".into());
+    }
+    Ok(Some(max))
+}
+
+fn encode_string(out: &mut Vec<u8>, s: &[u8]) {
+    out.push(b'"');
+    for &b in s {
+        match b {
+            b'"' => out.extend_from_slice(b"\\\""),
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'/' => out.extend_from_slice(b"\\/"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            0x08 => out.extend_from_slice(b"\\b"),
+            0x0c => out.extend_from_slice(b"\\f"),
+            0..=0x1f | 0x7f => out.extend_from_slice(format!("\\u{:04x}", b).as_bytes()),
+            _ => out.push(b),
+        }
+    }
+    out.push(b'"');
+}
+
+/// Formats `n` like C's `%.14g`, which lua-cjson uses for numbers.
+fn format_number(n: f64) -> Result<String, String> {
+    if !n.is_finite() {
+        return Err("Cannot serialise number: must not be NaN or Inf".into());
+    }
+    if n == 0.0 {
+        return Ok(if n.is_sign_negative() { "-0" } else { "0" }.into());
+    }
+
+    let sci = format!("{:.13e}", n);
+    let (mantissa, exp) = sci.split_once('e').expect("exponent format");
+    let exp: i32 = exp.parse().expect("exponent digits");
+    if !(-4..14).contains(&exp) {
+        let mantissa = trim_fraction(mantissa);
+        let sign = if exp < 0 { '-' } else { '+' };
+        return Ok(format!("{}e{}{:02}", mantissa, sign, exp.abs()));
+    }
+    Ok(trim_fraction(&format!("{:.*}", (13 - exp) as usize, n)).to_string())
+}
+
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+fn decode(lua: &Lua, json: serde_json::Value) -> mlua::Result<LuaValue<'_>> {
+    Ok(match json {
+        serde_json::Value::Null => json_null(),
+        serde_json::Value::Bool(b) => LuaValue::Boolean(b),
+        serde_json::Value::Number(n) => LuaValue::Number(n.as_f64().unwrap_or(0.0)),
+        serde_json::Value::String(s) => LuaValue::String(lua.create_string(&s)?),
+        serde_json::Value::Array(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for (i, item) in items.into_iter().enumerate() {
+                table.raw_set(i + 1, decode(lua, item)?)?;
+            }
+            LuaValue::Table(table)
+        }
+        serde_json::Value::Object(fields) => {
+            let table = lua.create_table_with_capacity(0, fields.len())?;
+            for (key, item) in fields {
+                table.raw_set(key, decode(lua, item)?)?;
+            }
+            LuaValue::Table(table)
+        }
+    })
+}
+
+/// Normalizes a number to the signed 32-bit integer LuaBitOp works on,
+/// rounding to the nearest integer and wrapping modulo 2^32.
+fn tobit(n: f64) -> i32 {
+    n.round_ties_even().rem_euclid(4_294_967_296.0) as u32 as i32
+}
+
+fn bit(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let bit = lua.create_table()?;
+    bit.set("tobit", lua.create_function(|_, x: f64| Ok(tobit(x)))?)?;
+    bit.set("bnot", lua.create_function(|_, x: f64| Ok(!tobit(x)))?)?;
+    bit.set("bswap", lua.create_function(|_, x: f64| Ok(tobit(x).swap_bytes()))?)?;
+    bit.set(
+        "tohex",
+        lua.create_function(|_, (x, n): (f64, Option<i64>)| {
+            let n = n.unwrap_or(8);
+            let digits = n.unsigned_abs().min(8) as usize;
+            let hex = if n < 0 {
+                format!("{:08X}", tobit(x) as u32)
+            } else {
+                format!("{:08x}", tobit(x) as u32)
+            };
+            Ok(hex[8 - digits..].to_string())
+        })?,
+    )?;
+
+    let fold = |op: fn(i32, i32) -> i32| {
+        lua.create_function(move |_, (x, rest): (f64, Variadic<f64>)| {
+            Ok(rest.iter().fold(tobit(x), |acc, y| op(acc, tobit(*y))))
+        })
+    };
+    bit.set("band", fold(|a, b| a & b)?)?;
+    bit.set("bor", fold(|a, b| a | b)?)?;
+    bit.set("bxor", fold(|a, b| a ^ b)?)?;
+
+    // Shift counts use their low five bits only.
+    let shift = |op: fn(i32, u32) -> i32| {
+        lua.create_function(move |_, (x, n): (f64, f64)| Ok(op(tobit(x), tobit(n) as u32 & 31)))
+    };
+    bit.set("lshift", shift(|x, n| ((x as u32) << n) as i32)?)?;
+    bit.set("rshift", shift(|x, n| ((x as u32) >> n) as i32)?)?;
+    bit.set("arshift", shift(|x, n| x >> n)?)?;
+    bit.set("rol", shift(|x, n| x.rotate_left(n))?)?;
+    bit.set("ror", shift(|x, n| x.rotate_right(n))?)?;
+    Ok(bit)
+}
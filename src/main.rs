@@ -1,23 +1,17 @@
-mod server;
-mod connection;
-mod resp;
-mod command;
-mod errors;
-mod db;
-mod value;
-mod list;
-mod expiration;
-mod skiplist;
-mod aof;
-
 use std::sync::Arc;
-use db::Db;
+
+use rust_redis::{aof, databases, encoding, eviction, expiration, keyspace, lazyfree, pubsub, scripting, server};
+use rust_redis::command::Command;
+use rust_redis::databases::Databases;
 
 #[derive(Debug)]
 struct Config {
     addr: String,
     aof_path: String,
     aof_fsync: aof::AofFsync,
+    shards: usize,
+    databases: usize,
+    expire: expiration::ExpireConfig,
 }
 
 impl Config {
@@ -25,6 +19,9 @@ impl Config {
         let mut addr = "0.0.0.0:6379".to_string();
         let mut aof_path = "appendonly.aof".to_string();
         let mut aof_fsync = aof::AofFsync::EverySec;
+        let mut shards = keyspace::DEFAULT_SHARDS;
+        let mut databases = databases::DEFAULT_DATABASES;
+        let mut expire = expiration::ExpireConfig::default();
 
         let mut args = std::env::args().skip(1);
         while let Some(arg) = args.next() {
@@ -46,16 +43,78 @@ impl Config {
                     aof_fsync = aof::AofFsync::parse(&v)
                         .map_err(|e| anyhow::anyhow!(e.to_string()))?;
                 }
-                other => {
-                    return Err(anyhow::anyhow!("unknown argument: {}", other));
+                "--shards" | "--databases" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("{} requires a value", arg))?;
+                    let n = v
+                        .parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| anyhow::anyhow!("invalid value for {}: {}", arg, v))?;
+                    if arg == "--shards" {
+                        shards = n;
+                    } else {
+                        databases = n;
+                    }
+                }
+                "--hz" | "--active-expire-effort" => {
+                    let v = args
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("{} requires a value", arg))?;
+                    let n = v
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("invalid value for {}: {}", arg, v))?;
+                    if arg == "--hz" {
+                        expire.hz = n;
+                    } else {
+                        expire.effort = n;
+                    }
                 }
+                other => match other.strip_prefix("--") {
+                    Some(name) if encoding::LIMIT_NAMES.contains(&name) => {
+                        let v = args
+                            .next()
+                            .ok_or_else(|| anyhow::anyhow!("{} requires a value", other))?;
+                        encoding::LIMITS.set(name, &v).map_err(|e| anyhow::anyhow!(e))?;
+                    }
+                    Some(name) if eviction::CONFIG_NAMES.contains(&name) => {
+                        let v = args
+                            .next()
+                            .ok_or_else(|| anyhow::anyhow!("{} requires a value", other))?;
+                        eviction::CONFIG.set(name, &v).map_err(|e| anyhow::anyhow!(e))?;
+                    }
+                    Some(name) if lazyfree::CONFIG_NAMES.contains(&name) => {
+                        let v = args
+                            .next()
+                            .ok_or_else(|| anyhow::anyhow!("{} requires a value", other))?;
+                        lazyfree::CONFIG.set(name, &v).map_err(|e| anyhow::anyhow!(e))?;
+                    }
+                    Some(name) if scripting::CONFIG_NAMES.contains(&name) => {
+                        let v = args
+                            .next()
+                            .ok_or_else(|| anyhow::anyhow!("{} requires a value", other))?;
+                        scripting::CONFIG.set(name, &v).map_err(|e| anyhow::anyhow!(e))?;
+                    }
+                    Some(name) if pubsub::CONFIG_NAMES.contains(&name) => {
+                        let v = args
+                            .next()
+                            .ok_or_else(|| anyhow::anyhow!("{} requires a value", other))?;
+                        pubsub::CONFIG.set(name, &v).map_err(|e| anyhow::anyhow!(e))?;
+                    }
+                    _ => return Err(anyhow::anyhow!("unknown argument: {}", other)),
+                },
             }
         }
+        expire.validate().map_err(|e| anyhow::anyhow!(e))?;
 
         Ok(Self {
             addr,
             aof_path,
             aof_fsync,
+            shards,
+            databases,
+            expire,
         })
     }
 }
@@ -69,24 +128,43 @@ async fn main() -> anyhow::Result<()> {
         cfg.addr, cfg.aof_path, cfg.aof_fsync
     );
 
-    let db = Arc::new(Db::new());
+    let dbs = Arc::new(Databases::new(cfg.databases, cfg.shards));
     let aof = aof::Aof::open(&cfg.aof_path, cfg.aof_fsync).await?;
 
     match tokio::fs::read(aof.path()).await {
         Ok(bytes) => {
             let frames = aof::parse_frames_from_bytes(&bytes)?;
+            let mut selected = 0;
+            // Commands of a transaction are only applied once its EXEC is
+            // read, so a block cut short by a crash is left out entirely.
+            let mut multi: Option<Vec<Command>> = None;
             for frame in frames {
-                if let Ok(cmd) = crate::command::Command::try_from(frame) {
-                    let _ = db.apply(cmd).await;
+                match Command::try_from(frame) {
+                    Ok(Command::Multi) => multi = Some(Vec::new()),
+                    Ok(Command::Exec) => {
+                        for cmd in multi.take().unwrap_or_default() {
+                            let _ = dbs.apply(&mut selected, cmd).await;
+                        }
+                    }
+                    Ok(cmd) => match &mut multi {
+                        Some(queued) => queued.push(cmd),
+                        None => {
+                            let _ = dbs.apply(&mut selected, cmd).await;
+                        }
+                    },
+                    Err(_) => {}
                 }
             }
+            if multi.is_some() {
+                eprintln!("AOF ends inside a transaction; its commands were not applied");
+            }
         }
         Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
         Err(e) => return Err(e.into()),
     }
 
-    tokio::spawn(expiration::run(db.clone()));
-    server::run(&cfg.addr, db, aof).await?;
+    tokio::spawn(expiration::run(dbs.clone(), cfg.expire));
+    server::run(&cfg.addr, dbs, aof).await?;
 
     Ok(())
 }
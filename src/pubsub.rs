@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::connection::Output;
+use crate::eviction::parse_memory;
+use crate::resp::Frame;
+
+/// How far behind on its output a subscribed client may fall before it is
+/// disconnected, as set by `client-output-buffer-limit pubsub` in Redis.
+#[derive(Debug)]
+pub struct PubSubConfig {
+    /// Bytes; 0 means no limit.
+    pub hard_limit: AtomicUsize,
+    /// Bytes a client may stay over for `soft_seconds`; 0 means no limit.
+    pub soft_limit: AtomicUsize,
+    pub soft_seconds: AtomicU64,
+}
+
+/// The limits of [`PubSubConfig`], read together.
+#[derive(Debug, Clone, Copy)]
+pub struct OutputBufferLimits {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_time: Duration,
+}
+
+/// Every setting accepted by [`PubSubConfig::set`].
+pub const CONFIG_NAMES: &[&str] = &["client-output-buffer-limit"];
+
+pub static CONFIG: PubSubConfig = PubSubConfig {
+    hard_limit: AtomicUsize::new(32 * 1024 * 1024),
+    soft_limit: AtomicUsize::new(8 * 1024 * 1024),
+    soft_seconds: AtomicU64::new(60),
+};
+
+impl PubSubConfig {
+    /// Sets the limits from a value such as `pubsub 32mb 8mb 60`. Only the
+    /// `pubsub` class is supported: other clients are never disconnected.
+    pub fn set(&self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value for {}: {}", name, value);
+        if name != "client-output-buffer-limit" {
+            return Err(format!("unknown pubsub setting: {}", name));
+        }
+
+        let parts: Vec<&str> = value.split_whitespace().collect();
+        let [class, hard, soft, seconds] = parts[..] else {
+            return Err(invalid());
+        };
+        if !class.eq_ignore_ascii_case("pubsub") {
+            return Err(format!("unsupported client class for {}: {}", name, class));
+        }
+        let hard = parse_memory(hard).ok_or_else(invalid)?;
+        let soft = parse_memory(soft).ok_or_else(invalid)?;
+        let seconds = seconds.parse().map_err(|_| invalid())?;
+        self.hard_limit.store(hard, Ordering::Relaxed);
+        self.soft_limit.store(soft, Ordering::Relaxed);
+        self.soft_seconds.store(seconds, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn output_buffer_limits(&self) -> OutputBufferLimits {
+        OutputBufferLimits {
+            hard: self.hard_limit.load(Ordering::Relaxed),
+            soft: self.soft_limit.load(Ordering::Relaxed),
+            soft_time: Duration::from_secs(self.soft_seconds.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// The clients subscribed to each channel, by client id.
+///
+/// Publishing only queues the message on each subscriber's [`Output`];
+/// their connections write it out concurrently.
+#[derive(Debug, Default)]
+pub struct PubSub {
+    channels: Mutex<HashMap<String, HashMap<u64, Arc<Output>>>>,
+}
+
+impl PubSub {
+    /// Subscribes client `id` to `channel`. Returns false if it already was.
+    pub fn subscribe(&self, channel: &str, id: u64, output: &Arc<Output>) -> bool {
+        let mut channels = self.channels.lock().unwrap();
+        let subscribers = channels.entry(channel.to_string()).or_default();
+        subscribers.insert(id, output.clone()).is_none()
+    }
+
+    /// Returns false if client `id` was not subscribed to `channel`.
+    pub fn unsubscribe(&self, channel: &str, id: u64) -> bool {
+        let mut channels = self.channels.lock().unwrap();
+        let Some(subscribers) = channels.get_mut(channel) else {
+            return false;
+        };
+        let removed = subscribers.remove(&id).is_some();
+        if subscribers.is_empty() {
+            channels.remove(channel);
+        }
+        removed
+    }
+
+    /// Sends `message` to the subscribers of `channel`, returning how many
+    /// received it.
+    pub fn publish(&self, channel: &str, message: Vec<u8>) -> usize {
+        let channels = self.channels.lock().unwrap();
+        let Some(subscribers) = channels.get(channel) else {
+            return 0;
+        };
+        let frame = Frame::Array(vec![
+            Frame::Bulk(b"message".to_vec()),
+            Frame::Bulk(channel.as_bytes().to_vec()),
+            Frame::Bulk(message),
+        ]);
+        subscribers.values().filter(|output| output.push(&frame)).count()
+    }
+
+    /// The channels with at least one subscriber. Like KEYS, the pattern only
+    /// matches every channel or exactly one.
+    pub fn channels(&self, pattern: Option<&str>) -> Frame {
+        let channels = self.channels.lock().unwrap();
+        Frame::Array(
+            channels
+                .keys()
+                .filter(|c| pattern.is_none_or(|p| p == "*" || p == c.as_str()))
+                .map(|c| Frame::Bulk(c.as_bytes().to_vec()))
+                .collect(),
+        )
+    }
+
+    /// Each of `names` followed by its number of subscribers.
+    pub fn numsub(&self, names: &[String]) -> Frame {
+        let channels = self.channels.lock().unwrap();
+        let mut out = Vec::with_capacity(names.len() * 2);
+        for name in names {
+            let count = channels.get(name).map_or(0, HashMap::len);
+            out.push(Frame::Bulk(name.as_bytes().to_vec()));
+            out.push(Frame::Integer(count as i64));
+        }
+        Frame::Array(out)
+    }
+}
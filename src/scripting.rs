@@ -0,0 +1,515 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mlua::{HookTriggers, Lua, LuaOptions, MultiValue, StdLib, Table, Value as LuaValue};
+use tokio::runtime::Handle;
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+use crate::aof::{capture, command_frame, propagate_to};
+use crate::command::Command;
+use crate::databases::Exclusive;
+use crate::functions::{Engine, Functions};
+use crate::lualib;
+use crate::resp::Frame;
+
+/// Reply to commands from other clients while a script runs past
+/// `busy-reply-threshold`.
+pub const BUSY_ERROR: &str =
+    "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.";
+
+const NOSCRIPT_ERROR: &str = "NOSCRIPT No matching script. Please use EVAL.";
+
+const KILLED_ERROR: &str = "ERR Script killed by user with SCRIPT KILL...";
+
+/// Lua instructions between checks for SCRIPT KILL and the busy threshold.
+pub const HOOK_INTERVAL: u32 = 100_000;
+
+/// Settings for scripts, named after their `redis.conf` counterparts.
+#[derive(Debug)]
+pub struct ScriptConfig {
+    /// Milliseconds a script runs before other clients get [`BUSY_ERROR`]
+    /// instead of waiting for it.
+    pub busy_reply_threshold: AtomicU64,
+}
+
+/// Every setting accepted by [`ScriptConfig::set`]. `lua-time-limit` is the
+/// older name of `busy-reply-threshold`.
+pub const CONFIG_NAMES: &[&str] = &["busy-reply-threshold", "lua-time-limit"];
+
+pub static CONFIG: ScriptConfig = ScriptConfig {
+    busy_reply_threshold: AtomicU64::new(5000),
+};
+
+impl ScriptConfig {
+    pub fn set(&self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "busy-reply-threshold" | "lua-time-limit" => {
+                let ms = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for {}: {}", name, value))?;
+                self.busy_reply_threshold.store(ms, Ordering::Relaxed);
+                Ok(())
+            }
+            _ => Err(format!("unknown scripting setting: {}", name)),
+        }
+    }
+
+    fn busy_reply_threshold(&self) -> Duration {
+        Duration::from_millis(self.busy_reply_threshold.load(Ordering::Relaxed))
+    }
+}
+
+/// What SCRIPT KILL needs to know of the script in progress.
+#[derive(Debug, Default)]
+struct Progress {
+    /// A script that wrote can no longer be killed without leaving its
+    /// writes half done.
+    wrote: bool,
+    killed: bool,
+}
+
+#[derive(Debug)]
+struct Running {
+    started: Instant,
+    busy: AtomicBool,
+    /// Set for functions flagged `no-writes`, which must not call writes.
+    read_only: bool,
+    /// Held while the script runs a command, so that SCRIPT KILL cannot slip
+    /// in between the command and the script recording that it wrote.
+    progress: Mutex<Progress>,
+}
+
+/// Scripts cached by SHA1, and the one currently running if any.
+///
+/// A script runs with the databases held exclusively, in a fresh Lua
+/// interpreter on the calling thread; functions run in the interpreter their
+/// library was loaded in. Its `redis.call`s go through the same
+/// [`Exclusive`] as a transaction's commands, and it is logged to the AOF as
+/// the effects of those calls rather than as its source.
+#[derive(Debug, Default)]
+pub struct Scripts {
+    cache: Mutex<HashMap<String, String>>,
+    running: Mutex<Option<Arc<Running>>>,
+    /// Notified when the running script crosses `busy-reply-threshold`, so
+    /// that clients waiting for it reply [`BUSY_ERROR`] instead.
+    busy: Arc<Notify>,
+}
+
+/// Lowercase hex SHA1 of `script`, the name it is cached under.
+pub fn sha1hex(script: &[u8]) -> String {
+    sha1_smol::Sha1::from(script).digest().to_string()
+}
+
+impl Scripts {
+    /// Caches `script`, returning its SHA1.
+    pub fn load(&self, script: String) -> String {
+        let sha = sha1hex(script.as_bytes());
+        self.cache.lock().unwrap().insert(sha.clone(), script);
+        sha
+    }
+
+    pub fn exists(&self, shas: &[String]) -> Frame {
+        let cache = self.cache.lock().unwrap();
+        Frame::Array(
+            shas.iter()
+                .map(|sha| Frame::Integer(cache.contains_key(&sha.to_ascii_lowercase()) as i64))
+                .collect(),
+        )
+    }
+
+    pub fn flush(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Whether a script has been running for longer than
+    /// `busy-reply-threshold`.
+    pub fn is_busy(&self) -> bool {
+        self.running
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|r| r.busy.load(Ordering::Relaxed))
+    }
+
+    /// Completes once the running script becomes busy. Must be created
+    /// before checking [`Scripts::is_busy`] so that no notification is missed.
+    pub fn busy_notified(&self) -> Notified<'_> {
+        self.busy.notified()
+    }
+
+    /// Stops the running script at its next check, unless it already wrote.
+    pub fn kill(&self) -> Frame {
+        let Some(running) = self.running.lock().unwrap().clone() else {
+            return Frame::Error("NOTBUSY No scripts in execution right now.".into());
+        };
+        let mut progress = running.progress.lock().unwrap();
+        if progress.wrote {
+            return Frame::Error(
+                "UNKILLABLE Sorry the script already executed write commands against the dataset. \
+                 You can either wait the script termination or kill the server in a hard way using \
+                 the SHUTDOWN NOSAVE command."
+                    .into(),
+            );
+        }
+        progress.killed = true;
+        Frame::Simple("OK".into())
+    }
+
+    /// Runs EVAL or EVALSHA through `tx` for a client that has database
+    /// `selected` selected. The script's own SELECTs do not outlive it.
+    pub fn eval(&self, tx: &Exclusive<'_>, selected: usize, cmd: Command) -> Frame {
+        let (script, keys, args) = match cmd {
+            Command::Eval(script, keys, args) => {
+                self.load(script.clone());
+                (script, keys, args)
+            }
+            Command::EvalSha(sha, keys, args) => {
+                let cached = self.cache.lock().unwrap().get(&sha.to_ascii_lowercase()).cloned();
+                match cached {
+                    Some(script) => (script, keys, args),
+                    None => return Frame::Error(NOSCRIPT_ERROR.into()),
+                }
+            }
+            _ => unreachable!("only EVAL and EVALSHA run scripts"),
+        };
+        self.run(tx, selected, Entry::Script(&script), keys, args, false)
+    }
+
+    /// Runs FCALL or FCALL_RO like [`Scripts::eval`]. Functions flagged
+    /// `no-writes` may not write whichever way they are called.
+    pub fn fcall(&self, tx: &Exclusive<'_>, selected: usize, functions: &Functions, cmd: Command) -> Frame {
+        let (name, keys, args, read_only) = match cmd {
+            Command::FCall(name, keys, args) => (name, keys, args, false),
+            Command::FCallRo(name, keys, args) => (name, keys, args, true),
+            _ => unreachable!("only FCALL and FCALL_RO run functions"),
+        };
+        let Some((engine, info)) = functions.find(&name) else {
+            return Frame::Error("ERR Function not found".into());
+        };
+        if read_only && !info.no_writes() {
+            return Frame::Error("ERR Can not execute a script with write flag using *_ro command.".into());
+        }
+        let engine = engine.lock().unwrap();
+        let entry = Entry::Function { engine: &engine, name: &name };
+        self.run(tx, selected, entry, keys, args, info.no_writes())
+    }
+
+    fn run(
+        &self,
+        tx: &Exclusive<'_>,
+        selected: usize,
+        entry: Entry<'_>,
+        keys: Vec<String>,
+        args: Vec<Vec<u8>>,
+        read_only: bool,
+    ) -> Frame {
+        let running = Arc::new(Running {
+            started: Instant::now(),
+            busy: AtomicBool::new(false),
+            read_only,
+            progress: Mutex::new(Progress::default()),
+        });
+        *self.running.lock().unwrap() = Some(running.clone());
+        let _done = ClearRunning(&self.running);
+
+        // The interpreter is driven synchronously; block_in_place lets the
+        // runtime move other tasks off this worker in the meantime.
+        tokio::task::block_in_place(|| {
+            let reply = run_script(tx, selected, entry, keys, args, &running, &self.busy);
+            match reply {
+                Ok(reply) => reply,
+                Err(_) if running.progress.lock().unwrap().killed => Frame::Error(KILLED_ERROR.into()),
+                Err(mlua::Error::SyntaxError { message, .. }) => {
+                    Frame::Error(format!("ERR Error compiling script (new function): {}", message))
+                }
+                Err(e) => Frame::Error(format!("ERR Error running script: {}", error_message(&e))),
+            }
+        })
+    }
+}
+
+/// What a script run calls.
+#[derive(Debug, Clone, Copy)]
+enum Entry<'a> {
+    /// The body of an EVAL script, run in a fresh interpreter, which finds
+    /// its keys and arguments in the KEYS and ARGV globals.
+    Script(&'a str),
+    /// Function `name` of the library loaded in `engine`, which gets its keys
+    /// and arguments as its two parameters.
+    Function { engine: &'a Engine, name: &'a str },
+}
+
+/// Clears [`Scripts::running`] however the script ends, so that a failed
+/// script cannot leave the server busy.
+struct ClearRunning<'a>(&'a Mutex<Option<Arc<Running>>>);
+
+impl Drop for ClearRunning<'_> {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+/// The parts of the `redis` library written in Lua, on top of `redis.pcall`,
+/// which a running script replaces.
+const PRELUDE: &str = r#"
+redis.LOG_DEBUG = 0
+redis.LOG_VERBOSE = 1
+redis.LOG_NOTICE = 2
+redis.LOG_WARNING = 3
+
+function redis.pcall()
+    error('redis.call and redis.pcall can only be used while a script runs', 0)
+end
+
+function redis.call(...)
+    local reply = redis.pcall(...)
+    if type(reply) == 'table' and reply.err then
+        error(reply)
+    end
+    return reply
+end
+
+function redis.error_reply(msg)
+    return {err = msg}
+end
+
+function redis.status_reply(msg)
+    return {ok = msg}
+end
+"#;
+
+/// Calls the script with the given parameters, turning an error raised by `redis.call` (or by the
+/// script as a table) into the error reply it carries.
+const RUNNER: &str = r#"
+local ok, reply = pcall(...)
+if ok or (type(reply) == 'table' and reply.err) then
+    return reply
+end
+error(reply, 0)
+"#;
+
+/// Creates an interpreter with the libraries scripts may use, and no access
+/// to the server's file system. `redis.pcall`, and so `redis.call`, only
+/// work while a script runs.
+pub fn sandbox() -> mlua::Result<Lua> {
+    let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH, LuaOptions::new())?;
+    let globals = lua.globals();
+    globals.set("dofile", LuaValue::Nil)?;
+    globals.set("loadfile", LuaValue::Nil)?;
+    lualib::load(&lua)?;
+
+    let redis = lua.create_table()?;
+    redis.set(
+        "sha1hex",
+        lua.create_function(|_, s: mlua::String| Ok(sha1hex(s.as_bytes())))?,
+    )?;
+    redis.set(
+        "log",
+        lua.create_function(|_, (level, msg): (i64, mlua::String)| {
+            eprintln!("script log ({}): {}", level, msg.to_string_lossy());
+            Ok(())
+        })?,
+    )?;
+    globals.set("redis", redis)?;
+    lua.load(PRELUDE).set_name("=prelude").exec()?;
+    drop(globals);
+    Ok(lua)
+}
+
+fn run_script(
+    tx: &Exclusive<'_>,
+    selected: usize,
+    entry: Entry<'_>,
+    keys: Vec<String>,
+    args: Vec<Vec<u8>>,
+    running: &Arc<Running>,
+    busy: &Arc<Notify>,
+) -> mlua::Result<Frame> {
+    let fresh;
+    let lua = match entry {
+        Entry::Script(_) => {
+            fresh = sandbox()?;
+            &fresh
+        }
+        Entry::Function { engine, .. } => &engine.lua,
+    };
+    let globals = lua.globals();
+    let keys = lua.create_sequence_from(keys)?;
+    let argv = args
+        .iter()
+        .map(|a| lua.create_string(a))
+        .collect::<mlua::Result<Vec<_>>>()?;
+    let argv = lua.create_sequence_from(argv)?;
+
+    let hook_running = running.clone();
+    let busy = busy.clone();
+    lua.set_hook(HookTriggers::new().every_nth_instruction(HOOK_INTERVAL), move |_, _| {
+        if hook_running.progress.lock().unwrap().killed {
+            return Err(mlua::Error::RuntimeError(KILLED_ERROR.into()));
+        }
+        if !hook_running.busy.load(Ordering::Relaxed)
+            && hook_running.started.elapsed() >= CONFIG.busy_reply_threshold()
+        {
+            hook_running.busy.store(true, Ordering::Relaxed);
+            busy.notify_waiters();
+        }
+        Ok(())
+    });
+
+    let handle = Handle::current();
+    let mut selected = selected;
+    let redis: Table = globals.get("redis")?;
+    let idle: LuaValue = redis.get("pcall")?;
+    let reply = lua.scope(|scope| {
+        redis.set(
+            "pcall",
+            scope.create_function_mut(|lua, args: MultiValue| {
+                let reply = match command_from_lua(args) {
+                    Ok(frame) => call(tx, &handle, &mut selected, running, frame),
+                    Err(e) => Frame::Error(e),
+                };
+                frame_to_lua(lua, reply)
+            })?,
+        )?;
+
+        let runner = lua.load(RUNNER).set_name("=runner").into_function()?;
+        match entry {
+            Entry::Script(script) => {
+                globals.set("KEYS", keys)?;
+                globals.set("ARGV", argv)?;
+                let script = lua.load(script).set_name("@user_script").into_function()?;
+                runner.call::<_, LuaValue>(script).map(lua_to_frame)
+            }
+            Entry::Function { engine, name } => {
+                let Some(callback) = engine.callback(name)? else {
+                    return Ok(Frame::Error("ERR Function not found".into()));
+                };
+                runner.call::<_, LuaValue>((callback, keys, argv)).map(lua_to_frame)
+            }
+        }
+    });
+    // A library's interpreter outlives the call; leave nothing of it behind.
+    redis.set("pcall", idle)?;
+    lua.remove_hook();
+    reply
+}
+
+/// Runs one `redis.call` and propagates what it wrote to the databases it
+/// wrote to.
+fn call(tx: &Exclusive<'_>, handle: &Handle, selected: &mut usize, running: &Running, frame: Frame) -> Frame {
+    let cmd = match Command::try_from(frame.clone()) {
+        Ok(cmd) => cmd,
+        Err(e) => return Frame::Error(e.reply()),
+    };
+    if cmd.is_noscript() {
+        return Frame::Error("ERR This Redis command is not allowed from script".into());
+    }
+    if running.read_only && cmd.is_write() {
+        return Frame::Error("ERR Write commands are not allowed from read-only scripts.".into());
+    }
+
+    let mut progress = running.progress.lock().unwrap();
+    if progress.killed {
+        return Frame::Error(KILLED_ERROR.into());
+    }
+    let should_log = cmd.is_write_for_aof();
+    let before = *selected;
+    let (reply, effects) = handle.block_on(capture(tx.apply(selected, cmd)));
+    if should_log && !matches!(reply, Frame::Error(_)) {
+        progress.wrote = true;
+        propagate_to(before, frame);
+    }
+    for (db, effect) in effects {
+        progress.wrote = true;
+        propagate_to(db.unwrap_or(before), effect);
+    }
+    reply
+}
+
+/// Builds a command frame from the arguments of `redis.call`.
+fn command_from_lua(args: MultiValue) -> Result<Frame, String> {
+    if args.is_empty() {
+        return Err("ERR Please specify at least one argument for this redis lib call".into());
+    }
+    let mut parts = Vec::with_capacity(args.len());
+    for arg in args {
+        parts.push(match arg {
+            LuaValue::String(s) => s.as_bytes().to_vec(),
+            LuaValue::Integer(i) => i.to_string().into_bytes(),
+            LuaValue::Number(n) if n.fract() == 0.0 => (n as i64).to_string().into_bytes(),
+            LuaValue::Number(n) => n.to_string().into_bytes(),
+            _ => return Err("ERR Lua redis lib command arguments must be strings or integers".into()),
+        });
+    }
+    Ok(command_frame(parts))
+}
+
+/// Converts a reply for the script, following the RESP2 conversion rules of
+/// Redis: nulls become false, status and error replies tables with an `ok`
+/// or `err` field.
+fn frame_to_lua(lua: &Lua, frame: Frame) -> mlua::Result<LuaValue<'_>> {
+    Ok(match frame {
+        Frame::Simple(s) => LuaValue::Table(single_field(lua, "ok", s)?),
+        Frame::Error(s) => LuaValue::Table(single_field(lua, "err", s)?),
+        Frame::Integer(i) => LuaValue::Integer(i),
+        Frame::Bulk(b) => LuaValue::String(lua.create_string(&b)?),
+        Frame::Null => LuaValue::Boolean(false),
+        Frame::Array(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for (i, item) in items.into_iter().enumerate() {
+                table.raw_set(i + 1, frame_to_lua(lua, item)?)?;
+            }
+            LuaValue::Table(table)
+        }
+    })
+}
+
+fn single_field<'lua>(lua: &'lua Lua, name: &str, value: String) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set(name, value)?;
+    Ok(table)
+}
+
+/// Converts what the script returned into its reply. Numbers are truncated
+/// to integers, true is 1 and false is null, and arrays end at their first
+/// nil.
+fn lua_to_frame(value: LuaValue) -> Frame {
+    match value {
+        LuaValue::Boolean(true) => Frame::Integer(1),
+        LuaValue::Integer(i) => Frame::Integer(i),
+        LuaValue::Number(n) => Frame::Integer(n as i64),
+        LuaValue::String(s) => Frame::Bulk(s.as_bytes().to_vec()),
+        LuaValue::Table(table) => {
+            if let Ok(LuaValue::String(err)) = table.raw_get("err") {
+                return Frame::Error(err.to_string_lossy().into_owned());
+            }
+            if let Ok(LuaValue::String(ok)) = table.raw_get("ok") {
+                return Frame::Simple(ok.to_string_lossy().into_owned());
+            }
+            let mut items = Vec::new();
+            for i in 1.. {
+                match table.raw_get::<_, LuaValue>(i) {
+                    Ok(LuaValue::Nil) | Err(_) => break,
+                    Ok(item) => items.push(lua_to_frame(item)),
+                }
+            }
+            Frame::Array(items)
+        }
+        _ => Frame::Null,
+    }
+}
+
+/// The message of the error that failed the script, without the Rust
+/// callback frames mlua wraps it in or the Lua traceback, which cannot go in
+/// an error reply.
+pub fn error_message(err: &mlua::Error) -> String {
+    let msg = match err {
+        mlua::Error::CallbackError { cause, .. } => return error_message(cause),
+        mlua::Error::RuntimeError(msg) => msg.clone(),
+        other => other.to_string(),
+    };
+    msg.lines().next().unwrap_or_default().to_string()
+}
@@ -1,50 +1,341 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use indexmap::IndexSet;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use tokio::net::TcpListener;
 
-use crate::connection::Connection;
-use crate::db::Db;
+use crate::connection::{Connection, MAX_UNREAD_REPLIES};
+use crate::databases::{Databases, Watch};
+use crate::db::RandomPicks;
 use crate::command::Command;
 use crate::errors::RedisError;
-use crate::aof::Aof;
+use crate::aof::{capture, command_frame, Aof};
+use crate::eviction::OOM_ERROR;
+use crate::resp::{encode_frame, Frame};
 
-pub async fn run(addr: &str, db: Arc<Db>, aof: Arc<Aof>) -> Result<(), RedisError> {
+/// Commands queued by MULTI, waiting for EXEC.
+#[derive(Debug, Default)]
+struct Transaction {
+    /// Each command along with the frame it was parsed from, for the AOF.
+    queued: Vec<(Command, Frame)>,
+    /// Set when a command could not be queued, in which case EXEC discards
+    /// the whole transaction.
+    aborted: bool,
+}
+
+/// Identifies connections to the registries that hold on to them.
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+pub async fn run(addr: &str, dbs: Arc<Databases>, aof: Arc<Aof>) -> Result<(), RedisError> {
     let listener = TcpListener::bind(addr).await?;
     loop {
         let (socket, _) = listener.accept().await?;
-        let db = db.clone();
+        let dbs = dbs.clone();
         let aof = aof.clone();
 
         tokio::spawn(async move {
             let mut conn = Connection::new(socket);
+            let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+            let mut selected = 0;
+            let mut multi: Option<Transaction> = None;
+            let mut watched: Vec<Watch> = Vec::new();
+            // Non-empty puts the connection in subscribed mode.
+            let mut subscriptions: IndexSet<String> = IndexSet::new();
 
             while let Ok(Some(frame)) = conn.read_frame().await {
                 let original_frame = frame.clone();
 
-                match Command::try_from(frame) {
-                    Ok(cmd) => {
-                        let should_log = cmd.is_write_for_aof();
-                        let response = db.apply(cmd).await;
-                        if should_log && !matches!(response, crate::resp::Frame::Error(_)) {
-                            if let Err(e) = aof.append_frame(&original_frame).await {
-                                eprintln!("AOF append error: {:?}", e);
+                let response = match Command::try_from(frame) {
+                    Ok(Command::Subscribe(_) | Command::Unsubscribe(_)) if multi.is_some() => {
+                        Frame::Error("ERR Command not allowed inside a transaction".into())
+                    }
+                    // Confirmed once per channel, so replied to separately.
+                    Ok(Command::Subscribe(channels)) => {
+                        for channel in channels {
+                            if subscriptions.insert(channel.clone()) {
+                                dbs.pubsub().subscribe(&channel, id, conn.output());
                             }
+                            let count = subscriptions.len();
+                            let _ = conn.write_frame(&subscription_reply("subscribe", Some(channel), count));
+                        }
+                        continue;
+                    }
+                    Ok(Command::Unsubscribe(channels)) => {
+                        unsubscribe(&conn, &dbs, id, &mut subscriptions, channels);
+                        continue;
+                    }
+                    Ok(Command::Ping) if !subscriptions.is_empty() => {
+                        Frame::Array(vec![Frame::Bulk(b"pong".to_vec()), Frame::Bulk(Vec::new())])
+                    }
+                    Ok(_) if !subscriptions.is_empty() => Frame::Error(format!(
+                        "ERR Can't execute '{}': only SUBSCRIBE / UNSUBSCRIBE / PING are allowed in this context",
+                        command_name(&original_frame)
+                    )),
+                    Ok(Command::Multi) if multi.is_some() => Frame::Error("ERR MULTI calls can not be nested".into()),
+                    Ok(Command::Multi) => {
+                        multi = Some(Transaction::default());
+                        Frame::Simple("OK".into())
+                    }
+                    Ok(Command::Exec) => match multi.take() {
+                        None => Frame::Error("ERR EXEC without MULTI".into()),
+                        Some(tx) => {
+                            let response = if tx.aborted {
+                                Frame::Error("EXECABORT Transaction discarded because of previous errors.".into())
+                            } else {
+                                match exec(&dbs, &aof, &mut selected, tx.queued, &watched).await {
+                                    Ok(Some(replies)) => Frame::Array(replies),
+                                    Ok(None) => Frame::Null,
+                                    Err(e) => e,
+                                }
+                            };
+                            dbs.unwatch(std::mem::take(&mut watched)).await;
+                            response
                         }
-                        
-                        if let Err(e) = conn.write_frame(&response).await {
-                            eprintln!("error writing response: {:?}", e);
-                            break;
+                    },
+                    Ok(Command::Discard) => match multi.take() {
+                        None => Frame::Error("ERR DISCARD without MULTI".into()),
+                        Some(_) => {
+                            dbs.unwatch(std::mem::take(&mut watched)).await;
+                            Frame::Simple("OK".into())
                         }
+                    },
+                    Ok(Command::Watch(_)) if multi.is_some() => {
+                        Frame::Error("ERR WATCH inside MULTI is not allowed".into())
                     }
+                    Ok(Command::Watch(keys)) => {
+                        for key in keys {
+                            if !watched.iter().any(|w| w.is(selected, &key)) {
+                                watched.push(dbs.watch(selected, key).await);
+                            }
+                        }
+                        Frame::Simple("OK".into())
+                    }
+                    Ok(Command::Unwatch) if multi.is_none() => {
+                        dbs.unwatch(std::mem::take(&mut watched)).await;
+                        Frame::Simple("OK".into())
+                    }
+                    // Has to get through while a script holds everything else off.
+                    Ok(Command::ScriptKill) => dbs.scripts().kill(),
+                    // Run as a transaction of one, so that the writes of the
+                    // script are logged as a block.
+                    Ok(cmd) if cmd.is_script() && multi.is_none() => {
+                        match exec(&dbs, &aof, &mut selected, vec![(cmd, original_frame)], &[]).await {
+                            Ok(Some(mut replies)) => replies.pop().unwrap_or(Frame::Null),
+                            Ok(None) => Frame::Null,
+                            Err(e) => e,
+                        }
+                    }
+                    Ok(cmd) if multi.is_none() && cmd.picks_with_repetition() => {
+                        match dbs.random_picks(selected, &cmd).await {
+                            Ok(picks) => {
+                                if write_random_picks(&conn, picks).await.is_err() {
+                                    break;
+                                }
+                                continue;
+                            }
+                            Err(reply) => reply,
+                        }
+                    }
+                    Ok(cmd) => match &mut multi {
+                        Some(tx) => {
+                            tx.queued.push((cmd, original_frame));
+                            Frame::Simple("QUEUED".into())
+                        }
+                        None => execute(&dbs, &aof, &mut selected, cmd, original_frame, conn.closed()).await,
+                    },
                     Err(e) => {
                         eprintln!("command parse error: {}", e);
-                        let err_frame = crate::resp::Frame::Error(format!("ERR {}", e));
-                        if let Err(e) = conn.write_frame(&err_frame).await {
-                            eprintln!("error writing response: {:?}", e);
-                            break;
+                        if let Some(tx) = &mut multi {
+                            tx.aborted = true;
                         }
+                        Frame::Error(e.reply())
                     }
+                };
+
+                if conn.write_frame(&response).is_err() {
+                    break;
                 }
             }
+            dbs.unwatch(watched).await;
+            for channel in subscriptions {
+                dbs.pubsub().unsubscribe(&channel, id);
+            }
         });
     }
 }
+
+/// Unsubscribes from `channels`, or from every channel if none are given,
+/// confirming each.
+fn unsubscribe(conn: &Connection, dbs: &Databases, id: u64, subscriptions: &mut IndexSet<String>, channels: Vec<String>) {
+    let channels = if channels.is_empty() {
+        subscriptions.iter().cloned().collect()
+    } else {
+        channels
+    };
+    if channels.is_empty() {
+        let _ = conn.write_frame(&subscription_reply("unsubscribe", None, 0));
+    }
+    for channel in channels {
+        if subscriptions.shift_remove(&channel) {
+            dbs.pubsub().unsubscribe(&channel, id);
+        }
+        let _ = conn.write_frame(&subscription_reply("unsubscribe", Some(channel), subscriptions.len()));
+    }
+}
+
+fn subscription_reply(kind: &str, channel: Option<String>, count: usize) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(kind.as_bytes().to_vec()),
+        channel.map_or(Frame::Null, |c| Frame::Bulk(c.into_bytes())),
+        Frame::Integer(count as i64),
+    ])
+}
+
+/// The name of the command in `frame`, lowercase, for error replies.
+fn command_name(frame: &Frame) -> String {
+    match frame {
+        Frame::Array(items) => match items.first() {
+            Some(Frame::Bulk(name)) => String::from_utf8_lossy(name).to_lowercase(),
+            Some(Frame::Simple(name)) => name.to_lowercase(),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    }
+}
+
+/// Writes the reply of a command picking with repetition, a batch of picks at
+/// a time once the client has read the previous ones. However large the
+/// count, the reply is never held at once.
+async fn write_random_picks(conn: &Connection, picks: RandomPicks) -> std::io::Result<()> {
+    let width = picks.entries[0].len() as u64;
+    let encoded: Vec<Vec<u8>> = picks
+        .entries
+        .iter()
+        .map(|entry| entry.iter().flat_map(encode_frame).collect())
+        .collect();
+
+    let mut rng = StdRng::from_entropy();
+    let mut batch = format!("*{}\r\n", picks.count * width).into_bytes();
+    for _ in 0..picks.count {
+        batch.extend_from_slice(&encoded[rng.gen_range(0..encoded.len())]);
+        if batch.len() >= MAX_UNREAD_REPLIES {
+            conn.write_bytes(std::mem::take(&mut batch))?;
+            conn.output().writable().await;
+        }
+    }
+    conn.write_bytes(batch)
+}
+
+/// Runs one command outside a transaction and logs it, along with the
+/// effects it propagated, to the AOF.
+async fn execute(
+    dbs: &Databases,
+    aof: &Aof,
+    selected: &mut usize,
+    cmd: Command,
+    frame: Frame,
+    closed: impl Future<Output = ()>,
+) -> Frame {
+    let should_log = cmd.is_write_for_aof();
+    let before = *selected;
+
+    // Evictions are logged ahead of the command they made room for, so that
+    // replay cannot evict what it wrote.
+    let (admitted, mut effects) = capture(dbs.make_room(&cmd)).await;
+    let response = if admitted {
+        let (response, applied) = capture(dbs.apply_until(selected, cmd, closed)).await;
+        if should_log && !matches!(response, Frame::Error(_)) {
+            effects.push((Some(before), frame));
+        }
+        effects.extend(applied);
+        response
+    } else {
+        Frame::Error(OOM_ERROR.to_string())
+    };
+
+    let entries: Vec<_> = effects.into_iter().map(|(db, f)| (db.unwrap_or(before), f)).collect();
+    if let Err(e) = aof.append_frames(&entries).await {
+        eprintln!("AOF append error: {:?}", e);
+    }
+    response
+}
+
+/// Runs the commands queued by MULTI with no other client's commands in
+/// between, returning all their replies, unless one of the `watched` keys
+/// changed, in which case nothing runs. The writes among them are logged as
+/// a MULTI ... EXEC block so that replay applies them together.
+///
+/// Fails with the error reply for the whole transaction if it is refused.
+async fn exec(
+    dbs: &Databases,
+    aof: &Aof,
+    selected: &mut usize,
+    queued: Vec<(Command, Frame)>,
+    watched: &[Watch],
+) -> Result<Option<Vec<Frame>>, Frame> {
+    // Room is made up front: a transaction is either refused as a whole or
+    // runs to the end.
+    let mut entries = Vec::new();
+    if let Some((cmd, _)) = queued.iter().find(|(cmd, _)| cmd.is_denyoom()) {
+        let (admitted, evicted) = capture(dbs.make_room(cmd)).await;
+        entries.extend(evicted.into_iter().map(|(db, f)| (db.unwrap_or(*selected), f)));
+        if !admitted {
+            if let Err(e) = aof.append_frames(&entries).await {
+                eprintln!("AOF append error: {:?}", e);
+            }
+            return Err(Frame::Error(OOM_ERROR.to_string()));
+        }
+    }
+
+    let tx = match dbs.exclusive().await {
+        Ok(tx) => tx,
+        Err(busy) => {
+            if let Err(e) = aof.append_frames(&entries).await {
+                eprintln!("AOF append error: {:?}", e);
+            }
+            return Err(busy);
+        }
+    };
+    if tx.changed(watched).await {
+        drop(tx);
+        if let Err(e) = aof.append_frames(&entries).await {
+            eprintln!("AOF append error: {:?}", e);
+        }
+        return Ok(None);
+    }
+    let mut block = Vec::new();
+    let mut replies = Vec::with_capacity(queued.len());
+    for (cmd, frame) in queued {
+        let should_log = cmd.is_write_for_aof();
+        let before = *selected;
+        let (response, effects) = capture(tx.apply(selected, cmd)).await;
+        if should_log && !matches!(response, Frame::Error(_)) {
+            block.push((before, frame));
+        }
+        block.extend(effects.into_iter().map(|(db, f)| (db.unwrap_or(before), f)));
+        replies.push(response);
+    }
+
+    if let (Some((first, _)), Some((last, _))) = (block.first(), block.last()) {
+        let (first, last) = (*first, *last);
+        entries.push((first, command_frame(["MULTI"])));
+        entries.append(&mut block);
+        entries.push((last, command_frame(["EXEC"])));
+    }
+
+    // Clients blocked on keys the transaction filled are served after it,
+    // and their writes logged after its block.
+    let ((), served) = capture(tx.serve_blocked()).await;
+    entries.extend(served.into_iter().map(|(db, f)| (db.unwrap_or(*selected), f)));
+
+    // Logged before other clients may run again, so the log keeps the order
+    // in which the transaction ran relative to them.
+    if let Err(e) = aof.append_frames(&entries).await {
+        eprintln!("AOF append error: {:?}", e);
+    }
+    drop(tx);
+
+    Ok(Some(replies))
+}
@@ -0,0 +1,304 @@
+use std::borrow::Cow;
+use std::mem::size_of;
+
+use indexmap::IndexSet;
+use rand::Rng;
+
+use crate::encoding::{limit, LIMITS};
+use crate::intset::{parse_canonical, IntSet};
+use crate::listpack::ListPack;
+use crate::value::sampled_size;
+
+#[derive(Debug)]
+enum Encoding {
+    IntSet(IntSet),
+    ListPack(ListPack),
+    /// Indexed so that random members are found in constant time.
+    Table(IndexSet<Vec<u8>>),
+}
+
+/// A set value. Integer-only sets start out as an intset, small sets of
+/// other strings as a listpack, and both move to a hash table once they
+/// pass the `set-max-*` limits.
+#[derive(Debug)]
+pub struct SetState {
+    data: Encoding,
+}
+
+impl Default for SetState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SetState {
+    pub fn new() -> Self {
+        Self {
+            data: Encoding::IntSet(IntSet::new()),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self.data {
+            Encoding::IntSet(_) => "intset",
+            Encoding::ListPack(_) => "listpack",
+            Encoding::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.data {
+            Encoding::IntSet(s) => s.len(),
+            Encoding::ListPack(lp) => lp.len(),
+            Encoding::Table(t) => t.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Estimated heap bytes held by the members.
+    pub fn mem_usage(&self, samples: usize) -> usize {
+        match &self.data {
+            Encoding::IntSet(s) => s.len() * size_of::<i64>(),
+            Encoding::ListPack(lp) => lp.mem_usage(),
+            Encoding::Table(t) => sampled_size(
+                t.len(),
+                size_of::<Vec<u8>>() + size_of::<usize>(),
+                samples,
+                t.iter().map(|m| m.capacity()),
+            ),
+        }
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match &self.data {
+            Encoding::IntSet(s) => parse_canonical(member).is_some_and(|n| s.contains(n)),
+            Encoding::ListPack(lp) => lp.iter().any(|m| m == member),
+            Encoding::Table(t) => t.contains(member),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Cow<'_, [u8]>> + '_> {
+        match &self.data {
+            Encoding::IntSet(s) => Box::new(s.iter().map(|n| Cow::Owned(n.to_string().into_bytes()))),
+            Encoding::ListPack(lp) => Box::new(lp.iter().map(Cow::Borrowed)),
+            Encoding::Table(t) => Box::new(t.iter().map(|m| Cow::Borrowed(m.as_slice()))),
+        }
+    }
+
+    pub fn insert(&mut self, member: Vec<u8>) -> bool {
+        if self.contains(&member) {
+            return false;
+        }
+
+        let max_value = limit(&LIMITS.set_max_listpack_value);
+        let max_entries = limit(&LIMITS.set_max_listpack_entries);
+        let fits_listpack = self.len() < max_entries && member.len() <= max_value;
+
+        match &mut self.data {
+            Encoding::IntSet(s) => match parse_canonical(&member) {
+                Some(n) if s.len() < limit(&LIMITS.set_max_intset_entries) => {
+                    s.insert(n);
+                }
+                _ if fits_listpack => {
+                    self.convert_to_listpack();
+                    self.insert(member);
+                }
+                _ => {
+                    self.convert_to_table();
+                    self.insert(member);
+                }
+            },
+            Encoding::ListPack(lp) if fits_listpack => lp.push_back(&member),
+            Encoding::ListPack(_) => {
+                self.convert_to_table();
+                self.insert(member);
+            }
+            Encoding::Table(t) => {
+                t.insert(member);
+            }
+        }
+        true
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match &mut self.data {
+            Encoding::IntSet(s) => parse_canonical(member).is_some_and(|n| s.remove(n)),
+            Encoding::ListPack(lp) => match lp.iter().position(|m| m == member) {
+                Some(pos) => {
+                    lp.remove(pos);
+                    true
+                }
+                None => false,
+            },
+            Encoding::Table(t) => t.swap_remove(member),
+        }
+    }
+
+    /// The member at position `idx` of the encoding's own order.
+    fn get(&self, idx: usize) -> Option<Cow<'_, [u8]>> {
+        match &self.data {
+            Encoding::IntSet(s) => s.get(idx).map(|n| Cow::Owned(n.to_string().into_bytes())),
+            Encoding::ListPack(lp) => lp.get(idx).map(Cow::Borrowed),
+            Encoding::Table(t) => t.get_index(idx).map(|m| Cow::Borrowed(m.as_slice())),
+        }
+    }
+
+    /// A member picked at random, or None if the set is empty.
+    pub fn random_member(&self) -> Option<Cow<'_, [u8]>> {
+        if self.is_empty() {
+            return None;
+        }
+        self.get(rand::thread_rng().gen_range(0..self.len()))
+    }
+
+    /// Up to `count` distinct members picked at random.
+    pub fn random_members(&self, count: usize) -> Vec<Cow<'_, [u8]>> {
+        let n = count.min(self.len());
+        rand::seq::index::sample(&mut rand::thread_rng(), self.len(), n)
+            .into_iter()
+            .filter_map(|i| self.get(i))
+            .collect()
+    }
+
+    /// Removes up to `count` members picked at random and returns them.
+    pub fn pop_random(&mut self, count: usize) -> Vec<Vec<u8>> {
+        let mut rng = rand::thread_rng();
+        let n = count.min(self.len());
+        let mut popped = Vec::with_capacity(n);
+        for _ in 0..n {
+            let idx = rng.gen_range(0..self.len());
+            popped.push(match &mut self.data {
+                Encoding::IntSet(s) => s.remove_at(idx).to_string().into_bytes(),
+                Encoding::ListPack(lp) => lp.remove(idx),
+                Encoding::Table(t) => t.swap_remove_index(idx).expect("index within the set"),
+            });
+        }
+        popped
+    }
+
+    fn convert_to_listpack(&mut self) {
+        let lp = self.iter().collect::<Vec<_>>().iter().map(|m| m.as_ref()).collect();
+        self.data = Encoding::ListPack(lp);
+    }
+
+    fn convert_to_table(&mut self) {
+        let table = self.iter().map(Cow::into_owned).collect();
+        self.data = Encoding::Table(table);
+    }
+}
+
+impl FromIterator<Vec<u8>> for SetState {
+    fn from_iter<I: IntoIterator<Item = Vec<u8>>>(iter: I) -> Self {
+        let mut set = SetState::new();
+        for member in iter {
+            set.insert(member);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    /// The encoding `set` should be in after inserting `member` while it held
+    /// `len` members in `encoding`.
+    fn encoding_after_insert(encoding: &'static str, len: usize, member: &[u8]) -> &'static str {
+        let fits_listpack = len < limit(&LIMITS.set_max_listpack_entries)
+            && member.len() <= limit(&LIMITS.set_max_listpack_value);
+        match encoding {
+            "intset" if parse_canonical(member).is_some() && len < limit(&LIMITS.set_max_intset_entries) => "intset",
+            "intset" | "listpack" if fits_listpack => "listpack",
+            _ => "hashtable",
+        }
+    }
+
+    fn member(rng: &mut StdRng) -> Vec<u8> {
+        match rng.gen_range(0..10) {
+            0..=6 => rng.gen_range(-300..300).to_string().into_bytes(),
+            7 => format!("0{}", rng.gen_range(0..10)).into_bytes(),
+            8 => format!("m{}", rng.gen_range(0..200)).into_bytes(),
+            _ => {
+                let len = limit(&LIMITS.set_max_listpack_value) + rng.gen_range(0..2);
+                vec![b'v'; len]
+            }
+        }
+    }
+
+    fn assert_matches(set: &SetState, model: &HashSet<Vec<u8>>) {
+        assert_eq!(set.len(), model.len());
+        let mut members: Vec<Vec<u8>> = set.iter().map(Cow::into_owned).collect();
+        members.sort();
+        let mut expected: Vec<Vec<u8>> = model.iter().cloned().collect();
+        expected.sort();
+        assert_eq!(members, expected);
+        for m in model {
+            assert!(set.contains(m));
+        }
+    }
+
+    #[test]
+    fn converts_at_the_limits() {
+        let intset_max = limit(&LIMITS.set_max_intset_entries);
+        let mut set: SetState = (0..intset_max).map(|n| n.to_string().into_bytes()).collect();
+        assert_eq!(set.encoding(), "intset");
+        set.insert(intset_max.to_string().into_bytes());
+        assert_eq!(set.encoding(), "hashtable");
+
+        let value_max = limit(&LIMITS.set_max_listpack_value);
+        let mut set: SetState = [b"a".to_vec(), vec![b'x'; value_max]].into_iter().collect();
+        assert_eq!(set.encoding(), "listpack");
+        set.insert(vec![b'x'; value_max + 1]);
+        assert_eq!(set.encoding(), "hashtable");
+
+        let entries_max = limit(&LIMITS.set_max_listpack_entries);
+        let mut set: SetState = (0..entries_max).map(|n| format!("m{}", n).into_bytes()).collect();
+        assert_eq!(set.encoding(), "listpack");
+        assert!(!set.insert(b"m0".to_vec()));
+        assert_eq!(set.encoding(), "listpack");
+        set.insert(b"new".to_vec());
+        assert_eq!(set.encoding(), "hashtable");
+        assert_eq!(set.len(), entries_max + 1);
+    }
+
+    #[test]
+    fn matches_hashset_across_conversions() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..20 {
+            let mut set = SetState::new();
+            let mut model = HashSet::new();
+            for _ in 0..800 {
+                let m = member(&mut rng);
+                match rng.gen_range(0..10) {
+                    0..=6 => {
+                        let expected = if model.contains(&m) {
+                            set.encoding()
+                        } else {
+                            encoding_after_insert(set.encoding(), model.len(), &m)
+                        };
+                        assert_eq!(set.insert(m.clone()), model.insert(m.clone()));
+                        assert_eq!(set.encoding(), expected);
+                    }
+                    7 | 8 => assert_eq!(set.remove(&m), model.remove(&m)),
+                    _ => {
+                        let encoding = set.encoding();
+                        for popped in set.pop_random(rng.gen_range(0..3)) {
+                            assert!(model.remove(&popped));
+                        }
+                        assert_eq!(set.encoding(), encoding);
+                    }
+                }
+                assert_eq!(set.contains(&m), model.contains(&m));
+            }
+            assert_matches(&set, &model);
+        }
+    }
+}
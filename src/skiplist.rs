@@ -1,50 +1,66 @@
 use rand::Rng;
-use std::cmp::Ordering;
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::mem::size_of;
 
-const MAX_LEVEL: usize = 16;
+use crate::command::{LexRange, ScoreRange};
+use crate::value::sampled_size;
+
+const MAX_LEVEL: usize = 32;
 const P: f64 = 0.25;
 
-type NodeRef = Arc<Mutex<Node>>;
+/// Arena index of the header node, which holds no entry.
+const HEAD: usize = 0;
+/// Stand-in for a missing link.
+const NIL: usize = usize::MAX;
 
-#[derive(Debug)]
-pub struct Level {
-    pub forward: Option<NodeRef>,
+#[derive(Debug, Clone, Copy)]
+struct Level {
+    forward: usize,
+    /// Number of level-0 steps this link skips over, used to compute ranks.
+    span: usize,
 }
 
 #[derive(Debug)]
-pub struct Node {
-    pub score: f64,
-    pub member: Vec<u8>,
-    pub levels: Vec<Level>,
-}
-
-impl Node {
-    pub fn new(score: f64, member: Vec<u8>, level: usize) -> NodeRef {
-        Arc::new(Mutex::new(Self {
-            score,
-            member,
-            levels: (0..level)
-                .map(|_| Level { forward: None })
-                .collect(),
-        }))
-    }
+struct Node {
+    score: f64,
+    member: Vec<u8>,
+    backward: usize,
+    levels: Vec<Level>,
 }
 
+/// Sorted set storage: a skiplist ordered by (score, member) whose nodes live
+/// in a `Vec` and link to each other by index, plus a member -> score map for
+/// constant-time lookups.
 #[derive(Debug)]
 pub struct SkipList {
-    pub head: NodeRef,
-    pub level: usize,
-    pub length: usize,
+    nodes: Vec<Node>,
+    /// Arena slots freed by removals, reused by later inserts.
+    free: Vec<usize>,
+    dict: HashMap<Vec<u8>, f64>,
+    tail: usize,
+    level: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SkipList {
     pub fn new() -> Self {
-        let head = Node::new(f64::NEG_INFINITY, Vec::new(), MAX_LEVEL);
+        let head = Node {
+            score: f64::NEG_INFINITY,
+            member: Vec::new(),
+            backward: NIL,
+            levels: vec![Level { forward: NIL, span: 0 }; MAX_LEVEL],
+        };
         Self {
-            head,
+            nodes: vec![head],
+            free: Vec::new(),
+            dict: HashMap::new(),
+            tail: NIL,
             level: 1,
-            length: 0,
         }
     }
 
@@ -57,222 +73,461 @@ impl SkipList {
         lvl
     }
 
-    fn compare(a_score: f64, a_member: &[u8], b_score: f64, b_member: &[u8]) -> Ordering {
-        match a_score.partial_cmp(&b_score).unwrap_or(Ordering::Equal) {
-            Ordering::Less => Ordering::Less,
-            Ordering::Greater => Ordering::Greater,
-            Ordering::Equal => a_member.cmp(b_member),
-        }
+    /// Whether node `idx` sorts strictly before (score, member).
+    fn is_before(&self, idx: usize, score: f64, member: &[u8]) -> bool {
+        let node = &self.nodes[idx];
+        node.score < score || (node.score == score && node.member.as_slice() < member)
+    }
+
+    fn forward(&self, idx: usize, lvl: usize) -> usize {
+        self.nodes[idx].levels[lvl].forward
     }
 
     pub fn len(&self) -> usize {
-        self.length
+        self.dict.len()
     }
 
-    pub fn insert(&mut self, score: f64, member: Vec<u8>) {
-        self.remove_member(&member);
+    pub fn is_empty(&self) -> bool {
+        self.dict.is_empty()
+    }
 
-        let mut update: Vec<NodeRef> = Vec::with_capacity(MAX_LEVEL);
-        for _ in 0..MAX_LEVEL {
-            update.push(self.head.clone());
+    /// Estimated heap bytes held by the nodes and the member index. Nodes
+    /// average 1/(1-P) levels.
+    pub fn mem_usage(&self, samples: usize) -> usize {
+        let node = size_of::<Node>() + size_of::<Level>() * 4 / 3;
+        let dict = size_of::<(Vec<u8>, f64)>() + size_of::<usize>();
+        // Every member is stored twice, once in its node and once in the dict.
+        sampled_size(self.dict.len(), node + dict, samples, self.dict.keys().map(|m| 2 * m.capacity()))
+    }
+
+    /// Adds `member` or moves it to `score` if already present.
+    pub fn insert(&mut self, score: f64, member: Vec<u8>) {
+        match self.dict.get(&member) {
+            Some(current) if *current == score => return,
+            Some(_) => {
+                self.remove_member(&member);
+            }
+            None => {}
         }
+        self.dict.insert(member.clone(), score);
+        self.insert_node(score, member);
+    }
 
-        let mut current = self.head.clone();
+    fn insert_node(&mut self, score: f64, member: Vec<u8>) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0usize; MAX_LEVEL];
 
+        let mut x = HEAD;
         for lvl in (0..self.level).rev() {
+            rank[lvl] = if lvl == self.level - 1 { 0 } else { rank[lvl + 1] };
             loop {
-                let next_opt = current.lock().unwrap().levels[lvl].forward.clone();
-                match next_opt {
-                    Some(ref next) => {
-                        let nb = next.lock().unwrap();
-                        if Self::compare(nb.score, &nb.member, score, &member) == Ordering::Less {
-                            current = next.clone();
-                        } else {
-                            break;
-                        }
-                    }
-                    None => break,
+                let next = self.forward(x, lvl);
+                if next != NIL && self.is_before(next, score, &member) {
+                    rank[lvl] += self.nodes[x].levels[lvl].span;
+                    x = next;
+                } else {
+                    break;
                 }
             }
-            update[lvl] = current.clone();
+            update[lvl] = x;
         }
 
+        // `dict` already holds the new member, so the old length is one less.
+        let len_before = self.dict.len() - 1;
         let new_level = Self::random_level();
         if new_level > self.level {
             for lvl in self.level..new_level {
-                update[lvl] = self.head.clone();
+                rank[lvl] = 0;
+                update[lvl] = HEAD;
+                self.nodes[HEAD].levels[lvl].span = len_before;
             }
             self.level = new_level;
         }
 
-        let new_node = Node::new(score, member, new_level);
+        let idx = self.alloc(Node {
+            score,
+            member,
+            backward: NIL,
+            levels: vec![Level { forward: NIL, span: 0 }; new_level],
+        });
 
         for lvl in 0..new_level {
-            let next = update[lvl].lock().unwrap().levels[lvl].forward.clone();
-            new_node.lock().unwrap().levels[lvl].forward = next.clone();
-            update[lvl].lock().unwrap().levels[lvl].forward = Some(new_node.clone());
+            let prev = update[lvl];
+            let prev_level = self.nodes[prev].levels[lvl];
+            self.nodes[idx].levels[lvl] = Level {
+                forward: prev_level.forward,
+                span: prev_level.span - (rank[0] - rank[lvl]),
+            };
+            self.nodes[prev].levels[lvl] = Level {
+                forward: idx,
+                span: rank[0] - rank[lvl] + 1,
+            };
+        }
+        for (lvl, &prev) in update.iter().enumerate().take(self.level).skip(new_level) {
+            self.nodes[prev].levels[lvl].span += 1;
         }
 
-        self.length += 1;
+        self.nodes[idx].backward = if update[0] == HEAD { NIL } else { update[0] };
+        match self.forward(idx, 0) {
+            NIL => self.tail = idx,
+            next => self.nodes[next].backward = idx,
+        }
     }
-    
-    pub fn remove_member(&mut self, member: &[u8]) -> bool {
-        let mut target: Option<NodeRef> = None;
-        let mut current_opt = self.head.lock().unwrap().levels[0].forward.clone();
 
-        while let Some(node_rc) = current_opt.clone() {
-            if node_rc.lock().unwrap().member == member {
-                target = Some(node_rc.clone());
-                break;
+    fn alloc(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = node;
+                idx
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
             }
-            current_opt = node_rc.lock().unwrap().levels[0].forward.clone();
         }
+    }
 
-        let target = match target {
-            Some(t) => t,
-            None => return false,
+    pub fn remove_member(&mut self, member: &[u8]) -> bool {
+        let Some(score) = self.dict.remove(member) else {
+            return false;
         };
-        
+
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut x = HEAD;
         for lvl in (0..self.level).rev() {
-            let mut current = self.head.clone();
             loop {
-                let next_opt = current.lock().unwrap().levels[lvl].forward.clone();
-                match next_opt {
-                    Some(ref next) => {
-                        if Arc::ptr_eq(next, &target) {
-                            let next_next = next.lock().unwrap().levels[lvl].forward.clone();
-                            current.lock().unwrap().levels[lvl].forward = next_next;
-                            break;
-                        } else {
-                            current = next.clone();
-                        }
-                    }
-                    None => break,
+                let next = self.forward(x, lvl);
+                if next != NIL && self.is_before(next, score, member) {
+                    x = next;
+                } else {
+                    break;
                 }
             }
-        }
-        
-        self.length -= 1;
-
-        while self.level > 1
-            && self.head.lock().unwrap().levels[self.level - 1]
-                .forward
-                .is_none()
-        {
-            self.level -= 1;
+            update[lvl] = x;
         }
 
+        let target = self.forward(x, 0);
+        debug_assert!(target != NIL && self.nodes[target].member == member);
+        self.delete_node(target, &update);
         true
     }
 
-    pub fn get_score(&self, member: &[u8]) -> Option<f64> {
-        let mut current_opt = self.head.lock().unwrap().levels[0].forward.clone();
-        while let Some(node_rc) = current_opt {
-            let node = node_rc.lock().unwrap();
-            if node.member == member {
-                return Some(node.score)
+    fn delete_node(&mut self, x: usize, update: &[usize; MAX_LEVEL]) {
+        for (lvl, &prev) in update.iter().enumerate().take(self.level) {
+            if self.forward(prev, lvl) == x {
+                let removed = self.nodes[x].levels[lvl];
+                let prev_level = &mut self.nodes[prev].levels[lvl];
+                prev_level.span = prev_level.span + removed.span - 1;
+                prev_level.forward = removed.forward;
+            } else {
+                self.nodes[prev].levels[lvl].span -= 1;
             }
-            current_opt = node.levels[0].forward.clone();
         }
-        None
+
+        let backward = self.nodes[x].backward;
+        match self.forward(x, 0) {
+            NIL => self.tail = backward,
+            next => self.nodes[next].backward = backward,
+        }
+
+        while self.level > 1 && self.forward(HEAD, self.level - 1) == NIL {
+            self.level -= 1;
+        }
+
+        let node = &mut self.nodes[x];
+        node.member = Vec::new();
+        node.levels = Vec::new();
+        self.free.push(x);
+    }
+
+    pub fn first(&self) -> Option<(Vec<u8>, f64)> {
+        match self.forward(HEAD, 0) {
+            NIL => None,
+            idx => Some(self.entry(idx)),
+        }
+    }
+
+    pub fn last(&self) -> Option<(Vec<u8>, f64)> {
+        match self.tail {
+            NIL => None,
+            idx => Some(self.entry(idx)),
+        }
+    }
+
+    fn entry(&self, idx: usize) -> (Vec<u8>, f64) {
+        let node = &self.nodes[idx];
+        (node.member.clone(), node.score)
     }
-    
-    pub fn range_by_score(&self, min: f64, max: f64) -> Vec<Vec<u8>> {
-        let mut result = Vec::new();
-        let mut current = self.head.clone();
 
+    pub fn get_score(&self, member: &[u8]) -> Option<f64> {
+        self.dict.get(member).copied()
+    }
+
+    /// Walks to the first node for which `before` is false and collects
+    /// entries from there on while `within` holds.
+    fn collect_range(
+        &self,
+        before: impl Fn(f64, &[u8]) -> bool,
+        within: impl Fn(f64, &[u8]) -> bool,
+    ) -> Vec<(Vec<u8>, f64)> {
+        let mut x = HEAD;
         for lvl in (0..self.level).rev() {
             loop {
-                let next_opt = current.lock().unwrap().levels[lvl].forward.clone();
-                match next_opt {
-                    Some(ref next) => {
-                        let nb = next.lock().unwrap();
-                        if nb.score < min {
-                            current = next.clone();
-                        } else {
-                            break;
-                        }
-                    }
-                    None => break,
+                let next = self.forward(x, lvl);
+                if next != NIL && before(self.nodes[next].score, &self.nodes[next].member) {
+                    x = next;
+                } else {
+                    break;
                 }
             }
         }
 
-        let mut current_opt = current.lock().unwrap().levels[0].forward.clone();
-
-        while let Some(node_rc) = current_opt {
-            let nb = node_rc.lock().unwrap();
-            if nb.score > max {
-                break;
-            }
-            result.push(nb.member.clone());
-            current_opt = nb.levels[0].forward.clone();
+        let mut result = Vec::new();
+        let mut x = self.forward(x, 0);
+        while x != NIL && within(self.nodes[x].score, &self.nodes[x].member) {
+            result.push(self.entry(x));
+            x = self.forward(x, 0);
         }
-
         result
     }
 
-    pub fn remove_range_by_score(&mut self, min: f64, max: f64) -> usize {
-        let mut removed = 0;
+    /// Every entry in (score, member) order.
+    pub fn entries(&self) -> Vec<(Vec<u8>, f64)> {
+        self.collect_range(|_, _| false, |_, _| true)
+    }
 
-        let mut to_remove = Vec::new();
+    pub fn range_by_score(&self, range: &ScoreRange) -> Vec<(Vec<u8>, f64)> {
+        if range.is_empty() {
+            return Vec::new();
+        }
+        self.collect_range(|score, _| !range.above_min(score), |score, _| range.below_max(score))
+    }
 
-        let mut current_opt = self.head.lock().unwrap().levels[0].forward.clone();
-        while let Some(node_rc) = current_opt {
-            let node = node_rc.lock().unwrap();
+    /// Lexicographical range; only meaningful when all members share a score.
+    pub fn range_by_lex(&self, range: &LexRange) -> Vec<(Vec<u8>, f64)> {
+        if range.is_empty() {
+            return Vec::new();
+        }
+        self.collect_range(|_, member| !range.above_min(member), |_, member| range.below_max(member))
+    }
 
-            if node.score >= min && node.score <= max {
-                to_remove.push(node.member.clone());
-            }
-            current_opt = node.levels[0].forward.clone();
+    /// Arena index of the node at 0-based `rank`, found by summing spans.
+    fn node_at_rank(&self, rank: usize) -> Option<usize> {
+        if rank >= self.len() {
+            return None;
         }
 
-        for member in to_remove {
-            if self.remove_member(&member) {
-                removed += 1;
+        // Spans count the header as position 0, so target the 1-based rank.
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+        for lvl in (0..self.level).rev() {
+            loop {
+                let next = self.forward(x, lvl);
+                let span = self.nodes[x].levels[lvl].span;
+                if next != NIL && traversed + span <= target {
+                    traversed += span;
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            if traversed == target {
+                return Some(x);
             }
         }
+        None
+    }
 
-        removed
+    /// The entry at 0-based `rank`, in O(log n).
+    pub fn get_by_rank(&self, rank: usize) -> Option<(Vec<u8>, f64)> {
+        self.node_at_rank(rank).map(|idx| self.entry(idx))
     }
 
+    /// Entries with ranks in `start..=end`; both must already be in bounds.
+    pub fn range_by_rank(&self, start: usize, end: usize) -> Vec<(Vec<u8>, f64)> {
+        let mut result = Vec::with_capacity(end + 1 - start);
+        let mut x = self.node_at_rank(start).unwrap_or(NIL);
+        while x != NIL && result.len() < end + 1 - start {
+            result.push(self.entry(x));
+            x = self.forward(x, 0);
+        }
+        result
+    }
+
+    fn remove_all(&mut self, entries: Vec<(Vec<u8>, f64)>) -> usize {
+        entries.iter()
+            .filter(|(member, _)| self.remove_member(member))
+            .count()
+    }
+
+    pub fn remove_range_by_score(&mut self, range: &ScoreRange) -> usize {
+        let entries = self.range_by_score(range);
+        self.remove_all(entries)
+    }
+
+    pub fn remove_range_by_lex(&mut self, range: &LexRange) -> usize {
+        let entries = self.range_by_lex(range);
+        self.remove_all(entries)
+    }
+
+    pub fn remove_range_by_rank(&mut self, start: usize, end: usize) -> usize {
+        let entries = self.range_by_rank(start, end);
+        self.remove_all(entries)
+    }
+
+    /// 0-based position of `member` in ascending order.
     pub fn rank(&self, member: &[u8]) -> Option<usize> {
-        let mut rank: usize = 0;
-        let mut current_opt = self.head.lock().unwrap().levels[0].forward.clone();
+        let score = *self.dict.get(member)?;
 
-        while let Some(node_rc) = current_opt {
-            let node = node_rc.lock().unwrap();
-            if node.member == member {
-                return Some(rank);
+        let mut rank = 0;
+        let mut x = HEAD;
+        for lvl in (0..self.level).rev() {
+            loop {
+                let next = self.forward(x, lvl);
+                if next != NIL && (self.is_before(next, score, member) || self.nodes[next].member == member) {
+                    rank += self.nodes[x].levels[lvl].span;
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            if x != HEAD && self.nodes[x].member == member {
+                return Some(rank - 1);
             }
-            rank += 1;
-            current_opt = node.levels[0].forward.clone();
         }
-
         None
     }
+}
 
-    pub fn range_by_rank(&self, start: i64, end: i64) -> Vec<Vec<u8>> {
-        let mut result = Vec::new();
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
-        let mut idx: i64 = 0;
-        let mut current_opt = self.head.lock().unwrap().levels[0].forward.clone();
+    use super::*;
+    use crate::command::ScoreBound;
 
-        while let Some(node_rc) = current_opt {
-            let node = node_rc.lock().unwrap();
+    /// The same entries as a plain Vec kept in (score, member) order.
+    #[derive(Default)]
+    struct Model(Vec<(Vec<u8>, f64)>);
 
-            if idx > end {
-                break;
-            }
+    impl Model {
+        fn insert(&mut self, score: f64, member: Vec<u8>) {
+            self.remove_member(&member);
+            let pos = self.0.partition_point(|(m, s)| (*s, m.as_slice()) < (score, member.as_slice()));
+            self.0.insert(pos, (member, score));
+        }
+
+        fn remove_member(&mut self, member: &[u8]) -> bool {
+            let Some(pos) = self.0.iter().position(|(m, _)| m == member) else {
+                return false;
+            };
+            self.0.remove(pos);
+            true
+        }
+
+        fn rank(&self, member: &[u8]) -> Option<usize> {
+            self.0.iter().position(|(m, _)| m == member)
+        }
+
+        fn range_by_score(&self, range: &ScoreRange) -> Vec<(Vec<u8>, f64)> {
+            self.0.iter()
+                .filter(|(_, s)| range.above_min(*s) && range.below_max(*s))
+                .cloned()
+                .collect()
+        }
+    }
+
+    fn member(i: usize) -> Vec<u8> {
+        format!("m{}", i).into_bytes()
+    }
+
+    fn score_range(rng: &mut StdRng) -> ScoreRange {
+        let bound = |rng: &mut StdRng| {
+            let score = rng.gen_range(0..20) as f64;
+            if rng.gen() { ScoreBound::Inclusive(score) } else { ScoreBound::Exclusive(score) }
+        };
+        ScoreRange { min: bound(rng), max: bound(rng) }
+    }
 
-            if idx >= start {
-                result.push(node.member.clone());
+    fn assert_matches(list: &SkipList, model: &Model) {
+        assert_eq!(list.len(), model.0.len());
+        assert_eq!(list.entries(), model.0);
+        assert_eq!(list.first(), model.0.first().cloned());
+        assert_eq!(list.last(), model.0.last().cloned());
+        for (rank, (m, score)) in model.0.iter().enumerate() {
+            assert_eq!(list.rank(m), Some(rank));
+            assert_eq!(list.get_by_rank(rank), Some((m.clone(), *score)));
+            assert_eq!(list.get_score(m), Some(*score));
+        }
+        assert_eq!(list.get_by_rank(model.0.len()), None);
+    }
+
+    #[test]
+    fn matches_sorted_vec_under_random_operations() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut list = SkipList::new();
+        let mut model = Model::default();
+
+        for round in 0..2000 {
+            // Few distinct scores, so ties are ordered by member.
+            let m = member(rng.gen_range(0..300));
+            match rng.gen_range(0..10) {
+                0..=5 => {
+                    let score = rng.gen_range(0..20) as f64;
+                    list.insert(score, m.clone());
+                    model.insert(score, m.clone());
+                }
+                6 | 7 => assert_eq!(list.remove_member(&m), model.remove_member(&m)),
+                8 => {
+                    let range = score_range(&mut rng);
+                    let expected = model.range_by_score(&range);
+                    assert_eq!(list.range_by_score(&range), expected);
+                    if round % 4 == 0 {
+                        assert_eq!(list.remove_range_by_score(&range), expected.len());
+                        for (m, _) in &expected {
+                            model.remove_member(m);
+                        }
+                    }
+                }
+                _ => {
+                    if model.0.is_empty() {
+                        continue;
+                    }
+                    let start = rng.gen_range(0..model.0.len());
+                    let end = rng.gen_range(start..model.0.len());
+                    let expected = model.0[start..=end].to_vec();
+                    assert_eq!(list.range_by_rank(start, end), expected);
+                    if round % 4 == 0 {
+                        assert_eq!(list.remove_range_by_rank(start, end), expected.len());
+                        model.0.drain(start..=end);
+                    }
+                }
             }
+            assert_eq!(list.rank(&m), model.rank(&m));
+            if round % 50 == 0 {
+                assert_matches(&list, &model);
+            }
+        }
+        assert_matches(&list, &model);
+    }
 
-            idx += 1;
-            current_opt = node.levels[0].forward.clone();
+    #[test]
+    fn reuses_freed_slots_after_emptying() {
+        let mut list = SkipList::new();
+        let mut model = Model::default();
+        for i in 0..100 {
+            list.insert(i as f64, member(i));
         }
+        assert_eq!(list.remove_range_by_rank(0, 99), 100);
+        assert!(list.is_empty());
+        assert_eq!(list.rank(&member(0)), None);
 
-        result
+        for i in (0..50).rev() {
+            list.insert(1.0, member(i));
+            model.insert(1.0, member(i));
+        }
+        assert_matches(&list, &model);
     }
 }
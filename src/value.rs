@@ -1,33 +1,132 @@
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
 
+use crate::hash::HashState;
+use crate::intset::parse_canonical;
 use crate::list::ListState;
-use crate::skiplist::SkipList;
+use crate::set::SetState;
+use crate::zset::ZSetState;
+
+/// Strings that spell a canonical 64-bit integer are stored as the integer.
+#[derive(Debug, Clone)]
+pub enum StringValue {
+    Int(i64),
+    Raw(Vec<u8>),
+}
+
+/// Longest string Redis stores inline with its object header.
+const EMBSTR_MAX_LEN: usize = 44;
+
+/// Elements measured when estimating the size of a large collection, as in
+/// the default of Redis' `MEMORY USAGE`.
+pub const DEFAULT_MEM_SAMPLES: usize = 5;
+
+/// Estimated heap bytes of a collection of `len` elements, extrapolated from
+/// the first `samples` element `sizes`, or all of them if `samples` is 0.
+/// Each element additionally costs `overhead` bytes of bookkeeping.
+pub fn sampled_size(len: usize, overhead: usize, samples: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    let samples = if samples == 0 { usize::MAX } else { samples };
+    let (n, total) = sizes.take(samples).fold((0, 0), |(n, total), size| (n + 1, total + size));
+    if n == 0 {
+        return 0;
+    }
+    len * (overhead + total / n)
+}
+
+impl StringValue {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        match parse_canonical(&bytes) {
+            Some(n) => StringValue::Int(n),
+            None => StringValue::Raw(bytes),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            StringValue::Int(_) => "int",
+            StringValue::Raw(b) if b.len() <= EMBSTR_MAX_LEN => "embstr",
+            StringValue::Raw(_) => "raw",
+        }
+    }
+
+    pub fn as_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            StringValue::Int(n) => Cow::Owned(n.to_string().into_bytes()),
+            StringValue::Raw(b) => Cow::Borrowed(b),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.as_bytes().into_owned()
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            StringValue::Int(n) => n.to_string().len(),
+            StringValue::Raw(b) => b.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The value as an integer, if it parses as one.
+    pub fn parse_int(&self) -> Option<i64> {
+        match self {
+            StringValue::Int(n) => Some(*n),
+            StringValue::Raw(b) => std::str::from_utf8(b).ok()?.parse().ok(),
+        }
+    }
+
+    /// Heap bytes held beyond the value itself.
+    pub fn mem_usage(&self) -> usize {
+        match self {
+            StringValue::Int(_) => 0,
+            StringValue::Raw(b) => b.capacity(),
+        }
+    }
+
+    pub fn append(&mut self, tail: &[u8]) -> usize {
+        let mut bytes = match std::mem::replace(self, StringValue::Raw(Vec::new())) {
+            StringValue::Int(n) => n.to_string().into_bytes(),
+            StringValue::Raw(b) => b,
+        };
+        bytes.extend_from_slice(tail);
+        let len = bytes.len();
+        *self = StringValue::Raw(bytes);
+        len
+    }
+}
 
 #[derive(Debug)]
 pub enum Value {
-    String(Vec<u8>),
+    String(StringValue),
     List(ListState),
-    Hash(HashMap<String, Vec<u8>>),
-    Set(HashSet<Vec<u8>>),
-    ZSet(SkipList),
+    Hash(HashState),
+    Set(SetState),
+    ZSet(ZSetState),
 }
 
 impl Value {
-    pub fn as_string(&self) -> Option<&[u8]> {
+    pub fn string(bytes: Vec<u8>) -> Self {
+        Value::String(StringValue::new(bytes))
+    }
+
+    pub fn as_string(&self) -> Option<Cow<'_, [u8]>> {
         match self {
-            Value::String(v) => Some(v.as_slice()),
+            Value::String(v) => Some(v.as_bytes()),
             _ => None,
         }
     }
 
-    pub fn as_hash_mut(&mut self) -> Option<&mut HashMap<String, Vec<u8>>> {
+    pub fn as_hash_mut(&mut self) -> Option<&mut HashState> {
         match self {
             Value::Hash(ref mut h) => Some(h),
             _ => None,
         }
     }
 
-    pub fn as_set_mut(&mut self) -> Option<&mut HashSet<Vec<u8>>> {
+    pub fn as_set_mut(&mut self) -> Option<&mut SetState> {
         match self {
             Value::Set(ref mut s) => Some(s),
             _ => None,
@@ -41,10 +140,49 @@ impl Value {
         }
     }
 
-    pub fn as_zset_mut(&mut self) -> Option<&mut SkipList> {
+    pub fn as_zset_mut(&mut self) -> Option<&mut ZSetState> {
         match self {
             Value::ZSet(ref mut zs) => Some(zs),
             _ => None,
         }
     }
-}
\ No newline at end of file
+
+    /// Estimated heap bytes held by the value. Large collections are
+    /// extrapolated from `samples` elements (all if 0), which with
+    /// [`DEFAULT_MEM_SAMPLES`] is cheap enough to run after every write.
+    pub fn mem_usage(&self, samples: usize) -> usize {
+        match self {
+            Value::String(s) => s.mem_usage(),
+            Value::List(l) => l.mem_usage(samples),
+            Value::Hash(h) => h.mem_usage(samples),
+            Value::Set(s) => s.mem_usage(samples),
+            Value::ZSet(z) => z.mem_usage(samples),
+        }
+    }
+
+    /// Roughly how many allocations freeing the value takes. Compact
+    /// encodings live in a single buffer whatever their length.
+    pub fn free_effort(&self) -> usize {
+        let len = match self {
+            Value::String(_) => return 1,
+            Value::List(l) => l.len(),
+            Value::Hash(h) => h.len(),
+            Value::Set(s) => s.len(),
+            Value::ZSet(z) => z.len(),
+        };
+        match self.encoding() {
+            "listpack" | "intset" => 1,
+            _ => len,
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(s) => s.encoding(),
+            Value::List(l) => l.encoding(),
+            Value::Hash(h) => h.encoding(),
+            Value::Set(s) => s.encoding(),
+            Value::ZSet(z) => z.encoding(),
+        }
+    }
+}
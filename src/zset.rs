@@ -0,0 +1,321 @@
+use crate::command::{LexRange, ScoreRange};
+use crate::encoding::{limit, LIMITS};
+use crate::listpack::ListPack;
+use crate::skiplist::SkipList;
+
+#[derive(Debug)]
+enum Encoding {
+    /// Member and score entries alternating, ordered by (score, member).
+    ListPack(ListPack),
+    SkipList(SkipList),
+}
+
+/// A sorted set value, packed into a listpack until it passes
+/// `zset-max-listpack-entries` or `zset-max-listpack-value`.
+///
+/// Mirrors the [`SkipList`] API so callers don't care which encoding is in
+/// use; the packed variant answers everything with a linear scan.
+#[derive(Debug)]
+pub struct ZSetState {
+    data: Encoding,
+}
+
+impl Default for ZSetState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decodes the (member, score) pairs of a packed sorted set in order.
+fn packed(lp: &ListPack) -> impl Iterator<Item = (&[u8], f64)> {
+    let scores = lp.iter().skip(1).step_by(2).map(|s| {
+        std::str::from_utf8(s).ok().and_then(|s| s.parse().ok()).unwrap_or(0.0)
+    });
+    lp.iter().step_by(2).zip(scores)
+}
+
+fn packed_position(lp: &ListPack, member: &[u8]) -> Option<usize> {
+    lp.iter().step_by(2).position(|m| m == member)
+}
+
+fn owned<'a>(entries: impl Iterator<Item = (&'a [u8], f64)>) -> Vec<(Vec<u8>, f64)> {
+    entries.map(|(m, s)| (m.to_vec(), s)).collect()
+}
+
+impl ZSetState {
+    pub fn new() -> Self {
+        Self {
+            data: Encoding::ListPack(ListPack::new()),
+        }
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self.data {
+            Encoding::ListPack(_) => "listpack",
+            Encoding::SkipList(_) => "skiplist",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.data {
+            Encoding::ListPack(lp) => lp.len() / 2,
+            Encoding::SkipList(sl) => sl.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Estimated heap bytes held by the members and scores.
+    pub fn mem_usage(&self, samples: usize) -> usize {
+        match &self.data {
+            Encoding::ListPack(lp) => lp.mem_usage(),
+            Encoding::SkipList(sl) => sl.mem_usage(samples),
+        }
+    }
+
+    /// Adds `member` or moves it to `score` if already present.
+    pub fn insert(&mut self, score: f64, member: Vec<u8>) {
+        if let Encoding::ListPack(lp) = &self.data {
+            let grows = self.get_score(&member).is_none();
+            if member.len() > limit(&LIMITS.zset_max_listpack_value)
+                || (grows && lp.len() / 2 >= limit(&LIMITS.zset_max_listpack_entries))
+            {
+                let mut sl = SkipList::new();
+                for (m, s) in packed(lp) {
+                    sl.insert(s, m.to_vec());
+                }
+                self.data = Encoding::SkipList(sl);
+            }
+        }
+
+        match &mut self.data {
+            Encoding::ListPack(lp) => {
+                if let Some(pos) = packed_position(lp, &member) {
+                    lp.remove(pos * 2);
+                    lp.remove(pos * 2);
+                }
+                let pos = packed(lp)
+                    .take_while(|(m, s)| *s < score || (*s == score && *m < member.as_slice()))
+                    .count();
+                lp.insert(pos * 2, &member);
+                lp.insert(pos * 2 + 1, score.to_string().as_bytes());
+            }
+            Encoding::SkipList(sl) => sl.insert(score, member),
+        }
+    }
+
+    pub fn remove_member(&mut self, member: &[u8]) -> bool {
+        match &mut self.data {
+            Encoding::ListPack(lp) => match packed_position(lp, member) {
+                Some(pos) => {
+                    lp.remove(pos * 2);
+                    lp.remove(pos * 2);
+                    true
+                }
+                None => false,
+            },
+            Encoding::SkipList(sl) => sl.remove_member(member),
+        }
+    }
+
+    pub fn first(&self) -> Option<(Vec<u8>, f64)> {
+        match &self.data {
+            Encoding::ListPack(lp) => packed(lp).next().map(|(m, s)| (m.to_vec(), s)),
+            Encoding::SkipList(sl) => sl.first(),
+        }
+    }
+
+    pub fn last(&self) -> Option<(Vec<u8>, f64)> {
+        match &self.data {
+            Encoding::ListPack(lp) => packed(lp).last().map(|(m, s)| (m.to_vec(), s)),
+            Encoding::SkipList(sl) => sl.last(),
+        }
+    }
+
+    pub fn get_score(&self, member: &[u8]) -> Option<f64> {
+        match &self.data {
+            Encoding::ListPack(lp) => packed(lp).find(|(m, _)| *m == member).map(|(_, s)| s),
+            Encoding::SkipList(sl) => sl.get_score(member),
+        }
+    }
+
+    /// Every entry in (score, member) order.
+    pub fn entries(&self) -> Vec<(Vec<u8>, f64)> {
+        match &self.data {
+            Encoding::ListPack(lp) => owned(packed(lp)),
+            Encoding::SkipList(sl) => sl.entries(),
+        }
+    }
+
+    pub fn range_by_score(&self, range: &ScoreRange) -> Vec<(Vec<u8>, f64)> {
+        match &self.data {
+            Encoding::ListPack(lp) => owned(
+                packed(lp).filter(|(_, s)| range.above_min(*s) && range.below_max(*s)),
+            ),
+            Encoding::SkipList(sl) => sl.range_by_score(range),
+        }
+    }
+
+    /// Lexicographical range; only meaningful when all members share a score.
+    pub fn range_by_lex(&self, range: &LexRange) -> Vec<(Vec<u8>, f64)> {
+        match &self.data {
+            Encoding::ListPack(lp) => owned(
+                packed(lp).filter(|(m, _)| range.above_min(m) && range.below_max(m)),
+            ),
+            Encoding::SkipList(sl) => sl.range_by_lex(range),
+        }
+    }
+
+    pub fn get_by_rank(&self, rank: usize) -> Option<(Vec<u8>, f64)> {
+        match &self.data {
+            Encoding::ListPack(lp) => packed(lp).nth(rank).map(|(m, s)| (m.to_vec(), s)),
+            Encoding::SkipList(sl) => sl.get_by_rank(rank),
+        }
+    }
+
+    /// Entries with ranks in `start..=end`; both must already be in bounds.
+    pub fn range_by_rank(&self, start: usize, end: usize) -> Vec<(Vec<u8>, f64)> {
+        match &self.data {
+            Encoding::ListPack(lp) => owned(packed(lp).skip(start).take(end + 1 - start)),
+            Encoding::SkipList(sl) => sl.range_by_rank(start, end),
+        }
+    }
+
+    fn remove_all(&mut self, entries: Vec<(Vec<u8>, f64)>) -> usize {
+        entries.iter()
+            .filter(|(member, _)| self.remove_member(member))
+            .count()
+    }
+
+    pub fn remove_range_by_score(&mut self, range: &ScoreRange) -> usize {
+        let entries = self.range_by_score(range);
+        self.remove_all(entries)
+    }
+
+    pub fn remove_range_by_lex(&mut self, range: &LexRange) -> usize {
+        let entries = self.range_by_lex(range);
+        self.remove_all(entries)
+    }
+
+    pub fn remove_range_by_rank(&mut self, start: usize, end: usize) -> usize {
+        let entries = self.range_by_rank(start, end);
+        self.remove_all(entries)
+    }
+
+    /// 0-based position of `member` in ascending order.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        match &self.data {
+            Encoding::ListPack(lp) => packed_position(lp, member),
+            Encoding::SkipList(sl) => sl.rank(member),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    /// The same entries as a plain Vec kept in (score, member) order.
+    #[derive(Default)]
+    struct Model(Vec<(Vec<u8>, f64)>);
+
+    impl Model {
+        fn insert(&mut self, score: f64, member: Vec<u8>) {
+            self.remove_member(&member);
+            let pos = self.0.partition_point(|(m, s)| (*s, m.as_slice()) < (score, member.as_slice()));
+            self.0.insert(pos, (member, score));
+        }
+
+        fn remove_member(&mut self, member: &[u8]) -> bool {
+            let Some(pos) = self.0.iter().position(|(m, _)| m == member) else {
+                return false;
+            };
+            self.0.remove(pos);
+            true
+        }
+    }
+
+    fn member(rng: &mut StdRng) -> Vec<u8> {
+        if rng.gen_range(0..40) == 0 {
+            let len = limit(&LIMITS.zset_max_listpack_value) + rng.gen_range(0..2);
+            return vec![b'v'; len];
+        }
+        format!("m{}", rng.gen_range(0..200)).into_bytes()
+    }
+
+    fn score(rng: &mut StdRng) -> f64 {
+        match rng.gen_range(0..4) {
+            0 => rng.gen_range(-1e6..1e6),
+            _ => rng.gen_range(-10..10) as f64,
+        }
+    }
+
+    fn assert_matches(zset: &ZSetState, model: &Model) {
+        assert_eq!(zset.len(), model.0.len());
+        assert_eq!(zset.entries(), model.0);
+        for (rank, (m, score)) in model.0.iter().enumerate() {
+            assert_eq!(zset.rank(m), Some(rank));
+            assert_eq!(zset.get_score(m), Some(*score));
+        }
+    }
+
+    #[test]
+    fn converts_at_the_limits() {
+        let value_max = limit(&LIMITS.zset_max_listpack_value);
+        let mut zset = ZSetState::new();
+        zset.insert(1.0, vec![b'x'; value_max]);
+        assert_eq!(zset.encoding(), "listpack");
+        zset.insert(2.0, vec![b'x'; value_max + 1]);
+        assert_eq!(zset.encoding(), "skiplist");
+
+        let entries_max = limit(&LIMITS.zset_max_listpack_entries);
+        let mut zset = ZSetState::new();
+        for i in 0..entries_max {
+            zset.insert(i as f64, format!("m{}", i).into_bytes());
+        }
+        zset.insert(-1.0, b"m0".to_vec());
+        assert_eq!(zset.encoding(), "listpack");
+        zset.insert(0.0, b"new".to_vec());
+        assert_eq!(zset.encoding(), "skiplist");
+        assert_eq!(zset.len(), entries_max + 1);
+        assert_eq!(zset.first(), Some((b"m0".to_vec(), -1.0)));
+    }
+
+    #[test]
+    fn matches_sorted_vec_across_conversion() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..20 {
+            let mut zset = ZSetState::new();
+            let mut model = Model::default();
+            for round in 0..600 {
+                let m = member(&mut rng);
+                if rng.gen_range(0..4) == 0 {
+                    assert_eq!(zset.remove_member(&m), model.remove_member(&m));
+                } else {
+                    let grows = model.0.iter().all(|(x, _)| *x != m);
+                    let expected = if zset.encoding() == "skiplist"
+                        || m.len() > limit(&LIMITS.zset_max_listpack_value)
+                        || (grows && model.0.len() >= limit(&LIMITS.zset_max_listpack_entries))
+                    {
+                        "skiplist"
+                    } else {
+                        "listpack"
+                    };
+                    let s = score(&mut rng);
+                    zset.insert(s, m.clone());
+                    model.insert(s, m.clone());
+                    assert_eq!(zset.encoding(), expected);
+                }
+                if round % 50 == 0 {
+                    assert_matches(&zset, &model);
+                }
+            }
+            assert_matches(&zset, &model);
+        }
+    }
+}